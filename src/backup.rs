@@ -0,0 +1,294 @@
+use crate::error::{Result, StauError};
+use crate::symlink;
+use std::fs;
+use std::os::unix::fs as unix_fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Directory inside the state directory that holds backups
+const BACKUP_DIR: &str = "backups";
+
+/// Format of the timestamp that starts every backup id
+const ID_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// Files moved aside during one stau run
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub id: String,
+    pub dir: PathBuf,
+}
+
+/// A single file (or symlink) kept in a backup
+#[derive(Debug, Clone)]
+pub struct BackupFile {
+    /// Where the file was before it was moved aside
+    pub original: PathBuf,
+    /// Where the backup copy is kept
+    pub stored: PathBuf,
+    pub size: u64,
+}
+
+/// Id of the backup used by this stau run: a timestamp plus the process id
+fn session_id() -> &'static str {
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        format!(
+            "{}-{}",
+            chrono::Local::now().format(ID_FORMAT),
+            std::process::id()
+        )
+    })
+}
+
+/// Move whatever is at a path into this run's backup and return where it went
+pub fn save(state_dir: &Path, path: &Path) -> Result<PathBuf> {
+    let relative = path.strip_prefix("/").unwrap_or(path);
+    let stored = state_dir.join(BACKUP_DIR).join(session_id()).join(relative);
+
+    if let Some(parent) = stored.parent() {
+        fs::create_dir_all(parent).map_err(StauError::Io)?;
+    }
+
+    match fs::rename(path, &stored) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_tree(path, &stored)?;
+            symlink::remove_existing(path, false)?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            return Err(StauError::PermissionDenied(format!(
+                "Cannot back up {}",
+                path.display()
+            )));
+        }
+        Err(e) => return Err(StauError::Io(e)),
+    }
+
+    Ok(stored)
+}
+
+/// Copy a file, symlink or directory tree without following symlinks
+fn copy_tree(source: &Path, dest: &Path) -> Result<()> {
+    let metadata = source.symlink_metadata().map_err(StauError::Io)?;
+    if metadata.is_symlink() {
+        let link = fs::read_link(source).map_err(StauError::Io)?;
+        unix_fs::symlink(link, dest).map_err(StauError::Io)
+    } else if metadata.is_dir() {
+        fs::create_dir_all(dest).map_err(StauError::Io)?;
+        for entry in fs::read_dir(source).map_err(StauError::Io)? {
+            let entry = entry.map_err(StauError::Io)?;
+            copy_tree(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        symlink::copy_file(source, dest, false)
+    }
+}
+
+/// List all backups, oldest first
+pub fn list(state_dir: &Path) -> Result<Vec<Backup>> {
+    let dir = state_dir.join(BACKUP_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&dir).map_err(StauError::Io)? {
+        let path = entry.map_err(StauError::Io)?.path();
+        if path.is_dir()
+            && let Some(id) = path.file_name()
+        {
+            backups.push(Backup {
+                id: id.to_string_lossy().into_owned(),
+                dir: path,
+            });
+        }
+    }
+    backups.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(backups)
+}
+
+/// Find a backup by id
+pub fn find(state_dir: &Path, id: &str) -> Result<Backup> {
+    list(state_dir)?
+        .into_iter()
+        .find(|backup| backup.id == id)
+        .ok_or_else(|| StauError::BackupNotFound(id.to_string()))
+}
+
+impl Backup {
+    /// When the backup was taken, read from its id
+    pub fn created(&self) -> Option<chrono::NaiveDateTime> {
+        let timestamp = self.id.get(..15)?;
+        chrono::NaiveDateTime::parse_from_str(timestamp, ID_FORMAT).ok()
+    }
+
+    /// Age of the backup, or None if its id has no valid timestamp
+    pub fn age(&self) -> Option<Duration> {
+        let created = self.created()?.and_local_timezone(chrono::Local).single()?;
+        (chrono::Local::now() - created).to_std().ok()
+    }
+
+    /// All files and symlinks kept in the backup, sorted by original path
+    pub fn files(&self) -> Result<Vec<BackupFile>> {
+        let mut files = Vec::new();
+        self.collect_files(&self.dir, &mut files)?;
+        files.sort_by(|a, b| a.original.cmp(&b.original));
+        Ok(files)
+    }
+
+    fn collect_files(&self, dir: &Path, files: &mut Vec<BackupFile>) -> Result<()> {
+        for entry in fs::read_dir(dir).map_err(StauError::Io)? {
+            let path = entry.map_err(StauError::Io)?.path();
+            let metadata = path.symlink_metadata().map_err(StauError::Io)?;
+            if metadata.is_dir() {
+                self.collect_files(&path, files)?;
+                continue;
+            }
+
+            let relative = path.strip_prefix(&self.dir).unwrap_or(&path);
+            files.push(BackupFile {
+                original: Path::new("/").join(relative),
+                stored: path.clone(),
+                size: metadata.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Total size of the files in the backup in bytes
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.files()?.iter().map(|file| file.size).sum())
+    }
+}
+
+/// Copy a backed-up file back to where it came from
+///
+/// Only a missing path or one of stau's own links (a symlink into
+/// `stau_dirs`) is replaced, unless `force` is given. The backup is kept.
+pub fn restore_file(
+    file: &BackupFile,
+    stau_dirs: &[PathBuf],
+    force: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if let Ok(metadata) = file.original.symlink_metadata() {
+        let ours = metadata.is_symlink()
+            && fs::read_link(&file.original)
+                .is_ok_and(|dest| stau_dirs.iter().any(|dir| dest.starts_with(dir)));
+        if !ours && !force {
+            return Err(StauError::ConflictingFile(file.original.clone()));
+        }
+        symlink::remove_existing(&file.original, dry_run)?;
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+    if let Some(parent) = file.original.parent() {
+        fs::create_dir_all(parent).map_err(StauError::Io)?;
+    }
+    copy_tree(&file.stored, &file.original)
+}
+
+/// Pick the backups to prune: those older than `max_age`, then the oldest
+/// ones until the rest fit into `max_size` bytes
+pub fn select_for_pruning(
+    backups: &[Backup],
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+) -> Result<Vec<Backup>> {
+    let mut prune = Vec::new();
+    let mut kept_size = 0;
+
+    // Newest first, so the size budget goes to the most recent backups
+    for backup in backups.iter().rev() {
+        let too_old = max_age.is_some_and(|max| backup.age().is_some_and(|age| age > max));
+        let size = backup.size()?;
+        let too_big = max_size.is_some_and(|max| kept_size + size > max);
+
+        if too_old || too_big {
+            prune.push(backup.clone());
+        } else {
+            kept_size += size;
+        }
+    }
+
+    prune.reverse();
+    Ok(prune)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_save_list_and_restore() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_dir = temp_dir.path().join("state");
+        let home = temp_dir.path().join("home");
+        fs::create_dir_all(home.join(".config")).unwrap();
+        let bashrc = home.join(".bashrc");
+        fs::write(&bashrc, "old bashrc").unwrap();
+
+        let stored = save(&state_dir, &bashrc).unwrap();
+        assert!(!bashrc.exists());
+        assert_eq!(fs::read_to_string(&stored).unwrap(), "old bashrc");
+
+        let backups = list(&state_dir).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].created().is_some());
+        let files = backups[0].files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].original, bashrc);
+        assert_eq!(backups[0].size().unwrap(), 10);
+
+        // Something else took its place; only --force replaces it
+        fs::write(&bashrc, "new bashrc").unwrap();
+        assert!(matches!(
+            restore_file(&files[0], &[], false, false).unwrap_err(),
+            StauError::ConflictingFile(_)
+        ));
+        restore_file(&files[0], &[], true, false).unwrap();
+        assert_eq!(fs::read_to_string(&bashrc).unwrap(), "old bashrc");
+        assert!(files[0].stored.exists());
+    }
+
+    #[test]
+    fn test_select_for_pruning() {
+        let temp_dir = TempDir::new().unwrap();
+        let make = |id: &str, bytes: usize| {
+            let dir = temp_dir.path().join(id);
+            fs::create_dir_all(dir.join("home")).unwrap();
+            fs::write(dir.join("home/file"), vec![b'x'; bytes]).unwrap();
+            Backup {
+                id: id.to_string(),
+                dir,
+            }
+        };
+        let backups = vec![
+            make("20200101-120000-1", 100),
+            make("20990101-120000-2", 100),
+            make("20990102-120000-3", 100),
+        ];
+
+        let ids = |pruned: Vec<Backup>| pruned.into_iter().map(|b| b.id).collect::<Vec<_>>();
+
+        let by_age = select_for_pruning(&backups, Some(Duration::from_secs(86400)), None).unwrap();
+        assert_eq!(ids(by_age), vec!["20200101-120000-1"]);
+
+        let by_size = select_for_pruning(&backups, None, Some(150)).unwrap();
+        assert_eq!(ids(by_size), vec!["20200101-120000-1", "20990101-120000-2"]);
+    }
+
+    #[test]
+    fn test_find_missing_backup() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(matches!(
+            find(temp_dir.path(), "nope").unwrap_err(),
+            StauError::BackupNotFound(_)
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::{Result, StauError};
+use crate::package::Discovery;
+use crate::symlink::SymlinkMapping;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Directory inside the state directory that holds the discovered files of packages
+const CACHE_DIR: &str = "discovery";
+
+/// The files discovered in a package, valid as long as none of the watched
+/// paths changed
+///
+/// Adding, removing or renaming a file changes the modification time of its
+/// directory, so the walked directories are watched along with the
+/// `.gitignore` files that may be in them. Checking those takes one `stat`
+/// per directory instead of reading every directory and matching every file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// What the files were discovered with: the layers, target and options
+    key: String,
+    watched: Vec<(PathBuf, Option<SystemTime>)>,
+    mappings: Vec<(PathBuf, PathBuf)>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn cache_path(state_dir: &Path, package: &str) -> PathBuf {
+    state_dir.join(CACHE_DIR).join(format!("{}.json", package))
+}
+
+/// The cached files of a package, if they were discovered with the same key
+/// and nothing they depend on changed since
+pub fn load(state_dir: &Path, package: &str, key: &str) -> Option<Vec<SymlinkMapping>> {
+    let contents = fs::read_to_string(cache_path(state_dir, package)).ok()?;
+    let entry: Entry = serde_json::from_str(&contents).ok()?;
+    let fresh = entry.key == key
+        && entry
+            .watched
+            .iter()
+            .all(|(path, time)| modified(path) == *time);
+    fresh.then(|| {
+        entry
+            .mappings
+            .into_iter()
+            .map(|(source, target)| SymlinkMapping::new(source, target))
+            .collect()
+    })
+}
+
+/// Cache what discovering a package found, replacing the cached entry atomically
+///
+/// `ignore_files` are watched along with the walked directories and the
+/// `.gitignore` files inside them.
+pub fn save(
+    state_dir: &Path,
+    package: &str,
+    key: &str,
+    discovery: &Discovery,
+    ignore_files: &[PathBuf],
+) -> Result<()> {
+    let mut watched = Vec::new();
+    for dir in &discovery.dirs {
+        watched.push((dir.clone(), modified(dir)));
+        let gitignore = dir.join(".gitignore");
+        watched.push((gitignore.clone(), modified(&gitignore)));
+    }
+    for path in ignore_files {
+        watched.push((path.clone(), modified(path)));
+    }
+    let entry = Entry {
+        key: key.to_string(),
+        watched,
+        mappings: discovery
+            .mappings
+            .iter()
+            .map(|m| (m.source.clone(), m.target.clone()))
+            .collect(),
+    };
+
+    let path = cache_path(state_dir, package);
+    let dir = state_dir.join(CACHE_DIR);
+    fs::create_dir_all(&dir).map_err(StauError::Io)?;
+    let contents = serde_json::to_string(&entry)
+        .map_err(|e| StauError::Other(format!("Cannot serialize discovery cache: {}", e)))?;
+    let tmp_path = dir.join(format!("{}.{}.tmp", package, std::process::id()));
+    fs::write(&tmp_path, contents).map_err(StauError::Io)?;
+    fs::rename(&tmp_path, &path).map_err(StauError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_until_something_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_dir = temp_dir.path().join("state");
+        let package_dir = temp_dir.path().join("vim");
+        fs::create_dir_all(package_dir.join(".vim")).unwrap();
+        fs::write(package_dir.join(".vimrc"), "").unwrap();
+
+        let discovery = Discovery {
+            mappings: vec![SymlinkMapping::new(
+                package_dir.join(".vimrc"),
+                PathBuf::from("/home/user/.vimrc"),
+            )],
+            dirs: vec![package_dir.clone(), package_dir.join(".vim")],
+            warned: false,
+        };
+        assert!(load(&state_dir, "vim", "key").is_none());
+        save(&state_dir, "vim", "key", &discovery, &[]).unwrap();
+
+        assert_eq!(load(&state_dir, "vim", "key"), Some(discovery.mappings));
+        assert!(load(&state_dir, "vim", "other key").is_none());
+
+        // A .gitignore appearing in a walked directory invalidates the entry
+        fs::write(package_dir.join(".vim/.gitignore"), "*.log\n").unwrap();
+        assert!(load(&state_dir, "vim", "key").is_none());
+    }
+}
@@ -1,55 +1,271 @@
+use crate::cache;
+use crate::conflict::{ConflictPolicy, Precedence};
 use crate::error::{Result, StauError};
+use crate::manifest::Manifest;
+use crate::package::{self, Discovery, DiscoveryOptions, PackageFiles};
+use crate::platform;
+use crate::script::ScriptKind;
+use crate::settings::{self, Settings};
+use crate::state::State;
+use crate::symlink::SymlinkMapping;
+use crate::verbosity;
+use std::collections::BTreeSet;
 use std::env;
-use std::path::PathBuf;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Directories inside a package that hold hook scripts and are never linked
+pub const HOOK_DIRS: [&str; 2] = ["scripts", ".stau"];
+
+/// Directory in STAU_DIR holding hooks that run for every package
+pub const REPO_HOOK_DIR: &str = ".stau";
+
+/// Where a configuration value came from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Source {
+    /// A command-line flag
+    Flag,
+    /// An environment variable
+    Env(&'static str),
+    /// The global config file
+    ConfigFile,
+    /// The built-in default
+    #[default]
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Flag => write!(f, "flag"),
+            Source::Env(name) => write!(f, "env {}", name),
+            Source::ConfigFile => write!(f, "config file"),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// Where each resolved configuration value came from
+#[derive(Debug, Clone, Default)]
+pub struct Provenance {
+    /// Path of the global config file that was read
+    pub config_file: PathBuf,
+    pub config_file_source: Source,
+    pub stau_dir: Source,
+    pub target: Source,
+    pub state_dir: Source,
+}
 
 /// Configuration for stau, handles STAU_DIR and STAU_TARGET environment variables
+///
+/// Values are resolved with the precedence: command-line flag (applied by
+/// `get_target`), environment variable, config file, built-in default.
 #[derive(Debug, Clone)]
 pub struct Config {
     /// Directory where dotfiles are stored (default: ~/dotfiles)
     pub stau_dir: PathBuf,
     /// Default target directory for symlinks (default: $HOME)
     pub default_target: PathBuf,
+    /// Directory for per-machine state (default: $XDG_STATE_HOME/stau)
+    pub state_dir: PathBuf,
+    /// Further package directories layered on top of stau_dir, in priority order
+    pub overlay_dirs: Vec<PathBuf>,
+    /// Whether install creates a missing target directory (config file only)
+    pub create_target: bool,
+    /// Whether adopt commits the adopted files (config file only)
+    pub adopt_commit: bool,
+    /// How existing files in the target are handled by default (config file only)
+    pub conflicts: ConflictPolicy,
+    /// Whether install and uninstall update the machines registry (config file only)
+    pub record_machines: bool,
+    /// Which package files are linked (config file and command line only)
+    pub discovery: DiscoveryOptions,
+    /// Which links of other packages are deferred to or taken over (command line only)
+    pub precedence: Precedence,
+    /// Where each of the values above came from
+    pub provenance: Provenance,
 }
 
 impl Config {
-    /// Create a new Config by reading environment variables
+    /// Create a new Config from environment variables and the global config file
+    ///
+    /// Environment variables take precedence over the config file.
     pub fn new() -> Result<Self> {
-        let stau_dir = Self::get_stau_dir()?;
-        let default_target = Self::get_default_target()?;
+        let (settings, provenance) = Self::load_settings()?;
+        let (mut stau_dirs, stau_dir_source) = Self::read_stau_dirs(&settings)?;
+        let stau_dir = stau_dirs.remove(0);
+        Self::build(settings, provenance, stau_dir, stau_dir_source, stau_dirs)
+    }
+
+    /// Create a Config from the loaded settings and the package directories
+    fn build(
+        settings: Settings,
+        mut provenance: Provenance,
+        stau_dir: PathBuf,
+        stau_dir_source: Source,
+        overlay_dirs: Vec<PathBuf>,
+    ) -> Result<Self> {
+        let (default_target, target_source) = Self::get_default_target(&settings)?;
+        let (state_dir, state_dir_source) = Self::get_state_dir(&settings)?;
+
+        provenance.stau_dir = stau_dir_source;
+        provenance.target = target_source;
+        provenance.state_dir = state_dir_source;
 
         Ok(Config {
             stau_dir,
             default_target,
+            state_dir,
+            overlay_dirs,
+            create_target: settings.create_target.unwrap_or(false),
+            adopt_commit: settings.adopt_commit.unwrap_or(false),
+            conflicts: settings.conflicts.unwrap_or_default(),
+            record_machines: settings.machines.unwrap_or(false),
+            discovery: DiscoveryOptions {
+                gitignore: settings.gitignore.unwrap_or(true),
+                skip: settings.skip.clone().unwrap_or_default(),
+                junk: settings
+                    .junk
+                    .clone()
+                    .unwrap_or_else(|| DiscoveryOptions::default().junk),
+                max_depth: None,
+                follow_symlinks: false,
+                targets: Vec::new(),
+                fold: Vec::new(),
+            },
+            precedence: Precedence::default(),
+            provenance,
         })
     }
 
-    /// Get STAU_DIR from environment or use default ~/dotfiles
-    fn get_stau_dir() -> Result<PathBuf> {
-        if let Ok(dir) = env::var("STAU_DIR") {
-            let path = PathBuf::from(dir);
-            if path.exists() {
-                Ok(path)
+    /// Load the global config file, recording where its path came from
+    fn load_settings() -> Result<(Settings, Provenance)> {
+        let config_file = Settings::path()?;
+        let provenance = Provenance {
+            config_file_source: if env::var_os("STAU_CONFIG").is_some_and(|p| !p.is_empty()) {
+                Source::Env("STAU_CONFIG")
             } else {
-                Err(StauError::StauDirNotFound(path))
+                Source::Default
+            },
+            config_file: config_file.clone(),
+            ..Provenance::default()
+        };
+        Ok((Settings::load(&config_file)?, provenance))
+    }
+
+    /// Create a Config for a standalone package directory outside STAU_DIR
+    ///
+    /// The package's parent directory stands in for STAU_DIR, so the package
+    /// can be handled like any other. Returns the config and the package name.
+    pub fn for_package_dir(package_dir: &Path) -> Result<(Self, String)> {
+        let package_dir = package_dir
+            .canonicalize()
+            .map_err(|_| StauError::PackageNotFound(package_dir.display().to_string()))?;
+        if !package_dir.is_dir() {
+            return Err(StauError::PackageNotFound(
+                package_dir.display().to_string(),
+            ));
+        }
+
+        let (Some(parent), Some(name)) = (package_dir.parent(), package_dir.file_name()) else {
+            return Err(StauError::InvalidPath(package_dir));
+        };
+
+        let config = Self::with_stau_dir(parent)?;
+        Ok((config, name.to_string_lossy().into_owned()))
+    }
+
+    /// Create a Config for an explicitly given STAU_DIR, ignoring overlays
+    pub fn with_stau_dir(stau_dir: &Path) -> Result<Self> {
+        let (settings, provenance) = Self::load_settings()?;
+        Self::build(
+            settings,
+            provenance,
+            stau_dir.to_path_buf(),
+            Source::Flag,
+            Vec::new(),
+        )
+    }
+
+    /// Get where STAU_DIR is configured to be, whether or not it exists yet
+    ///
+    /// With several directories in STAU_DIR, this is the first one.
+    pub fn stau_dir_location() -> Result<PathBuf> {
+        let (settings, _) = Self::load_settings()?;
+        let configured = env::var_os("STAU_DIR")
+            .or_else(|| settings.stau_dir.map(|dir| dir.into_os_string()))
+            .and_then(|dirs| env::split_paths(&dirs).find(|path| !path.as_os_str().is_empty()));
+        match configured {
+            Some(dir) => Ok(dir),
+            None => Ok(Self::get_home_dir()?.join("dotfiles")),
+        }
+    }
+
+    /// Get STAU_DIR from environment or the config file, or use default ~/dotfiles
+    ///
+    /// STAU_DIR may hold several directories separated like PATH; later
+    /// directories overlay earlier ones.
+    fn read_stau_dirs(settings: &Settings) -> Result<(Vec<PathBuf>, Source)> {
+        let dirs = env::var_os("STAU_DIR")
+            .map(|dirs| (dirs, Source::Env("STAU_DIR")))
+            .or_else(|| {
+                settings
+                    .stau_dir
+                    .as_ref()
+                    .map(|dir| (dir.as_os_str().to_os_string(), Source::ConfigFile))
+            });
+        if let Some((dirs, source)) = dirs {
+            let paths: Vec<PathBuf> = env::split_paths(&dirs)
+                .filter(|path| !path.as_os_str().is_empty())
+                .collect();
+            if paths.is_empty() {
+                return Err(StauError::StauDirNotFound(PathBuf::new()));
+            }
+            if let Some(missing) = paths.iter().find(|path| !path.exists()) {
+                return Err(StauError::StauDirNotFound(missing.clone()));
             }
+            Ok((paths, source))
         } else {
             // Default to ~/dotfiles
             let home = Self::get_home_dir()?;
             let dotfiles = home.join("dotfiles");
             if dotfiles.exists() {
-                Ok(dotfiles)
+                Ok((vec![dotfiles], Source::Default))
             } else {
                 Err(StauError::StauDirNotFound(dotfiles))
             }
         }
     }
 
-    /// Get default target directory from STAU_TARGET or use $HOME
-    fn get_default_target() -> Result<PathBuf> {
+    /// Get default target directory from STAU_TARGET or the config file, or use $HOME
+    fn get_default_target(settings: &Settings) -> Result<(PathBuf, Source)> {
         if let Ok(target) = env::var("STAU_TARGET") {
-            Ok(PathBuf::from(target))
+            Ok((PathBuf::from(target), Source::Env("STAU_TARGET")))
+        } else if let Some(target) = &settings.target {
+            Ok((target.clone(), Source::ConfigFile))
         } else {
-            Self::get_home_dir()
+            Ok((Self::get_home_dir()?, Source::Default))
+        }
+    }
+
+    /// Get the state directory from STAU_STATE_DIR, config file, XDG_STATE_HOME or ~/.local/state
+    fn get_state_dir(settings: &Settings) -> Result<(PathBuf, Source)> {
+        if let Ok(dir) = env::var("STAU_STATE_DIR") {
+            return Ok((PathBuf::from(dir), Source::Env("STAU_STATE_DIR")));
+        }
+        if let Some(dir) = &settings.state_dir {
+            return Ok((dir.clone(), Source::ConfigFile));
+        }
+
+        match env::var("XDG_STATE_HOME") {
+            Ok(dir) if !dir.is_empty() => Ok((
+                PathBuf::from(dir).join("stau"),
+                Source::Env("XDG_STATE_HOME"),
+            )),
+            _ => Ok((
+                Self::get_home_dir()?.join(".local/state/stau"),
+                Source::Default,
+            )),
         }
     }
 
@@ -65,37 +281,430 @@ impl Config {
         override_target.unwrap_or_else(|| self.default_target.clone())
     }
 
+    /// Get all package directories: stau_dir followed by the overlays
+    pub fn get_stau_dirs(&self) -> Vec<PathBuf> {
+        std::iter::once(&self.stau_dir)
+            .chain(&self.overlay_dirs)
+            .cloned()
+            .collect()
+    }
+
     /// Get the package directory path
+    ///
+    /// This is the first layer containing the package, or the path it would
+    /// have in stau_dir if no layer does.
     pub fn get_package_dir(&self, package: &str) -> PathBuf {
-        self.stau_dir.join(package)
+        self.get_package_layers(package)
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| self.stau_dir.join(package))
+    }
+
+    /// Get every existing directory of a package, lowest priority first
+    pub fn get_package_layers(&self, package: &str) -> Vec<PathBuf> {
+        self.get_stau_dirs()
+            .into_iter()
+            .map(|dir| dir.join(package))
+            .filter(|dir| dir.exists())
+            .collect()
+    }
+
+    /// Get a package's manifest, merged across overlay layers
+    pub fn get_manifest(&self, package: &str) -> Result<Manifest> {
+        Manifest::load_layers(&self.get_package_layers(package))
+    }
+
+    /// Get the conflict policy for a package
+    ///
+    /// A policy given on the command line wins over the package's manifest,
+    /// which wins over the global setting.
+    pub fn conflict_policy(
+        &self,
+        package: &str,
+        cli: Option<ConflictPolicy>,
+    ) -> Result<ConflictPolicy> {
+        if let Some(policy) = cli {
+            return Ok(policy);
+        }
+        Ok(self
+            .get_manifest(package)?
+            .conflicts
+            .unwrap_or(self.conflicts))
+    }
+
+    /// Discover the files of a package across all its layers
+    ///
+    /// Files the package's manifest lists in `skip` are left out along with
+    /// those of the global setting. A `max_depth` given on the command line
+    /// wins over the one the package was linked with, which wins over the
+    /// manifest's. Symlinked directories are followed if the package was
+    /// linked that way or it's asked for now. Subdirectories listed in its
+    /// `targets` are linked below their own destinations.
+    ///
+    /// The result is cached in the state directory until something in the
+    /// package changes, except when files were warned about or `-vv` asks
+    /// for the decision about each file.
+    pub fn discover(&self, package: &str, target_dir: &Path) -> Result<Vec<SymlinkMapping>> {
+        self.package_files(package, target_dir)?.collect()
+    }
+
+    /// Discover the files of a package like `discover`, one at a time
+    ///
+    /// A package with a single layer is walked as the files are asked for;
+    /// the files of overlaid packages are only known once every layer was
+    /// walked. The files are cached once all of them were found.
+    pub fn package_files(
+        &self,
+        package: &str,
+        target_dir: &Path,
+    ) -> Result<Box<dyn Iterator<Item = Result<SymlinkMapping>> + '_>> {
+        let span = tracing::info_span!("discover", package);
+        let entered = span.enter();
+        let options = self.discovery_options(package, target_dir)?;
+        let layers = self.get_package_layers(package);
+
+        let key = format!("{:?}", (&layers, target_dir, &options));
+        if !verbosity::shows_detail()
+            && let Some(mappings) = cache::load(&self.state_dir, package, &key)
+        {
+            return Ok(Box::new(mappings.into_iter().map(Ok)));
+        }
+
+        if let [layer] = layers.as_slice() {
+            let files = package::walk_package_files(layer, target_dir, &options)?;
+            drop(entered);
+            return Ok(Box::new(Discovering {
+                config: self,
+                package: package.to_string(),
+                key,
+                layers,
+                files,
+                found: Some(Vec::new()),
+                span,
+            }));
+        }
+
+        let discovery = package::discover_overlay_files(&layers, target_dir, &options)?;
+        self.cache_discovery(package, &key, &layers, &discovery);
+        Ok(Box::new(discovery.mappings.into_iter().map(Ok)))
+    }
+
+    /// Cache what discovering a package found, unless files were warned about
+    fn cache_discovery(&self, package: &str, key: &str, layers: &[PathBuf], discovery: &Discovery) {
+        if discovery.warned {
+            return;
+        }
+        // The dotfiles directory's .gitignore applies to the package too
+        let ignore_files: Vec<PathBuf> = layers
+            .iter()
+            .filter_map(|layer| Some(layer.parent()?.join(".gitignore")))
+            .collect();
+        // The cache only saves time, so a state directory that can't be
+        // written to is no reason to fail
+        let _ = cache::save(&self.state_dir, package, key, discovery, &ignore_files);
+    }
+
+    /// Where a path in a package is linked, following the manifest's `targets`
+    pub fn link_target(
+        &self,
+        package: &str,
+        rel_path: &Path,
+        target_dir: &Path,
+    ) -> Result<PathBuf> {
+        let options = self.discovery_options(package, target_dir)?;
+        Ok(package::target_path(target_dir, rel_path, &options.targets))
+    }
+
+    /// The discovery options for a package, with its manifest merged in
+    fn discovery_options(&self, package: &str, target_dir: &Path) -> Result<DiscoveryOptions> {
+        let manifest = self.get_manifest(package)?;
+        let linked = State::load(&self.state_dir)?.link_options(package, target_dir);
+        let mut options = self.discovery.clone();
+        options.skip.extend(manifest.skip);
+        options.max_depth = options
+            .max_depth
+            .or(linked.max_depth)
+            .or(manifest.max_depth);
+        options.follow_symlinks |= linked.follow_symlinks;
+        for (dir, dest) in manifest.targets {
+            let inside_package = dir
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)));
+            if !inside_package || dir.as_os_str().is_empty() {
+                return Err(StauError::InvalidConfig(format!(
+                    "target section '{}' of package '{}' must be a subdirectory of the package",
+                    dir.display(),
+                    package
+                )));
+            }
+            let dest = settings::expand_home(&dest)?;
+            options.targets.push((dir, target_dir.join(dest)));
+        }
+        for dir in manifest.fold {
+            let inside_package = dir
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)));
+            if !inside_package || dir.as_os_str().is_empty() {
+                return Err(StauError::InvalidConfig(format!(
+                    "folded directory '{}' of package '{}' must be a subdirectory of the package",
+                    dir.display(),
+                    package
+                )));
+            }
+            options.fold.push(dir);
+        }
+        Ok(options)
+    }
+
+    /// Remember the discovery options given on the command line for a package
+    /// linked into a target, so later commands find the same links
+    pub fn remember_link_options(&self, package: &str, target_dir: &Path) -> Result<()> {
+        let DiscoveryOptions {
+            max_depth,
+            follow_symlinks,
+            ..
+        } = self.discovery;
+        if max_depth.is_none() && !follow_symlinks {
+            return Ok(());
+        }
+        State::update(&self.state_dir, |state| {
+            let linked = state
+                .link_options
+                .entry(package.to_string())
+                .or_default()
+                .entry(target_dir.to_path_buf())
+                .or_default();
+            linked.max_depth = max_depth.or(linked.max_depth);
+            linked.follow_symlinks |= follow_symlinks;
+        })
+    }
+
+    /// Remember the directories a package links into within a target, so a
+    /// restow still finds the links there once the package has no files left in them
+    pub fn remember_link_dirs(
+        &self,
+        package: &str,
+        target_dir: &Path,
+        mappings: &[SymlinkMapping],
+    ) -> Result<()> {
+        let known = State::load(&self.state_dir)?.link_dirs(package, target_dir);
+        let dirs: BTreeSet<PathBuf> = mappings
+            .iter()
+            .filter_map(|m| m.target.parent())
+            .filter(|dir| !known.contains(*dir))
+            .map(Path::to_path_buf)
+            .collect();
+        if dirs.is_empty() {
+            return Ok(());
+        }
+        State::update(&self.state_dir, |state| {
+            state
+                .link_dirs
+                .entry(package.to_string())
+                .or_default()
+                .entry(target_dir.to_path_buf())
+                .or_default()
+                .extend(dirs);
+        })
+    }
+
+    /// The directories a package has had links in within a target
+    pub fn link_dirs(&self, package: &str, target_dir: &Path) -> Result<BTreeSet<PathBuf>> {
+        Ok(State::load(&self.state_dir)?.link_dirs(package, target_dir))
+    }
+
+    /// Forget the options a package was linked into a target with, and the
+    /// directories it linked into
+    pub fn forget_link_options(&self, package: &str, target_dir: &Path) -> Result<()> {
+        let state = State::load(&self.state_dir)?;
+        if state.link_options(package, target_dir) == Default::default()
+            && state.link_dirs(package, target_dir).is_empty()
+        {
+            return Ok(());
+        }
+        State::update(&self.state_dir, |state| {
+            if let Some(targets) = state.link_options.get_mut(package) {
+                targets.remove(target_dir);
+                if targets.is_empty() {
+                    state.link_options.remove(package);
+                }
+            }
+            if let Some(targets) = state.link_dirs.get_mut(package) {
+                targets.remove(target_dir);
+                if targets.is_empty() {
+                    state.link_dirs.remove(package);
+                }
+            }
+        })
     }
 
     /// Check if a package exists
     pub fn package_exists(&self, package: &str) -> bool {
-        self.get_package_dir(package).exists()
+        !self.get_package_layers(package).is_empty()
     }
 
-    /// Get the setup script path for a package
-    pub fn get_setup_script(&self, package: &str) -> Option<PathBuf> {
-        let script_path = self.get_package_dir(package).join("setup.sh");
-        if script_path.exists() && script_path.is_file() {
-            Some(script_path)
-        } else {
-            None
+    /// Get all scripts for a hook in execution order
+    ///
+    /// Scripts are looked up in the package root (setup/teardown only), then in
+    /// the `scripts/` and `.stau/` directories. In each location the generic
+    /// `<hook>.sh` comes first, followed by the script matching the current OS
+    /// (e.g. `setup.linux.sh`) and the host-specific `<hook>.<hostname>.sh` and
+    /// `<hook>.d/<hostname>.sh`. Teardown-style hooks run in reverse order.
+    pub fn get_hook_scripts(&self, package: &str, kind: ScriptKind) -> Vec<PathBuf> {
+        let mut locations = Vec::new();
+        if kind.allowed_in_root() {
+            locations.push("");
         }
+        locations.extend(HOOK_DIRS);
+
+        let mut scripts: Vec<PathBuf> = locations
+            .iter()
+            .flat_map(|dir| self.get_script_variants(package, dir, kind.base_name()))
+            .collect();
+
+        if kind.runs_reversed() {
+            scripts.reverse();
+        }
+        scripts
     }
 
-    /// Get the teardown script path for a package
-    pub fn get_teardown_script(&self, package: &str) -> Option<PathBuf> {
-        let script_path = self.get_package_dir(package).join("teardown.sh");
-        if script_path.exists() && script_path.is_file() {
-            Some(script_path)
-        } else {
-            None
+    /// Get all repository-wide scripts for a hook in execution order
+    ///
+    /// These live in `.stau/` in STAU_DIR and have the same OS- and
+    /// host-specific variants as package hooks.
+    pub fn get_repo_hook_scripts(&self, kind: ScriptKind) -> Vec<PathBuf> {
+        let dir = self.stau_dir.join(REPO_HOOK_DIR);
+        let mut scripts: Vec<PathBuf> = script_names(kind.base_name())
+            .iter()
+            .map(|name| dir.join(name))
+            .filter(|path| path.is_file())
+            .collect();
+
+        if kind.runs_reversed() {
+            scripts.reverse();
+        }
+        scripts
+    }
+
+    /// Get the path of a named package script (`scripts/<name>.sh`)
+    pub fn get_named_script(&self, package: &str, name: &str) -> Option<PathBuf> {
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return None;
         }
+        self.get_package_file(package, &format!("scripts/{}.sh", name))
+    }
+
+    /// List the names of all scripts in a package's scripts/ directories
+    pub fn list_named_scripts(&self, package: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for layer in self.get_package_layers(package) {
+            let scripts_dir = layer.join("scripts");
+            if !scripts_dir.is_dir() {
+                continue;
+            }
+
+            for entry in std::fs::read_dir(&scripts_dir).map_err(StauError::Io)? {
+                let path = entry.map_err(StauError::Io)?.path();
+                if path.is_file()
+                    && path.extension().is_some_and(|ext| ext == "sh")
+                    && let Some(stem) = path.file_stem()
+                {
+                    names.push(stem.to_string_lossy().to_string());
+                }
+            }
+        }
+
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Find the generic, OS-specific and host-specific variants of a script in a directory
+    fn get_script_variants(&self, package: &str, dir: &str, base: &str) -> Vec<PathBuf> {
+        script_names(base)
+            .iter()
+            .map(|name| {
+                if dir.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}/{}", dir, name)
+                }
+            })
+            .filter_map(|name| self.get_package_file(package, &name))
+            .collect()
+    }
+
+    /// Get a file path relative to the package root if it exists as a file
+    ///
+    /// With overlays, the file from the highest-priority layer wins.
+    pub fn get_package_file(&self, package: &str, file_name: &str) -> Option<PathBuf> {
+        self.get_package_layers(package)
+            .into_iter()
+            .rev()
+            .map(|layer| layer.join(file_name))
+            .find(|script_path| script_path.is_file())
     }
 }
 
+/// The files of a package with a single layer as it is walked, cached once
+/// the walk is done
+struct Discovering<'a> {
+    config: &'a Config,
+    package: String,
+    key: String,
+    layers: Vec<PathBuf>,
+    files: PackageFiles,
+    /// What was found so far, until it is cached or the walk failed
+    found: Option<Vec<SymlinkMapping>>,
+    span: tracing::Span,
+}
+
+impl Iterator for Discovering<'_> {
+    type Item = Result<SymlinkMapping>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let _entered = self.span.enter();
+        match self.files.next() {
+            Some(Ok(mapping)) => {
+                if let Some(found) = &mut self.found {
+                    found.push(mapping.clone());
+                }
+                Some(Ok(mapping))
+            }
+            Some(Err(e)) => {
+                self.found = None;
+                Some(Err(e))
+            }
+            None => {
+                if let Some(mappings) = self.found.take() {
+                    let discovery = Discovery {
+                        mappings,
+                        dirs: std::mem::take(&mut self.files.dirs),
+                        warned: self.files.warned,
+                    };
+                    self.config
+                        .cache_discovery(&self.package, &self.key, &self.layers, &discovery);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// File names of the generic, OS-specific and host-specific variants of a script
+fn script_names(base: &str) -> Vec<String> {
+    let mut names = vec![
+        format!("{}.sh", base),
+        format!("{}.{}.sh", base, platform::os()),
+    ];
+    if let Some(host) = platform::hostname() {
+        names.push(format!("{}.{}.sh", base, host));
+        names.push(format!("{}.d/{}.sh", base, host));
+    }
+    names.dedup();
+    names
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -115,6 +724,54 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_config_for_package_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("checkout/vim");
+        fs::create_dir_all(&package_dir).unwrap();
+
+        temp_env::with_var("STAU_DIR", None::<&str>, || {
+            let (config, package) = Config::for_package_dir(&package_dir).unwrap();
+            assert_eq!(package, "vim");
+            assert_eq!(
+                config.get_package_dir(&package),
+                package_dir.canonicalize().unwrap()
+            );
+        });
+
+        let missing = Config::for_package_dir(&temp_dir.path().join("missing"));
+        assert!(matches!(
+            missing.unwrap_err(),
+            StauError::PackageNotFound(_)
+        ));
+    }
+
+    #[test]
+    fn test_config_with_overlay_dirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let personal = temp_dir.path().join("dotfiles");
+        let work = temp_dir.path().join("work");
+        fs::create_dir_all(personal.join("zsh")).unwrap();
+        fs::create_dir_all(work.join("zsh")).unwrap();
+        fs::write(personal.join("zsh/setup.sh"), "#!/bin/sh").unwrap();
+        fs::write(work.join("zsh/setup.sh"), "#!/bin/sh").unwrap();
+
+        let stau_dirs = env::join_paths([&personal, &work]).unwrap();
+        temp_env::with_var("STAU_DIR", Some(&stau_dirs), || {
+            let config = Config::new().unwrap();
+            assert_eq!(config.stau_dir, personal);
+            assert_eq!(config.overlay_dirs, vec![work.clone()]);
+
+            assert_eq!(config.get_package_dir("zsh"), personal.join("zsh"));
+            assert_eq!(config.get_package_layers("zsh").len(), 2);
+            // The overlay's copy of a script wins
+            assert_eq!(
+                config.get_hook_scripts("zsh", ScriptKind::Setup),
+                vec![work.join("zsh/setup.sh")]
+            );
+        });
+    }
+
     #[test]
     fn test_config_stau_dir_not_found() {
         let temp_dir = TempDir::new().unwrap();
@@ -127,6 +784,38 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_stau_dir_location() {
+        let temp_dir = TempDir::new().unwrap();
+        let missing = temp_dir.path().join("missing");
+        let config_file = temp_dir.path().join("config.toml");
+        let stau_dirs = env::join_paths([&missing, &temp_dir.path().to_path_buf()]).unwrap();
+
+        temp_env::with_vars(
+            vec![
+                ("STAU_CONFIG", Some(config_file.as_os_str())),
+                ("STAU_DIR", Some(stau_dirs.as_os_str())),
+            ],
+            || {
+                assert_eq!(Config::stau_dir_location().unwrap(), missing);
+            },
+        );
+
+        temp_env::with_vars(
+            vec![
+                ("STAU_CONFIG", Some(config_file.as_os_str())),
+                ("STAU_DIR", None),
+                ("HOME", Some(temp_dir.path().as_os_str())),
+            ],
+            || {
+                assert_eq!(
+                    Config::stau_dir_location().unwrap(),
+                    temp_dir.path().join("dotfiles")
+                );
+            },
+        );
+    }
+
     #[test]
     fn test_config_with_stau_target_env() {
         let temp_dir = TempDir::new().unwrap();
@@ -146,6 +835,88 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_config_with_stau_state_dir_env() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path().join("dotfiles");
+        let state_dir = temp_dir.path().join("state");
+        fs::create_dir(&stau_dir).unwrap();
+
+        temp_env::with_vars(
+            vec![
+                ("STAU_DIR", Some(stau_dir.to_str().unwrap())),
+                ("STAU_STATE_DIR", Some(state_dir.to_str().unwrap())),
+            ],
+            || {
+                let config = Config::new().unwrap();
+                assert_eq!(config.state_dir, state_dir);
+            },
+        );
+
+        temp_env::with_vars(
+            vec![
+                ("STAU_DIR", Some(stau_dir.to_str().unwrap())),
+                ("STAU_STATE_DIR", None),
+                ("XDG_STATE_HOME", Some(state_dir.to_str().unwrap())),
+            ],
+            || {
+                let config = Config::new().unwrap();
+                assert_eq!(config.state_dir, state_dir.join("stau"));
+            },
+        );
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path().join("dotfiles");
+        let config_file = temp_dir.path().join("config.toml");
+        fs::create_dir(&stau_dir).unwrap();
+        fs::write(
+            &config_file,
+            format!(
+                "stau_dir = {:?}\ntarget = \"/from/file\"\n",
+                stau_dir.to_str().unwrap()
+            ),
+        )
+        .unwrap();
+
+        // The config file is used when no environment variable is set
+        temp_env::with_vars(
+            vec![
+                ("STAU_CONFIG", Some(config_file.to_str().unwrap())),
+                ("STAU_DIR", None),
+                ("STAU_TARGET", None),
+            ],
+            || {
+                let config = Config::new().unwrap();
+                assert_eq!(config.stau_dir, stau_dir);
+                assert_eq!(config.provenance.stau_dir, Source::ConfigFile);
+                assert_eq!(config.default_target, PathBuf::from("/from/file"));
+                assert_eq!(config.provenance.target, Source::ConfigFile);
+                assert_eq!(config.provenance.config_file, config_file);
+                assert_eq!(
+                    config.provenance.config_file_source,
+                    Source::Env("STAU_CONFIG")
+                );
+            },
+        );
+
+        // Environment variables take precedence over the config file
+        temp_env::with_vars(
+            vec![
+                ("STAU_CONFIG", Some(config_file.to_str().unwrap())),
+                ("STAU_DIR", None),
+                ("STAU_TARGET", Some("/from/env")),
+            ],
+            || {
+                let config = Config::new().unwrap();
+                assert_eq!(config.default_target, PathBuf::from("/from/env"));
+                assert_eq!(config.provenance.target, Source::Env("STAU_TARGET"));
+            },
+        );
+    }
+
     #[test]
     fn test_get_target_with_override() {
         let temp_dir = TempDir::new().unwrap();
@@ -158,6 +929,15 @@ mod tests {
         let config = Config {
             stau_dir,
             default_target: default_target.clone(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
         };
 
         // With override
@@ -169,6 +949,55 @@ mod tests {
         assert_eq!(target, default_target);
     }
 
+    #[test]
+    fn test_discover_is_cached_until_package_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path().join("dotfiles");
+        let target_dir = temp_dir.path().join("home");
+        fs::create_dir_all(stau_dir.join("vim/.vim")).unwrap();
+        fs::write(stau_dir.join("vim/.vimrc"), "").unwrap();
+
+        let config = Config {
+            stau_dir: stau_dir.clone(),
+            default_target: target_dir.clone(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
+        };
+        let targets = || {
+            let mut targets: Vec<PathBuf> = config
+                .discover("vim", &target_dir)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.target)
+                .collect();
+            targets.sort();
+            targets
+        };
+
+        assert_eq!(targets(), [target_dir.join(".vimrc")]);
+        assert!(temp_dir.path().join("state/discovery/vim.json").is_file());
+        assert_eq!(targets(), [target_dir.join(".vimrc")]);
+
+        fs::write(stau_dir.join("vim/.vim/filetype.vim"), "").unwrap();
+        assert_eq!(
+            targets(),
+            [
+                target_dir.join(".vim/filetype.vim"),
+                target_dir.join(".vimrc")
+            ]
+        );
+
+        fs::write(stau_dir.join(".gitignore"), "filetype.vim\n").unwrap();
+        assert_eq!(targets(), [target_dir.join(".vimrc")]);
+    }
+
     #[test]
     fn test_get_package_dir() {
         let temp_dir = TempDir::new().unwrap();
@@ -178,6 +1007,15 @@ mod tests {
         let config = Config {
             stau_dir: stau_dir.clone(),
             default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
         };
 
         let package_dir = config.get_package_dir("vim");
@@ -197,6 +1035,15 @@ mod tests {
         let config = Config {
             stau_dir: stau_dir.clone(),
             default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
         };
 
         assert!(config.package_exists("vim"));
@@ -219,16 +1066,24 @@ mod tests {
         let config = Config {
             stau_dir: stau_dir.clone(),
             default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
         };
 
         // Package with setup script
-        let script = config.get_setup_script("vim");
-        assert!(script.is_some());
-        assert_eq!(script.unwrap(), setup_script);
+        let scripts = config.get_hook_scripts("vim", ScriptKind::Setup);
+        assert_eq!(scripts, vec![setup_script]);
 
         // Package without setup script
-        let script = config.get_setup_script("git");
-        assert!(script.is_none());
+        let scripts = config.get_hook_scripts("git", ScriptKind::Setup);
+        assert!(scripts.is_empty());
     }
 
     #[test]
@@ -247,16 +1102,238 @@ mod tests {
         let config = Config {
             stau_dir: stau_dir.clone(),
             default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
         };
 
         // Package with teardown script
-        let script = config.get_teardown_script("vim");
-        assert!(script.is_some());
-        assert_eq!(script.unwrap(), teardown_script);
+        let scripts = config.get_hook_scripts("vim", ScriptKind::Teardown);
+        assert_eq!(scripts, vec![teardown_script]);
 
         // Package without teardown script
-        let script = config.get_teardown_script("git");
-        assert!(script.is_none());
+        let scripts = config.get_hook_scripts("git", ScriptKind::Teardown);
+        assert!(scripts.is_empty());
+    }
+
+    #[test]
+    fn test_get_os_specific_scripts() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path().join("dotfiles");
+        let vim_dir = stau_dir.join("vim");
+        fs::create_dir_all(&vim_dir).unwrap();
+
+        let os = env::consts::OS;
+        for name in [
+            "setup.sh".to_string(),
+            format!("setup.{}.sh", os),
+            "setup.plan9.sh".to_string(),
+            "teardown.sh".to_string(),
+            format!("teardown.{}.sh", os),
+        ] {
+            fs::write(vim_dir.join(name), "#!/bin/bash\n").unwrap();
+        }
+
+        let config = Config {
+            stau_dir: stau_dir.clone(),
+            default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
+        };
+
+        assert_eq!(
+            config.get_hook_scripts("vim", ScriptKind::Setup),
+            vec![
+                vim_dir.join("setup.sh"),
+                vim_dir.join(format!("setup.{}.sh", os))
+            ]
+        );
+        assert_eq!(
+            config.get_hook_scripts("vim", ScriptKind::Teardown),
+            vec![
+                vim_dir.join(format!("teardown.{}.sh", os)),
+                vim_dir.join("teardown.sh")
+            ]
+        );
+        assert!(config.get_hook_scripts("git", ScriptKind::Setup).is_empty());
+    }
+
+    #[test]
+    fn test_get_host_specific_scripts() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path().join("dotfiles");
+        let vim_dir = stau_dir.join("vim");
+        fs::create_dir_all(vim_dir.join("setup.d")).unwrap();
+
+        let Some(host) = platform::hostname() else {
+            return;
+        };
+
+        fs::write(vim_dir.join("setup.sh"), "#!/bin/bash\n").unwrap();
+        fs::write(vim_dir.join(format!("setup.{}.sh", host)), "#!/bin/bash\n").unwrap();
+        fs::write(
+            vim_dir.join(format!("setup.d/{}.sh", host)),
+            "#!/bin/bash\n",
+        )
+        .unwrap();
+        fs::write(vim_dir.join("setup.d/other-machine.sh"), "#!/bin/bash\n").unwrap();
+
+        let config = Config {
+            stau_dir: stau_dir.clone(),
+            default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
+        };
+
+        assert_eq!(
+            config.get_hook_scripts("vim", ScriptKind::Setup),
+            vec![
+                vim_dir.join("setup.sh"),
+                vim_dir.join(format!("setup.{}.sh", host)),
+                vim_dir.join(format!("setup.d/{}.sh", host)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_hook_scripts_from_hook_dirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path().join("dotfiles");
+        let vim_dir = stau_dir.join("vim");
+        fs::create_dir_all(vim_dir.join("scripts")).unwrap();
+        fs::create_dir_all(vim_dir.join(".stau")).unwrap();
+
+        fs::write(vim_dir.join("setup.sh"), "#!/bin/bash\n").unwrap();
+        fs::write(vim_dir.join("scripts/setup.sh"), "#!/bin/bash\n").unwrap();
+        fs::write(vim_dir.join(".stau/pre-install.sh"), "#!/bin/bash\n").unwrap();
+        fs::write(vim_dir.join("pre-install.sh"), "#!/bin/bash\n").unwrap();
+        fs::write(vim_dir.join("scripts/post-uninstall.sh"), "#!/bin/bash\n").unwrap();
+        fs::write(vim_dir.join(".stau/post-uninstall.sh"), "#!/bin/bash\n").unwrap();
+
+        let config = Config {
+            stau_dir: stau_dir.clone(),
+            default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
+        };
+
+        assert_eq!(
+            config.get_hook_scripts("vim", ScriptKind::Setup),
+            vec![vim_dir.join("setup.sh"), vim_dir.join("scripts/setup.sh")]
+        );
+        // New hook types are not looked up in the package root
+        assert_eq!(
+            config.get_hook_scripts("vim", ScriptKind::PreInstall),
+            vec![vim_dir.join(".stau/pre-install.sh")]
+        );
+        assert_eq!(
+            config.get_hook_scripts("vim", ScriptKind::PostUninstall),
+            vec![
+                vim_dir.join(".stau/post-uninstall.sh"),
+                vim_dir.join("scripts/post-uninstall.sh")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_repo_hook_scripts() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path().join("dotfiles");
+        fs::create_dir_all(stau_dir.join(".stau")).unwrap();
+        fs::write(stau_dir.join(".stau/post-adopt.sh"), "#!/bin/bash\n").unwrap();
+        fs::write(
+            stau_dir.join(format!(".stau/post-adopt.{}.sh", platform::os())),
+            "#!/bin/bash\n",
+        )
+        .unwrap();
+
+        let config = Config {
+            stau_dir: stau_dir.clone(),
+            default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
+        };
+
+        assert_eq!(
+            config.get_repo_hook_scripts(ScriptKind::PostAdopt),
+            vec![
+                stau_dir.join(".stau/post-adopt.sh"),
+                stau_dir.join(format!(".stau/post-adopt.{}.sh", platform::os())),
+            ]
+        );
+        assert!(config.get_repo_hook_scripts(ScriptKind::Setup).is_empty());
+    }
+
+    #[test]
+    fn test_named_scripts() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path().join("dotfiles");
+        let scripts_dir = stau_dir.join("nvim/scripts");
+        fs::create_dir_all(&scripts_dir).unwrap();
+
+        fs::write(scripts_dir.join("update-plugins.sh"), "#!/bin/bash\n").unwrap();
+        fs::write(scripts_dir.join("clean-cache.sh"), "#!/bin/bash\n").unwrap();
+        fs::write(scripts_dir.join("README"), "notes").unwrap();
+
+        let config = Config {
+            stau_dir: stau_dir.clone(),
+            default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
+        };
+
+        assert_eq!(
+            config.get_named_script("nvim", "update-plugins"),
+            Some(scripts_dir.join("update-plugins.sh"))
+        );
+        assert!(config.get_named_script("nvim", "missing").is_none());
+        assert!(config.get_named_script("nvim", "../setup").is_none());
+        assert_eq!(
+            config.list_named_scripts("nvim").unwrap(),
+            vec!["clean-cache".to_string(), "update-plugins".to_string()]
+        );
+        assert!(config.list_named_scripts("git").unwrap().is_empty());
     }
 
     #[test]
@@ -275,10 +1352,19 @@ mod tests {
         let config = Config {
             stau_dir: stau_dir.clone(),
             default_target: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            overlay_dirs: Vec::new(),
+            create_target: false,
+            adopt_commit: false,
+            record_machines: false,
+            conflicts: ConflictPolicy::Fail,
+            discovery: DiscoveryOptions::default(),
+            precedence: Precedence::default(),
+            provenance: Provenance::default(),
         };
 
-        // Should return None since setup.sh is not a file
-        let script = config.get_setup_script("vim");
-        assert!(script.is_none());
+        // Should find nothing since setup.sh is not a file
+        let scripts = config.get_hook_scripts("vim", ScriptKind::Setup);
+        assert!(scripts.is_empty());
     }
 }
@@ -0,0 +1,184 @@
+use crate::error::{Result, StauError};
+use crate::prompt;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// How to handle a target path taken by something stau doesn't manage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Stop with an error
+    #[default]
+    Fail,
+    /// Leave the existing path alone and don't link it
+    Skip,
+    /// Move the existing path into the backups, then link
+    Backup,
+    /// Delete the existing path, then link
+    Overwrite,
+    /// Move the existing file into the package, then link
+    Adopt,
+    /// Ask for each conflict
+    Prompt,
+}
+
+/// What to do about one conflicting path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Fail,
+    Skip,
+    Backup,
+    Overwrite,
+    Adopt,
+}
+
+const CHOICES: [(&str, Resolution); 5] = [
+    ("fail", Resolution::Fail),
+    ("skip", Resolution::Skip),
+    ("backup", Resolution::Backup),
+    ("overwrite", Resolution::Overwrite),
+    ("adopt", Resolution::Adopt),
+];
+
+impl ConflictPolicy {
+    /// Decide what to do about a conflicting path, asking if the policy is `prompt`
+    pub fn resolve(self, path: &Path) -> Result<Resolution> {
+        Ok(match self {
+            ConflictPolicy::Fail => Resolution::Fail,
+            ConflictPolicy::Skip => Resolution::Skip,
+            ConflictPolicy::Backup => Resolution::Backup,
+            ConflictPolicy::Overwrite => Resolution::Overwrite,
+            ConflictPolicy::Adopt => Resolution::Adopt,
+            ConflictPolicy::Prompt => {
+                let question = format!("{} already exists.", path.display());
+                let names: Vec<&str> = CHOICES.iter().map(|(name, _)| *name).collect();
+                // Answering yes keeps the existing file around in the backups
+                CHOICES[prompt::choose(&question, &names, 2)?].1
+            }
+        })
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConflictPolicy::Fail => "fail",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Backup => "backup",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::Adopt => "adopt",
+            ConflictPolicy::Prompt => "prompt",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The policy that always resolves a conflict this way
+impl From<Resolution> for ConflictPolicy {
+    fn from(resolution: Resolution) -> Self {
+        match resolution {
+            Resolution::Fail => ConflictPolicy::Fail,
+            Resolution::Skip => ConflictPolicy::Skip,
+            Resolution::Backup => ConflictPolicy::Backup,
+            Resolution::Overwrite => ConflictPolicy::Overwrite,
+            Resolution::Adopt => ConflictPolicy::Adopt,
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = StauError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        <Self as clap::ValueEnum>::from_str(value, false).map_err(|_| {
+            StauError::InvalidConfig(format!(
+                "invalid conflict policy '{}': expected fail, skip, backup, overwrite, adopt or prompt",
+                value
+            ))
+        })
+    }
+}
+
+/// Precedence between packages linking the same paths, like GNU Stow's
+/// `--defer` and `--override`
+///
+/// Patterns are matched against paths relative to the target directory,
+/// anchored at the start.
+#[derive(Debug, Clone, Default)]
+pub struct Precedence {
+    /// Paths linked by another package are left to that package
+    pub defer: Vec<Regex>,
+    /// Paths linked by another package are taken over
+    pub overrides: Vec<Regex>,
+}
+
+impl Precedence {
+    /// Decide about a path already linked by another package, if a pattern matches it
+    pub fn resolve(&self, relative: &Path) -> Option<Resolution> {
+        let path = relative.to_string_lossy();
+        if self.defer.iter().any(|regex| regex.is_match(&path)) {
+            Some(Resolution::Skip)
+        } else if self.overrides.iter().any(|regex| regex.is_match(&path)) {
+            Some(Resolution::Overwrite)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parse a `--defer` or `--override` pattern, anchoring it at the start of the path
+pub fn parse_path_regex(value: &str) -> std::result::Result<Regex, String> {
+    Regex::new(&format!("^(?:{})", value)).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        for name in ["fail", "skip", "backup", "overwrite", "adopt", "prompt"] {
+            let policy: ConflictPolicy = name.parse().unwrap();
+            assert_eq!(policy.to_string(), name);
+        }
+        assert!(matches!(
+            "merge".parse::<ConflictPolicy>().unwrap_err(),
+            StauError::InvalidConfig(_)
+        ));
+    }
+
+    #[test]
+    fn test_fixed_policies_resolve_without_asking() {
+        let path = Path::new("/home/user/.bashrc");
+        assert_eq!(
+            ConflictPolicy::Skip.resolve(path).unwrap(),
+            Resolution::Skip
+        );
+        assert_eq!(
+            ConflictPolicy::default().resolve(path).unwrap(),
+            Resolution::Fail
+        );
+    }
+
+    #[test]
+    fn test_precedence_prefers_defer() {
+        let precedence = Precedence {
+            defer: vec![parse_path_regex(r"\.config/git").unwrap()],
+            overrides: vec![parse_path_regex(r"\.config/").unwrap()],
+        };
+        assert_eq!(
+            precedence.resolve(Path::new(".config/git/config")),
+            Some(Resolution::Skip)
+        );
+        assert_eq!(
+            precedence.resolve(Path::new(".config/nvim/init.lua")),
+            Some(Resolution::Overwrite)
+        );
+        // Anchored at the start of the path
+        assert_eq!(precedence.resolve(Path::new("work/.config/nvim")), None);
+        assert!(parse_path_regex("(").is_err());
+    }
+}
@@ -12,30 +12,160 @@ pub enum StauError {
     PackageNotFound(String),
 
     #[error(
-        "Conflicting file exists: {0}\nHint: A file already exists at this location. Either:\n  - Remove the existing file manually\n  - Use --force to overwrite it (caution: this will delete the existing file)\n  - Adopt the existing file with 'stau adopt <package> {0}'"
+        "Invalid package name: {0}\nHint: Package names are directory names inside STAU_DIR and cannot contain '/' or be '.' or '..'."
+    )]
+    InvalidPackageName(String),
+
+    #[error(
+        "No packages match pattern: {0}\nHint: Use 'stau list' to see available packages. Quote the pattern to keep your shell from expanding it."
+    )]
+    NoMatchingPackages(String),
+
+    #[error(
+        "Conflicting file exists: {0}\nHint: A file already exists at this location. Either:\n  - Remove the existing file manually\n  - Use --force to move it into the backups, or pick another policy with --conflicts\n  - Adopt the existing file with 'stau adopt <package> {0}'"
     )]
     ConflictingFile(PathBuf),
 
     #[error(
-        "Permission denied: {0}\nHint: You may need elevated privileges. Try running with 'sudo' or check file permissions."
+        "Packages conflict: {0}\nHint: Several packages link the same path. Rename or remove the file in all but one of them."
+    )]
+    PackageConflict(String),
+
+    #[error(
+        "Dependency cycle: {0}\nHint: Remove one of these packages from another's 'depends' list in its stau.toml."
+    )]
+    DependencyCycle(String),
+
+    #[error(
+        "Permission denied: {0}\nHint: You may need elevated privileges. Pass --sudo to run just these file operations through sudo, or check file permissions."
     )]
     PermissionDenied(String),
 
     #[error(
-        "Setup script failed for package {package}: {message}\nHint: Check the setup script at <STAU_DIR>/{package}/setup.sh for errors. You can skip the setup script with --no-setup."
+        "Cannot write to the target, nothing was changed:{0}\nHint: Pass --sudo to make changes in directories you can't write to. Read-only file systems have to be remounted first."
+    )]
+    NotWritable(String),
+
+    #[error(
+        "Not enough disk space: {0}\nHint: Nothing was changed. Free up some space, or pass --no-copy to only remove the links."
+    )]
+    InsufficientSpace(String),
+
+    #[error(
+        "Setup script failed for package {package}: {message}{}\nHint: Check the setup script at <STAU_DIR>/{package}/setup.sh for errors. You can skip the setup script with --no-setup.",
+        stderr_block(.stderr)
+    )]
+    SetupScriptFailed {
+        package: String,
+        message: String,
+        stderr: Vec<String>,
+    },
+
+    #[error(
+        "Teardown script failed for package {package}: {message}{}\nHint: Check the teardown script at <STAU_DIR>/{package}/teardown.sh for errors. You can skip the teardown script with --no-teardown.",
+        stderr_block(.stderr)
+    )]
+    TeardownScriptFailed {
+        package: String,
+        message: String,
+        stderr: Vec<String>,
+    },
+
+    #[error(
+        "Uninstall aborted for package {package}: {message}{}\nHint: The package's check-uninstall hook refused the uninstall. Resolve the condition it reports and try again, or skip uninstall hooks with --no-teardown.",
+        stderr_block(.stderr)
+    )]
+    UninstallAborted {
+        package: String,
+        message: String,
+        stderr: Vec<String>,
+    },
+
+    #[error(
+        "Script failed for package {package}: {message}{}\nHint: Check the script at <STAU_DIR>/{package}/scripts/{script}.sh for errors.",
+        stderr_block(.stderr)
+    )]
+    ScriptFailed {
+        package: String,
+        script: String,
+        message: String,
+        /// The last lines the script wrote to stderr
+        stderr: Vec<String>,
+    },
+
+    #[error(
+        "Script not found for package {package}: {script}\nHint: Package scripts live in <STAU_DIR>/{package}/scripts/<name>.sh. Run 'stau run {package}' to list them."
+    )]
+    ScriptNotFound { package: String, script: String },
+
+    #[error(
+        "Cannot run scripts of package {package} as {user}: {message}\nHint: Scripts run without a terminal, so sudo can't ask for a password. Run 'sudo -v' first, or allow running as {user} without a password in sudoers."
+    )]
+    RunAsFailed {
+        package: String,
+        user: String,
+        message: String,
+    },
+
+    #[error(
+        "Command aborted: {0}\nHint: The pre-command hook in <STAU_DIR>/.stau/ refused to run the command. Resolve the condition it reports and try again."
+    )]
+    CommandAborted(String),
+
+    #[error(
+        "Unhealthy packages: {0}\nHint: Some links are broken or their targets are occupied by other files. Run 'stau status <package>' for details, then 'stau restow' or 'stau clean' to repair them."
+    )]
+    Unhealthy(String),
+
+    #[error(
+        "Packages drifted from the machines registry: {0}\nHint: Install, restow or uninstall them as listed to converge, or run 'stau install'/'stau uninstall' to record the current state instead."
     )]
-    SetupScriptFailed { package: String, message: String },
+    Drift(String),
 
     #[error(
-        "Teardown script failed for package {package}: {message}\nHint: Check the teardown script at <STAU_DIR>/{package}/teardown.sh for errors. You can skip the teardown script with --no-teardown."
+        "Stray links into STAU_DIR: {0}\nHint: Broken links point at files that no longer exist, orphaned ones at package files no package links there. Restow the packages they came from or remove the links."
     )]
-    TeardownScriptFailed { package: String, message: String },
+    StrayLinks(String),
+
+    #[error(
+        "Package has uncommitted changes: {0}\nHint: Commit or stash the changes in your STAU_DIR first, or use --discard-changes to delete the package anyway."
+    )]
+    UncommittedChanges(String),
+
+    #[error("Git error: {0}")]
+    Git(String),
+
+    #[error(
+        "Snapshot not found: {0}\nHint: Run 'stau snapshot --list' to see available snapshots."
+    )]
+    SnapshotNotFound(String),
+
+    #[error("{0}\nHint: This is a warning, which --strict turns into an error.")]
+    Strict(String),
+
+    #[error("Backup not found: {0}\nHint: Run 'stau backups list' to see available backups.")]
+    BackupNotFound(String),
+
+    #[error(
+        "Invalid configuration: {0}\nHint: Run 'stau config list' to see the available keys and their values."
+    )]
+    InvalidConfig(String),
 
     #[error(
         "STAU_DIR not found: {0}\nHint: Create your dotfiles directory or set the STAU_DIR environment variable to point to your existing dotfiles."
     )]
     StauDirNotFound(PathBuf),
 
+    #[error(
+        "Target directory not found: {0}\nHint: Create it first, pass --create-target, or run 'stau config set create_target true' to create missing targets on install."
+    )]
+    TargetNotFound(PathBuf),
+
+    #[error(
+        "Interrupted\nHint: The command stopped before it was done. Check 'stau status' and run the command again to finish."
+    )]
+    Interrupted,
+
     #[error("Invalid path: {0}\nHint: The specified path is invalid or inaccessible.")]
     InvalidPath(PathBuf),
 
@@ -44,20 +174,60 @@ pub enum StauError {
 
     #[error("{0}")]
     Other(String),
+
+    /// A failure already reported in the output, leaving only the exit code
+    #[error("exit status {0}")]
+    Status(i32),
+}
+
+/// The last lines a failing script wrote to stderr, indented below its error
+pub fn stderr_block(lines: &[String]) -> String {
+    if lines.is_empty() {
+        return String::new();
+    }
+    let mut block = String::from(":");
+    for line in lines {
+        block.push_str("\n  ");
+        block.push_str(line);
+    }
+    block
 }
 
 impl StauError {
     pub fn exit_code(&self) -> i32 {
         match self {
             StauError::PackageNotFound(_) => 1,
+            StauError::InvalidPackageName(_) => 1,
+            StauError::NoMatchingPackages(_) => 1,
             StauError::ConflictingFile(_) => 2,
+            StauError::PackageConflict(_) => 2,
+            StauError::DependencyCycle(_) => 1,
             StauError::PermissionDenied(_) => 3,
+            StauError::NotWritable(_) => 3,
+            StauError::InsufficientSpace(_) => 3,
             StauError::SetupScriptFailed { .. } => 4,
             StauError::TeardownScriptFailed { .. } => 4,
+            StauError::UninstallAborted { .. } => 4,
+            StauError::ScriptFailed { .. } => 4,
+            StauError::ScriptNotFound { .. } => 1,
+            StauError::RunAsFailed { .. } => 3,
+            StauError::CommandAborted(_) => 4,
+            StauError::Unhealthy(_) => 5,
+            StauError::Drift(_) => 5,
+            StauError::StrayLinks(_) => 5,
+            StauError::UncommittedChanges(_) => 1,
+            StauError::Git(_) => 1,
+            StauError::SnapshotNotFound(_) => 1,
+            StauError::BackupNotFound(_) => 1,
+            StauError::Strict(_) => 1,
+            StauError::InvalidConfig(_) => 1,
             StauError::StauDirNotFound(_) => 1,
+            StauError::TargetNotFound(_) => 1,
+            StauError::Interrupted => 130,
             StauError::InvalidPath(_) => 1,
             StauError::Io(_) => 3,
             StauError::Other(_) => 1,
+            StauError::Status(code) => *code,
         }
     }
 }
@@ -75,6 +245,21 @@ mod tests {
         assert!(err.to_string().contains("stau list"));
     }
 
+    #[test]
+    fn test_invalid_package_name_error() {
+        let err = StauError::InvalidPackageName("../etc".to_string());
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().contains("../etc"));
+    }
+
+    #[test]
+    fn test_no_matching_packages_error() {
+        let err = StauError::NoMatchingPackages("nvim*".to_string());
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().contains("nvim*"));
+        assert!(err.to_string().contains("stau list"));
+    }
+
     #[test]
     fn test_conflicting_file_error() {
         let path = PathBuf::from("/home/user/.vimrc");
@@ -93,14 +278,36 @@ mod tests {
         assert!(err.to_string().contains("sudo"));
     }
 
+    #[test]
+    fn test_not_writable_error() {
+        let err = StauError::NotWritable("\n  /etc (permission denied, 1 path)".to_string());
+        assert_eq!(err.exit_code(), 3);
+        assert!(err.to_string().contains("nothing was changed:\n  /etc"));
+        assert!(err.to_string().contains("--sudo"));
+    }
+
+    #[test]
+    fn test_insufficient_space_error() {
+        let err =
+            StauError::InsufficientSpace("copying files back to /home needs 2 GB".to_string());
+        assert_eq!(err.exit_code(), 3);
+        assert!(err.to_string().contains("/home needs 2 GB"));
+        assert!(err.to_string().contains("--no-copy"));
+    }
+
     #[test]
     fn test_setup_script_failed_error() {
         let err = StauError::SetupScriptFailed {
             package: "vim".to_string(),
             message: "script exited with code 1".to_string(),
+            stderr: vec!["vim: not found".to_string()],
         };
         assert_eq!(err.exit_code(), 4);
         assert!(err.to_string().contains("vim"));
+        assert!(
+            err.to_string()
+                .contains("script exited with code 1:\n  vim: not found\nHint:")
+        );
         assert!(err.to_string().contains("script exited with code 1"));
         assert!(err.to_string().contains("--no-setup"));
     }
@@ -110,6 +317,7 @@ mod tests {
         let err = StauError::TeardownScriptFailed {
             package: "zsh".to_string(),
             message: "script exited with code 2".to_string(),
+            stderr: Vec::new(),
         };
         assert_eq!(err.exit_code(), 4);
         assert!(err.to_string().contains("zsh"));
@@ -117,6 +325,144 @@ mod tests {
         assert!(err.to_string().contains("--no-teardown"));
     }
 
+    #[test]
+    fn test_uninstall_aborted_error() {
+        let err = StauError::UninstallAborted {
+            package: "gpg".to_string(),
+            message: "check-uninstall script exited with code 1".to_string(),
+            stderr: Vec::new(),
+        };
+        assert_eq!(err.exit_code(), 4);
+        assert!(err.to_string().contains("gpg"));
+        assert!(err.to_string().contains("--no-teardown"));
+    }
+
+    #[test]
+    fn test_script_failed_error() {
+        let err = StauError::ScriptFailed {
+            package: "nvim".to_string(),
+            script: "update-plugins".to_string(),
+            message: "script exited with code 1".to_string(),
+            stderr: Vec::new(),
+        };
+        assert_eq!(err.exit_code(), 4);
+        assert!(err.to_string().contains("nvim"));
+        assert!(err.to_string().contains("scripts/update-plugins.sh"));
+    }
+
+    #[test]
+    fn test_command_aborted_error() {
+        let err = StauError::CommandAborted("pre-command script exited with code 1".to_string());
+        assert_eq!(err.exit_code(), 4);
+        assert!(err.to_string().contains("pre-command hook"));
+    }
+
+    #[test]
+    fn test_script_not_found_error() {
+        let err = StauError::ScriptNotFound {
+            package: "nvim".to_string(),
+            script: "missing".to_string(),
+        };
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().contains("missing"));
+        assert!(err.to_string().contains("stau run nvim"));
+    }
+
+    #[test]
+    fn test_unhealthy_error() {
+        let err = StauError::Unhealthy("vim, zsh".to_string());
+        assert_eq!(err.exit_code(), 5);
+        assert!(err.to_string().contains("vim, zsh"));
+        assert!(err.to_string().contains("stau status"));
+    }
+
+    #[test]
+    fn test_drift_error() {
+        let err = StauError::Drift("vim, zsh".to_string());
+        assert_eq!(err.exit_code(), 5);
+        assert!(err.to_string().contains("vim, zsh"));
+        assert!(err.to_string().contains("machines registry"));
+    }
+
+    #[test]
+    fn test_stray_links_error() {
+        let err = StauError::StrayLinks("1 broken, 2 orphaned".to_string());
+        assert_eq!(err.exit_code(), 5);
+        assert!(err.to_string().contains("1 broken, 2 orphaned"));
+        assert!(err.to_string().contains("Restow"));
+    }
+
+    #[test]
+    fn test_uncommitted_changes_error() {
+        let err = StauError::UncommittedChanges("vim".to_string());
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().contains("vim"));
+        assert!(err.to_string().contains("--discard-changes"));
+    }
+
+    #[test]
+    fn test_invalid_config_error() {
+        let err = StauError::InvalidConfig("unknown key 'colour'".to_string());
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().contains("colour"));
+        assert!(err.to_string().contains("stau config list"));
+    }
+
+    #[test]
+    fn test_snapshot_not_found_error() {
+        let err = StauError::SnapshotNotFound("before-refactor".to_string());
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().contains("before-refactor"));
+        assert!(err.to_string().contains("stau snapshot --list"));
+    }
+
+    #[test]
+    fn test_strict_error() {
+        let err = StauError::Strict("File does not exist: /home/user/.vimrc".to_string());
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().starts_with("File does not exist"));
+        assert!(err.to_string().contains("--strict"));
+    }
+
+    #[test]
+    fn test_backup_not_found_error() {
+        let err = StauError::BackupNotFound("20260101-120000-42".to_string());
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().contains("20260101-120000-42"));
+        assert!(err.to_string().contains("stau backups list"));
+    }
+
+    #[test]
+    fn test_target_not_found_error() {
+        let err = StauError::TargetNotFound(PathBuf::from("/srv/home"));
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().contains("/srv/home"));
+        assert!(err.to_string().contains("--create-target"));
+    }
+
+    #[test]
+    fn test_package_conflict_error() {
+        let err = StauError::PackageConflict("bash, zsh".to_string());
+        assert_eq!(err.exit_code(), 2);
+        assert!(err.to_string().contains("bash, zsh"));
+    }
+
+    #[test]
+    fn test_dependency_cycle_error() {
+        let err = StauError::DependencyCycle("a -> b -> a".to_string());
+        assert_eq!(err.exit_code(), 1);
+        assert!(err.to_string().contains("a -> b -> a"));
+        assert!(err.to_string().contains("stau.toml"));
+    }
+
+    #[test]
+    fn test_interrupted_error() {
+        let err = StauError::Interrupted;
+        assert_eq!(err.exit_code(), 130);
+        assert!(err.to_string().contains("Interrupted"));
+        assert!(!err.to_string().contains("removed"));
+    }
+
     #[test]
     fn test_stau_dir_not_found_error() {
         let path = PathBuf::from("/home/user/dotfiles");
@@ -0,0 +1,400 @@
+use crate::error::{Result, StauError};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Run a git command in a directory and return its output if it succeeded
+///
+/// Read-only commands like `git status` don't refresh the index, so they
+/// never write to `.git/`, where the prompt watcher would take it for a change.
+fn git(dir: &Path, args: &[&str]) -> Result<Output> {
+    let _span = tracing::info_span!("git", args = args.join(" ")).entered();
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .env("GIT_OPTIONAL_LOCKS", "0")
+        .output()
+        .map_err(|e| StauError::Git(format!("cannot run git: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(StauError::Git(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(output)
+}
+
+/// Check if a directory is inside a git work tree
+pub fn is_repo(dir: &Path) -> bool {
+    git(dir, &["rev-parse", "--is-inside-work-tree"])
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim() == "true")
+        .unwrap_or(false)
+}
+
+/// Check if a path (relative to `dir`) contains any files tracked by git
+pub fn is_tracked(dir: &Path, path: &str) -> Result<bool> {
+    let output = git(dir, &["ls-files", "--", path])?;
+    Ok(!output.stdout.is_empty())
+}
+
+/// Check if a path (relative to `dir`) has uncommitted or untracked changes
+pub fn has_uncommitted_changes(dir: &Path, path: &str) -> Result<bool> {
+    let output = git(dir, &["status", "--porcelain", "--", path])?;
+    Ok(!output.stdout.is_empty())
+}
+
+/// List the paths below `dir` with uncommitted or untracked changes, relative to `dir`
+///
+/// Untracked directories are listed as a whole, with a trailing slash.
+pub fn uncommitted_paths(dir: &Path) -> Result<Vec<String>> {
+    let prefix = git(dir, &["rev-parse", "--show-prefix"])?;
+    let prefix = String::from_utf8_lossy(&prefix.stdout).trim().to_string();
+    let output = git(dir, &["status", "--porcelain", "-z", "--", "."])?;
+
+    let mut paths = Vec::new();
+    let mut entries = output.stdout.split(|&b| b == 0).filter(|e| e.len() > 3);
+    while let Some(entry) = entries.next() {
+        let path = String::from_utf8_lossy(&entry[3..]);
+        if let Some(path) = path.strip_prefix(&prefix) {
+            paths.push(path.to_string());
+        }
+        // Renames and copies are followed by their original path
+        if entry[..2].contains(&b'R') || entry[..2].contains(&b'C') {
+            entries.next();
+        }
+    }
+    Ok(paths)
+}
+
+/// Clone a repository into a directory, creating its parent if needed
+pub fn clone(url: &str, dest: &Path, recurse_submodules: bool) -> Result<()> {
+    let parent = match dest.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(parent).map_err(StauError::Io)?;
+
+    let dest = dest.to_string_lossy();
+    let mut args = vec!["clone", "-q"];
+    if recurse_submodules {
+        args.push("--recurse-submodules");
+    }
+    args.extend(["--", url, &dest]);
+    git(parent, &args)?;
+    Ok(())
+}
+
+/// Check if the current branch tracks an upstream branch
+pub fn has_upstream(dir: &Path) -> bool {
+    git(dir, &["rev-parse", "--verify", "-q", "@{upstream}"]).is_ok()
+}
+
+/// Check if the committed state of a path (relative to `dir`) differs from the upstream branch
+///
+/// Returns false when the current branch has no upstream. Compares against
+/// the last fetched state of the upstream; nothing is fetched.
+pub fn differs_from_upstream(dir: &Path, path: &str) -> Result<bool> {
+    if !has_upstream(dir) {
+        return Ok(false);
+    }
+    let output = git(
+        dir,
+        &["diff", "--name-only", "@{upstream}", "HEAD", "--", path],
+    )?;
+    Ok(!output.stdout.is_empty())
+}
+
+/// List submodules below a directory that haven't been initialized, relative to it
+pub fn uninitialized_submodules(dir: &Path) -> Result<Vec<String>> {
+    let output = git(dir, &["submodule", "status", "--", "."])?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.strip_prefix('-'))
+        .filter_map(|line| line.split_once(' ').map(|(_, path)| path.to_string()))
+        .collect())
+}
+
+/// Initialize and check out all submodules below a directory
+pub fn init_submodules(dir: &Path) -> Result<()> {
+    git(
+        dir,
+        &["submodule", "update", "--init", "--recursive", "--", "."],
+    )?;
+    Ok(())
+}
+
+/// Stage the given paths and commit only them, leaving other staged changes alone
+pub fn commit_paths(dir: &Path, paths: &[PathBuf], message: &str) -> Result<()> {
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+
+    let mut args = vec!["add", "--"];
+    args.extend(&paths);
+    git(dir, &args)?;
+
+    let mut args = vec!["commit", "-q", "-m", message, "--"];
+    args.extend(&paths);
+    git(dir, &args)?;
+    Ok(())
+}
+
+/// Get the commit id of HEAD
+pub fn head(dir: &Path) -> Result<String> {
+    let output = git(dir, &["rev-parse", "HEAD"])?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Fast-forward the current branch to its upstream, failing if it has diverged
+pub fn pull(dir: &Path) -> Result<()> {
+    git(dir, &["pull", "--ff-only", "-q"])?;
+    Ok(())
+}
+
+/// Resolve a revision given by the user to the hash of a commit
+///
+/// A revision starting with a dash is taken as a revision and not an option.
+fn resolve_commit(dir: &Path, rev: &str) -> Result<String> {
+    let commit = format!("{}^{{commit}}", rev);
+    let output = git(
+        dir,
+        &["rev-parse", "-q", "--verify", "--end-of-options", &commit],
+    )
+    .map_err(|_| StauError::Git(format!("'{}' is not a commit", rev)))?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// List the files changed between two commits, relative to `dir`
+pub fn changed_paths(dir: &Path, from: &str, to: &str) -> Result<Vec<String>> {
+    let from = resolve_commit(dir, from)?;
+    let to = resolve_commit(dir, to)?;
+    let output = git(
+        dir,
+        &[
+            "diff",
+            "--name-only",
+            "--relative",
+            "--end-of-options",
+            &from,
+            &to,
+        ],
+    )?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Remove a path (relative to `dir`) from the work tree and stage the deletion,
+/// along with any changes to it
+pub fn remove(dir: &Path, path: &str) -> Result<()> {
+    git(dir, &["rm", "-r", "-f", "-q", "--", path])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn init_repo(dir: &Path) {
+        for args in [
+            vec!["init", "-q"],
+            vec!["config", "user.email", "test@example.com"],
+            vec!["config", "user.name", "Test"],
+        ] {
+            git(dir, &args).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_is_repo() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(!is_repo(temp_dir.path()));
+
+        init_repo(temp_dir.path());
+        assert!(is_repo(temp_dir.path()));
+    }
+
+    #[test]
+    fn test_status_leaves_index_alone() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = temp_dir.path();
+        init_repo(repo);
+        fs::write(repo.join(".vimrc"), "set nocompatible").unwrap();
+        git(repo, &["add", "."]).unwrap();
+        git(repo, &["commit", "-q", "-m", "add vim"]).unwrap();
+
+        // A file touched since it was staged makes a refreshing status rewrite the index
+        let index = repo.join(".git/index");
+        let before = fs::read(&index).unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(repo.join(".vimrc"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        assert!(!has_uncommitted_changes(repo, ".").unwrap());
+        assert_eq!(fs::read(&index).unwrap(), before);
+    }
+
+    #[test]
+    fn test_tracked_and_uncommitted_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = temp_dir.path();
+        init_repo(repo);
+
+        fs::create_dir(repo.join("vim")).unwrap();
+        fs::write(repo.join("vim/.vimrc"), "set nocompatible").unwrap();
+
+        assert!(!is_tracked(repo, "vim").unwrap());
+        assert!(has_uncommitted_changes(repo, "vim").unwrap());
+
+        git(repo, &["add", "."]).unwrap();
+        git(repo, &["commit", "-q", "-m", "add vim"]).unwrap();
+
+        assert!(is_tracked(repo, "vim").unwrap());
+        assert!(!has_uncommitted_changes(repo, "vim").unwrap());
+
+        remove(repo, "vim").unwrap();
+        assert!(!repo.join("vim").exists());
+        assert!(has_uncommitted_changes(repo, "vim").unwrap());
+    }
+
+    #[test]
+    fn test_uncommitted_paths_relative_to_subdirectory() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = temp_dir.path();
+        init_repo(repo);
+
+        fs::create_dir_all(repo.join("dotfiles/vim")).unwrap();
+        fs::create_dir_all(repo.join("dotfiles/zsh")).unwrap();
+        fs::write(repo.join("dotfiles/vim/.vimrc"), "set nocompatible").unwrap();
+        fs::write(repo.join("dotfiles/zsh/.zshrc"), "").unwrap();
+        fs::write(repo.join("notes.txt"), "todo").unwrap();
+        git(repo, &["add", "dotfiles"]).unwrap();
+        git(repo, &["commit", "-q", "-m", "initial"]).unwrap();
+
+        let dotfiles = repo.join("dotfiles");
+        assert!(uncommitted_paths(&dotfiles).unwrap().is_empty());
+
+        fs::write(repo.join("dotfiles/vim/.vimrc"), "set number").unwrap();
+        git(&dotfiles, &["mv", "zsh", "shell"]).unwrap();
+        fs::create_dir(repo.join("dotfiles/git")).unwrap();
+        fs::write(repo.join("dotfiles/git/.gitconfig"), "").unwrap();
+
+        let mut paths = uncommitted_paths(&dotfiles).unwrap();
+        paths.sort();
+        assert_eq!(paths, vec!["git/", "shell/.zshrc", "vim/.vimrc"]);
+    }
+
+    #[test]
+    fn test_commit_paths_only_commits_given_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = temp_dir.path();
+        init_repo(repo);
+
+        fs::create_dir_all(repo.join("shell")).unwrap();
+        fs::write(repo.join("shell/.bashrc"), "alias ll='ls -l'").unwrap();
+        fs::write(repo.join("notes.txt"), "todo").unwrap();
+
+        commit_paths(
+            repo,
+            &[repo.join("shell/.bashrc")],
+            "adopt .bashrc into shell",
+        )
+        .unwrap();
+
+        assert!(!has_uncommitted_changes(repo, "shell").unwrap());
+        assert!(!is_tracked(repo, "notes.txt").unwrap());
+        let log = git(repo, &["log", "--format=%s"]).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&log.stdout).trim(),
+            "adopt .bashrc into shell"
+        );
+    }
+
+    #[test]
+    fn test_differs_from_upstream() {
+        let temp_dir = TempDir::new().unwrap();
+        let origin = temp_dir.path().join("origin");
+        let clone_dir = temp_dir.path().join("clone");
+        fs::create_dir(&origin).unwrap();
+        init_repo(&origin);
+        fs::create_dir(origin.join("vim")).unwrap();
+        fs::write(origin.join("vim/.vimrc"), "set nocompatible").unwrap();
+        fs::write(origin.join(".zshrc"), "").unwrap();
+        git(&origin, &["add", "."]).unwrap();
+        git(&origin, &["commit", "-q", "-m", "initial"]).unwrap();
+
+        // Without an upstream nothing can differ
+        assert!(!differs_from_upstream(&origin, "vim").unwrap());
+
+        clone(origin.to_str().unwrap(), &clone_dir, false).unwrap();
+        git(&clone_dir, &["config", "user.email", "test@example.com"]).unwrap();
+        git(&clone_dir, &["config", "user.name", "Test"]).unwrap();
+        assert!(!differs_from_upstream(&clone_dir, "vim").unwrap());
+
+        fs::write(clone_dir.join("vim/.vimrc"), "set number").unwrap();
+        git(&clone_dir, &["commit", "-q", "-am", "number"]).unwrap();
+        assert!(differs_from_upstream(&clone_dir, "vim").unwrap());
+        assert!(!differs_from_upstream(&clone_dir, ".zshrc").unwrap());
+    }
+
+    #[test]
+    fn test_pull_and_changed_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let origin = temp_dir.path().join("origin");
+        let clone_dir = temp_dir.path().join("clone");
+        fs::create_dir(&origin).unwrap();
+        init_repo(&origin);
+        fs::create_dir(origin.join("vim")).unwrap();
+        fs::write(origin.join("vim/.vimrc"), "set nocompatible").unwrap();
+        git(&origin, &["add", "."]).unwrap();
+        git(&origin, &["commit", "-q", "-m", "initial"]).unwrap();
+        clone(origin.to_str().unwrap(), &clone_dir, false).unwrap();
+
+        fs::create_dir(origin.join("zsh")).unwrap();
+        fs::write(origin.join("zsh/.zshrc"), "").unwrap();
+        git(&origin, &["add", "."]).unwrap();
+        git(&origin, &["commit", "-q", "-m", "add zsh"]).unwrap();
+
+        let before = head(&clone_dir).unwrap();
+        pull(&clone_dir).unwrap();
+        let after = head(&clone_dir).unwrap();
+        assert_ne!(before, after);
+        assert_eq!(
+            changed_paths(&clone_dir, &before, &after).unwrap(),
+            vec!["zsh/.zshrc"]
+        );
+    }
+
+    #[test]
+    fn test_changed_paths_rejects_options_as_revisions() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = temp_dir.path().join("repo");
+        fs::create_dir(&repo).unwrap();
+        init_repo(&repo);
+        fs::write(repo.join(".vimrc"), "").unwrap();
+        git(&repo, &["add", "."]).unwrap();
+        git(&repo, &["commit", "-q", "-m", "initial"]).unwrap();
+
+        let written = temp_dir.path().join("written");
+        let option = format!("--output={}", written.display());
+        let result = changed_paths(&repo, &option, "HEAD");
+        assert!(
+            matches!(result, Err(StauError::Git(message)) if message.contains("is not a commit"))
+        );
+        assert!(!written.exists());
+        assert!(changed_paths(&repo, "HEAD", &option).is_err());
+        assert!(!written.exists());
+    }
+}
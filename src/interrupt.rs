@@ -0,0 +1,39 @@
+use crate::error::{Result, StauError};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the user pressed Ctrl-C
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit code used when stau is interrupted (128 + SIGINT)
+pub const EXIT_CODE: i32 = 130;
+
+/// Install a SIGINT handler that asks running operations to stop
+///
+/// The first Ctrl-C only sets a flag, so stau can stop between mappings and
+/// undo what it started. A second Ctrl-C exits immediately.
+pub fn install_handler() {
+    let result = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_CODE);
+        }
+        eprintln!("Interrupted, stopping (press Ctrl-C again to quit immediately)");
+    });
+
+    if let Err(e) = result {
+        eprintln!("Warning: Cannot install Ctrl-C handler: {}", e);
+    }
+}
+
+/// Check whether the user pressed Ctrl-C
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Return an error if the user pressed Ctrl-C
+pub fn check() -> Result<()> {
+    if is_interrupted() {
+        Err(StauError::Interrupted)
+    } else {
+        Ok(())
+    }
+}
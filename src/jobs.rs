@@ -0,0 +1,216 @@
+use crate::error::Result;
+use crate::output::{self, Held};
+use std::sync::{Condvar, Mutex};
+
+/// Held while something runs that must not overlap with the same in another package
+static ONE_AT_A_TIME: Mutex<()> = Mutex::new(());
+
+/// Run `f` while no other package runs a script, asks a question or changes
+/// a shared repository
+///
+/// Scripts run one at a time also with `--jobs`, as the package managers they
+/// call tend to take locks of their own.
+pub fn one_at_a_time<T>(f: impl FnOnce() -> T) -> T {
+    let _guard = ONE_AT_A_TIME.lock().unwrap_or_else(|e| e.into_inner());
+    f()
+}
+
+/// For each package, the positions of the earlier packages in the list it depends on
+///
+/// `packages` is expected in dependency order, as `order_by_dependencies` returns them.
+pub fn dependencies(
+    packages: &[String],
+    depends: impl Fn(&str) -> Result<Vec<String>>,
+) -> Result<Vec<Vec<usize>>> {
+    packages
+        .iter()
+        .enumerate()
+        .map(|(i, package)| {
+            let depends = depends(package)?;
+            Ok((0..i).filter(|&j| depends.contains(&packages[j])).collect())
+        })
+        .collect()
+}
+
+/// How far running the packages got, shared between the workers
+struct Progress<T> {
+    started: Vec<bool>,
+    results: Vec<Option<Result<T>>>,
+    held: Vec<Option<Held>>,
+    /// Packages up to here had their output printed
+    printed: usize,
+    failed: bool,
+}
+
+impl<T> Progress<T> {
+    /// The first package not started yet whose dependencies all succeeded
+    fn next(&self, dependencies: &[Vec<usize>]) -> Option<usize> {
+        (0..self.started.len()).find(|&i| {
+            !self.started[i]
+                && dependencies
+                    .get(i)
+                    .is_none_or(|deps| deps.iter().all(|&j| matches!(self.results[j], Some(Ok(_)))))
+        })
+    }
+
+    /// Print the output of the finished packages that all packages before them
+    /// were printed for, keeping it in the order of the list
+    fn print_finished(&mut self) {
+        while let Some(Some(held)) = self.held.get_mut(self.printed) {
+            std::mem::take(held).print();
+            self.printed += 1;
+        }
+    }
+}
+
+/// Run an operation on each package with up to `jobs` packages at a time,
+/// starting no more packages after one failed
+///
+/// A package starts once the packages it depends on succeeded. What the
+/// operation prints is held back and printed in one piece per package, in
+/// the order of the list. The result of each package is `None` if it didn't
+/// run. With one job, packages run in turn and print as they go.
+pub fn run<T: Send>(
+    packages: &[String],
+    jobs: usize,
+    dependencies: &[Vec<usize>],
+    operation: impl Fn(&str) -> Result<T> + Sync,
+) -> Vec<Option<Result<T>>> {
+    if jobs <= 1 {
+        let mut failed = false;
+        return packages
+            .iter()
+            .map(|package| {
+                if failed {
+                    return None;
+                }
+                let result = operation(package);
+                failed = result.is_err();
+                Some(result)
+            })
+            .collect();
+    }
+
+    let progress = Mutex::new(Progress {
+        started: vec![false; packages.len()],
+        results: packages.iter().map(|_| None).collect(),
+        held: packages.iter().map(|_| None).collect(),
+        printed: 0,
+        failed: false,
+    });
+    let changed = Condvar::new();
+    let worker = || {
+        loop {
+            let mut state = progress.lock().unwrap_or_else(|e| e.into_inner());
+            let i = loop {
+                if state.failed {
+                    return;
+                }
+                if let Some(i) = state.next(dependencies) {
+                    break i;
+                }
+                if state.started.iter().all(|&started| started) {
+                    return;
+                }
+                state = changed.wait(state).unwrap_or_else(|e| e.into_inner());
+            };
+            state.started[i] = true;
+            drop(state);
+
+            let (result, held) = output::hold(|| operation(&packages[i]));
+            let mut state = progress.lock().unwrap_or_else(|e| e.into_inner());
+            state.failed |= result.is_err();
+            state.results[i] = Some(result);
+            state.held[i] = Some(held);
+            state.print_finished();
+            changed.notify_all();
+        }
+    };
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(packages.len()) {
+            scope.spawn(worker);
+        }
+    });
+
+    let mut state = progress.into_inner().unwrap_or_else(|e| e.into_inner());
+    // Packages that finished after one before them failed to start
+    for held in state.held.iter_mut().flatten() {
+        std::mem::take(held).print();
+    }
+    state.results
+}
+
+/// Compute something for each package with up to `jobs` packages at a time,
+/// keeping the results and output in the order of the list
+pub fn map<T: Send>(packages: &[String], jobs: usize, f: impl Fn(&str) -> T + Sync) -> Vec<T> {
+    run(packages, jobs, &[], |package| Ok(f(package)))
+        .into_iter()
+        .map(|result| match result {
+            Some(Ok(value)) => value,
+            _ => unreachable!("computing a value for a package cannot fail"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::StauError;
+    use std::time::Duration;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_run_waits_for_dependencies() {
+        let packages = names(&["git", "zsh", "vim", "tmux"]);
+        let dependencies = dependencies(&packages, |package| {
+            Ok(match package {
+                "vim" => names(&["git"]),
+                "tmux" => names(&["zsh", "vim"]),
+                _ => Vec::new(),
+            })
+        })
+        .unwrap();
+        assert_eq!(dependencies, [vec![], vec![], vec![0], vec![1, 2]]);
+
+        let finished = Mutex::new(Vec::new());
+        let results = run(&packages, 4, &dependencies, |package| {
+            if package == "git" {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            finished.lock().unwrap().push(package.to_string());
+            Ok(())
+        });
+        assert!(results.iter().all(|result| matches!(result, Some(Ok(())))));
+
+        let finished = finished.into_inner().unwrap();
+        let position = |name: &str| finished.iter().position(|p| p == name).unwrap();
+        assert!(position("git") < position("vim"));
+        assert!(position("vim") < position("tmux"));
+        assert!(position("zsh") < position("git"));
+    }
+
+    #[test]
+    fn test_run_starts_nothing_after_a_failure() {
+        let packages = names(&["git", "zsh", "vim"]);
+        let results = run(&packages, 2, &[vec![], vec![], vec![0]], |package| {
+            // zsh starts alongside git, vim waits for it
+            std::thread::sleep(Duration::from_millis(50));
+            match package {
+                "git" => Err(StauError::Other("broken".to_string())),
+                _ => Ok(()),
+            }
+        });
+        assert!(matches!(results[0], Some(Err(_))));
+        assert!(matches!(results[1], Some(Ok(()))));
+        assert!(results[2].is_none());
+    }
+
+    #[test]
+    fn test_map_keeps_order() {
+        let packages = names(&["a", "bb", "ccc", "dddd", "eeeee"]);
+        assert_eq!(map(&packages, 3, |package| package.len()), [1, 2, 3, 4, 5]);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::error::{Result, StauError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Registry of the packages each machine has installed, relative to STAU_DIR
+/// so it can be committed along with the dotfiles
+pub const MACHINES_FILE: &str = ".stau/machines.toml";
+
+/// What one machine has installed
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Machine {
+    /// Installed packages, sorted by name
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// When a package was last installed or uninstalled
+    #[serde(default)]
+    pub updated: String,
+}
+
+/// All machines recorded in the registry, by hostname
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Registry {
+    #[serde(default)]
+    pub machines: BTreeMap<String, Machine>,
+}
+
+impl Registry {
+    pub fn path(stau_dir: &Path) -> PathBuf {
+        stau_dir.join(MACHINES_FILE)
+    }
+
+    /// Load the registry of a dotfiles directory, returning an empty one if none exists
+    pub fn load(stau_dir: &Path) -> Result<Self> {
+        let path = Self::path(stau_dir);
+        if !path.is_file() {
+            return Ok(Registry::default());
+        }
+
+        let contents = fs::read_to_string(&path).map_err(StauError::Io)?;
+        toml::from_str(&contents).map_err(|e| {
+            StauError::Other(format!(
+                "Cannot parse machines registry {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    pub fn save(&self, stau_dir: &Path) -> Result<()> {
+        let path = Self::path(stau_dir);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(StauError::Io)?;
+        }
+
+        let contents = toml::to_string_pretty(self)
+            .map_err(|e| StauError::Other(format!("Cannot serialize machines registry: {}", e)))?;
+        fs::write(path, contents).map_err(StauError::Io)
+    }
+
+    /// Load the registry, apply a change and save it again if it changed
+    ///
+    /// Packages worked on concurrently update the registry one at a time, so
+    /// no change gets lost.
+    pub fn update(stau_dir: &Path, change: impl FnOnce(&mut Registry) -> bool) -> Result<()> {
+        static UPDATING: Mutex<()> = Mutex::new(());
+        let _guard = UPDATING.lock().unwrap_or_else(|e| e.into_inner());
+        let mut registry = Registry::load(stau_dir)?;
+        if change(&mut registry) {
+            registry.save(stau_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Record that a machine installed or uninstalled a package
+    ///
+    /// Returns whether the registry changed; a machine without packages left
+    /// is dropped.
+    pub fn record(&mut self, host: &str, package: &str, installed: bool, now: String) -> bool {
+        let machine = self.machines.entry(host.to_string()).or_default();
+        let position = machine
+            .packages
+            .binary_search_by(|p| p.as_str().cmp(package));
+        let changed = match (position, installed) {
+            (Err(index), true) => {
+                machine.packages.insert(index, package.to_string());
+                true
+            }
+            (Ok(index), false) => {
+                machine.packages.remove(index);
+                true
+            }
+            _ => false,
+        };
+
+        if changed {
+            machine.updated = now;
+        }
+        if machine.packages.is_empty() {
+            self.machines.remove(host);
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_record_install_and_uninstall() {
+        let mut registry = Registry::default();
+        assert!(registry.record("laptop", "zsh", true, "t1".to_string()));
+        assert!(registry.record("laptop", "git", true, "t2".to_string()));
+        assert!(!registry.record("laptop", "git", true, "t3".to_string()));
+        assert!(registry.record("server", "zsh", true, "t4".to_string()));
+
+        let laptop = &registry.machines["laptop"];
+        assert_eq!(laptop.packages, vec!["git", "zsh"]);
+        assert_eq!(laptop.updated, "t2");
+
+        assert!(registry.record("server", "zsh", false, "t5".to_string()));
+        assert!(!registry.record("server", "vim", false, "t6".to_string()));
+        assert!(!registry.machines.contains_key("server"));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(Registry::load(temp_dir.path()).unwrap().machines.is_empty());
+
+        let mut registry = Registry::default();
+        registry.record("laptop", "zsh", true, "2026-01-01 12:00:00".to_string());
+        registry.save(temp_dir.path()).unwrap();
+        assert!(temp_dir.path().join(".stau/machines.toml").is_file());
+
+        let loaded = Registry::load(temp_dir.path()).unwrap();
+        assert_eq!(loaded.machines, registry.machines);
+    }
+
+    #[test]
+    fn test_concurrent_updates_keep_every_package() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path();
+        std::thread::scope(|scope| {
+            for i in 0..16 {
+                scope.spawn(move || {
+                    Registry::update(stau_dir, |registry| {
+                        registry.record("laptop", &format!("p{:02}", i), true, "t".to_string())
+                    })
+                    .unwrap();
+                });
+            }
+        });
+        let loaded = Registry::load(stau_dir).unwrap();
+        assert_eq!(loaded.machines["laptop"].packages.len(), 16);
+    }
+}
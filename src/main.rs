@@ -1,14 +1,45 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
 
+mod backup;
+mod cache;
 mod config;
+mod conflict;
 mod error;
+mod git;
+mod interrupt;
+mod jobs;
+mod machines;
+mod manifest;
+#[macro_use]
+mod output;
 mod package;
+mod plan;
+mod platform;
+mod porcelain;
+mod preflight;
+mod prompt;
+mod scan;
 mod script;
+mod scriptlog;
+mod segment;
+mod settings;
+mod snapshot;
+mod state;
+mod sudo;
 mod symlink;
+mod sync;
+mod timings;
+mod verbosity;
+mod warning;
+mod xattr;
 
 use config::Config;
+use conflict::{ConflictPolicy, Precedence, Resolution};
 use error::Result;
 
 #[derive(Parser)]
@@ -21,716 +52,5059 @@ struct Cli {
     #[command(subcommand)]
     command: Commands,
 
-    /// Verbose output
-    #[arg(short, long, global = true)]
-    verbose: bool,
+    /// Verbose output; repeat for per-file decisions (-vv) and every file system change (-vvv)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
 
     /// Dry run - show what would be done without making changes
     #[arg(short = 'n', long, global = true)]
     dry_run: bool,
+
+    /// Answer yes to every question, for unattended runs
+    #[arg(short, long, global = true)]
+    yes: bool,
+
+    /// Treat warnings as errors
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Retry file operations in directories you can't write to through sudo
+    #[arg(long, global = true)]
+    sudo: bool,
+
+    /// Also link package files matched by .gitignore
+    #[arg(long, global = true)]
+    no_gitignore: bool,
+
+    /// Link directories at this depth of a package as a whole instead of their files
+    #[arg(long, global = true, value_name = "N")]
+    max_depth: Option<NonZeroUsize>,
+
+    /// Link the contents of symlinked directories inside packages
+    #[arg(long, global = true)]
+    follow_package_symlinks: bool,
+
+    /// Leave paths matching this regex alone when another package links them (repeatable)
+    #[arg(long, global = true, value_name = "REGEX", value_parser = conflict::parse_path_regex)]
+    defer: Vec<regex::Regex>,
+
+    /// Take over paths matching this regex when another package links them (repeatable)
+    #[arg(long = "override", global = true, value_name = "REGEX", value_parser = conflict::parse_path_regex)]
+    overrides: Vec<regex::Regex>,
+
+    /// Write a Chrome trace of where the time goes (open it in Perfetto or chrome://tracing)
+    #[arg(long, global = true, value_name = "FILE")]
+    trace_file: Option<PathBuf>,
+
+    /// Print how long discovery, linking, scripts and git took per package
+    #[arg(long, global = true)]
+    timings: bool,
+
+    /// Work on up to N packages at once in install, uninstall, restow and list
+    #[arg(short, long, global = true, value_name = "N", default_value = "1")]
+    jobs: NonZeroUsize,
+}
+
+/// Package arguments shared by commands that operate on several packages
+#[derive(Args)]
+#[command(group = ArgGroup::new("selection").required(true).args(["packages", "all"]))]
+struct PackageSelection {
+    /// Package names or glob patterns ("-" reads names from stdin)
+    packages: Vec<String>,
+
+    /// Operate on all packages in STAU_DIR
+    #[arg(short, long)]
+    all: bool,
+
+    /// Skip a package (name or glob pattern, repeatable)
+    #[arg(long, value_name = "PACKAGE")]
+    exclude: Vec<String>,
+}
+
+impl PackageSelection {
+    /// Resolve the selection to a list of package names
+    fn resolve(&self, config: &Config) -> Result<Vec<String>> {
+        let packages = if self.all {
+            package::list_packages(&config.get_stau_dirs())?
+        } else if self.packages.iter().any(|p| p == "-") {
+            let mut patterns = Vec::new();
+            for pattern in &self.packages {
+                if pattern == "-" {
+                    patterns.extend(package::read_package_names(std::io::stdin().lock())?);
+                } else {
+                    patterns.push(pattern.clone());
+                }
+            }
+            package::resolve_packages(&config.get_stau_dirs(), &patterns)?
+        } else {
+            package::resolve_packages(&config.get_stau_dirs(), &self.packages)?
+        };
+        let packages = package::expand_bundles(&config.get_stau_dirs(), packages, |pkg| {
+            config.get_manifest(pkg).map(|manifest| manifest.members)
+        })?;
+
+        package::exclude_packages(packages, &self.exclude)
+    }
+
+    /// Resolve the selection, limiting `--all` to packages with links in the target
+    fn resolve_installed(&self, config: &Config, target: Option<&PathBuf>) -> Result<Vec<String>> {
+        let packages = self.resolve(config)?;
+        if !self.all {
+            return Ok(packages);
+        }
+
+        let target_dir = config.get_target(target.cloned());
+        let mut installed = Vec::new();
+        for pkg in packages {
+            if is_installed(config, &pkg, &target_dir)? {
+                installed.push(pkg);
+            }
+        }
+        Ok(installed)
+    }
+}
+
+/// Check whether any of a package's symlinks exist in the target
+fn is_installed(config: &Config, package: &str, target_dir: &std::path::Path) -> Result<bool> {
+    let mappings = config.discover(package, target_dir)?;
+    Ok(mappings
+        .iter()
+        .any(|m| symlink::is_stau_symlink(&m.target, &m.source).unwrap_or(false)))
+}
+
+/// Output format of `stau list`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ListFormat {
+    /// One line per package
+    Plain,
+    /// Aligned columns with link counts, target and description
+    Table,
+    /// Packages grouped by the tags in their stau.toml
+    Tree,
+}
+
+/// Output format of a dry run of install, uninstall or restow
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum PlanFormat {
+    /// What would be done, as it would be done
+    #[default]
+    Text,
+    /// The computed plan as a JSON document, without running scripts
+    Json,
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the value of a key
+    Get {
+        /// Config key (see 'stau config list')
+        key: String,
+    },
+
+    /// Set the value of a key
+    Set {
+        /// Config key (see 'stau config list')
+        key: String,
+
+        /// New value
+        value: String,
+    },
+
+    /// List all keys and their values
+    List,
+}
+
+#[derive(Subcommand)]
+enum BackupAction {
+    /// List backups with their size and number of files
+    List,
+
+    /// List the files kept in a backup
+    Show {
+        /// Backup id (see 'stau backups list')
+        id: String,
+    },
+
+    /// Copy files from a backup back to where they were
+    Restore {
+        /// Backup id (see 'stau backups list')
+        id: String,
+
+        /// Only restore this path (default: every file in the backup)
+        path: Option<PathBuf>,
+
+        /// Replace files that stau doesn't manage
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Delete old backups
+    #[command(group(ArgGroup::new("policy").required(true).multiple(true)))]
+    Prune {
+        /// Delete backups older than this many days
+        #[arg(long, value_name = "DAYS", group = "policy")]
+        older_than: Option<u64>,
+
+        /// Delete the oldest backups until the rest fit into this many megabytes
+        #[arg(long, value_name = "MB", group = "policy")]
+        max_size: Option<u64>,
+    },
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Install a package by creating symlinks
     Install {
-        /// Package name to install
-        package: String,
+        #[command(flatten)]
+        selection: PackageSelection,
 
-        /// Target directory (default: $HOME or $STAU_TARGET)
-        #[arg(short, long, env = "STAU_TARGET")]
-        target: Option<PathBuf>,
+        /// Target directory (default: $HOME or $STAU_TARGET; repeatable)
+        #[arg(short, long)]
+        target: Vec<PathBuf>,
 
         /// Skip running setup script
         #[arg(long)]
         no_setup: bool,
 
-        /// Force install even if conflicts exist
+        /// Move existing files out of the way into the backups (same as --conflicts backup)
         #[arg(short, long)]
         force: bool,
+
+        /// How to handle existing files in the target (default: from stau.toml or config, else fail)
+        #[arg(long, value_name = "POLICY", conflicts_with = "force")]
+        conflicts: Option<ConflictPolicy>,
+
+        /// Install the package at this path instead of looking it up in STAU_DIR
+        #[arg(long, value_name = "DIR", group = "selection")]
+        package_dir: Option<PathBuf>,
+
+        /// Create the target directory if it doesn't exist
+        #[arg(long)]
+        create_target: bool,
+
+        /// Initialize git submodules inside the package without asking
+        #[arg(long)]
+        init_submodules: bool,
+
+        /// Only link this file or directory of the package, relative to its root (repeatable)
+        #[arg(long = "path", value_name = "PATH")]
+        paths: Vec<PathBuf>,
+
+        /// Output format of --dry-run
+        #[arg(long, value_enum, default_value_t)]
+        format: PlanFormat,
     },
 
     /// Uninstall a package by removing symlinks and copying files back
     Uninstall {
-        /// Package name to uninstall
-        package: String,
+        #[command(flatten)]
+        selection: PackageSelection,
 
-        /// Target directory (default: $HOME or $STAU_TARGET)
-        #[arg(short, long, env = "STAU_TARGET")]
-        target: Option<PathBuf>,
+        /// Target directory (default: $HOME or $STAU_TARGET; repeatable)
+        #[arg(short, long)]
+        target: Vec<PathBuf>,
 
         /// Skip running teardown script
         #[arg(long)]
         no_teardown: bool,
 
-        /// Force uninstall even if conflicts exist
+        /// Move existing files out of the way when copying back
         #[arg(long)]
         force: bool,
+
+        /// How to handle existing files when copying back (default: from stau.toml or config, else fail)
+        #[arg(long, value_name = "POLICY")]
+        conflicts: Option<ConflictPolicy>,
+
+        /// Only remove the symlinks, without copying files back into the target
+        #[arg(long)]
+        no_copy: bool,
+
+        /// Also delete the package from STAU_DIR after unlinking it (asks for confirmation)
+        #[arg(long)]
+        purge: bool,
+
+        /// Purge packages even if they have uncommitted changes in STAU_DIR
+        #[arg(long, requires = "purge")]
+        discard_changes: bool,
+
+        /// Output format of --dry-run
+        #[arg(long, value_enum, default_value_t)]
+        format: PlanFormat,
     },
 
-    /// Restow a package (uninstall and reinstall)
+    /// Restow a package (update its symlinks to match the package contents)
     Restow {
-        /// Package name to restow
-        package: String,
+        #[command(flatten)]
+        selection: PackageSelection,
 
-        /// Target directory (default: $HOME or $STAU_TARGET)
-        #[arg(short, long, env = "STAU_TARGET")]
-        target: Option<PathBuf>,
+        /// Target directory (default: $HOME or $STAU_TARGET; repeatable)
+        #[arg(short, long)]
+        target: Vec<PathBuf>,
 
         /// Run setup script during restow
         #[arg(long)]
         run_setup: bool,
+
+        /// How to handle existing files in the target (default: from stau.toml or config, else fail)
+        #[arg(long, value_name = "POLICY")]
+        conflicts: Option<ConflictPolicy>,
+
+        /// Restow the installed packages with files changed since this git revision
+        #[arg(long, value_name = "REV", group = "selection")]
+        since: Option<String>,
+
+        /// Output format of --dry-run
+        #[arg(long, value_enum, default_value_t)]
+        format: PlanFormat,
+    },
+
+    /// Re-run setup scripts for installed packages without touching symlinks
+    Upgrade {
+        #[command(flatten)]
+        selection: PackageSelection,
+
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+
+        /// Only run setup for packages whose setup scripts changed since their last run
+        #[arg(long)]
+        changed: bool,
     },
 
     /// Adopt existing files into a package
     Adopt {
         /// Package name to adopt files into
-        package: String,
+        #[arg(required_unless_present = "scan")]
+        package: Option<String>,
 
         /// File paths to adopt
+        #[arg(required_unless_present = "scan")]
+        files: Vec<PathBuf>,
+
+        /// Look for unmanaged dotfiles in DIR (default: the target) and pick which to adopt
+        #[arg(long, value_name = "DIR", num_args = 0..=1, conflicts_with_all = ["package", "files"])]
+        scan: Option<Option<PathBuf>>,
+
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+
+        /// Commit the adopted files if STAU_DIR is a git repository
+        #[arg(long)]
+        commit: bool,
+
+        /// Link adopted directories as a whole, recording them in the package's stau.toml
+        #[arg(long)]
+        fold: bool,
+
+        /// Where the file goes in the package, instead of its path in the target
+        #[arg(long, value_name = "PATH", conflicts_with = "scan")]
+        into: Option<PathBuf>,
+    },
+
+    /// Remove files from a package along with their symlinks
+    Rm {
+        /// Package name to remove files from
+        package: String,
+
+        /// Files to remove, relative to the package (or absolute paths in the target directory)
         #[arg(required = true)]
         files: Vec<PathBuf>,
 
         /// Target directory (default: $HOME or $STAU_TARGET)
-        #[arg(short, long, env = "STAU_TARGET")]
+        #[arg(short, long)]
         target: Option<PathBuf>,
     },
 
     /// List all packages and their installation status
     List {
         /// Target directory to check status (default: $HOME or $STAU_TARGET)
-        #[arg(short, long, env = "STAU_TARGET")]
+        #[arg(short, long)]
         target: Option<PathBuf>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ListFormat::Plain)]
+        format: ListFormat,
+
+        /// Exit with an error if an installed package has broken or conflicting links
+        #[arg(long)]
+        check: bool,
+
+        /// Only show packages in these states (comma-separated or repeated)
+        #[arg(long, value_enum, value_delimiter = ',', value_name = "STATE")]
+        filter: Vec<PackageState>,
+
+        /// Mark packages with uncommitted changes or that differ from the upstream branch
+        #[arg(short, long)]
+        long: bool,
+
+        /// Print only the absolute directory of each package, one per line, for scripts
+        #[arg(long, conflicts_with_all = ["format", "long"])]
+        paths: bool,
+
+        /// Print a stable, line-oriented format for scripts (see the README)
+        #[arg(long, value_enum, value_name = "VERSION", num_args = 0..=1, default_missing_value = "v1", conflicts_with_all = ["format", "long", "paths"])]
+        porcelain: Option<porcelain::Version>,
+
+        /// Follow each package directory with the target directory, separated by a tab
+        #[arg(long, requires = "paths")]
+        with_target: bool,
+
+        /// End each path with a NUL byte instead of a tab or newline, for xargs -0
+        #[arg(short = '0', long, requires = "paths")]
+        null: bool,
     },
 
-    /// Show detailed status for a specific package
+    /// Show detailed status for one or more packages
     Status {
-        /// Package name to show status for
+        #[command(flatten)]
+        selection: PackageSelection,
+
+        /// Target directory to check status (default: $HOME or $STAU_TARGET; repeatable)
+        #[arg(short, long)]
+        target: Vec<PathBuf>,
+
+        /// Show link destinations, file sizes and modification times
+        #[arg(short, long)]
+        long: bool,
+
+        /// Print one line of link counts, e.g. for a shell prompt (--all: installed packages only)
+        #[arg(short, long, conflicts_with = "long")]
+        short: bool,
+
+        /// Scan the whole target for links into STAU_DIR instead of checking packages
+        #[arg(long, group = "selection", conflicts_with = "long")]
+        global: bool,
+
+        /// Print a stable, line-oriented format for scripts (see the README)
+        #[arg(long, value_enum, value_name = "VERSION", num_args = 0..=1, default_missing_value = "v1", conflicts_with_all = ["long", "short", "global"])]
+        porcelain: Option<porcelain::Version>,
+    },
+
+    /// Read and write the global config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// Show the effective configuration and where each value comes from
+    Env {
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+    },
+
+    /// Move a package's setup and teardown scripts into scripts/ and order its stau.toml keys
+    Fmt {
+        /// Package name
+        package: String,
+    },
+
+    /// Show a package's description, tags and README
+    Info {
+        /// Package name
         package: String,
+    },
 
-        /// Target directory to check status (default: $HOME or $STAU_TARGET)
-        #[arg(short, long, env = "STAU_TARGET")]
+    /// Run a named script from a package's scripts/ directory
+    Run {
+        /// Package containing the script
+        package: String,
+
+        /// Script name (runs scripts/<name>.sh); lists available scripts if omitted
+        script: Option<String>,
+
+        /// Arguments passed through to the script
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
         target: Option<PathBuf>,
     },
 
-    /// Clean up broken symlinks for a package
+    /// Clean up broken symlinks for one or more packages
     Clean {
-        /// Package name to clean
-        package: String,
+        #[command(flatten)]
+        selection: PackageSelection,
 
         /// Target directory to clean (default: $HOME or $STAU_TARGET)
-        #[arg(short, long, env = "STAU_TARGET")]
+        #[arg(short, long)]
         target: Option<PathBuf>,
     },
-}
 
-fn main() {
-    let cli = Cli::parse();
+    /// Clone a dotfiles repository into STAU_DIR and optionally install it
+    Clone {
+        /// Git URL of the dotfiles repository
+        url: String,
 
-    if let Err(e) = run(cli) {
-        eprintln!("Error: {}", e);
+        /// Clone into this directory instead of STAU_DIR
+        #[arg(long, value_name = "DIR")]
+        path: Option<PathBuf>,
 
-        // Use appropriate exit code based on error type
-        let exit_code = e.exit_code();
+        /// Also clone the repository's submodules
+        #[arg(long)]
+        recurse_submodules: bool,
 
-        process::exit(exit_code);
-    }
-}
+        /// Install all packages after cloning without asking
+        #[arg(long)]
+        install: bool,
 
-fn run(cli: Cli) -> Result<()> {
-    let config = Config::new()?;
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+    },
 
-    if cli.verbose {
-        println!("STAU_DIR: {}", config.stau_dir.display());
-    }
+    /// Pull the dotfiles repository and restow the installed packages it changed
+    Sync {
+        /// Keep running and sync again after this long, e.g. 30m or 1h
+        #[arg(long, value_parser = sync::parse_interval)]
+        interval: Option<Duration>,
 
-    match cli.command {
-        Commands::Install {
-            package,
-            target,
-            no_setup,
-            force,
-        } => install_package(
-            &config,
-            &package,
-            target,
-            no_setup,
-            force,
-            cli.dry_run,
-            cli.verbose,
-        ),
+        /// Run in the background (requires --interval)
+        #[arg(long, requires = "interval")]
+        daemon: bool,
 
-        Commands::Uninstall {
-            package,
-            target,
-            no_teardown,
-            force,
-        } => uninstall_package(
-            &config,
-            &package,
-            target,
-            no_teardown,
-            force,
-            cli.dry_run,
-            cli.verbose,
-        ),
+        /// Log file of the background sync (default: sync.log in the state directory)
+        #[arg(long, value_name = "FILE", requires = "daemon")]
+        log: Option<PathBuf>,
 
-        Commands::Restow {
-            package,
-            target,
-            run_setup,
-        } => {
-            // Uninstall first (without teardown, without copying files back)
-            let opts = UninstallOptions {
-                no_teardown: true,
-                force: false,
-                copy_files_back: false, // Don't copy for restow!
-                dry_run: cli.dry_run,
-                verbose: cli.verbose,
-            };
-            uninstall_package_internal(&config, &package, target.clone(), opts)?;
-
-            // Then install (with setup if requested)
-            install_package(
-                &config,
-                &package,
-                target,
-                !run_setup,
-                false, // Don't force during restow
-                cli.dry_run,
-                cli.verbose,
-            )
-        }
+        /// Run as the background process started by --daemon
+        #[arg(long, hide = true, requires = "daemon")]
+        foreground: bool,
 
-        Commands::Adopt {
-            package,
-            files,
-            target,
-        } => adopt_files(&config, &package, &files, target, cli.dry_run, cli.verbose),
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+    },
 
-        Commands::List { target } => list_packages(&config, target),
+    /// Record the state of all stau-managed paths in the target
+    Snapshot {
+        /// Name of the snapshot (default: current date and time)
+        name: Option<String>,
 
-        Commands::Status { package, target } => show_status(&config, &package, target),
+        /// Also keep copies of regular files so they can be restored
+        #[arg(long)]
+        archive: bool,
 
-        Commands::Clean { package, target } => {
-            clean_broken_symlinks(&config, &package, target, cli.dry_run, cli.verbose)
-        }
-    }
-}
+        /// List existing snapshots instead of taking one
+        #[arg(long, conflicts_with_all = ["name", "archive"])]
+        list: bool,
 
-fn install_package(
-    config: &Config,
-    package: &str,
-    target: Option<PathBuf>,
-    no_setup: bool,
-    force: bool,
-    dry_run: bool,
-    verbose: bool,
-) -> Result<()> {
-    let target_dir = config.get_target(target);
-    let package_dir = config.get_package_dir(package);
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+    },
 
-    if verbose {
-        println!("Package directory: {}", package_dir.display());
-        println!("Target directory: {}", target_dir.display());
-    }
+    /// Inspect, restore and prune files that --force moved aside
+    Backups {
+        #[command(subcommand)]
+        action: BackupAction,
+    },
 
-    // Check if package exists
-    if !config.package_exists(package) {
-        return Err(error::StauError::PackageNotFound(package.to_string()));
-    }
+    /// Print the output of a package's last hook script run
+    Log {
+        /// Package whose log to print, or to list the logs of with --scripts
+        #[arg(required_unless_present = "scripts")]
+        package: Option<String>,
 
-    // Discover all files in the package
-    let mappings = package::discover_package_files(&package_dir, &target_dir)?;
+        /// List the saved script logs, newest first, instead of printing one
+        #[arg(long)]
+        scripts: bool,
+    },
 
-    if verbose {
-        println!("Found {} files to link", mappings.len());
-    }
+    /// Put stau-managed paths back the way a snapshot recorded them
+    Restore {
+        /// Name of the snapshot to restore
+        name: String,
 
-    if mappings.is_empty() {
-        println!("No files to link in package '{}'", package);
-        return Ok(());
-    }
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+    },
 
-    // Create symlinks for all files
-    for mapping in &mappings {
-        if verbose || dry_run {
-            println!(
-                "  {} -> {}",
+    /// Search the contents of package files
+    Grep {
+        /// Regular expression to search for
+        pattern: String,
+
+        /// Package names or glob patterns to search (default: all packages)
+        packages: Vec<String>,
+
+        /// Match case-insensitively
+        #[arg(short, long)]
+        ignore_case: bool,
+
+        /// Only print the names of matching files
+        #[arg(short = 'l', long)]
+        files_with_matches: bool,
+    },
+
+    /// Report target paths claimed by more than one package
+    Check {
+        #[command(flatten)]
+        selection: PackageSelection,
+
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+    },
+
+    /// Show which packages each machine has installed, from the registry in STAU_DIR
+    Machines,
+
+    /// Compare the packages installed here with those the machines registry records
+    Drift {
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+    },
+
+    /// Print broken links and dirty packages in a line short enough for a shell prompt
+    ///
+    /// Results are cached for a few seconds. When checking takes longer than
+    /// the budget, the last result is printed and refreshed in the background.
+    Prompt {
+        /// Target directory (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+
+        /// Longest time to spend checking, in milliseconds
+        #[arg(long, value_name = "MS", default_value_t = 100)]
+        budget: u64,
+
+        /// Check and update the cache without printing (used for background refreshes)
+        #[arg(long, hide = true)]
+        refresh: bool,
+
+        /// Keep the cache up to date from file system events until stopped
+        #[arg(long, conflicts_with = "refresh")]
+        watch: bool,
+
+        /// Watch in the background (requires --watch)
+        #[arg(long, requires = "watch")]
+        daemon: bool,
+
+        /// Log file of the background watch (default: prompt-watch.log in the state directory)
+        #[arg(long, value_name = "FILE", requires = "daemon")]
+        log: Option<PathBuf>,
+
+        /// Run as the background process started by --daemon
+        #[arg(long, hide = true, requires = "daemon")]
+        foreground: bool,
+    },
+
+    /// Remove empty directories left behind in the target by uninstalled packages
+    Gc {
+        #[command(flatten)]
+        selection: PackageSelection,
+
+        /// Target directory to clean (default: $HOME or $STAU_TARGET)
+        #[arg(short, long)]
+        target: Option<PathBuf>,
+    },
+}
+
+impl Commands {
+    /// The operation of a command that changes files, for the pre-command hook
+    fn operation(&self) -> Option<script::Operation> {
+        use script::Operation;
+        match self {
+            Commands::Install { .. } => Some(Operation::Install),
+            Commands::Uninstall { .. } => Some(Operation::Uninstall),
+            Commands::Restow { .. } => Some(Operation::Restow),
+            Commands::Upgrade { .. } => Some(Operation::Upgrade),
+            Commands::Adopt { .. } => Some(Operation::Adopt),
+            Commands::Rm { .. } => Some(Operation::Rm),
+            Commands::Fmt { .. } => Some(Operation::Fmt),
+            Commands::Clean { .. } => Some(Operation::Clean),
+            Commands::Sync { .. } => Some(Operation::Sync),
+            Commands::Restore { .. } => Some(Operation::Restore),
+            Commands::Gc { .. } => Some(Operation::Gc),
+            _ => None,
+        }
+    }
+
+    /// Whether the command is to print its plan as JSON instead of running
+    fn json_plan(&self) -> bool {
+        match self {
+            Commands::Install { format, .. }
+            | Commands::Uninstall { format, .. }
+            | Commands::Restow { format, .. } => *format == PlanFormat::Json,
+            _ => false,
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    interrupt::install_handler();
+    prompt::set_assume_yes(cli.yes);
+    warning::set_strict(cli.strict);
+    sudo::set_enabled(cli.sudo);
+    verbosity::set_level(cli.verbose);
+
+    let timings = cli.timings.then(timings::Timings::default);
+    let trace = match start_tracing(cli.trace_file.as_deref(), timings.clone()) {
+        Ok(trace) => trace,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(e.exit_code());
+        }
+    };
+    let start = std::time::Instant::now();
+    let result = run(cli);
+    // Write out the trace before exiting
+    drop(trace);
+
+    // On stderr, so the output of the command itself can still be parsed
+    if let Some(timings) = timings {
+        eprintln!();
+        eprintln!("{}", timings.report(start.elapsed()));
+    }
+
+    if let Err(e) = result {
+        if !matches!(e, error::StauError::Status(_)) {
+            eprintln!("Error: {}", e);
+        }
+
+        // Use appropriate exit code based on error type
+        let exit_code = e.exit_code();
+
+        process::exit(exit_code);
+    }
+}
+
+/// Record tracing spans into a Chrome trace file, written when the guard is
+/// dropped, and add up their times for `--timings`
+///
+/// Without either, spans aren't recorded at all.
+fn start_tracing(
+    trace_file: Option<&Path>,
+    timings: Option<timings::Timings>,
+) -> Result<Option<tracing_chrome::FlushGuard>> {
+    use tracing_subscriber::prelude::*;
+
+    if trace_file.is_none() && timings.is_none() {
+        return Ok(None);
+    }
+    let (chrome, guard) = match trace_file {
+        Some(path) => {
+            let file = std::fs::File::create(path).map_err(error::StauError::Io)?;
+            let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+                .writer(file)
+                .include_args(true)
+                .build();
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(chrome)
+        .with(timings)
+        .init();
+    Ok(guard)
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let verbose = cli.verbose > 0;
+    let jobs = cli.jobs.get();
+
+    // Editing the config file must work even when STAU_DIR can't be resolved
+    if let Commands::Config { action } = &cli.command {
+        return edit_settings(action, cli.dry_run);
+    }
+
+    // Cloning creates STAU_DIR, so it can't require it to exist
+    if let Commands::Clone {
+        url,
+        path,
+        recurse_submodules,
+        install,
+        target,
+    } = &cli.command
+    {
+        let opts = CloneOptions {
+            recurse_submodules: *recurse_submodules,
+            install: *install,
+            dry_run: cli.dry_run,
+            verbose,
+        };
+        return clone_dotfiles(url, path.clone(), target.clone(), opts);
+    }
+
+    // Commands naming a single package take it literally, so check it can't escape STAU_DIR
+    if let Commands::Adopt {
+        package: Some(package),
+        ..
+    }
+    | Commands::Log {
+        package: Some(package),
+        ..
+    }
+    | Commands::Rm { package, .. }
+    | Commands::Info { package }
+    | Commands::Fmt { package }
+    | Commands::Run { package, .. } = &cli.command
+    {
+        package::validate_name(package)?;
+    }
+
+    // A standalone package directory stands in for STAU_DIR entirely
+    let (mut config, standalone_package) = match &cli.command {
+        Commands::Install {
+            package_dir: Some(dir),
+            ..
+        } => {
+            let (config, package) = Config::for_package_dir(dir)?;
+            (config, Some(package))
+        }
+        _ => (Config::new()?, None),
+    };
+    if cli.no_gitignore {
+        config.discovery.gitignore = false;
+    }
+    config.discovery.max_depth = cli.max_depth;
+    config.discovery.follow_symlinks = cli.follow_package_symlinks;
+    config.precedence = Precedence {
+        defer: cli.defer.clone(),
+        overrides: cli.overrides.clone(),
+    };
+
+    if verbose {
+        println!("STAU_DIR: {}", config.stau_dir.display());
+    }
+
+    let json_plan = cli.command.json_plan();
+    if json_plan && !cli.dry_run {
+        return Err(error::StauError::Other(
+            "--format json prints the plan of a dry run; add --dry-run".to_string(),
+        ));
+    }
+
+    // The plan is recorded on this thread, one package after another
+    let jobs = if json_plan { 1 } else { jobs };
+
+    // A standalone package has no STAU_DIR whose hooks could apply, and a
+    // plan only shows the links
+    if standalone_package.is_none()
+        && !json_plan
+        && let Some(operation) = cli.command.operation()
+    {
+        run_pre_command(&config, operation, cli.dry_run, verbose)?;
+    }
+
+    match cli.command {
+        Commands::Install {
+            selection,
+            target,
+            no_setup,
+            force,
+            conflicts,
+            create_target,
+            init_submodules,
+            paths,
+            ..
+        } => {
+            let conflicts = conflicts.or(force.then_some(ConflictPolicy::Backup));
+            let opts = InstallOptions {
+                no_setup,
+                conflicts,
+                paths: &paths,
+                dry_run: cli.dry_run,
+                verbose,
+            };
+            let depends = |pkg: &str| config.get_manifest(pkg).map(|manifest| manifest.depends);
+            let packages = match standalone_package {
+                Some(package) => vec![package],
+                None => package::order_by_dependencies(&selection.resolve(&config)?, depends)?,
+            };
+            let install = || {
+                for_each_target(&target, |target| {
+                    ensure_target_dir(
+                        &config.get_target(target.clone()),
+                        create_target || config.create_target,
+                        cli.dry_run,
+                    )?;
+                    let dependencies = jobs::dependencies(&packages, depends)?;
+                    for_each_package(&packages, jobs, &dependencies, |package| {
+                        prepare_submodules(&config, package, init_submodules, cli.dry_run)?;
+                        install_package(&config, package, target.clone(), &opts)
+                    })
+                })
+            };
+            if json_plan {
+                return print_plan("install", install);
+            }
+            install()
+        }
+
+        Commands::Uninstall {
+            selection,
+            target,
+            no_teardown,
+            force,
+            conflicts,
+            no_copy,
+            purge,
+            discard_changes,
+            ..
+        } => {
+            let conflicts = conflicts.or(force.then_some(ConflictPolicy::Backup));
+            if purge && target.len() > 1 {
+                return Err(error::StauError::Other(
+                    "--purge can only be used with a single target".to_string(),
+                ));
+            }
+            let uninstall = || {
+                for_each_target(&target, |target| {
+                    let packages = selection.resolve_installed(&config, target.as_ref())?;
+                    // Asked up front, as the packages may be uninstalled concurrently
+                    let mut purged = Vec::new();
+                    if purge {
+                        for package in &packages {
+                            if confirm_purge(&config, package, discard_changes, cli.dry_run)? {
+                                purged.push(package.clone());
+                            }
+                        }
+                    }
+                    for_each_package(&packages, jobs, &[], |package| {
+                        let opts = UninstallOptions {
+                            no_teardown,
+                            conflicts,
+                            copy_files_back: !no_copy,
+                            dry_run: cli.dry_run,
+                            verbose,
+                        };
+                        let report = uninstall_package(&config, package, target.clone(), opts)?;
+                        if purged.iter().any(|p| p == package) {
+                            purge_package(&config, package, cli.dry_run)?;
+                        }
+                        Ok(report)
+                    })
+                })
+            };
+            if json_plan {
+                return print_plan("uninstall", uninstall);
+            }
+            uninstall()
+        }
+
+        Commands::Restow {
+            selection,
+            target,
+            run_setup,
+            conflicts,
+            since,
+            ..
+        } => {
+            let resolve = |target: Option<&PathBuf>| -> Result<Vec<String>> {
+                match &since {
+                    Some(rev) => {
+                        let target_dir = config.get_target(target.cloned());
+                        let mut installed = Vec::new();
+                        for package in packages_changed_since(&config, rev)? {
+                            if config.package_exists(&package)
+                                && is_installed(&config, &package, &target_dir)?
+                            {
+                                installed.push(package);
+                            }
+                        }
+                        Ok(installed)
+                    }
+                    None => selection.resolve_installed(&config, target),
+                }
+            };
+            let restow = || {
+                for_each_target(&target, |target| {
+                    let packages = resolve(target.as_ref())?;
+                    if packages.is_empty()
+                        && let Some(rev) = &since
+                    {
+                        outln!("No installed packages changed since {}", rev);
+                        return Ok(());
+                    }
+                    for_each_package(&packages, jobs, &[], |package| {
+                        restow_package(
+                            &config,
+                            package,
+                            target.clone(),
+                            run_setup,
+                            conflicts,
+                            cli.dry_run,
+                            verbose,
+                        )
+                    })
+                })
+            };
+            if json_plan {
+                return print_plan("restow", restow);
+            }
+            restow()
+        }
+
+        Commands::Upgrade {
+            selection,
+            target,
+            changed,
+        } => {
+            let packages = selection.resolve_installed(&config, target.as_ref())?;
+            for_each_package(&packages, 1, &[], |package| {
+                upgrade_package(
+                    &config,
+                    package,
+                    target.clone(),
+                    changed,
+                    cli.dry_run,
+                    verbose,
+                )
+            })
+        }
+
+        Commands::Adopt {
+            package,
+            files,
+            scan,
+            target,
+            commit,
+            fold,
+            into,
+        } => {
+            if into.is_some() && files.len() != 1 {
+                return Err(error::StauError::Other(
+                    "--into takes exactly one file to adopt".to_string(),
+                ));
+            }
+            let opts = AdoptOptions {
+                commit: commit || config.adopt_commit,
+                fold,
+                into,
+                dry_run: cli.dry_run,
+                verbose,
+            };
+            match (scan, package) {
+                (Some(dir), _) => adopt_scanned(&config, dir, target, &opts),
+                (None, Some(package)) => adopt_files(&config, &package, &files, target, &opts),
+                (None, None) => unreachable!("clap requires a package without --scan"),
+            }
+        }
+
+        Commands::Rm {
+            package,
+            files,
+            target,
+        } => remove_files(&config, &package, &files, target, cli.dry_run, verbose),
+
+        Commands::List {
+            target,
+            format,
+            check,
+            filter,
+            long,
+            paths,
+            with_target,
+            null,
+            porcelain,
+        } => {
+            let opts = ListOptions {
+                format,
+                check,
+                filter: &filter,
+                long,
+                paths: paths.then_some(PathsOutput { with_target, null }),
+                porcelain,
+                jobs,
+            };
+            list_packages(&config, target, &opts)
+        }
+
+        Commands::Status {
+            selection,
+            target,
+            long,
+            short,
+            global,
+            porcelain,
+        } => {
+            let mut healthy = true;
+            let mut unhealthy = Vec::new();
+            let mut stray = (0, 0);
+            if let Some(version) = porcelain {
+                // Every target gets a record of its own instead of a heading
+                println!("{}", porcelain::header(version));
+                for target in targets_or_default(&target) {
+                    let target_dir = config.get_target(target);
+                    println!("{}", porcelain::target(&target_dir));
+                    for package in selection.resolve(&config)? {
+                        if !show_porcelain_status(&config, &package, &target_dir)?
+                            && !unhealthy.contains(&package)
+                        {
+                            unhealthy.push(package);
+                        }
+                    }
+                }
+                if unhealthy.is_empty() {
+                    return Ok(());
+                }
+                return Err(error::StauError::Unhealthy(unhealthy.join(", ")));
+            }
+            for_each_target(&target, |target| {
+                if global {
+                    let (broken, orphaned) = show_global_status(&config, target, short)?;
+                    healthy &= broken + orphaned == 0;
+                    stray = (stray.0 + broken, stray.1 + orphaned);
+                } else if short {
+                    let packages = selection.resolve_installed(&config, target.as_ref())?;
+                    let counts = StatusCounts::check(&config, &packages, target)?;
+                    println!("{}", counts);
+                    healthy &= counts.is_healthy();
+                } else {
+                    for (i, package) in selection.resolve(&config)?.iter().enumerate() {
+                        if i > 0 {
+                            println!();
+                        }
+                        if !show_status(&config, package, target.clone(), long)? {
+                            healthy = false;
+                            if !unhealthy.contains(package) {
+                                unhealthy.push(package.clone());
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            })?;
+
+            if healthy {
+                return Ok(());
+            }
+            let error = if global {
+                error::StauError::StrayLinks(format!("{} broken, {} orphaned", stray.0, stray.1))
+            } else {
+                error::StauError::Unhealthy(unhealthy.join(", "))
+            };
+            if short {
+                // Only the exit code, so the line stays usable in a prompt
+                return Err(error::StauError::Status(error.exit_code()));
+            }
+            Err(error)
+        }
+
+        Commands::Config { .. } | Commands::Clone { .. } => {
+            unreachable!("handled before loading the config")
+        }
+
+        Commands::Env { target } => show_env(&config, target),
+
+        Commands::Info { package } => show_info(&config, &package),
+
+        Commands::Fmt { package } => format_package(&config, &package, cli.dry_run),
+
+        Commands::Run {
+            package,
+            script,
+            args,
+            target,
+        } => run_script(
+            &config,
+            &package,
+            script.as_deref(),
+            &args,
+            target,
+            cli.dry_run,
+            verbose,
+        ),
+
+        Commands::Clean { selection, target } => {
+            for package in selection.resolve(&config)? {
+                clean_broken_symlinks(&config, &package, target.clone(), cli.dry_run, verbose)?;
+            }
+            Ok(())
+        }
+
+        Commands::Sync {
+            interval,
+            daemon,
+            log,
+            foreground,
+            target,
+        } => {
+            // The background process runs the loop itself
+            let daemon = daemon && !foreground;
+            sync_dotfiles(&config, interval, daemon, log, target, cli.dry_run, verbose)
+        }
+
+        Commands::Snapshot {
+            name,
+            archive,
+            list,
+            target,
+        } => {
+            if list {
+                list_snapshots(&config)
+            } else {
+                take_snapshot(&config, name, archive, target, cli.dry_run)
+            }
+        }
+
+        Commands::Restore { name, target } => {
+            restore_snapshot(&config, &name, target, cli.dry_run, verbose)
+        }
+
+        Commands::Backups { action } => manage_backups(&config, action, cli.dry_run),
+
+        Commands::Log { package, scripts } => show_script_logs(&config, package, scripts),
+
+        Commands::Grep {
+            pattern,
+            packages,
+            ignore_case,
+            files_with_matches,
+        } => grep_packages(
+            &config,
+            &pattern,
+            &packages,
+            ignore_case,
+            files_with_matches,
+        ),
+
+        Commands::Check { selection, target } => {
+            check_conflicts(&config, &selection.resolve(&config)?, target)
+        }
+
+        Commands::Machines => show_machines(&config),
+
+        Commands::Drift { target } => show_drift(&config, target),
+
+        Commands::Prompt {
+            target,
+            budget,
+            refresh,
+            watch,
+            daemon,
+            log,
+            foreground,
+        } => {
+            if watch {
+                watch_prompt(&config, target, daemon && !foreground, log)
+            } else {
+                show_prompt(&config, target, Duration::from_millis(budget), refresh)
+            }
+        }
+
+        Commands::Gc { selection, target } => {
+            for package in selection.resolve(&config)? {
+                remove_empty_dirs(&config, &package, target.clone(), cli.dry_run, verbose)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// What an operation did to one package, for the summary after several packages
+#[derive(Default)]
+struct PackageReport {
+    created: usize,
+    removed: usize,
+    conflicts: usize,
+    scripts: usize,
+}
+
+/// Check up front that stau may write every path it is about to change
+///
+/// All problems are reported together before anything is touched. Paths in
+/// directories the user can't write to are fine with `--sudo`, which
+/// performs just those operations through sudo; read-only mounts never are.
+fn check_permissions<'a>(paths: impl IntoIterator<Item = &'a Path>, verbose: bool) -> Result<()> {
+    let _span = tracing::info_span!("preflight").entered();
+    let report = preflight::check(paths);
+    if report.is_empty() {
+        return Ok(());
+    }
+
+    if sudo::enabled() && report.only_permissions() {
+        if verbose {
+            outln!("Using sudo for {} paths", report.path_count());
+        }
+        return Ok(());
+    }
+
+    Err(error::StauError::NotWritable(report.to_string()))
+}
+
+/// Check that the file systems in the target have room for the files copied back on uninstall
+fn check_space(mappings: &[&symlink::SymlinkMapping]) -> Result<()> {
+    let copies = mappings.iter().map(|m| {
+        // A directory linked as a whole is copied back with everything in it
+        let size = ignore::WalkBuilder::new(&m.source)
+            .standard_filters(false)
+            .build()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum();
+        (m.target.as_path(), size)
+    });
+
+    match preflight::check_space(copies) {
+        Some(shortage) => Err(error::StauError::InsufficientSpace(format!(
+            "copying files back to {} needs {}, but only {} are free",
+            shortage.dir.display(),
+            format_size(shortage.needed),
+            format_size(shortage.available)
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Do a dry run of a command, printing the steps it took as a JSON plan
+/// instead of its usual output
+///
+/// The plan ends where the dry run stopped; its error is returned after the plan.
+fn print_plan(command: &'static str, dry_run: impl FnOnce() -> Result<()>) -> Result<()> {
+    let (result, steps) = plan::record(dry_run);
+    let mut plan = plan::Plan::new(command);
+    plan.steps = steps;
+    println!("{}", plan.to_json()?);
+    result
+}
+
+/// The `--target`s given, or just the default target
+fn targets_or_default(targets: &[PathBuf]) -> Vec<Option<PathBuf>> {
+    if targets.is_empty() {
+        return vec![None];
+    }
+    targets.iter().cloned().map(Some).collect()
+}
+
+/// Run an operation for each `--target` given, or once for the default target
+fn for_each_target(
+    targets: &[PathBuf],
+    mut operation: impl FnMut(Option<PathBuf>) -> Result<()>,
+) -> Result<()> {
+    if targets.is_empty() {
+        return operation(None);
+    }
+    for (i, target) in targets.iter().enumerate() {
+        if targets.len() > 1 {
+            if i > 0 {
+                outln!();
+            }
+            outln!("Target {}:", target.display());
+        }
+        operation(Some(target.clone()))?;
+    }
+    Ok(())
+}
+
+/// Run an operation on each package, up to `jobs` at a time, starting no more
+/// packages after the first failure
+///
+/// `dependencies` holds the earlier packages each package waits for, see
+/// `jobs::dependencies`. With more than one package, a summary table is
+/// printed at the end, also when an operation failed.
+fn for_each_package(
+    packages: &[String],
+    jobs: usize,
+    dependencies: &[Vec<usize>],
+    operation: impl Fn(&str) -> Result<PackageReport> + Sync,
+) -> Result<()> {
+    let mut rows = vec![
+        [
+            "PACKAGE",
+            "CREATED",
+            "REMOVED",
+            "CONFLICTS",
+            "SCRIPTS",
+            "RESULT",
+        ]
+        .map(String::from)
+        .to_vec(),
+    ];
+    let mut failure = None;
+
+    let results = jobs::run(packages, jobs, dependencies, operation);
+    for (package, result) in packages.iter().zip(results) {
+        let (report, result) = match result {
+            None => (None, "not run"),
+            Some(Ok(report)) => (Some(report), "ok"),
+            Some(Err(e)) => {
+                // Packages running alongside the first to fail may fail as well
+                match failure {
+                    None => failure = Some(e),
+                    Some(_) => eprintln!("Error: {}", e),
+                }
+                (None, "failed")
+            }
+        };
+
+        let mut row = vec![package.clone()];
+        match report {
+            Some(r) => {
+                row.extend([r.created, r.removed, r.conflicts, r.scripts].map(|n| n.to_string()))
+            }
+            None => row.extend(["-"; 4].map(String::from)),
+        }
+        row.push(result.to_string());
+        rows.push(row);
+    }
+
+    if packages.len() > 1 {
+        outln!();
+        print_table(&rows);
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+struct InstallOptions<'a> {
+    no_setup: bool,
+    conflicts: Option<ConflictPolicy>,
+    /// Paths inside the package to link, all of it when empty
+    paths: &'a [PathBuf],
+    dry_run: bool,
+    verbose: bool,
+}
+
+fn install_package(
+    config: &Config,
+    package: &str,
+    target: Option<PathBuf>,
+    opts: &InstallOptions,
+) -> Result<PackageReport> {
+    let InstallOptions {
+        no_setup,
+        conflicts,
+        dry_run,
+        verbose,
+        ..
+    } = *opts;
+    let target_dir = config.get_target(target);
+    let package_dir = config.get_package_dir(package);
+    let _span = tracing::info_span!("install", package).entered();
+
+    if verbose {
+        outln!("Package directory: {}", package_dir.display());
+        outln!("Target directory: {}", target_dir.display());
+    }
+
+    // Check if package exists
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    // Discover all files in the package
+    let mut mappings = config.discover(package, &target_dir)?;
+    if !opts.paths.is_empty() {
+        mappings = select_paths(config, package, mappings, opts.paths)?;
+    }
+
+    if verbose {
+        outln!("Found {} files to link", mappings.len());
+    }
+
+    if mappings.is_empty() {
+        outln!("No files to link in package '{}'", package);
+        return Ok(PackageReport::default());
+    }
+
+    check_permissions(
+        mappings
+            .iter()
+            .filter(|m| !symlink::is_stau_symlink(&m.target, &m.source).unwrap_or(false))
+            .map(|m| m.target.as_path()),
+        verbose,
+    )?;
+
+    let run_as = config.get_manifest(package)?.run_as;
+    let script_ctx = script::ScriptContext {
+        package,
+        stau_dir: &config.stau_dir,
+        target_dir: &target_dir,
+        dry_run,
+        verbose,
+        run_as: run_as.as_deref(),
+        operation: script::Operation::Install,
+        state_dir: Some(&config.state_dir),
+    };
+
+    let mut report = PackageReport::default();
+
+    // Run pre-install scripts before touching the target; failure aborts the install
+    if !no_setup {
+        report.scripts += run_hook(config, script::ScriptKind::PreInstall, &script_ctx)?;
+    }
+
+    let policy = config.conflict_policy(package, conflicts)?;
+    if !dry_run {
+        config.remember_link_options(package, &target_dir)?;
+        config.remember_link_dirs(package, &target_dir, &mappings)?;
+    }
+
+    // Create symlinks for all files, remembering new ones so Ctrl-C can undo them
+    let link_span = tracing::info_span!("link").entered();
+    let mut created = Vec::new();
+    let mut skipped = 0;
+    for mapping in &mappings {
+        if interrupt::is_interrupted() {
+            rollback_links(&created, dry_run);
+            return Err(error::StauError::Interrupted);
+        }
+
+        if verbose || dry_run {
+            outln!(
+                "  {} -> {}",
+                mapping.target.display(),
+                mapping.source.display()
+            );
+        }
+
+        if symlink::is_stau_symlink(&mapping.target, &mapping.source)? {
+            plan::add(plan_link(
+                package,
+                plan::Action::Skip,
+                mapping,
+                "already linked",
+            ));
+        } else {
+            if mapping.target.symlink_metadata().is_ok() {
+                report.conflicts += 1;
+                let (resolution, step) =
+                    decide_conflict(config, package, &target_dir, mapping, policy)?;
+                plan::add(step);
+                if !resolve_conflict(config, mapping, resolution, dry_run, verbose)? {
+                    skipped += 1;
+                    continue;
+                }
+            } else {
+                plan::add(plan_link(
+                    package,
+                    plan::Action::Link,
+                    mapping,
+                    "not linked yet",
+                ));
+            }
+            symlink::create_symlink_with_force(&mapping.source, &mapping.target, dry_run, true)?;
+            created.push(mapping);
+        }
+    }
+    drop(link_span);
+
+    if !dry_run {
+        if skipped > 0 {
+            outln!(
+                "Successfully installed {} ({} symlinks created, {} skipped)",
+                package,
+                mappings.len() - skipped,
+                skipped
+            );
+        } else {
+            outln!(
+                "Successfully installed {} ({} symlinks created)",
+                package,
+                mappings.len()
+            );
+        }
+    }
+
+    // Run setup scripts (generic, then OS- and host-specific) if they exist and not skipped
+    if !no_setup {
+        report.scripts += run_hook(config, script::ScriptKind::Setup, &script_ctx)?;
+        if !dry_run {
+            record_setup_hash(config, package)?;
+        }
+    }
+
+    record_machine(config, package, true, dry_run)?;
+    report.created = created.len();
+    Ok(report)
+}
+
+fn plan_link(
+    package: &str,
+    action: plan::Action,
+    mapping: &symlink::SymlinkMapping,
+    reason: &str,
+) -> plan::Step {
+    plan::Step::new(
+        package,
+        action,
+        Some(mapping.source.clone()),
+        mapping.target.clone(),
+        reason,
+    )
+}
+
+/// Note a package installed or uninstalled on this machine in the registry, if enabled
+fn record_machine(config: &Config, package: &str, installed: bool, dry_run: bool) -> Result<()> {
+    if !config.record_machines || dry_run {
+        return Ok(());
+    }
+    let Some(host) = platform::hostname() else {
+        return Ok(());
+    };
+
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    machines::Registry::update(&config.stau_dir, |registry| {
+        registry.record(&host, package, installed, now)
+    })
+}
+
+/// List what it takes for this machine to match what the registry records for it
+///
+/// Recorded packages that aren't installed are to be installed, those with
+/// missing, broken or blocked links to be restowed, and installed packages
+/// that aren't recorded to be uninstalled.
+fn show_drift(config: &Config, target: Option<PathBuf>) -> Result<()> {
+    let host = platform::hostname()
+        .ok_or_else(|| error::StauError::Other("Cannot determine the hostname".to_string()))?;
+    let registry = machines::Registry::load(&config.stau_dir)?;
+    let recorded = registry
+        .machines
+        .get(&host)
+        .map(|machine| machine.packages.clone())
+        .unwrap_or_default();
+    let target_dir = config.get_target(target);
+
+    let mut install = Vec::new();
+    let mut restow = Vec::new();
+    let mut remove = Vec::new();
+    let mut missing = Vec::new();
+    for package in &recorded {
+        if !config.package_exists(package) {
+            missing.push(package.clone());
+        }
+    }
+    for package in package::list_packages(&config.get_stau_dirs())? {
+        let health = PackageHealth::check(config, &package, &target_dir)?;
+        let is_recorded = recorded.contains(&package);
+        match health.state() {
+            PackageState::NotInstalled if is_recorded => install.push(package),
+            PackageState::NotInstalled => {}
+            _ if !is_recorded => remove.push(package),
+            PackageState::Installed if health.is_healthy() => {}
+            _ => restow.push(package),
+        }
+    }
+
+    if install.is_empty() && restow.is_empty() && remove.is_empty() && missing.is_empty() {
+        println!(
+            "No drift on {} ({} packages installed as recorded)",
+            host,
+            recorded.len()
+        );
+        return Ok(());
+    }
+
+    println!("Drift on {}:", host);
+    for (label, packages) in [
+        ("install", &install),
+        ("restow", &restow),
+        ("uninstall", &remove),
+        ("missing from STAU_DIR", &missing),
+    ] {
+        if !packages.is_empty() {
+            println!("  {}: {}", label, packages.join(", "));
+        }
+    }
+
+    let mut drifted: Vec<String> = install
+        .into_iter()
+        .chain(restow)
+        .chain(remove)
+        .chain(missing)
+        .collect();
+    drifted.sort();
+    Err(error::StauError::Drift(drifted.join(", ")))
+}
+
+/// Print the prompt segment, from the cache while it is fresh
+///
+/// A check that exceeds the budget is left to a background `stau prompt
+/// --refresh`, and the last result is printed meanwhile.
+fn show_prompt(
+    config: &Config,
+    target: Option<PathBuf>,
+    budget: Duration,
+    refresh: bool,
+) -> Result<()> {
+    let target_dir = config.get_target(target);
+    if refresh {
+        return check_prompt_segment(config, &target_dir)?.save(&config.state_dir);
+    }
+
+    let now = segment::now();
+    let cached = segment::Segment::load(&config.state_dir, &target_dir);
+    if let Some(cached) = &cached
+        && cached.is_fresh(now)
+    {
+        println!("{}", cached);
+        return Ok(());
+    }
+
+    // Checking runs on its own thread so it can be abandoned when the budget runs out
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let thread_config = config.clone();
+    let thread_target = target_dir.clone();
+    std::thread::spawn(move || {
+        let _ = sender.send(check_prompt_segment(&thread_config, &thread_target));
+    });
+
+    match receiver.recv_timeout(budget) {
+        Ok(segment) => {
+            let segment = segment?;
+            // Another prompt may be writing the cache as well; showing the result matters more
+            let _ = segment.save(&config.state_dir);
+            println!("{}", segment);
+        }
+        Err(_) => {
+            refresh_prompt_in_background();
+            if let Some(mut cached) = cached {
+                // Prompts drawn while the refresh runs shouldn't start another one
+                cached.checked = now;
+                let _ = cached.save(&config.state_dir);
+                println!("{}", cached);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Count broken links of installed packages and packages with uncommitted changes
+fn check_prompt_segment(config: &Config, target_dir: &Path) -> Result<segment::Segment> {
+    Ok(check_prompt_status(config, target_dir)?.segment(target_dir, segment::now()))
+}
+
+/// Check every package for what the prompt segment counts
+fn check_prompt_status(config: &Config, target_dir: &Path) -> Result<segment::Status> {
+    let packages = package::list_packages(&config.get_stau_dirs())?;
+    let mut status = segment::Status::default();
+    for package in &packages {
+        status
+            .broken
+            .insert(package.clone(), broken_links(config, package, target_dir)?);
+    }
+    status.dirty = dirty_packages(config, &packages)?;
+    Ok(status)
+}
+
+/// Number of dangling symlinks of a package, none unless it is installed
+fn broken_links(config: &Config, package: &str, target_dir: &Path) -> Result<usize> {
+    let health = PackageHealth::check(config, package, target_dir)?;
+    if health.state() == PackageState::NotInstalled {
+        return Ok(0);
+    }
+    Ok(health.broken)
+}
+
+/// Which of the packages have uncommitted or untracked changes
+fn dirty_packages(
+    config: &Config,
+    packages: &[String],
+) -> Result<std::collections::BTreeSet<String>> {
+    let mut dirty = std::collections::BTreeSet::new();
+    for stau_dir in config.get_stau_dirs() {
+        if !git::is_repo(&stau_dir) {
+            continue;
+        }
+        for path in git::uncommitted_paths(&stau_dir)? {
+            if let Some(name) = path.split('/').next()
+                && packages.iter().any(|p| p == name)
+            {
+                dirty.insert(name.to_string());
+            }
+        }
+    }
+    Ok(dirty)
+}
+
+/// Keep the cached prompt segment up to date from file system events until stopped
+///
+/// A package is only checked again when something changed in it or at its
+/// links in the target, and uncommitted changes only when something changed
+/// in a dotfiles directory. The segment is saved well before it goes stale,
+/// so `stau prompt` can always print it right away.
+fn watch_prompt(
+    config: &Config,
+    target: Option<PathBuf>,
+    daemon: bool,
+    log: Option<PathBuf>,
+) -> Result<()> {
+    use notify::Watcher;
+    use std::sync::mpsc::RecvTimeoutError;
+
+    let target_dir = config.get_target(target);
+    let mut lock = sync::Lock::acquire(
+        &config.state_dir.join("prompt-watch.lock"),
+        "stau prompt --watch",
+    )?;
+    if daemon {
+        let log = log.unwrap_or_else(|| config.state_dir.join("prompt-watch.log"));
+        return sync::daemonize("stau prompt --watch", &log, lock);
+    }
+    lock.write_pid()?;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Reading files, as checking does itself, changes nothing
+        if let Ok(event) = event
+            && !event.kind.is_access()
+        {
+            let _ = sender.send(event.paths);
+        }
+    })
+    .map_err(watch_error)?;
+    let stau_dirs = config.get_stau_dirs();
+    for dir in &stau_dirs {
+        watcher
+            .watch(dir, notify::RecursiveMode::Recursive)
+            .map_err(watch_error)?;
+    }
+
+    let mut status = check_prompt_status(config, &target_dir)?;
+    let mut links = WatchedLinks::default();
+    for package in status.broken.keys() {
+        links.update(config, package, &target_dir, &mut watcher)?;
+    }
+    status
+        .segment(&target_dir, segment::now())
+        .save(&config.state_dir)?;
+    let mut saved = std::time::Instant::now();
+
+    loop {
+        interrupt::check()?;
+        let mut paths = match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(paths) => paths,
+            Err(RecvTimeoutError::Timeout) => Vec::new(),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(error::StauError::Other(
+                    "File system watcher stopped".to_string(),
+                ));
+            }
+        };
+        if !paths.is_empty() {
+            // Let a burst of changes, such as a checkout, settle first
+            std::thread::sleep(Duration::from_millis(100));
+            paths.extend(receiver.try_iter().flatten());
+        }
+
+        let mut changed = std::collections::BTreeSet::new();
+        let mut repo_changed = false;
+        for path in &paths {
+            let Some(rel) = stau_dirs.iter().find_map(|dir| path.strip_prefix(dir).ok()) else {
+                changed.extend(links.owners_of(path));
+                continue;
+            };
+            let mut components = rel.components();
+            let Some(first) = components.next() else {
+                continue;
+            };
+            let first = first.as_os_str().to_string_lossy();
+            if first == ".git" {
+                // Git writes to other files in there all the time, also for
+                // our own checks; only staging, commits and checkouts matter
+                repo_changed |= matches!(components.as_path().to_str(), Some("index" | "HEAD"));
+            } else if first == ".gitignore" {
+                repo_changed = true;
+                changed.extend(status.broken.keys().cloned());
+            } else {
+                repo_changed = true;
+                changed.insert(first.into_owned());
+            }
+        }
+
+        if !changed.is_empty() || repo_changed {
+            // A package being edited may not make sense for a moment; keep watching
+            let result = update_prompt_status(
+                config,
+                &target_dir,
+                &changed,
+                repo_changed,
+                &mut status,
+                &mut links,
+                &mut watcher,
+            );
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        } else if saved.elapsed() < segment::FRESH_FOR / 3 {
+            continue;
+        }
+        status
+            .segment(&target_dir, segment::now())
+            .save(&config.state_dir)?;
+        saved = std::time::Instant::now();
+    }
+}
+
+/// Check the changed packages again, and which packages are dirty if the
+/// repository changed
+fn update_prompt_status(
+    config: &Config,
+    target_dir: &Path,
+    changed: &std::collections::BTreeSet<String>,
+    repo_changed: bool,
+    status: &mut segment::Status,
+    links: &mut WatchedLinks,
+    watcher: &mut impl notify::Watcher,
+) -> Result<()> {
+    let packages = package::list_packages(&config.get_stau_dirs())?;
+    for package in changed {
+        if packages.contains(package) {
+            status
+                .broken
+                .insert(package.clone(), broken_links(config, package, target_dir)?);
+            links.update(config, package, target_dir, watcher)?;
+        } else {
+            status.broken.remove(package);
+            links.targets.remove(package);
+        }
+    }
+    if repo_changed {
+        status.dirty = dirty_packages(config, &packages)?;
+    }
+    Ok(())
+}
+
+/// Where each package links to in the target, watched for changes by `stau prompt --watch`
+#[derive(Default)]
+struct WatchedLinks {
+    targets: HashMap<String, Vec<PathBuf>>,
+}
+
+impl WatchedLinks {
+    /// Look up a package's links again and watch the directories they are in
+    fn update(
+        &mut self,
+        config: &Config,
+        package: &str,
+        target_dir: &Path,
+        watcher: &mut impl notify::Watcher,
+    ) -> Result<()> {
+        let targets: Vec<PathBuf> = config
+            .discover(package, target_dir)?
+            .into_iter()
+            .map(|m| m.target)
+            .collect();
+
+        // Where a directory doesn't exist yet, its closest existing parent
+        // shows it being created
+        let dirs: std::collections::BTreeSet<&Path> = targets
+            .iter()
+            .filter_map(|target| target.ancestors().skip(1).find(|dir| dir.is_dir()))
+            .collect();
+        for dir in dirs {
+            watcher
+                .watch(dir, notify::RecursiveMode::NonRecursive)
+                .map_err(watch_error)?;
+        }
+
+        self.targets.insert(package.to_string(), targets);
+        Ok(())
+    }
+
+    /// Packages with links at or below a changed path
+    fn owners_of<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = String> + 'a {
+        self.targets
+            .iter()
+            .filter(move |(_, targets)| targets.iter().any(|target| target.starts_with(path)))
+            .map(|(package, _)| package.clone())
+    }
+}
+
+fn watch_error(e: notify::Error) -> error::StauError {
+    error::StauError::Other(format!("Cannot watch for file changes: {}", e))
+}
+
+/// Run the same `stau prompt` again with `--refresh`, detached from the shell
+fn refresh_prompt_in_background() {
+    use std::os::unix::process::CommandExt;
+
+    let Ok(exe) = std::env::current_exe() else {
+        return;
+    };
+    let _ = process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .arg("--refresh")
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .process_group(0)
+        .spawn();
+}
+
+/// Print the packages each machine in the registry has installed
+fn show_machines(config: &Config) -> Result<()> {
+    let registry = machines::Registry::load(&config.stau_dir)?;
+    if registry.machines.is_empty() {
+        if !config.record_machines {
+            println!(
+                "No machines recorded; run `stau config set machines true` to record installs"
+            );
+        } else {
+            println!("No machines recorded");
+        }
+        return Ok(());
+    }
+
+    let mut rows = vec![vec![
+        "HOST".to_string(),
+        "UPDATED".to_string(),
+        "PACKAGES".to_string(),
+    ]];
+    for (host, machine) in &registry.machines {
+        rows.push(vec![
+            host.clone(),
+            machine.updated.clone(),
+            machine.packages.join(", "),
+        ]);
+    }
+    print_table(&rows);
+    Ok(())
+}
+
+/// Bring a package's symlinks in line with its files, leaving correct links untouched
+fn restow_package(
+    config: &Config,
+    package: &str,
+    target: Option<PathBuf>,
+    run_setup: bool,
+    conflicts: Option<ConflictPolicy>,
+    dry_run: bool,
+    verbose: bool,
+) -> Result<PackageReport> {
+    let target_dir = config.get_target(target);
+    let _span = tracing::info_span!("restow", package).entered();
+
+    if verbose {
+        outln!(
+            "Package directory: {}",
+            config.get_package_dir(package).display()
+        );
+        outln!("Target directory: {}", target_dir.display());
+    }
+
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    let layers = config.get_package_layers(package);
+    let mappings = config.discover(package, &target_dir)?;
+
+    // Plan everything up front so a conflict aborts before any link is touched
+    let linked_dirs = config.link_dirs(package, &target_dir)?;
+    let diff = symlink::diff_links(&mappings, &layers, &linked_dirs)?;
+    let policy = config.conflict_policy(package, conflicts)?;
+    let mut resolutions = Vec::new();
+    for mapping in &diff.conflict {
+        match decide_conflict(config, package, &target_dir, mapping, policy)? {
+            (Resolution::Fail, step) => {
+                plan::add(step);
+                return Err(error::StauError::ConflictingFile(mapping.target.clone()));
+            }
+            decision => resolutions.push((mapping, decision)),
+        }
+    }
+    check_permissions(
+        diff.add
+            .iter()
+            .chain(&diff.repoint)
+            .chain(&diff.conflict)
+            .map(|m| m.target.as_path())
+            .chain(diff.remove.iter().map(PathBuf::as_path)),
+        verbose,
+    )?;
+
+    let run_as = config.get_manifest(package)?.run_as;
+    let script_ctx = script::ScriptContext {
+        package,
+        stau_dir: &config.stau_dir,
+        target_dir: &target_dir,
+        dry_run,
+        verbose,
+        run_as: run_as.as_deref(),
+        operation: script::Operation::Restow,
+        state_dir: Some(&config.state_dir),
+    };
+
+    let mut report = PackageReport {
+        created: diff.add.len() + diff.repoint.len(),
+        removed: diff.remove.len(),
+        conflicts: diff.conflict.len(),
+        scripts: 0,
+    };
+
+    if run_setup {
+        report.scripts += run_hook(config, script::ScriptKind::PreInstall, &script_ctx)?;
+    }
+    if !dry_run {
+        config.remember_link_options(package, &target_dir)?;
+        config.remember_link_dirs(package, &target_dir, &mappings)?;
+    }
+
+    let link_span = tracing::info_span!("link").entered();
+    for path in &diff.remove {
+        interrupt::check()?;
+        if verbose || dry_run {
+            outln!("  Removing stale symlink: {}", path.display());
+        }
+        let source = std::fs::read_link(path).map_err(error::StauError::Io)?;
+        plan::add(plan::Step::new(
+            package,
+            plan::Action::Unlink,
+            Some(source.clone()),
+            path.clone(),
+            "no longer in the package",
+        ));
+        symlink::remove_symlink(path, &source, dry_run)?;
+    }
+
+    for mapping in &diff.repoint {
+        interrupt::check()?;
+        if verbose || dry_run {
+            outln!(
+                "  Re-pointing symlink: {} -> {}",
+                mapping.target.display(),
+                mapping.source.display()
+            );
+        }
+        plan::add(plan_link(
+            package,
+            plan::Action::Relink,
+            mapping,
+            "links to another file of the package",
+        ));
+        symlink::create_symlink_with_force(&mapping.source, &mapping.target, dry_run, true)?;
+    }
+
+    for mapping in &diff.add {
+        interrupt::check()?;
+        if verbose || dry_run {
+            outln!(
+                "  Adding symlink: {} -> {}",
                 mapping.target.display(),
                 mapping.source.display()
             );
         }
+        plan::add(plan_link(
+            package,
+            plan::Action::Link,
+            mapping,
+            "not linked yet",
+        ));
+        symlink::create_symlink(&mapping.source, &mapping.target, dry_run)?;
+    }
+
+    let mut replaced = 0;
+    for (mapping, (resolution, step)) in resolutions {
+        interrupt::check()?;
+        plan::add(step);
+        if resolve_conflict(config, mapping, resolution, dry_run, verbose)? {
+            symlink::create_symlink_with_force(&mapping.source, &mapping.target, dry_run, true)?;
+            replaced += 1;
+            report.created += 1;
+        }
+    }
+    if plan::recording() {
+        let changed: Vec<&Path> = diff
+            .add
+            .iter()
+            .chain(&diff.repoint)
+            .chain(&diff.conflict)
+            .map(|m| m.target.as_path())
+            .collect();
+        for mapping in mappings
+            .iter()
+            .filter(|m| !changed.contains(&m.target.as_path()))
+        {
+            plan::add(plan_link(
+                package,
+                plan::Action::Skip,
+                mapping,
+                "already linked",
+            ));
+        }
+    }
+    drop(link_span);
+
+    if diff.is_empty() {
+        outln!("{} is up to date ({} symlinks)", package, diff.unchanged);
+    } else if !dry_run {
+        let conflicts = if diff.conflict.is_empty() {
+            String::new()
+        } else {
+            format!(
+                ", {} replaced, {} skipped",
+                replaced,
+                diff.conflict.len() - replaced
+            )
+        };
+        outln!(
+            "Restowed {}: {} added, {} re-pointed, {} removed, {} unchanged{}",
+            package,
+            diff.add.len(),
+            diff.repoint.len(),
+            diff.remove.len(),
+            diff.unchanged,
+            conflicts
+        );
+    }
+
+    if run_setup {
+        report.scripts += run_hook(config, script::ScriptKind::Setup, &script_ctx)?;
+        if !dry_run {
+            record_setup_hash(config, package)?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Initialize uninitialized git submodules inside a package before linking it
+///
+/// Without `init`, asks first when stdin is a terminal and only warns otherwise.
+fn prepare_submodules(config: &Config, package: &str, init: bool, dry_run: bool) -> Result<()> {
+    use std::io::IsTerminal;
+
+    for layer in config.get_package_layers(package) {
+        if !git::is_repo(&layer) {
+            continue;
+        }
+        let submodules = git::uninitialized_submodules(&layer)?;
+        if submodules.is_empty() {
+            continue;
+        }
+
+        let list = submodules.join(", ");
+        if dry_run {
+            outln!("Would initialize submodules in '{}': {}", package, list);
+            continue;
+        }
+
+        let question = format!(
+            "Package '{}' has uninitialized submodules ({}). Initialize them?",
+            package, list
+        );
+        let interactive = prompt::assume_yes() || std::io::stdin().is_terminal();
+        let init = init || (interactive && prompt::confirm(&question)?);
+        if !init {
+            warning::warn(format!(
+                "Package '{}' has uninitialized submodules ({}); pass --init-submodules to initialize them",
+                package, list
+            ))?;
+            continue;
+        }
+
+        outln!("Initializing submodules in '{}': {}", package, list);
+        jobs::one_at_a_time(|| git::init_submodules(&layer))?;
+    }
+    Ok(())
+}
+
+/// Decide about a target path linked by another package with `--defer` or
+/// `--override`, if one of their patterns matches it
+fn resolve_precedence(
+    config: &Config,
+    package: &str,
+    target_dir: &Path,
+    mapping: &symlink::SymlinkMapping,
+) -> Option<Resolution> {
+    let other_package = symlink::links_into(&mapping.target, &config.get_stau_dirs())
+        && !symlink::links_into(&mapping.target, &config.get_package_layers(package));
+    if !other_package {
+        return None;
+    }
+    let relative = mapping
+        .target
+        .strip_prefix(target_dir)
+        .unwrap_or(&mapping.target);
+    config.precedence.resolve(relative)
+}
+
+/// Decide what to do with a target path taken by something else, along with
+/// the plan step for it
+///
+/// Nobody is asked while a plan is recorded; the path is then left alone in
+/// the dry run and planned with the `prompt` policy.
+fn decide_conflict(
+    config: &Config,
+    package: &str,
+    target_dir: &Path,
+    mapping: &symlink::SymlinkMapping,
+    policy: ConflictPolicy,
+) -> Result<(Resolution, plan::Step)> {
+    let (resolution, reason) = match resolve_precedence(config, package, target_dir, mapping) {
+        Some(resolution) => (Some(resolution), "linked by another package"),
+        None if policy == ConflictPolicy::Prompt && plan::recording() => (None, "already exists"),
+        None => (Some(policy.resolve(&mapping.target)?), "already exists"),
+    };
+    let action = match resolution {
+        Some(Resolution::Fail) => plan::Action::Abort,
+        Some(Resolution::Skip) => plan::Action::Skip,
+        _ => plan::Action::Link,
+    };
+    let mut step = plan_link(package, action, mapping, reason);
+    step.conflict = Some(resolution.map_or(policy, ConflictPolicy::from));
+    Ok((resolution.unwrap_or(Resolution::Skip), step))
+}
+
+/// Clear a conflicting target path the way the conflict policy decided
+///
+/// Returns whether the path is now free to be linked; with `Skip` it is left
+/// alone and nothing should be linked there.
+fn resolve_conflict(
+    config: &Config,
+    mapping: &symlink::SymlinkMapping,
+    resolution: Resolution,
+    dry_run: bool,
+    verbose: bool,
+) -> Result<bool> {
+    let target = &mapping.target;
+    match resolution {
+        Resolution::Fail => Err(error::StauError::ConflictingFile(target.clone())),
+        Resolution::Skip => {
+            outln!("  Skipping {} (already exists)", target.display());
+            Ok(false)
+        }
+        Resolution::Backup => {
+            back_up(config, target, dry_run, verbose)?;
+            Ok(true)
+        }
+        Resolution::Overwrite => {
+            if verbose || dry_run {
+                outln!("  Overwriting {}", target.display());
+            }
+            symlink::remove_existing(target, dry_run)?;
+            Ok(true)
+        }
+        Resolution::Adopt => {
+            if !target.symlink_metadata().is_ok_and(|m| m.is_file()) {
+                return Err(error::StauError::Other(format!(
+                    "Cannot adopt {}: only regular files can be adopted",
+                    target.display()
+                )));
+            }
+            if verbose || dry_run {
+                outln!(
+                    "  Adopting {} into {}",
+                    target.display(),
+                    mapping.source.display()
+                );
+            }
+            symlink::adopt_file(target, &mapping.source, dry_run)?;
+            Ok(true)
+        }
+    }
+}
+
+/// Move a path that is about to be replaced into the backups
+fn back_up(config: &Config, path: &Path, dry_run: bool, verbose: bool) -> Result<()> {
+    if dry_run {
+        outln!("  Would back up {}", path.display());
+        return Ok(());
+    }
+
+    let stored = backup::save(&config.state_dir, path)?;
+    if verbose {
+        outln!("  Backed up {} to {}", path.display(), stored.display());
+    }
+    Ok(())
+}
+
+/// Remove links created by an interrupted install
+///
+/// Files replaced with --force stay in the backups.
+fn rollback_links(created: &[&symlink::SymlinkMapping], dry_run: bool) {
+    for mapping in created.iter().rev() {
+        if let Err(e) = symlink::remove_symlink(&mapping.target, &mapping.source, dry_run) {
+            errln!(
+                "Warning: Cannot remove {} while rolling back: {}",
+                mapping.target.display(),
+                e
+            );
+        }
+    }
+    if !created.is_empty() {
+        errln!("Rolled back {} new symlinks", created.len());
+    }
+}
+
+/// Make sure the target directory exists, creating it if allowed
+fn ensure_target_dir(target_dir: &Path, create: bool, dry_run: bool) -> Result<()> {
+    if target_dir.is_dir() {
+        return Ok(());
+    }
+    if !create || target_dir.exists() {
+        return Err(error::StauError::TargetNotFound(target_dir.to_path_buf()));
+    }
+
+    outln!("Creating target directory: {}", target_dir.display());
+    if !dry_run {
+        std::fs::create_dir_all(target_dir).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                error::StauError::PermissionDenied(format!(
+                    "Cannot create directory: {}",
+                    target_dir.display()
+                ))
+            } else {
+                error::StauError::Io(e)
+            }
+        })?;
+    }
+    Ok(())
+}
+
+/// Hash a package's current setup scripts, or None if it has none
+fn setup_hash(config: &Config, package: &str) -> Result<Option<String>> {
+    let scripts = config.get_hook_scripts(package, script::ScriptKind::Setup);
+    if scripts.is_empty() {
+        return Ok(None);
+    }
+    state::hash_files(&scripts).map(Some)
+}
+
+/// Move the hook scripts in the root of each layer of a package into its
+/// scripts/ directory, and put the keys of its stau.toml in their usual order
+///
+/// Nothing linked changes, so installed links stay as they are. The recorded
+/// hash of the setup scripts follows them, so `upgrade --changed` doesn't
+/// take the move for a change.
+fn format_package(config: &Config, package: &str, dry_run: bool) -> Result<()> {
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    let mut moves = Vec::new();
+    let mut manifests = Vec::new();
+    for layer in config.get_package_layers(package) {
+        let path = layer.join(manifest::MANIFEST_FILE);
+        if path.is_file() {
+            let contents = std::fs::read_to_string(&path).map_err(error::StauError::Io)?;
+            let sorted = manifest::sort_keys(&contents)
+                .map_err(|e| error::StauError::Other(format!("{}: {}", path.display(), e)))?;
+            if let Some(sorted) = sorted {
+                manifests.push((path, sorted));
+            }
+        }
+        for script in package::root_hook_scripts(&layer)? {
+            let dest = layer
+                .join("scripts")
+                .join(script.file_name().unwrap_or_default());
+            if dest.symlink_metadata().is_ok() {
+                return Err(error::StauError::Other(format!(
+                    "Cannot move {} into scripts/: {} already exists",
+                    script.display(),
+                    dest.display()
+                )));
+            }
+            moves.push((layer.clone(), script, dest));
+        }
+    }
+
+    if moves.is_empty() && manifests.is_empty() {
+        println!("{} is already formatted", package);
+        return Ok(());
+    }
+
+    let old_hash = setup_hash(config, package)?;
+    for (layer, script, dest) in &moves {
+        let relative = |path: &Path| {
+            path.strip_prefix(layer)
+                .unwrap_or(path)
+                .display()
+                .to_string()
+        };
+        if dry_run {
+            println!("Would move {} to {}", relative(script), relative(dest));
+            continue;
+        }
+        std::fs::create_dir_all(layer.join("scripts")).map_err(error::StauError::Io)?;
+        std::fs::rename(script, dest).map_err(error::StauError::Io)?;
+        println!("Moved {} to {}", relative(script), relative(dest));
+    }
+
+    for (path, sorted) in &manifests {
+        if dry_run {
+            println!("Would reorder the keys of {}", path.display());
+            continue;
+        }
+        std::fs::write(path, sorted).map_err(error::StauError::Io)?;
+        println!("Reordered the keys of {}", path.display());
+    }
+
+    if !dry_run
+        && let Some(old_hash) = old_hash
+        && let Some(new_hash) = setup_hash(config, package)?
+        && state::State::load(&config.state_dir)?
+            .setup_hashes
+            .get(package)
+            == Some(&old_hash)
+    {
+        state::State::update(&config.state_dir, |state| {
+            state.setup_hashes.insert(package.to_string(), new_hash);
+        })?;
+    }
+    Ok(())
+}
+
+/// Remember the setup scripts that last ran successfully for a package
+fn record_setup_hash(config: &Config, package: &str) -> Result<()> {
+    if let Some(hash) = setup_hash(config, package)? {
+        state::State::update(&config.state_dir, |state| {
+            state.setup_hashes.insert(package.to_string(), hash);
+        })?;
+    }
+    Ok(())
+}
+
+fn upgrade_package(
+    config: &Config,
+    package: &str,
+    target: Option<PathBuf>,
+    changed_only: bool,
+    dry_run: bool,
+    verbose: bool,
+) -> Result<PackageReport> {
+    let target_dir = config.get_target(target);
+
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    if !is_installed(config, package, &target_dir)? {
+        outln!("Skipping {} (not installed)", package);
+        return Ok(PackageReport::default());
+    }
+
+    let Some(hash) = setup_hash(config, package)? else {
+        if verbose {
+            outln!("Skipping {} (no setup scripts)", package);
+        }
+        return Ok(PackageReport::default());
+    };
+
+    if changed_only {
+        let state = state::State::load(&config.state_dir)?;
+        if state.setup_hashes.get(package) == Some(&hash) {
+            if verbose {
+                outln!("Skipping {} (setup scripts unchanged)", package);
+            }
+            return Ok(PackageReport::default());
+        }
+    }
+
+    outln!("Upgrading {}", package);
+
+    let run_as = config.get_manifest(package)?.run_as;
+    let script_ctx = script::ScriptContext {
+        package,
+        stau_dir: &config.stau_dir,
+        target_dir: &target_dir,
+        dry_run,
+        verbose,
+        run_as: run_as.as_deref(),
+        operation: script::Operation::Upgrade,
+        state_dir: Some(&config.state_dir),
+    };
+    let scripts = run_hook(config, script::ScriptKind::Setup, &script_ctx)?;
+
+    if !dry_run {
+        record_setup_hash(config, package)?;
+    }
+
+    Ok(PackageReport {
+        scripts,
+        ..PackageReport::default()
+    })
+}
+
+/// Run all scripts for a hook, stopping at the first failure, and return how many ran
+fn run_hook(
+    config: &Config,
+    kind: script::ScriptKind,
+    ctx: &script::ScriptContext,
+) -> Result<usize> {
+    let scripts = config.get_hook_scripts(ctx.package, kind);
+    for script_path in &scripts {
+        if ctx.verbose {
+            outln!(
+                "Found {} script: {}",
+                kind.base_name(),
+                script_path.display()
+            );
+        }
+
+        script::execute_script(script_path, kind, ctx)?;
+
+        if !ctx.dry_run {
+            outln!("{} script completed successfully", kind.label());
+        }
+    }
+
+    Ok(scripts.len())
+}
+
+/// Run all scripts for a hook, reporting failures as warnings and continuing
+///
+/// Returns how many scripts ran. With --strict, the first failure is returned instead.
+fn run_hook_continue_on_error(
+    config: &Config,
+    kind: script::ScriptKind,
+    ctx: &script::ScriptContext,
+    continuing: &str,
+) -> Result<usize> {
+    let scripts = config.get_hook_scripts(ctx.package, kind);
+    for script_path in &scripts {
+        if ctx.verbose {
+            outln!(
+                "Found {} script: {}",
+                kind.base_name(),
+                script_path.display()
+            );
+        }
+
+        if let Err(e) = script::execute_script(script_path, kind, ctx) {
+            warning::warn(format!("{} script failed: {}", kind.label(), e)).map_err(|_| e)?;
+            errln!("{}", continuing);
+        } else if !ctx.dry_run {
+            outln!("{} script completed successfully", kind.label());
+        }
+    }
+    Ok(scripts.len())
+}
+
+/// Decide whether a package may be purged, asking the user for confirmation
+///
+/// Refuses to purge a package with uncommitted changes in a git-managed
+/// STAU_DIR unless told to discard them, since deleting it would lose that
+/// work. With overlays, the package is purged from every layer providing it,
+/// so all of them are named.
+fn confirm_purge(
+    config: &Config,
+    package: &str,
+    discard_changes: bool,
+    dry_run: bool,
+) -> Result<bool> {
+    let layers = config.get_package_layers(package);
+
+    if !discard_changes {
+        for layer in &layers {
+            if git::is_repo(layer) && git::has_uncommitted_changes(layer, ".")? {
+                return Err(error::StauError::UncommittedChanges(package.to_string()));
+            }
+        }
+    }
+
+    if dry_run {
+        return Ok(true);
+    }
+
+    let dirs: Vec<String> = layers.iter().map(|dir| dir.display().to_string()).collect();
+    let question = match dirs.as_slice() {
+        [dir] => format!("Delete package directory {}?", dir),
+        dirs => format!("Delete package directories {}?", dirs.join(", ")),
+    };
+    if prompt::confirm(&question)? {
+        Ok(true)
+    } else {
+        outln!("Keeping package directory for '{}'", package);
+        Ok(false)
+    }
+}
+
+/// Delete a package from every layer of STAU_DIR providing it
+fn purge_package(config: &Config, package: &str, dry_run: bool) -> Result<()> {
+    for package_dir in config.get_package_layers(package) {
+        purge_package_dir(&package_dir, dry_run)?;
+    }
+    Ok(())
+}
+
+/// Delete one directory of a package, staging the deletion if it is tracked by git
+fn purge_package_dir(package_dir: &Path, dry_run: bool) -> Result<()> {
+    if dry_run {
+        outln!("Would delete package directory: {}", package_dir.display());
+        return Ok(());
+    }
+
+    let tracked = git::is_repo(package_dir) && git::is_tracked(package_dir, ".")?;
+    if tracked {
+        git::remove(package_dir, ".")?;
+    }
+
+    // git rm leaves untracked and ignored files behind
+    if package_dir.exists() {
+        std::fs::remove_dir_all(package_dir).map_err(error::StauError::Io)?;
+    }
+
+    if tracked {
+        outln!(
+            "Deleted package directory {} (deletion staged in git)",
+            package_dir.display()
+        );
+    } else {
+        outln!("Deleted package directory {}", package_dir.display());
+    }
+
+    Ok(())
+}
+
+/// Keep the mappings of files at or below the given paths of a package
+///
+/// A path that matches nothing is an error, as is one below a directory that
+/// is linked as a whole.
+fn select_paths(
+    config: &Config,
+    package: &str,
+    mappings: Vec<symlink::SymlinkMapping>,
+    paths: &[PathBuf],
+) -> Result<Vec<symlink::SymlinkMapping>> {
+    let layers = config.get_package_layers(package);
+    let relative = |mapping: &symlink::SymlinkMapping| {
+        layers
+            .iter()
+            .find_map(|layer| mapping.source.strip_prefix(layer).ok())
+            .map(Path::to_path_buf)
+    };
+
+    for path in paths {
+        if !path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            return Err(error::StauError::Other(format!(
+                "--path must be relative to the package root: {}",
+                path.display()
+            )));
+        }
+        if !mappings
+            .iter()
+            .any(|m| relative(m).is_some_and(|rel| rel.starts_with(path)))
+        {
+            return Err(error::StauError::Other(format!(
+                "Package '{}' links nothing at {}",
+                package,
+                path.display()
+            )));
+        }
+    }
+
+    Ok(mappings
+        .into_iter()
+        .filter(|m| relative(m).is_some_and(|rel| paths.iter().any(|p| rel.starts_with(p))))
+        .collect())
+}
+
+struct UninstallOptions {
+    no_teardown: bool,
+    conflicts: Option<ConflictPolicy>,
+    copy_files_back: bool,
+    dry_run: bool,
+    verbose: bool,
+}
+
+fn uninstall_package(
+    config: &Config,
+    package: &str,
+    target: Option<PathBuf>,
+    opts: UninstallOptions,
+) -> Result<PackageReport> {
+    let target_dir = config.get_target(target);
+    let package_dir = config.get_package_dir(package);
+    let policy = config.conflict_policy(package, opts.conflicts)?;
+    let _span = tracing::info_span!("uninstall", package).entered();
+
+    if opts.verbose {
+        outln!("Package directory: {}", package_dir.display());
+        outln!("Target directory: {}", target_dir.display());
+    }
+
+    // Check if package exists
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    let run_as = config.get_manifest(package)?.run_as;
+    let script_ctx = script::ScriptContext {
+        package,
+        stau_dir: &config.stau_dir,
+        target_dir: &target_dir,
+        dry_run: opts.dry_run,
+        verbose: opts.verbose,
+        run_as: run_as.as_deref(),
+        operation: script::Operation::Uninstall,
+        state_dir: Some(&config.state_dir),
+    };
+
+    // Discover all files that would be in the package
+    let mappings = config.discover(package, &target_dir)?;
+
+    // Make sure the links can be replaced before any script runs or link is removed
+    let installed: Vec<&symlink::SymlinkMapping> = mappings
+        .iter()
+        .filter(|m| symlink::is_stau_symlink(&m.target, &m.source).unwrap_or(false))
+        .collect();
+    check_permissions(installed.iter().map(|m| m.target.as_path()), opts.verbose)?;
+    if opts.copy_files_back {
+        check_space(&installed)?;
+    }
+
+    let mut report = PackageReport::default();
+
+    // Let the package veto the uninstall before anything is touched
+    if !opts.no_teardown {
+        report.scripts += run_hook(config, script::ScriptKind::CheckUninstall, &script_ctx)?;
+    }
+
+    // Run teardown scripts first if they exist and not skipped.
+    // Note: PRD says teardown should continue even if it fails
+    if !opts.no_teardown {
+        report.scripts += run_hook_continue_on_error(
+            config,
+            script::ScriptKind::Teardown,
+            &script_ctx,
+            "Continuing with uninstall...",
+        )?;
+    }
+
+    if opts.verbose {
+        outln!("Found {} symlinks to remove", mappings.len());
+    }
+
+    if mappings.is_empty() {
+        outln!("No symlinks to remove for package '{}'", package);
+        return Ok(report);
+    }
+
+    let mut removed_count = 0;
+
+    // Remove symlinks and copy files back
+    let link_span = tracing::info_span!("link").entered();
+    for mapping in &mappings {
+        interrupt::check()?;
+
+        // Remove the symlink if it points to our source
+        let was_removed = symlink::remove_symlink(&mapping.target, &mapping.source, opts.dry_run)?;
+
+        if was_removed {
+            if opts.verbose || opts.dry_run {
+                outln!("  Removing symlink: {}", mapping.target.display());
+            }
+            let action = if opts.copy_files_back {
+                plan::Action::Copy
+            } else {
+                plan::Action::Unlink
+            };
+            plan::add(plan_link(
+                package,
+                action,
+                mapping,
+                "linked by this package",
+            ));
+
+            // Copy the source file to target location (unless we're doing a restow)
+            if opts.copy_files_back {
+                if opts.verbose || opts.dry_run {
+                    outln!("  Copying file: {}", mapping.target.display());
+                }
+
+                // In dry-run mode the symlink wasn't actually removed yet, so there is
+                // no conflict to check. Adopting makes no sense here, so the file
+                // already there is kept instead.
+                let mut copy = true;
+                if !opts.dry_run && mapping.target.symlink_metadata().is_ok() {
+                    report.conflicts += 1;
+                    let resolution = match policy.resolve(&mapping.target)? {
+                        Resolution::Adopt => Resolution::Skip,
+                        resolution => resolution,
+                    };
+                    copy = resolve_conflict(config, mapping, resolution, false, opts.verbose)?;
+                }
+
+                if copy {
+                    symlink::copy_file(&mapping.source, &mapping.target, opts.dry_run)?;
+                }
+            }
+            removed_count += 1;
+        } else {
+            if mapping.target.symlink_metadata().is_ok() {
+                plan::add(plan_link(
+                    package,
+                    plan::Action::Skip,
+                    mapping,
+                    "not a stau-managed symlink",
+                ));
+                warning::fail_if_strict(format!(
+                    "{} is not a stau-managed symlink",
+                    mapping.target.display()
+                ))?;
+            }
+            if opts.verbose {
+                outln!(
+                    "  Skipping {} (not a stau-managed symlink)",
+                    mapping.target.display()
+                );
+            }
+        }
+    }
+    drop(link_span);
+
+    if !opts.dry_run {
+        if opts.copy_files_back {
+            outln!(
+                "Successfully uninstalled {} ({} symlinks removed, files copied back)",
+                package,
+                removed_count
+            );
+        } else {
+            outln!(
+                "Successfully removed {} symlinks for {}",
+                removed_count,
+                package
+            );
+        }
+    }
+
+    // Run post-uninstall scripts once the symlinks are gone
+    if !opts.no_teardown {
+        report.scripts += run_hook_continue_on_error(
+            config,
+            script::ScriptKind::PostUninstall,
+            &script_ctx,
+            "Package symlinks were removed regardless.",
+        )?;
+    }
+
+    if !opts.dry_run {
+        config.forget_link_options(package, &target_dir)?;
+    }
+    record_machine(config, package, false, opts.dry_run)?;
+    report.removed = removed_count;
+    Ok(report)
+}
+
+/// Installation state of a package in a target directory
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PackageState {
+    /// All links are in place
+    Installed,
+    /// Some links are missing
+    Partial,
+    /// Some links are dangling
+    Broken,
+    /// No links are in place
+    NotInstalled,
+}
+
+impl PackageState {
+    fn label(self) -> &'static str {
+        match self {
+            PackageState::Installed => "installed",
+            PackageState::Partial => "partial",
+            PackageState::Broken => "broken",
+            PackageState::NotInstalled => "not installed",
+        }
+    }
+}
+
+/// Link counts of a package in a target directory
+struct PackageHealth {
+    /// Number of files the package would link
+    links: usize,
+    /// Number of links that point at the package
+    installed: usize,
+    /// Number of dangling symlinks at the package's targets
+    broken: usize,
+    /// Number of targets occupied by something other than the package's link
+    conflicts: usize,
+}
+
+impl PackageHealth {
+    fn check(config: &Config, package: &str, target_dir: &std::path::Path) -> Result<Self> {
+        let mut health = PackageHealth {
+            links: 0,
+            installed: 0,
+            broken: 0,
+            conflicts: 0,
+        };
+
+        // The package's files were just discovered, so its own links can't
+        // be dangling and only need a `stat` when they are something else
+        for mapping in config.package_files(package, target_dir)? {
+            let mapping = mapping?;
+            health.links += 1;
+            let state = symlink::TargetState::of(&mapping.target);
+            if state.links_to(&mapping.source) {
+                health.installed += 1;
+            } else if state.is_dangling(&mapping.target) {
+                health.broken += 1;
+            } else if state != symlink::TargetState::Missing {
+                health.conflicts += 1;
+            }
+        }
+
+        Ok(health)
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.broken == 0 && self.conflicts == 0
+    }
+
+    fn state(&self) -> PackageState {
+        if self.installed == 0 {
+            PackageState::NotInstalled
+        } else if self.broken > 0 {
+            PackageState::Broken
+        } else if self.installed == self.links {
+            PackageState::Installed
+        } else {
+            PackageState::Partial
+        }
+    }
+}
+
+/// What `stau list` shows and checks
+struct ListOptions<'a> {
+    format: ListFormat,
+    check: bool,
+    /// Only packages in these states, all of them when empty
+    filter: &'a [PackageState],
+    long: bool,
+    /// Print only the paths of the packages, for `--paths`
+    paths: Option<PathsOutput>,
+    porcelain: Option<porcelain::Version>,
+    jobs: usize,
+}
+
+/// How `stau list --paths` prints the paths of each package
+#[derive(Clone, Copy)]
+struct PathsOutput {
+    with_target: bool,
+    /// End every path with a NUL byte
+    null: bool,
+}
+
+fn list_packages(config: &Config, target: Option<PathBuf>, opts: &ListOptions) -> Result<()> {
+    let ListOptions {
+        format,
+        check,
+        filter,
+        long,
+        paths,
+        porcelain,
+        jobs,
+    } = *opts;
+    let target_dir = config.get_target(target);
+    let packages = package::list_packages(&config.get_stau_dirs())?;
+
+    // Output for scripts is just empty
+    if packages.is_empty() && paths.is_none() && porcelain.is_none() {
+        println!("No packages found in {}", config.stau_dir.display());
+        return Ok(());
+    }
+
+    // Paths alone don't depend on how the packages are installed
+    let health = if paths.is_none() || check || !filter.is_empty() {
+        jobs::map(&packages, jobs, |pkg| {
+            PackageHealth::check(config, pkg, &target_dir).ok()
+        })
+    } else {
+        packages.iter().map(|_| None).collect()
+    };
+    let packages: Vec<(String, Option<PackageHealth>)> = packages
+        .into_iter()
+        .zip(health)
+        .filter(|(_, health)| {
+            filter.is_empty() || health.as_ref().is_some_and(|h| filter.contains(&h.state()))
+        })
+        .collect();
+
+    let mut git_status = HashMap::new();
+    if long {
+        let names: Vec<String> = packages.iter().map(|(pkg, _)| pkg.clone()).collect();
+        let statuses = jobs::map(&names, jobs, |pkg| package_git_status(config, pkg));
+        for (pkg, status) in names.into_iter().zip(statuses) {
+            git_status.insert(pkg, status?);
+        }
+    }
+
+    match (paths, porcelain, format) {
+        (Some(output), _, _) => print_package_paths(config, &packages, &target_dir, output)?,
+        (None, Some(version), _) => {
+            println!("{}", porcelain::header(version));
+            println!("{}", porcelain::target(&target_dir));
+            for (pkg, health) in &packages {
+                println!("{}", porcelain_package(pkg, health.as_ref()));
+            }
+        }
+        (None, None, ListFormat::Plain) => print_package_list(config, &packages, &git_status),
+        (None, None, ListFormat::Table) => {
+            print_package_table(config, &packages, &target_dir, &git_status)
+        }
+        (None, None, ListFormat::Tree) => print_package_tree(config, &packages),
+    }
+
+    if check {
+        // Packages that aren't installed at all are not expected to be healthy
+        let unhealthy: Vec<&str> = packages
+            .iter()
+            .filter(|(_, health)| {
+                health
+                    .as_ref()
+                    .is_some_and(|h| h.state() != PackageState::NotInstalled && !h.is_healthy())
+            })
+            .map(|(pkg, _)| pkg.as_str())
+            .collect();
+
+        if !unhealthy.is_empty() {
+            return Err(error::StauError::Unhealthy(unhealthy.join(", ")));
+        }
+    }
+
+    Ok(())
+}
+
+/// Describe the git state of a package's directories, or "" if nothing is pending
+///
+/// Layers that aren't inside a git repository are skipped.
+fn package_git_status(config: &Config, package: &str) -> Result<String> {
+    let mut uncommitted = false;
+    let mut differs = false;
+    for layer in config.get_package_layers(package) {
+        if git::is_repo(&layer) {
+            uncommitted |= git::has_uncommitted_changes(&layer, ".")?;
+            differs |= git::differs_from_upstream(&layer, ".")?;
+        }
+    }
+
+    let mut labels = Vec::new();
+    if uncommitted {
+        labels.push("uncommitted");
+    }
+    if differs {
+        labels.push("differs from upstream");
+    }
+    Ok(labels.join(", "))
+}
+
+/// The record of a package in the `--porcelain` output, whose states are
+/// spelled out here as they must not change with the human output
+fn porcelain_package(name: &str, health: Option<&PackageHealth>) -> String {
+    let Some(health) = health else {
+        return porcelain::package(name, "error", [0; 4]);
+    };
+    let state = match health.state() {
+        PackageState::Installed => "installed",
+        PackageState::Partial => "partial",
+        PackageState::Broken => "broken",
+        PackageState::NotInstalled => "not-installed",
+    };
+    porcelain::package(
+        name,
+        state,
+        [
+            health.links,
+            health.installed,
+            health.broken,
+            health.conflicts,
+        ],
+    )
+}
+
+/// Print the `--porcelain` records of a package and its files, returning
+/// whether the package is healthy
+///
+/// A package that can't be read gets an `error` record, like in `list
+/// --porcelain`, and counts as unhealthy.
+fn show_porcelain_status(config: &Config, package: &str, target_dir: &Path) -> Result<bool> {
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    let (health, files) = match check_package_files(config, package, target_dir) {
+        Ok(checked) => checked,
+        Err(e) => {
+            errln!("Error: {}", e);
+            println!("{}", porcelain_package(package, None));
+            return Ok(false);
+        }
+    };
+    println!("{}", porcelain_package(package, Some(&health)));
+    for (target, label) in files {
+        println!("{}", porcelain::file(label, &target));
+    }
+    Ok(health.is_healthy())
+}
+
+/// Count the links of a package and label the state of each of its files,
+/// sorted by target path
+fn check_package_files(
+    config: &Config,
+    package: &str,
+    target_dir: &Path,
+) -> Result<(PackageHealth, Vec<(PathBuf, &'static str)>)> {
+    let mut health = PackageHealth {
+        links: 0,
+        installed: 0,
+        broken: 0,
+        conflicts: 0,
+    };
+    let mut files = Vec::new();
+    for mapping in config.package_files(package, target_dir)? {
+        let mapping = mapping?;
+        health.links += 1;
+        let state = symlink::TargetState::of(&mapping.target);
+        let label = if state.links_to(&mapping.source) {
+            health.installed += 1;
+            "installed"
+        } else if state.is_dangling(&mapping.target) {
+            health.broken += 1;
+            "broken"
+        } else if state != symlink::TargetState::Missing {
+            health.conflicts += 1;
+            "conflict"
+        } else {
+            "missing"
+        };
+        files.push((mapping.target, label));
+    }
+
+    // Sorted, as the order the files are found in may differ between runs
+    files.sort();
+    Ok((health, files))
+}
+
+/// Print the absolute directory of each package, and the target directory
+/// with `--with-target`, as raw bytes so that any path comes out intact
+fn print_package_paths(
+    config: &Config,
+    packages: &[(String, Option<PackageHealth>)],
+    target_dir: &Path,
+    output: PathsOutput,
+) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+
+    let target_dir = std::path::absolute(target_dir).map_err(error::StauError::Io)?;
+    let mut stdout = std::io::stdout().lock();
+    for (pkg, _) in packages {
+        let package_dir =
+            std::path::absolute(config.get_package_dir(pkg)).map_err(error::StauError::Io)?;
+        let mut fields = vec![package_dir.as_path()];
+        if output.with_target {
+            fields.push(&target_dir);
+        }
+
+        let mut line = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            line.extend_from_slice(field.as_os_str().as_bytes());
+            line.push(match (output.null, i + 1 == fields.len()) {
+                (true, _) => b'\0',
+                (false, false) => b'\t',
+                (false, true) => b'\n',
+            });
+        }
+        stdout.write_all(&line).map_err(error::StauError::Io)?;
+    }
+    stdout.flush().map_err(error::StauError::Io)
+}
+
+fn print_package_list(
+    config: &Config,
+    packages: &[(String, Option<PackageHealth>)],
+    git_status: &HashMap<String, String>,
+) {
+    println!("Packages in {}:\n", config.stau_dir.display());
+
+    for (pkg, health) in packages {
+        let line = match health {
+            None => format!("  {:<20} [error reading package]", pkg),
+            Some(health) => match health.state() {
+                PackageState::NotInstalled => format!("  {:<20} [not installed]", pkg),
+                PackageState::Broken => format!(
+                    "  {:<20} [installed]  {} symlinks  ({} broken)",
+                    pkg, health.installed, health.broken
+                ),
+                PackageState::Installed => format!(
+                    "  {:<20} [installed]  {} symlink{}",
+                    pkg,
+                    health.installed,
+                    if health.installed == 1 { "" } else { "s" }
+                ),
+                PackageState::Partial => format!(
+                    "  {:<20} [partial]    {}/{} symlinks",
+                    pkg, health.installed, health.links
+                ),
+            },
+        };
+
+        match git_status.get(pkg) {
+            Some(status) if !status.is_empty() => println!("{}  <{}>", line, status),
+            _ => println!("{}", line),
+        }
+    }
+}
+
+fn print_package_table(
+    config: &Config,
+    packages: &[(String, Option<PackageHealth>)],
+    target_dir: &std::path::Path,
+    git_status: &HashMap<String, String>,
+) {
+    let long = !git_status.is_empty();
+    let mut header = vec![
+        "NAME".to_string(),
+        "STATE".to_string(),
+        "LINKS".to_string(),
+        "BROKEN".to_string(),
+        "TARGET".to_string(),
+    ];
+    if long {
+        header.push("GIT".to_string());
+    }
+    header.push("DESCRIPTION".to_string());
+    let mut rows = vec![header];
+
+    for (pkg, health) in packages {
+        // A broken stau.toml only costs its own package the description
+        let description = match config.get_manifest(pkg) {
+            Ok(manifest) => manifest.description.unwrap_or_default(),
+            Err(e) => format!(
+                "error: {}",
+                e.to_string().lines().next().unwrap_or_default()
+            ),
+        };
+        let (state, links, broken) = match health {
+            Some(h) => (
+                h.state().label().to_string(),
+                format!("{}/{}", h.installed, h.links),
+                h.broken.to_string(),
+            ),
+            None => ("error".to_string(), "-".to_string(), "-".to_string()),
+        };
+        let mut row = vec![
+            pkg.clone(),
+            state,
+            links,
+            broken,
+            target_dir.display().to_string(),
+        ];
+        if long {
+            let status = git_status.get(pkg).map(String::as_str).unwrap_or_default();
+            row.push(if status.is_empty() { "-" } else { status }.to_string());
+        }
+        row.push(description);
+        rows.push(row);
+    }
+
+    print_table(&rows);
+}
+
+/// Print rows as left-aligned columns, the first row being the header
+fn print_table(rows: &[Vec<String>]) {
+    let mut widths = vec![0; rows[0].len()];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        outln!("{}", line.join("  ").trim_end());
+    }
+}
+
+fn print_package_tree(config: &Config, packages: &[(String, Option<PackageHealth>)]) {
+    use std::collections::{BTreeMap, HashSet};
+
+    // Packages without tags are grouped last, along with those whose stau.toml can't be read
+    let mut groups: BTreeMap<Option<String>, Vec<usize>> = BTreeMap::new();
+    let mut unreadable = HashSet::new();
+    for (i, (pkg, _)) in packages.iter().enumerate() {
+        let tags = match config.get_manifest(pkg) {
+            Ok(manifest) => manifest.tags,
+            Err(_) => {
+                unreadable.insert(i);
+                Vec::new()
+            }
+        };
+        if tags.is_empty() {
+            groups.entry(None).or_default().push(i);
+        }
+        for tag in tags {
+            groups.entry(Some(tag)).or_default().push(i);
+        }
+    }
+
+    let name_width = packages.iter().map(|(pkg, _)| pkg.len()).max().unwrap_or(0);
+
+    println!("Packages in {}:\n", config.stau_dir.display());
+
+    let untagged = groups.remove(&None);
+    let groups = groups
+        .into_iter()
+        .chain(untagged.map(|members| (None, members)));
+
+    for (tag, members) in groups {
+        println!("{}", tag.as_deref().unwrap_or("(untagged)"));
+        for (n, &i) in members.iter().enumerate() {
+            let (pkg, health) = &packages[i];
+            let branch = if n + 1 == members.len() {
+                "└──"
+            } else {
+                "├──"
+            };
+            let state = match health {
+                Some(h) if !unreadable.contains(&i) => h.state().label(),
+                _ => "error reading package",
+            };
+            println!(
+                "{} {:<width$}  [{}]",
+                branch,
+                pkg,
+                state,
+                width = name_width
+            );
+        }
+    }
+}
+
+struct AdoptOptions {
+    commit: bool,
+    /// Link directories as a whole instead of file by file
+    fold: bool,
+    /// Path in the package for the single file adopted
+    into: Option<PathBuf>,
+    dry_run: bool,
+    verbose: bool,
+}
+
+fn adopt_files(
+    config: &Config,
+    package: &str,
+    files: &[PathBuf],
+    target: Option<PathBuf>,
+    opts: &AdoptOptions,
+) -> Result<()> {
+    use std::fs;
+
+    let AdoptOptions {
+        dry_run, verbose, ..
+    } = *opts;
+
+    let target_dir = config.get_target(target);
+    let package_dir = config.get_package_dir(package);
+
+    // Create package directory if it doesn't exist
+    if !package_dir.exists() {
+        if verbose || dry_run {
+            println!("Creating package directory: {}", package_dir.display());
+        }
+        if !dry_run {
+            fs::create_dir_all(&package_dir).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::PermissionDenied {
+                    error::StauError::PermissionDenied(format!(
+                        "Cannot create package directory: {}",
+                        package_dir.display()
+                    ))
+                } else {
+                    error::StauError::Io(e)
+                }
+            })?;
+        }
+    }
+
+    println!(
+        "Adopting {} file(s) into package '{}':",
+        files.len(),
+        package
+    );
+
+    let mut adopted = Vec::new();
+    let mut folded = Vec::new();
+    for file_path in files {
+        // Make sure the file exists
+        if !file_path.exists() {
+            warning::warn(format!("File does not exist: {}", file_path.display()))?;
+            continue;
+        }
+
+        // Calculate relative path from target directory
+        let rel_path = match file_path.strip_prefix(&target_dir) {
+            Ok(p) => p,
+            Err(_) => {
+                warning::warn(format!(
+                    "File {} is not in target directory {}",
+                    file_path.display(),
+                    target_dir.display()
+                ))?;
+                continue;
+            }
+        };
+
+        // Destination in package directory
+        let rel_path = match &opts.into {
+            Some(into) => adopt_into(config, package, into, file_path, &target_dir)?,
+            None => rel_path.to_path_buf(),
+        };
+        let rel_path = rel_path.as_path();
+        let dest = package_dir.join(rel_path);
+        let is_dir = file_path.symlink_metadata().is_ok_and(|m| m.is_dir());
+
+        // A file already in the package only needs linking if it is identical
+        if dest.exists() {
+            if !same_contents(file_path, &dest)? {
+                return Err(error::StauError::ConflictingFile(dest));
+            }
+            if verbose || dry_run {
+                println!(
+                    "  {} -> {} (already in package)",
+                    file_path.display(),
+                    dest.display()
+                );
+            }
+            if !dry_run {
+                fs::remove_file(file_path).map_err(error::StauError::Io)?;
+                symlink::create_symlink(&dest, file_path, false)?;
+            }
+            continue;
+        }
+
+        if verbose || dry_run {
+            println!("  {} -> {}", file_path.display(), dest.display());
+        }
+
+        if !dry_run {
+            // Create parent directories if needed
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent).map_err(error::StauError::Io)?;
+            }
+
+            // Move the file, copying it when the package is on another file system
+            match fs::rename(file_path, &dest) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                    symlink::copy_file(file_path, &dest, false)?;
+                    fs::remove_file(file_path).map_err(error::StauError::Io)?;
+                }
+                Err(e) => return Err(error::StauError::Io(e)),
+            }
+
+            // Create symlink at original location
+            symlink::create_symlink(&dest, file_path, false)?;
+        }
+
+        if is_dir && opts.fold {
+            folded.push(rel_path.to_path_buf());
+        }
+        adopted.push((rel_path.to_path_buf(), dest));
+    }
+
+    // Record folded directories so every install links them as a whole
+    let mut manifest_changed = false;
+    for dir in &folded {
+        if dry_run {
+            println!(
+                "Would add {} to fold in {}",
+                dir.display(),
+                manifest::MANIFEST_FILE
+            );
+        } else if manifest::Manifest::add_fold(&package_dir, dir)? {
+            manifest_changed = true;
+            if verbose {
+                println!(
+                    "Added {} to fold in {}",
+                    dir.display(),
+                    manifest::MANIFEST_FILE
+                );
+            }
+        }
+    }
+
+    if !dry_run {
+        println!(
+            "Successfully adopted {} file(s) into '{}'",
+            files.len(),
+            package
+        );
+    }
+
+    if !adopted.is_empty() {
+        let paths: Vec<PathBuf> = adopted.iter().map(|(_, dest)| dest.clone()).collect();
+        run_post_adopt(config, package, &target_dir, &paths, dry_run, verbose)?;
+    }
+
+    if opts.commit && !adopted.is_empty() {
+        commit_adopted(&package_dir, package, &adopted, manifest_changed, dry_run)?;
+    }
+
+    Ok(())
+}
+
+/// Run the repository-wide pre-command hooks, stopping at the first failure
+///
+/// They run once per command rather than per package, so STAU_PACKAGE is
+/// empty and STAU_TARGET is the default target.
+fn run_pre_command(
+    config: &Config,
+    operation: script::Operation,
+    dry_run: bool,
+    verbose: bool,
+) -> Result<()> {
+    let kind = script::ScriptKind::PreCommand;
+    let target_dir = config.get_target(None);
+    let script_ctx = script::ScriptContext {
+        package: "",
+        stau_dir: &config.stau_dir,
+        target_dir: &target_dir,
+        dry_run,
+        verbose,
+        run_as: None,
+        operation,
+        state_dir: None,
+    };
+    for script_path in config.get_repo_hook_scripts(kind) {
+        script::execute_script(&script_path, kind, &script_ctx)?;
+    }
+    Ok(())
+}
+
+/// Run the package's post-adopt hooks, then the repository-wide ones, with
+/// the adopted files' paths in the package as arguments
+///
+/// Failures only warn, since the files have already been adopted.
+fn run_post_adopt(
+    config: &Config,
+    package: &str,
+    target_dir: &Path,
+    paths: &[PathBuf],
+    dry_run: bool,
+    verbose: bool,
+) -> Result<()> {
+    let kind = script::ScriptKind::PostAdopt;
+    let mut scripts = config.get_hook_scripts(package, kind);
+    scripts.extend(config.get_repo_hook_scripts(kind));
+    if scripts.is_empty() {
+        return Ok(());
+    }
+
+    let run_as = config.get_manifest(package)?.run_as;
+    let script_ctx = script::ScriptContext {
+        package,
+        stau_dir: &config.stau_dir,
+        target_dir,
+        dry_run,
+        verbose,
+        run_as: run_as.as_deref(),
+        operation: script::Operation::Adopt,
+        state_dir: Some(&config.state_dir),
+    };
+    for script_path in &scripts {
+        if let Err(e) = script::execute_script_with_args(script_path, kind, &script_ctx, paths) {
+            warning::warn(format!("{} script failed: {}", kind.label(), e)).map_err(|_| e)?;
+        } else if !dry_run {
+            println!("{} script completed successfully", kind.label());
+        }
+    }
+    Ok(())
+}
+
+/// Offer unmanaged dotfiles found in a directory for adoption and adopt the chosen ones
+fn adopt_scanned(
+    config: &Config,
+    dir: Option<PathBuf>,
+    target: Option<PathBuf>,
+    opts: &AdoptOptions,
+) -> Result<()> {
+    let target_dir = config.get_target(target.clone());
+    let dir = match dir {
+        Some(dir) => std::path::absolute(dir).map_err(error::StauError::Io)?,
+        None => target_dir.clone(),
+    };
+
+    let stau_dirs = config.get_stau_dirs();
+    let mut own = stau_dirs.clone();
+    own.push(config.state_dir.clone());
+    let candidates = scan::find_candidates(&dir, &target_dir, &stau_dirs, &own)?;
+    if candidates.is_empty() {
+        println!("No unmanaged dotfiles found in {}", dir.display());
+        return Ok(());
+    }
+
+    println!("Unmanaged dotfiles in {}:", dir.display());
+    for (i, candidate) in candidates.iter().enumerate() {
+        println!(
+            "  {:>2}) {} -> {}",
+            i + 1,
+            candidate.path.display(),
+            candidate.package
+        );
+    }
+
+    let answer = prompt::ask(
+        "Adopt which (e.g. '1 3-5 7=shell' or 'all', empty for none)?",
+        "all",
+    )?;
+    let chosen = scan::parse_selection(&answer, candidates.len())?;
+    if chosen.is_empty() {
+        println!("Nothing adopted");
+        return Ok(());
+    }
+
+    // Group the files by package, in the order the packages first appear
+    let mut groups: Vec<(String, Vec<PathBuf>)> = Vec::new();
+    for (index, package) in chosen {
+        let package = package.unwrap_or_else(|| candidates[index].package.clone());
+        package::validate_name(&package)?;
+        let path = candidates[index].path.clone();
+        match groups.iter_mut().find(|(name, _)| *name == package) {
+            Some((_, files)) => files.push(path),
+            None => groups.push((package, vec![path])),
+        }
+    }
+
+    for (package, files) in groups {
+        adopt_files(config, &package, &files, target.clone(), opts)?;
+    }
+    Ok(())
+}
+
+/// The path in a package a file adopted with `--into` goes to
+///
+/// A path ending in `/` or naming an existing directory in the package takes
+/// the file by its name. The file must end up where stau links it back from,
+/// given the package's targets.
+fn adopt_into(
+    config: &Config,
+    package: &str,
+    into: &Path,
+    file_path: &Path,
+    target_dir: &Path,
+) -> Result<PathBuf> {
+    if !into
+        .components()
+        .all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        return Err(error::StauError::Other(format!(
+            "--into must be a path inside the package: {}",
+            into.display()
+        )));
+    }
+
+    let mut rel_path = into.to_path_buf();
+    let is_dir = into.to_string_lossy().ends_with('/')
+        || config.get_package_dir(package).join(into).is_dir();
+    if is_dir && let Some(name) = file_path.file_name() {
+        rel_path.push(name);
+    }
+
+    let linked_at = config.link_target(package, &rel_path, target_dir)?;
+    if linked_at != file_path {
+        return Err(error::StauError::InvalidConfig(format!(
+            "package '{}' would link {} at {}, not at {}; give it a [targets] section first",
+            package,
+            rel_path.display(),
+            linked_at.display(),
+            file_path.display()
+        )));
+    }
+    Ok(rel_path)
+}
+
+/// Check whether two paths are regular files with the same bytes
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let (Ok(a_meta), Ok(b_meta)) = (a.symlink_metadata(), b.symlink_metadata()) else {
+        return Ok(false);
+    };
+    if !a_meta.is_file() || !b_meta.is_file() || a_meta.len() != b_meta.len() {
+        return Ok(false);
+    }
+    let a = std::fs::read(a).map_err(error::StauError::Io)?;
+    let b = std::fs::read(b).map_err(error::StauError::Io)?;
+    Ok(a == b)
+}
+
+/// Commit adopted files, given as (path relative to the target, path in the package),
+/// along with the package's manifest if adopting changed it
+fn commit_adopted(
+    package_dir: &Path,
+    package: &str,
+    adopted: &[(PathBuf, PathBuf)],
+    manifest_changed: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if !dry_run && !git::is_repo(package_dir) {
+        return warning::warn(format!(
+            "Not committing, {} is not in a git repository",
+            package_dir.display()
+        ));
+    }
+
+    let names: Vec<String> = adopted
+        .iter()
+        .map(|(rel_path, _)| rel_path.display().to_string())
+        .collect();
+    let message = format!("adopt {} into {}", names.join(", "), package);
+
+    if dry_run {
+        println!("Would commit: {}", message);
+        return Ok(());
+    }
+
+    let mut paths: Vec<PathBuf> = adopted.iter().map(|(_, dest)| dest.clone()).collect();
+    if manifest_changed {
+        paths.push(package_dir.join(manifest::MANIFEST_FILE));
+    }
+    git::commit_paths(package_dir, &paths, &message)?;
+    println!("Committed: {}", message);
+    Ok(())
+}
+
+fn remove_files(
+    config: &Config,
+    package: &str,
+    files: &[PathBuf],
+    target: Option<PathBuf>,
+    dry_run: bool,
+    verbose: bool,
+) -> Result<()> {
+    let target_dir = config.get_target(target);
+    let layers = config.get_package_layers(package);
+
+    if layers.is_empty() {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    let mappings = config.discover(package, &target_dir)?;
+
+    for file_path in files {
+        // Accept paths in the target directory as well as paths inside the package
+        let rel_path = if file_path.is_absolute() {
+            file_path
+                .strip_prefix(&target_dir)
+                .map_err(|_| error::StauError::InvalidPath(file_path.clone()))?
+        } else {
+            file_path.as_path()
+        };
+
+        if rel_path
+            .components()
+            .any(|c| !matches!(c, std::path::Component::Normal(_)))
+        {
+            return Err(error::StauError::InvalidPath(file_path.clone()));
+        }
+
+        // With overlays, remove the copy from every layer, or the next one
+        // down would take its place
+        let sources: Vec<PathBuf> = layers
+            .iter()
+            .rev()
+            .map(|layer| layer.join(rel_path))
+            .filter(|source| source.symlink_metadata().is_ok())
+            .collect();
+        if sources.is_empty() {
+            return Err(error::StauError::Other(format!(
+                "File not found in package '{}': {}",
+                package,
+                rel_path.display()
+            )));
+        }
+
+        for source in &sources {
+            // Unlink everything that points into the removed file or directory
+            for mapping in mappings.iter().filter(|m| m.source.starts_with(source)) {
+                if symlink::remove_symlink(&mapping.target, &mapping.source, dry_run)? {
+                    if verbose || dry_run {
+                        println!("  Removing symlink: {}", mapping.target.display());
+                    }
+                } else if mapping.target.symlink_metadata().is_ok() {
+                    warning::warn(format!(
+                        "Leaving {} in place (not a stau-managed symlink)",
+                        mapping.target.display()
+                    ))?;
+                }
+            }
+
+            if verbose || dry_run {
+                println!("  Removing file: {}", source.display());
+            }
+
+            symlink::remove_existing(source, dry_run)?;
+        }
+    }
+
+    if !dry_run {
+        println!(
+            "Successfully removed {} file(s) from '{}'",
+            files.len(),
+            package
+        );
+    }
+
+    Ok(())
+}
+
+/// Links of several packages counted by state, for `status --short`
+#[derive(Default)]
+struct StatusCounts {
+    ok: usize,
+    missing: usize,
+    broken: usize,
+    conflicts: usize,
+}
+
+impl StatusCounts {
+    fn check(config: &Config, packages: &[String], target: Option<PathBuf>) -> Result<Self> {
+        let target_dir = config.get_target(target);
+        let mut counts = StatusCounts::default();
+        for package in packages {
+            if !config.package_exists(package) {
+                return Err(error::StauError::PackageNotFound(package.to_string()));
+            }
+            for mapping in config.package_files(package, &target_dir)? {
+                let mapping = mapping?;
+                let state = symlink::TargetState::of(&mapping.target);
+                if state.links_to(&mapping.source) {
+                    counts.ok += 1;
+                } else if state.is_dangling(&mapping.target) {
+                    counts.broken += 1;
+                } else if state != symlink::TargetState::Missing {
+                    counts.conflicts += 1;
+                } else {
+                    counts.missing += 1;
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.broken == 0 && self.conflicts == 0
+    }
+}
+
+impl std::fmt::Display for StatusCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ok, {} missing, {} broken, {} conflicts",
+            self.ok, self.missing, self.broken, self.conflicts
+        )
+    }
+}
+
+/// How a link into STAU_DIR found in the target relates to the packages
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LinkOrigin {
+    /// A package links its file here
+    Valid(String),
+    /// Its destination doesn't exist
+    Broken,
+    /// Points at a package file, but no package links it here
+    Orphaned,
+    /// Points at something in STAU_DIR outside the packages, so stau didn't create it
+    Foreign,
+}
+
+impl LinkOrigin {
+    fn label(&self) -> String {
+        match self {
+            LinkOrigin::Valid(package) => format!("[{}]", package),
+            LinkOrigin::Broken => "[BROKEN]".to_string(),
+            LinkOrigin::Orphaned => "[orphaned]".to_string(),
+            LinkOrigin::Foreign => "[foreign]".to_string(),
+        }
+    }
+}
+
+/// Classify every link into STAU_DIR in the target, sorted by path
+fn classify_target_links(
+    config: &Config,
+    target_dir: &Path,
+) -> Result<Vec<(PathBuf, PathBuf, LinkOrigin)>> {
+    let stau_dirs = config.get_stau_dirs();
+    let packages = package::list_packages(&stau_dirs)?;
+    let mut expected = HashMap::new();
+    for package in &packages {
+        for mapping in config.discover(package, target_dir)? {
+            expected.insert(mapping.target, (mapping.source, package.clone()));
+        }
+    }
+
+    let in_package = |dest: &Path| {
+        stau_dirs.iter().any(|dir| {
+            dest.strip_prefix(dir).is_ok_and(|relative| {
+                let mut components = relative.components();
+                components
+                    .next()
+                    .is_some_and(|first| packages.iter().any(|p| first.as_os_str() == p.as_str()))
+                    && components.next().is_some()
+            })
+        })
+    };
+
+    let mut links = Vec::new();
+    for (link, dest) in scan::find_links_into(target_dir, &stau_dirs) {
+        let origin = if !link.exists() {
+            LinkOrigin::Broken
+        } else if let Some((source, package)) = expected.get(&link)
+            && *source == dest
+        {
+            LinkOrigin::Valid(package.clone())
+        } else if in_package(&dest) {
+            LinkOrigin::Orphaned
+        } else {
+            LinkOrigin::Foreign
+        };
+        links.push((link, dest, origin));
+    }
+    Ok(links)
+}
+
+/// Print every link into STAU_DIR found anywhere in the target, returning
+/// the number of broken and orphaned links
+///
+/// Broken and orphaned links make the status fail; foreign links were made
+/// by hand and are only reported.
+fn show_global_status(
+    config: &Config,
+    target: Option<PathBuf>,
+    short: bool,
+) -> Result<(usize, usize)> {
+    let target_dir = config.get_target(target);
+    let links = classify_target_links(config, &target_dir)?;
+
+    let count = |wanted: fn(&LinkOrigin) -> bool| {
+        links.iter().filter(|(_, _, origin)| wanted(origin)).count()
+    };
+    let valid = count(|origin| matches!(origin, LinkOrigin::Valid(_)));
+    let broken = count(|origin| *origin == LinkOrigin::Broken);
+    let orphaned = count(|origin| *origin == LinkOrigin::Orphaned);
+    let foreign = count(|origin| *origin == LinkOrigin::Foreign);
+    let summary = format!(
+        "{} valid, {} broken, {} orphaned, {} foreign",
+        valid, broken, orphaned, foreign
+    );
+
+    if short {
+        println!("{}", summary);
+        return Ok((broken, orphaned));
+    }
+
+    println!("Links into STAU_DIR in {}:\n", target_dir.display());
+    if links.is_empty() {
+        println!("  (none)");
+    }
+    for (link, dest, origin) in &links {
+        match origin {
+            LinkOrigin::Valid(_) => println!("  {:<20} {}", origin.label(), link.display()),
+            _ => println!(
+                "  {:<20} {} -> {}",
+                origin.label(),
+                link.display(),
+                dest.display()
+            ),
+        }
+    }
+    println!();
+    println!("Summary: {}", summary);
+    Ok((broken, orphaned))
+}
+
+/// Print the status of a package, returning whether it is healthy
+///
+/// A package is healthy when none of its mappings are broken or conflicting.
+fn show_status(
+    config: &Config,
+    package: &str,
+    target: Option<PathBuf>,
+    long: bool,
+) -> Result<bool> {
+    let target_dir = config.get_target(target);
+    let package_dir = config.get_package_dir(package);
+
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    println!("Status for package '{}':\n", package);
+    println!("  Package directory: {}", package_dir.display());
+    for overlay in config.get_package_layers(package).iter().skip(1) {
+        println!("  Overlay:           {}", overlay.display());
+    }
+    println!("  Target directory:  {}", target_dir.display());
+
+    // Check for hook scripts
+    let setup_scripts = config.get_hook_scripts(package, script::ScriptKind::Setup);
+    if setup_scripts.is_empty() {
+        println!("  Setup script:      (none)");
+    }
+    for setup in &setup_scripts {
+        println!("  Setup script:      {} (exists)", setup.display());
+    }
+
+    let teardown_scripts = config.get_hook_scripts(package, script::ScriptKind::Teardown);
+    if teardown_scripts.is_empty() {
+        println!("  Teardown script:   (none)");
+    }
+    for teardown in &teardown_scripts {
+        println!("  Teardown script:   {} (exists)", teardown.display());
+    }
+
+    for kind in [
+        script::ScriptKind::PreInstall,
+        script::ScriptKind::CheckUninstall,
+        script::ScriptKind::PostUninstall,
+        script::ScriptKind::PostAdopt,
+    ] {
+        for hook in config.get_hook_scripts(package, kind) {
+            println!(
+                "  {:<19}{} (exists)",
+                format!("{} script:", kind.label()),
+                hook.display()
+            );
+        }
+    }
+
+    let mappings = config.discover(package, &target_dir)?;
 
-        symlink::create_symlink_with_force(&mapping.source, &mapping.target, dry_run, force)?;
+    if mappings.is_empty() {
+        println!("\nNo files in package.");
+        return Ok(true);
     }
 
-    if !dry_run {
-        println!(
-            "Successfully installed {} ({} symlinks created)",
-            package,
-            mappings.len()
-        );
+    println!("\nFiles ({} total):", mappings.len());
+
+    let mut installed = 0;
+    let mut not_installed = 0;
+    let mut broken = 0;
+    let mut conflicts = 0;
+
+    for mapping in &mappings {
+        let state = symlink::TargetState::of(&mapping.target);
+        let is_our_link = state.links_to(&mapping.source);
+
+        let status = if is_our_link {
+            installed += 1;
+            "[installed]"
+        } else if state.is_dangling(&mapping.target) {
+            broken += 1;
+            "[BROKEN]"
+        } else if state != symlink::TargetState::Missing {
+            not_installed += 1;
+            conflicts += 1;
+            "[conflict]"
+        } else {
+            not_installed += 1;
+            "[not installed]"
+        };
+
+        println!("  {:<20} {}", status, mapping.target.display());
+
+        if long {
+            print_mapping_details(mapping, is_our_link);
+        }
     }
 
-    // Run setup script if it exists and not skipped
-    if !no_setup && let Some(setup_script) = config.get_setup_script(package) {
-        if verbose {
-            println!("Found setup script: {}", setup_script.display());
+    println!();
+    println!(
+        "Summary: {} installed, {} not installed, {} broken",
+        installed, not_installed, broken
+    );
+
+    Ok(broken == 0 && conflicts == 0)
+}
+
+fn edit_settings(action: &ConfigAction, dry_run: bool) -> Result<()> {
+    let path = settings::Settings::path()?;
+    let mut settings = settings::Settings::load(&path)?;
+
+    match action {
+        ConfigAction::Get { key } => {
+            if let Some(value) = settings.get(key)? {
+                println!("{}", value);
+            }
+        }
+        ConfigAction::Set { key, value } => {
+            settings.set(key, value)?;
+            if dry_run {
+                println!("Would set {} in {}", key, path.display());
+            } else {
+                settings.save(&path)?;
+            }
+        }
+        ConfigAction::List => {
+            println!("Config file: {}\n", path.display());
+            for (key, description) in settings::KEYS {
+                let value = settings
+                    .get(key)?
+                    .unwrap_or_else(|| "(not set)".to_string());
+                println!("  {:<10} = {}", key, value);
+                println!("  {:<10}   {}", "", description);
+            }
         }
+    }
 
-        script::execute_script(
-            &setup_script,
-            package,
-            &config.stau_dir,
-            &target_dir,
-            dry_run,
-            verbose,
-        )?;
+    Ok(())
+}
 
-        if !dry_run {
-            println!("Setup script completed successfully");
+fn show_env(config: &Config, target: Option<PathBuf>) -> Result<()> {
+    let provenance = &config.provenance;
+    let target_source = if target.is_some() {
+        config::Source::Flag
+    } else {
+        provenance.target
+    };
+
+    let stau_dirs = std::env::join_paths(config.get_stau_dirs())
+        .map(|dirs| dirs.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| config.stau_dir.display().to_string());
+
+    let config_file = if provenance.config_file.exists() {
+        provenance.config_file.display().to_string()
+    } else {
+        format!("{} (not found)", provenance.config_file.display())
+    };
+
+    for (name, value, source) in [
+        ("STAU_CONFIG", config_file, provenance.config_file_source),
+        ("STAU_DIR", stau_dirs, provenance.stau_dir),
+        (
+            "STAU_TARGET",
+            config.get_target(target).display().to_string(),
+            target_source,
+        ),
+        (
+            "STAU_STATE_DIR",
+            config.state_dir.display().to_string(),
+            provenance.state_dir,
+        ),
+    ] {
+        println!("{:<15} {}  [{}]", name, value, source);
+    }
+
+    Ok(())
+}
+
+fn show_info(config: &Config, package: &str) -> Result<()> {
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    let manifest = config.get_manifest(package)?;
+
+    println!("Package: {}", package);
+    if let Some(description) = &manifest.description {
+        println!("Description: {}", description);
+    }
+    if !manifest.tags.is_empty() {
+        println!("Tags: {}", manifest.tags.join(", "));
+    }
+    if !manifest.depends.is_empty() {
+        println!("Depends: {}", manifest.depends.join(", "));
+    }
+    if !manifest.members.is_empty() {
+        println!("Members: {}", manifest.members.join(", "));
+    }
+    for layer in config.get_package_layers(package) {
+        println!("Directory: {}", layer.display());
+    }
+
+    match config.get_package_file(package, package::README_FILE) {
+        Some(readme) => {
+            let contents = std::fs::read_to_string(&readme).map_err(error::StauError::Io)?;
+            println!();
+            print!("{}", contents);
+            if !contents.ends_with('\n') {
+                println!();
+            }
         }
+        None => println!("\nNo {} in package.", package::README_FILE),
     }
 
     Ok(())
 }
 
-struct UninstallOptions {
-    no_teardown: bool,
-    force: bool,
-    copy_files_back: bool,
-    dry_run: bool,
-    verbose: bool,
+/// Print what is actually found at a mapping's target and its source
+fn print_mapping_details(mapping: &symlink::SymlinkMapping, is_our_link: bool) {
+    if let Ok(metadata) = mapping.target.symlink_metadata()
+        && !is_our_link
+    {
+        if metadata.file_type().is_symlink() {
+            let destination = std::fs::read_link(&mapping.target)
+                .map(|dest| dest.display().to_string())
+                .unwrap_or_else(|_| "?".to_string());
+            let missing = if mapping.target.exists() {
+                ""
+            } else {
+                " (missing)"
+            };
+            println!("      link:   -> {}{}", destination, missing);
+        } else if metadata.is_dir() {
+            println!("      target: directory, {}", describe_modified(&metadata));
+        } else {
+            println!("      target: {}", describe_file(&metadata));
+        }
+    }
+
+    match mapping.source.metadata() {
+        Ok(metadata) => println!("      source: {}", describe_file(&metadata)),
+        Err(_) => println!("      source: (unreadable)"),
+    }
 }
 
-fn uninstall_package(
+/// Describe a file's size and modification time
+fn describe_file(metadata: &std::fs::Metadata) -> String {
+    let size = metadata.len();
+    format!(
+        "{} byte{}, {}",
+        size,
+        if size == 1 { "" } else { "s" },
+        describe_modified(metadata)
+    )
+}
+
+/// Describe a file's modification time in local time
+fn describe_modified(metadata: &std::fs::Metadata) -> String {
+    match metadata.modified() {
+        Ok(time) => format!(
+            "modified {}",
+            chrono::DateTime::<chrono::Local>::from(time).format("%Y-%m-%d %H:%M:%S")
+        ),
+        Err(_) => "modification time unknown".to_string(),
+    }
+}
+
+fn run_script(
     config: &Config,
     package: &str,
+    script: Option<&str>,
+    args: &[String],
     target: Option<PathBuf>,
-    no_teardown: bool,
-    force: bool,
     dry_run: bool,
     verbose: bool,
 ) -> Result<()> {
-    let opts = UninstallOptions {
-        no_teardown,
-        force,
-        copy_files_back: true,
+    let target_dir = config.get_target(target);
+
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
+
+    let Some(script) = script else {
+        let names = config.list_named_scripts(package)?;
+        if names.is_empty() {
+            println!("No scripts found for package '{}'", package);
+        } else {
+            println!("Scripts for package '{}':\n", package);
+            for name in names {
+                println!("  {}", name);
+            }
+        }
+        return Ok(());
+    };
+
+    let script_path = config.get_named_script(package, script).ok_or_else(|| {
+        error::StauError::ScriptNotFound {
+            package: package.to_string(),
+            script: script.to_string(),
+        }
+    })?;
+
+    let run_as = config.get_manifest(package)?.run_as;
+    let script_ctx = script::ScriptContext {
+        package,
+        stau_dir: &config.stau_dir,
+        target_dir: &target_dir,
         dry_run,
         verbose,
+        run_as: run_as.as_deref(),
+        operation: script::Operation::Run,
+        // Named scripts write to the terminal directly, so there is no output to keep
+        state_dir: None,
     };
-    uninstall_package_internal(config, package, target, opts)
+    script::run_named_script(&script_path, script, args, &script_ctx)
 }
 
-fn uninstall_package_internal(
+fn clean_broken_symlinks(
     config: &Config,
     package: &str,
     target: Option<PathBuf>,
-    opts: UninstallOptions,
+    dry_run: bool,
+    verbose: bool,
 ) -> Result<()> {
-    let target_dir = config.get_target(target);
-    let package_dir = config.get_package_dir(package);
+    use std::fs;
 
-    if opts.verbose {
-        println!("Package directory: {}", package_dir.display());
-        println!("Target directory: {}", target_dir.display());
-    }
+    let target_dir = config.get_target(target);
 
-    // Check if package exists
     if !config.package_exists(package) {
         return Err(error::StauError::PackageNotFound(package.to_string()));
     }
 
-    // Run teardown script first if it exists and not skipped
-    if !opts.no_teardown
-        && let Some(teardown_script) = config.get_teardown_script(package)
-    {
-        if opts.verbose {
-            println!("Found teardown script: {}", teardown_script.display());
-        }
+    let mappings = config.discover(package, &target_dir)?;
+    let mut cleaned = 0;
 
-        // Note: PRD says teardown should continue even if it fails
-        if let Err(e) = script::execute_script(
-            &teardown_script,
-            package,
-            &config.stau_dir,
-            &target_dir,
-            opts.dry_run,
-            opts.verbose,
-        ) {
-            eprintln!("Warning: Teardown script failed: {}", e);
-            eprintln!("Continuing with uninstall...");
-        } else if !opts.dry_run {
-            println!("Teardown script completed successfully");
-        }
-    }
+    for mapping in &mappings {
+        if symlink::is_broken_symlink(&mapping.target) {
+            if verbose || dry_run {
+                println!("  Removing broken symlink: {}", mapping.target.display());
+            }
 
-    // Discover all files that would be in the package
-    let mappings = package::discover_package_files(&package_dir, &target_dir)?;
+            if !dry_run {
+                fs::remove_file(&mapping.target).map_err(|e| {
+                    if e.kind() == std::io::ErrorKind::PermissionDenied {
+                        error::StauError::PermissionDenied(format!(
+                            "Cannot remove symlink: {}",
+                            mapping.target.display()
+                        ))
+                    } else {
+                        error::StauError::Io(e)
+                    }
+                })?;
+            }
 
-    if opts.verbose {
-        println!("Found {} symlinks to remove", mappings.len());
+            cleaned += 1;
+        }
     }
 
-    if mappings.is_empty() {
-        println!("No symlinks to remove for package '{}'", package);
-        return Ok(());
+    if cleaned == 0 {
+        println!("No broken symlinks found for package '{}'", package);
+    } else if !dry_run {
+        println!(
+            "Cleaned {} broken symlink(s) for package '{}'",
+            cleaned, package
+        );
     }
 
-    let mut removed_count = 0;
+    Ok(())
+}
 
-    // Remove symlinks and copy files back
-    for mapping in &mappings {
-        // Remove the symlink if it points to our source
-        let was_removed = symlink::remove_symlink(&mapping.target, &mapping.source, opts.dry_run)?;
+/// Remove empty directories in the target that hold a package's links
+///
+/// Only directories the package's layout would create are considered, and
+/// only if they are empty, so nothing the user put there is ever removed.
+fn remove_empty_dirs(
+    config: &Config,
+    package: &str,
+    target: Option<PathBuf>,
+    dry_run: bool,
+    verbose: bool,
+) -> Result<()> {
+    use std::collections::HashSet;
+    use std::fs;
 
-        if was_removed {
-            if opts.verbose || opts.dry_run {
-                println!("  Removing symlink: {}", mapping.target.display());
-            }
+    let target_dir = config.get_target(target);
 
-            // Copy the source file to target location (unless we're doing a restow)
-            if opts.copy_files_back {
-                if opts.verbose || opts.dry_run {
-                    println!("  Copying file: {}", mapping.target.display());
-                }
+    if !config.package_exists(package) {
+        return Err(error::StauError::PackageNotFound(package.to_string()));
+    }
 
-                // In dry-run mode, skip the conflict check and removal since the symlink
-                // wasn't actually removed yet
-                if !opts.dry_run {
-                    // Check if target already exists (conflict)
-                    if mapping.target.exists() && !opts.force {
-                        return Err(error::StauError::ConflictingFile(mapping.target.clone()));
-                    }
+    let mappings = config.discover(package, &target_dir)?;
 
-                    // If force is enabled and file exists, remove it first
-                    if opts.force && mapping.target.exists() {
-                        let metadata = mapping
-                            .target
-                            .symlink_metadata()
-                            .map_err(error::StauError::Io)?;
-                        if metadata.is_dir() {
-                            std::fs::remove_dir_all(&mapping.target)
-                                .map_err(error::StauError::Io)?;
-                        } else {
-                            std::fs::remove_file(&mapping.target).map_err(error::StauError::Io)?;
-                        }
-                    }
-                }
+    let mut candidates: Vec<&Path> = mappings
+        .iter()
+        .flat_map(|mapping| mapping.target.ancestors().skip(1))
+        .filter(|dir| dir.starts_with(&target_dir) && *dir != target_dir)
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+
+    // Deepest first, so a parent is looked at after its children are gone
+    candidates.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+
+    let mut removed: HashSet<PathBuf> = HashSet::new();
+    for dir in candidates {
+        let is_real_dir = dir.symlink_metadata().is_ok_and(|m| m.is_dir());
+        if !is_real_dir {
+            continue;
+        }
 
-                symlink::copy_file(&mapping.source, &mapping.target, opts.dry_run)?;
+        let entries = fs::read_dir(dir).map_err(error::StauError::Io)?;
+        let mut is_empty = true;
+        for entry in entries {
+            let path = entry.map_err(error::StauError::Io)?.path();
+            if !removed.contains(&path) {
+                is_empty = false;
+                break;
             }
-            removed_count += 1;
-        } else if opts.verbose {
-            println!(
-                "  Skipping {} (not a stau-managed symlink)",
-                mapping.target.display()
-            );
         }
-    }
+        if !is_empty {
+            continue;
+        }
 
-    if !opts.dry_run {
-        if opts.copy_files_back {
-            println!(
-                "Successfully uninstalled {} ({} symlinks removed, files copied back)",
-                package, removed_count
-            );
-        } else {
-            println!(
-                "Successfully removed {} symlinks for {}",
-                removed_count, package
-            );
+        if verbose || dry_run {
+            println!("  Removing empty directory: {}", dir.display());
+        }
+
+        if !dry_run {
+            fs::remove_dir(dir).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::PermissionDenied {
+                    error::StauError::PermissionDenied(format!(
+                        "Cannot remove directory: {}",
+                        dir.display()
+                    ))
+                } else {
+                    error::StauError::Io(e)
+                }
+            })?;
         }
+
+        removed.insert(dir.to_path_buf());
+    }
+
+    if removed.is_empty() {
+        println!("No empty directories found for package '{}'", package);
+    } else if !dry_run {
+        println!(
+            "Removed {} empty director{} for package '{}'",
+            removed.len(),
+            if removed.len() == 1 { "y" } else { "ies" },
+            package
+        );
     }
 
     Ok(())
 }
 
-fn list_packages(config: &Config, target: Option<PathBuf>) -> Result<()> {
+/// Report target paths that more than one of the packages would link
+fn check_conflicts(config: &Config, packages: &[String], target: Option<PathBuf>) -> Result<()> {
     let target_dir = config.get_target(target);
-    let packages = package::list_packages(&config.stau_dir)?;
 
+    let mut all_mappings = Vec::new();
+    for package in packages {
+        if !config.package_exists(package) {
+            return Err(error::StauError::PackageNotFound(package.to_string()));
+        }
+        let mappings = config.discover(package, &target_dir)?;
+        all_mappings.push((package.clone(), mappings));
+    }
+
+    let conflicts = package::find_conflicts(&all_mappings);
+    if conflicts.is_empty() {
+        println!("No conflicts between {} packages", packages.len());
+        return Ok(());
+    }
+
+    let mut involved = Vec::new();
+    for (path, owners) in &conflicts {
+        println!("{}: {}", path.display(), owners.join(", "));
+        involved.extend(owners.iter().cloned());
+    }
+    involved.sort();
+    involved.dedup();
+
+    Err(error::StauError::PackageConflict(involved.join(", ")))
+}
+
+/// Options for cloning a dotfiles repository
+struct CloneOptions {
+    recurse_submodules: bool,
+    install: bool,
+    dry_run: bool,
+    verbose: bool,
+}
+
+/// Clone a dotfiles repository and offer to install its packages
+fn clone_dotfiles(
+    url: &str,
+    path: Option<PathBuf>,
+    target: Option<PathBuf>,
+    opts: CloneOptions,
+) -> Result<()> {
+    let default_dir = Config::stau_dir_location()?;
+    let stau_dir = path.unwrap_or_else(|| default_dir.clone());
+
+    if opts.dry_run {
+        println!("Would clone {} into {}", url, stau_dir.display());
+        return Ok(());
+    }
+
+    println!("Cloning {} into {}", url, stau_dir.display());
+    git::clone(url, &stau_dir, opts.recurse_submodules)?;
+
+    let stau_dir = stau_dir.canonicalize().map_err(error::StauError::Io)?;
+    if default_dir.canonicalize().ok().as_ref() != Some(&stau_dir) {
+        println!(
+            "Note: {} is not your STAU_DIR. Run 'stau config set stau_dir {}' to use it by default.",
+            stau_dir.display(),
+            stau_dir.display()
+        );
+    }
+
+    let config = Config::with_stau_dir(&stau_dir)?;
+    let packages = package::list_packages(&config.get_stau_dirs())?;
     if packages.is_empty() {
-        println!("No packages found in {}", config.stau_dir.display());
+        println!("No packages found in {}", stau_dir.display());
         return Ok(());
     }
 
-    println!("Packages in {}:\n", config.stau_dir.display());
-
-    for pkg in packages {
-        let package_dir = config.get_package_dir(&pkg);
-
-        // Check if package is installed by checking if any symlinks exist
-        match package::discover_package_files(&package_dir, &target_dir) {
-            Ok(mappings) => {
-                if mappings.is_empty() {
-                    println!("  {:<20} [not installed]", pkg);
-                } else {
-                    // Count how many are actually installed
-                    let mut installed_count = 0;
-                    let mut broken_count = 0;
-
-                    for mapping in &mappings {
-                        if let Ok(is_our_link) =
-                            symlink::is_stau_symlink(&mapping.target, &mapping.source)
-                            && is_our_link
-                        {
-                            installed_count += 1;
-                        }
-
-                        if symlink::is_broken_symlink(&mapping.target) {
-                            broken_count += 1;
-                        }
-                    }
+    let question = format!(
+        "Install all {} packages ({})?",
+        packages.len(),
+        packages.join(", ")
+    );
+    if !opts.install && !prompt::confirm(&question)? {
+        println!("Run 'stau install <package>' to install packages later");
+        return Ok(());
+    }
 
-                    if installed_count == 0 {
-                        println!("  {:<20} [not installed]", pkg);
-                    } else if broken_count > 0 {
-                        println!(
-                            "  {:<20} [installed]  {} symlinks  ({} broken)",
-                            pkg, installed_count, broken_count
-                        );
-                    } else if installed_count == mappings.len() {
-                        println!(
-                            "  {:<20} [installed]  {} symlink{}",
-                            pkg,
-                            installed_count,
-                            if installed_count == 1 { "" } else { "s" }
-                        );
-                    } else {
-                        println!(
-                            "  {:<20} [partial]    {}/{} symlinks",
-                            pkg,
-                            installed_count,
-                            mappings.len()
-                        );
-                    }
-                }
-            }
-            Err(_) => {
-                println!("  {:<20} [error reading package]", pkg);
-            }
-        }
+    let packages = package::order_by_dependencies(&packages, |pkg| {
+        config.get_manifest(pkg).map(|manifest| manifest.depends)
+    })?;
+    ensure_target_dir(
+        &config.get_target(target.clone()),
+        config.create_target,
+        false,
+    )?;
+    let install_opts = InstallOptions {
+        no_setup: false,
+        conflicts: None,
+        paths: &[],
+        dry_run: false,
+        verbose: opts.verbose,
+    };
+    for package in packages {
+        install_package(&config, &package, target.clone(), &install_opts)?;
     }
 
     Ok(())
 }
 
-fn adopt_files(
+/// Sync once, or keep syncing every `interval` until stopped
+///
+/// Only one sync runs at a time, guarded by a lock file in the state directory.
+fn sync_dotfiles(
     config: &Config,
-    package: &str,
-    files: &[PathBuf],
+    interval: Option<Duration>,
+    daemon: bool,
+    log: Option<PathBuf>,
     target: Option<PathBuf>,
     dry_run: bool,
     verbose: bool,
 ) -> Result<()> {
-    use std::fs;
+    let mut lock = sync::Lock::acquire(&config.state_dir.join("sync.lock"), "stau sync")?;
 
-    let target_dir = config.get_target(target);
-    let package_dir = config.get_package_dir(package);
+    let Some(interval) = interval else {
+        lock.write_pid()?;
+        return sync_once(config, target, dry_run, verbose);
+    };
 
-    // Create package directory if it doesn't exist
-    if !package_dir.exists() {
-        if verbose || dry_run {
-            println!("Creating package directory: {}", package_dir.display());
+    if daemon {
+        let log = log.unwrap_or_else(|| config.state_dir.join("sync.log"));
+        return sync::daemonize("stau sync", &log, lock);
+    }
+    lock.write_pid()?;
+
+    loop {
+        println!(
+            "[{}] Syncing",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+        );
+        // A failed run (e.g. no network) shouldn't end the loop
+        if let Err(e) = sync_once(config, target.clone(), dry_run, verbose) {
+            eprintln!("Error: {}", e);
         }
-        if !dry_run {
-            fs::create_dir_all(&package_dir).map_err(|e| {
-                if e.kind() == std::io::ErrorKind::PermissionDenied {
-                    error::StauError::PermissionDenied(format!(
-                        "Cannot create package directory: {}",
-                        package_dir.display()
-                    ))
-                } else {
-                    error::StauError::Io(e)
-                }
-            })?;
+
+        // Sleep in short steps so Ctrl-C is noticed
+        let wake = std::time::Instant::now() + interval + sync::jitter(interval);
+        while let Some(left) = wake.checked_duration_since(std::time::Instant::now()) {
+            interrupt::check()?;
+            std::thread::sleep(left.min(Duration::from_secs(1)));
         }
     }
+}
 
-    println!(
-        "Adopting {} file(s) into package '{}':",
-        files.len(),
-        package
-    );
+/// Pull every dotfiles directory that is a git repository and restow the
+/// installed packages whose files changed
+fn sync_once(config: &Config, target: Option<PathBuf>, dry_run: bool, verbose: bool) -> Result<()> {
+    let target_dir = config.get_target(target.clone());
+    let mut changed = std::collections::BTreeSet::new();
 
-    for file_path in files {
-        // Make sure the file exists
-        if !file_path.exists() {
-            eprintln!("Warning: File does not exist: {}", file_path.display());
+    for dir in config.get_stau_dirs() {
+        if !git::is_repo(&dir) {
+            if verbose {
+                println!("Skipping {} (not a git repository)", dir.display());
+            }
             continue;
         }
-
-        // Calculate relative path from target directory
-        let rel_path = match file_path.strip_prefix(&target_dir) {
-            Ok(p) => p,
-            Err(_) => {
-                eprintln!(
-                    "Warning: File {} is not in target directory {}",
-                    file_path.display(),
-                    target_dir.display()
-                );
-                continue;
-            }
-        };
-
-        // Destination in package directory
-        let dest = package_dir.join(rel_path);
-
-        // Check if destination already exists
-        if dest.exists() {
-            return Err(error::StauError::ConflictingFile(dest));
+        if !git::has_upstream(&dir) {
+            println!("Skipping {} (no upstream branch)", dir.display());
+            continue;
         }
-
-        if verbose || dry_run {
-            println!("  {} -> {}", file_path.display(), dest.display());
+        if dry_run {
+            println!("Would pull {}", dir.display());
+            continue;
         }
 
-        if !dry_run {
-            // Create parent directories if needed
-            if let Some(parent) = dest.parent() {
-                fs::create_dir_all(parent).map_err(error::StauError::Io)?;
-            }
-
-            // Move the file
-            fs::rename(file_path, &dest).map_err(error::StauError::Io)?;
-
-            // Create symlink at original location
-            symlink::create_symlink(&dest, file_path, false)?;
+        let before = git::head(&dir)?;
+        git::pull(&dir)?;
+        let after = git::head(&dir)?;
+        if before == after {
+            println!("{} is up to date", dir.display());
+            continue;
         }
+
+        println!("Pulled {}", dir.display());
+        changed.extend(changed_packages(&dir, &before, &after)?);
     }
 
-    if !dry_run {
-        println!(
-            "Successfully adopted {} file(s) into '{}'",
-            files.len(),
-            package
-        );
+    for package in changed {
+        // Removed packages have nothing left to restow
+        if !config.package_exists(&package) || !is_installed(config, &package, &target_dir)? {
+            if verbose {
+                println!("Skipping {} (not installed)", package);
+            }
+            continue;
+        }
+        restow_package(
+            config,
+            &package,
+            target.clone(),
+            false,
+            None,
+            dry_run,
+            verbose,
+        )?;
     }
 
     Ok(())
 }
 
-fn show_status(config: &Config, package: &str, target: Option<PathBuf>) -> Result<()> {
-    let target_dir = config.get_target(target);
-    let package_dir = config.get_package_dir(package);
+/// Names of the top-level directories with files changed between two commits
+fn changed_packages(dir: &Path, from: &str, to: &str) -> Result<Vec<String>> {
+    Ok(git::changed_paths(dir, from, to)?
+        .iter()
+        .filter_map(|path| path.split_once('/'))
+        .map(|(package, _)| package.to_string())
+        .collect())
+}
 
-    if !config.package_exists(package) {
-        return Err(error::StauError::PackageNotFound(package.to_string()));
+/// Packages in any dotfiles repository with files changed between a revision and HEAD
+fn packages_changed_since(
+    config: &Config,
+    rev: &str,
+) -> Result<std::collections::BTreeSet<String>> {
+    let mut changed = std::collections::BTreeSet::new();
+    let mut repos = 0;
+    for dir in config.get_stau_dirs() {
+        if git::is_repo(&dir) {
+            repos += 1;
+            changed.extend(changed_packages(&dir, rev, "HEAD")?);
+        }
     }
-
-    println!("Status for package '{}':\n", package);
-    println!("  Package directory: {}", package_dir.display());
-    println!("  Target directory:  {}", target_dir.display());
-
-    // Check for setup/teardown scripts
-    if let Some(setup) = config.get_setup_script(package) {
-        println!("  Setup script:      {} (exists)", setup.display());
-    } else {
-        println!("  Setup script:      (none)");
+    if repos == 0 {
+        return Err(error::StauError::Git(
+            "--since needs STAU_DIR to be a git repository".to_string(),
+        ));
     }
+    Ok(changed)
+}
 
-    if let Some(teardown) = config.get_teardown_script(package) {
-        println!("  Teardown script:   {} (exists)", teardown.display());
-    } else {
-        println!("  Teardown script:   (none)");
+/// Collect the target paths of every package in STAU_DIR
+fn managed_paths(config: &Config, target_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for package in package::list_packages(&config.get_stau_dirs())? {
+        let mappings = config.discover(&package, target_dir)?;
+        paths.extend(mappings.into_iter().map(|mapping| mapping.target));
     }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
 
-    // Get all mappings
-    let mappings = package::discover_package_files(&package_dir, &target_dir)?;
+fn take_snapshot(
+    config: &Config,
+    name: Option<String>,
+    archive: bool,
+    target: Option<PathBuf>,
+    dry_run: bool,
+) -> Result<()> {
+    let target_dir = config.get_target(target);
+    let now = chrono::Local::now();
+    let name = name.unwrap_or_else(|| now.format("%Y%m%d-%H%M%S").to_string());
+    let paths = managed_paths(config, &target_dir)?;
 
-    if mappings.is_empty() {
-        println!("\nNo files in package.");
+    if dry_run {
+        println!("Would record {} paths in snapshot '{}'", paths.len(), name);
         return Ok(());
     }
 
-    println!("\nFiles ({} total):", mappings.len());
-
-    let mut installed = 0;
-    let mut not_installed = 0;
-    let mut broken = 0;
+    if snapshot::Snapshot::list(&config.state_dir)?.contains(&name) {
+        return Err(error::StauError::Other(format!(
+            "Snapshot '{}' already exists",
+            name
+        )));
+    }
 
-    for mapping in &mappings {
-        let is_our_link = symlink::is_stau_symlink(&mapping.target, &mapping.source)?;
-        let is_broken = symlink::is_broken_symlink(&mapping.target);
+    let created = now.format("%Y-%m-%d %H:%M:%S").to_string();
+    let snapshot = snapshot::Snapshot::capture(&config.state_dir, &name, created, &paths, archive)?;
+    snapshot.save(&config.state_dir)?;
 
-        let status = if is_broken {
-            broken += 1;
-            "[BROKEN]"
-        } else if is_our_link {
-            installed += 1;
-            "[installed]"
-        } else if mapping.target.exists() {
-            not_installed += 1;
-            "[conflict]"
-        } else {
-            not_installed += 1;
-            "[not installed]"
-        };
+    println!("Recorded {} paths in snapshot '{}'", paths.len(), name);
+    Ok(())
+}
 
-        println!("  {:<20} {}", status, mapping.target.display());
+fn list_snapshots(config: &Config) -> Result<()> {
+    let names = snapshot::Snapshot::list(&config.state_dir)?;
+    if names.is_empty() {
+        println!("No snapshots found");
+        return Ok(());
     }
 
-    println!();
-    println!(
-        "Summary: {} installed, {} not installed, {} broken",
-        installed, not_installed, broken
-    );
-
+    for name in names {
+        let snapshot = snapshot::Snapshot::load(&config.state_dir, &name)?;
+        println!(
+            "{:<20} {}  {} paths",
+            name,
+            snapshot.created,
+            snapshot.entries.len()
+        );
+    }
     Ok(())
 }
 
-fn clean_broken_symlinks(
+fn restore_snapshot(
     config: &Config,
-    package: &str,
+    name: &str,
     target: Option<PathBuf>,
     dry_run: bool,
     verbose: bool,
 ) -> Result<()> {
-    use std::fs;
-
     let target_dir = config.get_target(target);
-    let package_dir = config.get_package_dir(package);
+    let snapshot = snapshot::Snapshot::load(&config.state_dir, name)?;
+    let current = managed_paths(config, &target_dir)?;
 
-    if !config.package_exists(package) {
-        return Err(error::StauError::PackageNotFound(package.to_string()));
+    if verbose {
+        println!(
+            "Restoring snapshot '{}' from {}",
+            snapshot.name, snapshot.created
+        );
     }
 
-    let mappings = package::discover_package_files(&package_dir, &target_dir)?;
-    let mut cleaned = 0;
+    let summary = snapshot.restore(
+        &config.state_dir,
+        &config.get_stau_dirs(),
+        &current,
+        dry_run,
+    )?;
 
-    for mapping in &mappings {
-        if symlink::is_broken_symlink(&mapping.target) {
-            if verbose || dry_run {
-                println!("  Removing broken symlink: {}", mapping.target.display());
+    let prefix = if dry_run { "Would restore" } else { "Restored" };
+    println!(
+        "{} snapshot '{}': {} restored, {} removed, {} skipped",
+        prefix, name, summary.restored, summary.removed, summary.skipped
+    );
+    if summary.skipped > 0 {
+        println!(
+            "Skipped paths hold files that stau doesn't manage, or files that were not archived"
+        );
+    }
+    Ok(())
+}
+
+/// List the saved script logs, or print the newest log of a package
+fn show_script_logs(config: &Config, package: Option<String>, list: bool) -> Result<()> {
+    let logs = scriptlog::list(&config.state_dir, package.as_deref())?;
+
+    if list {
+        if logs.is_empty() {
+            println!("No script logs found");
+        }
+        for log in logs {
+            let created = log
+                .created()
+                .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+            println!(
+                "{}  {:<16} {:<16} {}",
+                created,
+                log.package,
+                log.hook,
+                log.path.display()
+            );
+        }
+        return Ok(());
+    }
+
+    let package = package.unwrap_or_default();
+    let Some(latest) = logs.first() else {
+        return Err(error::StauError::Other(format!(
+            "No script logs for package '{}'",
+            package
+        )));
+    };
+    // Every hook of the last run, e.g. pre-install and setup of one install
+    for log in logs.iter().filter(|log| log.timestamp == latest.timestamp) {
+        let contents = std::fs::read_to_string(&log.path).map_err(error::StauError::Io)?;
+        print!("{}", contents);
+    }
+    Ok(())
+}
+
+fn manage_backups(config: &Config, action: BackupAction, dry_run: bool) -> Result<()> {
+    match action {
+        BackupAction::List => {
+            let backups = backup::list(&config.state_dir)?;
+            if backups.is_empty() {
+                println!("No backups found");
+            }
+            for backup in backups {
+                let files = backup.files()?;
+                let size: u64 = files.iter().map(|file| file.size).sum();
+                println!(
+                    "{:<24} {:>4} files  {}",
+                    backup.id,
+                    files.len(),
+                    format_size(size)
+                );
             }
+            Ok(())
+        }
 
-            if !dry_run {
-                fs::remove_file(&mapping.target).map_err(|e| {
-                    if e.kind() == std::io::ErrorKind::PermissionDenied {
-                        error::StauError::PermissionDenied(format!(
-                            "Cannot remove symlink: {}",
-                            mapping.target.display()
-                        ))
-                    } else {
-                        error::StauError::Io(e)
-                    }
-                })?;
+        BackupAction::Show { id } => {
+            for file in backup::find(&config.state_dir, &id)?.files()? {
+                println!("{}  ({})", file.original.display(), format_size(file.size));
             }
+            Ok(())
+        }
 
-            cleaned += 1;
+        BackupAction::Restore { id, path, force } => {
+            let files = backup::find(&config.state_dir, &id)?.files()?;
+            let files: Vec<_> = match &path {
+                Some(path) => {
+                    let path = std::path::absolute(path).map_err(error::StauError::Io)?;
+                    files
+                        .into_iter()
+                        .filter(|file| file.original == path)
+                        .collect()
+                }
+                None => files,
+            };
+            if let Some(path) = &path
+                && files.is_empty()
+            {
+                return Err(error::StauError::Other(format!(
+                    "{} is not in backup '{}'",
+                    path.display(),
+                    id
+                )));
+            }
+
+            for file in &files {
+                backup::restore_file(file, &config.get_stau_dirs(), force, dry_run)?;
+                let verb = if dry_run { "Would restore" } else { "Restored" };
+                println!("{} {}", verb, file.original.display());
+            }
+            Ok(())
+        }
+
+        BackupAction::Prune {
+            older_than,
+            max_size,
+        } => {
+            let max_age = older_than.map(|days| std::time::Duration::from_secs(days * 86400));
+            let max_size = max_size.map(|mb| mb * 1024 * 1024);
+            let backups = backup::list(&config.state_dir)?;
+            let prune = backup::select_for_pruning(&backups, max_age, max_size)?;
+
+            for backup in &prune {
+                if dry_run {
+                    println!("Would delete backup {}", backup.id);
+                } else {
+                    std::fs::remove_dir_all(&backup.dir).map_err(error::StauError::Io)?;
+                    println!("Deleted backup {}", backup.id);
+                }
+            }
+            println!("{} of {} backups pruned", prune.len(), backups.len());
+            Ok(())
         }
     }
+}
 
-    if cleaned == 0 {
-        println!("No broken symlinks found for package '{}'", package);
-    } else if !dry_run {
-        println!(
-            "Cleaned {} broken symlink(s) for package '{}'",
-            cleaned, package
-        );
+/// Format a byte count for humans
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Print the lines of package files that match a pattern, as `package/path:line:text`
+///
+/// Only files that stau would link are searched; binary files are skipped.
+fn grep_packages(
+    config: &Config,
+    pattern: &str,
+    packages: &[String],
+    ignore_case: bool,
+    files_only: bool,
+) -> Result<()> {
+    let regex = regex::RegexBuilder::new(pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|e| error::StauError::Other(format!("Invalid pattern: {}", e)))?;
+
+    let packages = if packages.is_empty() {
+        package::list_packages(&config.get_stau_dirs())?
+    } else {
+        package::resolve_packages(&config.get_stau_dirs(), packages)?
+    };
+
+    let mut matched = false;
+    for package in packages {
+        let layers = config.get_package_layers(&package);
+        let mappings = config.discover(&package, &config.default_target)?;
+
+        for mapping in mappings {
+            let Ok(contents) = std::fs::read(&mapping.source) else {
+                continue;
+            };
+            if contents.contains(&0) {
+                continue;
+            }
+
+            let relative = layers
+                .iter()
+                .find_map(|layer| mapping.source.strip_prefix(layer).ok())
+                .unwrap_or(&mapping.source);
+            let name = format!("{}/{}", package, relative.display());
+
+            let contents = String::from_utf8_lossy(&contents);
+            for (number, line) in contents.lines().enumerate() {
+                if !regex.is_match(line) {
+                    continue;
+                }
+                matched = true;
+                if files_only {
+                    println!("{}", name);
+                    break;
+                }
+                println!("{}:{}:{}", name, number + 1, line);
+            }
+        }
     }
 
+    if !matched {
+        return Err(error::StauError::Other(format!(
+            "No matches for '{}'",
+            pattern
+        )));
+    }
     Ok(())
 }
@@ -0,0 +1,369 @@
+use crate::conflict::ConflictPolicy;
+use crate::error::{Result, StauError};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+/// File name of the optional manifest in a package root
+pub const MANIFEST_FILE: &str = "stau.toml";
+
+/// Order of the top-level keys in a formatted manifest, that of the fields of `Manifest`
+const KEY_ORDER: &[&str] = &[
+    "description",
+    "tags",
+    "depends",
+    "members",
+    "conflicts",
+    "run_as",
+    "skip",
+    "max_depth",
+    "targets",
+    "fold",
+];
+
+/// Package metadata read from `stau.toml`
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Manifest {
+    /// Short human-readable description of the package
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Tags used to group packages (e.g. "shell", "editor")
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Packages that must be installed before this one
+    #[serde(default)]
+    pub depends: Vec<String>,
+    /// Packages this one stands for, making it a bundle without files of its own
+    #[serde(default)]
+    pub members: Vec<String>,
+    /// How to handle existing files in the target, overriding the global setting
+    #[serde(default)]
+    pub conflicts: Option<ConflictPolicy>,
+    /// User to run the package's scripts as, through sudo
+    #[serde(default)]
+    pub run_as: Option<String>,
+    /// Files in the package root that are never linked, on top of the global list
+    #[serde(default)]
+    pub skip: Vec<String>,
+    /// How many levels deep to link files; directories at that level are linked as a whole
+    #[serde(default)]
+    pub max_depth: Option<NonZeroUsize>,
+    /// Package subdirectories linked somewhere else than the target, e.g.
+    /// `bin = "~/.local/bin"`; relative destinations are below the target
+    #[serde(default)]
+    pub targets: BTreeMap<PathBuf, PathBuf>,
+    /// Package subdirectories linked as a whole instead of file by file
+    #[serde(default)]
+    pub fold: Vec<PathBuf>,
+}
+
+impl Manifest {
+    /// Load the manifest of a package directory, returning an empty one if none exists
+    pub fn load(package_dir: &Path) -> Result<Self> {
+        let path = package_dir.join(MANIFEST_FILE);
+        if !path.is_file() {
+            return Ok(Manifest::default());
+        }
+
+        let contents = fs::read_to_string(&path).map_err(StauError::Io)?;
+        toml::from_str(&contents).map_err(|e| {
+            StauError::Other(format!("Cannot parse manifest {}: {}", path.display(), e))
+        })
+    }
+
+    /// Load and merge the manifests of all layers of a package
+    ///
+    /// Fields set in a later layer replace those from earlier layers.
+    pub fn load_layers(layers: &[PathBuf]) -> Result<Self> {
+        let mut merged = Manifest::default();
+        for layer in layers {
+            let manifest = Manifest::load(layer)?;
+            if manifest.description.is_some() {
+                merged.description = manifest.description;
+            }
+            if !manifest.tags.is_empty() {
+                merged.tags = manifest.tags;
+            }
+            if !manifest.depends.is_empty() {
+                merged.depends = manifest.depends;
+            }
+            if !manifest.members.is_empty() {
+                merged.members = manifest.members;
+            }
+            if manifest.conflicts.is_some() {
+                merged.conflicts = manifest.conflicts;
+            }
+            if manifest.run_as.is_some() {
+                merged.run_as = manifest.run_as;
+            }
+            if !manifest.skip.is_empty() {
+                merged.skip = manifest.skip;
+            }
+            if manifest.max_depth.is_some() {
+                merged.max_depth = manifest.max_depth;
+            }
+            if !manifest.targets.is_empty() {
+                merged.targets = manifest.targets;
+            }
+            if !manifest.fold.is_empty() {
+                merged.fold = manifest.fold;
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Add a directory to the `fold` list of a package's manifest
+    ///
+    /// The file is edited in place so comments and layout survive: the path
+    /// goes first into an existing `fold = [...]` line, or a new `fold` line
+    /// goes at the top, ahead of any tables. Returns whether anything changed.
+    pub fn add_fold(package_dir: &Path, dir: &Path) -> Result<bool> {
+        let manifest = Manifest::load(package_dir)?;
+        if manifest.fold.iter().any(|folded| folded == dir) {
+            return Ok(false);
+        }
+
+        let path = package_dir.join(MANIFEST_FILE);
+        let contents = if path.is_file() {
+            fs::read_to_string(&path).map_err(StauError::Io)?
+        } else {
+            String::new()
+        };
+        let entry = toml::Value::String(dir.to_string_lossy().into_owned()).to_string();
+
+        let fold_line = Regex::new(r"(?m)^fold\s*=\s*\[").expect("valid regex");
+        let contents = if let Some(found) = fold_line.find(&contents) {
+            let (head, tail) = contents.split_at(found.end());
+            format!("{}{}, {}", head, entry, tail.trim_start())
+        } else if manifest.fold.is_empty() {
+            format!("fold = [{}]\n{}", entry, contents)
+        } else {
+            return Err(StauError::Other(format!(
+                "Cannot add {} to the fold list in {}; add it by hand",
+                dir.display(),
+                path.display()
+            )));
+        };
+
+        fs::write(&path, contents).map_err(StauError::Io)?;
+        Ok(true)
+    }
+}
+
+/// Put the top-level keys of a manifest in the order of `KEY_ORDER`, with
+/// unknown keys last, or return None if they already are
+///
+/// Each key takes the comments above it along; comments at the top of the
+/// file that are set apart by a blank line stay there. Tables come after
+/// the top-level keys in TOML anyway and are left as they are.
+pub fn sort_keys(contents: &str) -> Result<Option<String>> {
+    let parse = |text: &str| toml::from_str::<toml::Table>(text);
+    let original =
+        parse(contents).map_err(|e| StauError::Other(format!("Cannot parse manifest: {}", e)))?;
+
+    let lines: Vec<&str> = contents.split_inclusive('\n').collect();
+    let mut header = String::new();
+    let mut entries: Vec<(usize, String)> = Vec::new();
+    let mut comments = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim_start();
+        if line.starts_with('[') {
+            break;
+        }
+        if line.trim().is_empty() || line.starts_with('#') {
+            comments.push_str(lines[i]);
+            i += 1;
+            continue;
+        }
+
+        // A value may span several lines, up to where the key parses on its own
+        let mut entry = lines[i].to_string();
+        i += 1;
+        while parse(&entry).is_err() && i < lines.len() {
+            entry.push_str(lines[i]);
+            i += 1;
+        }
+        let key = parse(&entry)
+            .ok()
+            .and_then(|table| table.keys().next().cloned())
+            .unwrap_or_default();
+        if !entry.ends_with('\n') {
+            entry.push('\n');
+        }
+
+        if entries.is_empty()
+            && let Some(end) = comments.rfind("\n\n")
+        {
+            header = comments.drain(..end + 2).collect();
+        }
+        let rank = KEY_ORDER
+            .iter()
+            .position(|known| *known == key)
+            .unwrap_or(KEY_ORDER.len());
+        entries.push((rank, std::mem::take(&mut comments) + &entry));
+    }
+
+    if entries.is_sorted_by_key(|(rank, _)| *rank) {
+        return Ok(None);
+    }
+    entries.sort_by_key(|(rank, _)| *rank);
+
+    let mut sorted = header;
+    for (_, entry) in entries {
+        sorted.push_str(&entry);
+    }
+    sorted.push_str(&comments);
+    sorted.extend(lines[i..].iter().copied());
+
+    // Moving lines around must never change what the manifest says
+    if parse(&sorted).ok() != Some(original) {
+        return Err(StauError::Other(
+            "Cannot reorder the keys of the manifest without changing it".to_string(),
+        ));
+    }
+    Ok(Some(sorted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_missing_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let manifest = Manifest::load(temp_dir.path()).unwrap();
+        assert!(manifest.description.is_none());
+        assert!(manifest.tags.is_empty());
+    }
+
+    #[test]
+    fn test_load_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join(MANIFEST_FILE),
+            "description = \"Neovim config\"\ntags = [\"editor\", \"dev\"]\ndepends = [\"fonts\"]\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::load(temp_dir.path()).unwrap();
+        assert_eq!(manifest.description.as_deref(), Some("Neovim config"));
+        assert_eq!(manifest.tags, vec!["editor", "dev"]);
+        assert_eq!(manifest.depends, vec!["fonts"]);
+    }
+
+    #[test]
+    fn test_sort_keys() {
+        let contents = "# Neovim\n\nfold = [\"lua\"]\n# Shown by stau list\ndescription = \"Neovim config\"\nextra = 1\nskip = [\n  \"notes.md\",\n]\ntags = [\"editor\"]\n\n[targets]\nbin = \"~/.local/bin\"\n";
+        assert_eq!(
+            sort_keys(contents).unwrap().unwrap(),
+            "# Neovim\n\n# Shown by stau list\ndescription = \"Neovim config\"\ntags = [\"editor\"]\nskip = [\n  \"notes.md\",\n]\nfold = [\"lua\"]\nextra = 1\n\n[targets]\nbin = \"~/.local/bin\"\n"
+        );
+
+        assert_eq!(
+            sort_keys("description = \"Vim\"\ntags = [\"editor\"]\n").unwrap(),
+            None
+        );
+        assert_eq!(sort_keys("").unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join(MANIFEST_FILE), "tags = \"oops").unwrap();
+
+        let result = Manifest::load(temp_dir.path());
+        assert!(matches!(result.unwrap_err(), StauError::Other(_)));
+    }
+
+    #[test]
+    fn test_load_layers_overrides_fields() {
+        let temp_dir = TempDir::new().unwrap();
+        let base = temp_dir.path().join("base");
+        let overlay = temp_dir.path().join("overlay");
+        fs::create_dir(&base).unwrap();
+        fs::create_dir(&overlay).unwrap();
+        fs::write(
+            base.join(MANIFEST_FILE),
+            "description = \"Git config\"\ntags = [\"vcs\"]\nrun_as = \"git\"\nmax_depth = 2\n",
+        )
+        .unwrap();
+        fs::write(
+            overlay.join(MANIFEST_FILE),
+            "tags = [\"work\"]\nconflicts = \"backup\"\nskip = [\"LICENSE\"]\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::load_layers(&[base, overlay]).unwrap();
+        assert_eq!(manifest.description.as_deref(), Some("Git config"));
+        assert_eq!(manifest.tags, vec!["work"]);
+        assert_eq!(manifest.conflicts, Some(ConflictPolicy::Backup));
+        assert_eq!(manifest.run_as.as_deref(), Some("git"));
+        assert_eq!(manifest.skip, vec!["LICENSE"]);
+        assert_eq!(manifest.max_depth, NonZeroUsize::new(2));
+    }
+
+    #[test]
+    fn test_max_depth_must_be_positive() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join(MANIFEST_FILE), "max_depth = 0\n").unwrap();
+        assert!(Manifest::load(temp_dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_add_fold_keeps_existing_contents() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        assert!(Manifest::add_fold(dir, Path::new(".config/alacritty")).unwrap());
+        assert_eq!(
+            fs::read_to_string(dir.join(MANIFEST_FILE)).unwrap(),
+            "fold = [\".config/alacritty\"]\n"
+        );
+
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            "# Terminal\ndescription = \"Terminals\"\n\n[targets]\nbin = \"~/.local/bin\"\n",
+        )
+        .unwrap();
+        assert!(Manifest::add_fold(dir, Path::new(".config/kitty")).unwrap());
+        assert!(Manifest::add_fold(dir, Path::new(".config/alacritty")).unwrap());
+        assert!(!Manifest::add_fold(dir, Path::new(".config/kitty")).unwrap());
+
+        let manifest = Manifest::load(dir).unwrap();
+        assert_eq!(
+            manifest.fold,
+            vec![
+                PathBuf::from(".config/alacritty"),
+                PathBuf::from(".config/kitty")
+            ]
+        );
+        assert_eq!(manifest.description.as_deref(), Some("Terminals"));
+        assert_eq!(manifest.targets.len(), 1);
+        assert!(
+            fs::read_to_string(dir.join(MANIFEST_FILE))
+                .unwrap()
+                .contains("# Terminal")
+        );
+    }
+
+    #[test]
+    fn test_load_target_sections() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join(MANIFEST_FILE),
+            "[targets]\nbin = \"~/.local/bin\"\nunits = \".config/systemd/user\"\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::load(temp_dir.path()).unwrap();
+        assert_eq!(manifest.targets.len(), 2);
+        assert_eq!(
+            manifest.targets[Path::new("bin")],
+            Path::new("~/.local/bin")
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use std::cell::RefCell;
+
+/// Where a piece of output goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+thread_local! {
+    /// Output held back while the current thread works on one of several
+    /// packages at once
+    static HELD: RefCell<Option<Vec<(Stream, String)>>> = const { RefCell::new(None) };
+}
+
+/// Output of a package that was held back, to be printed in one piece
+#[derive(Debug, Default)]
+pub struct Held(Vec<(Stream, String)>);
+
+impl Held {
+    pub fn print(self) {
+        for (stream, text) in self.0 {
+            match stream {
+                Stream::Stdout => print!("{}", text),
+                Stream::Stderr => eprint!("{}", text),
+            }
+        }
+    }
+
+    /// Print just what was written to stderr, dropping the rest
+    pub fn print_stderr(self) {
+        for (stream, text) in self.0 {
+            if stream == Stream::Stderr {
+                eprint!("{}", text);
+            }
+        }
+    }
+}
+
+/// Print text, or keep it with the rest of the package's output while
+/// packages are worked on concurrently
+pub fn write(stream: Stream, text: String) {
+    let text = HELD.with_borrow_mut(|held| match held {
+        Some(held) => {
+            held.push((stream, text));
+            None
+        }
+        None => Some(text),
+    });
+    if let Some(text) = text {
+        Held(vec![(stream, text)]).print();
+    }
+}
+
+/// Run `f` with everything it prints held back, returning the output along
+/// with its result
+pub fn hold<T>(f: impl FnOnce() -> T) -> (T, Held) {
+    let outer = HELD.replace(Some(Vec::new()));
+    let result = f();
+    let held = HELD.replace(outer).unwrap_or_default();
+    (result, Held(held))
+}
+
+/// Like `println!`, but held back with the rest of a package's output under `--jobs`
+macro_rules! outln {
+    () => {
+        $crate::output::write($crate::output::Stream::Stdout, String::from("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::output::write(
+            $crate::output::Stream::Stdout,
+            format!("{}\n", format_args!($($arg)*)),
+        )
+    };
+}
+
+/// Like `eprintln!`, but held back with the rest of a package's output under `--jobs`
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        $crate::output::write(
+            $crate::output::Stream::Stderr,
+            format!("{}\n", format_args!($($arg)*)),
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hold_keeps_output_in_order() {
+        let (result, held) = hold(|| {
+            outln!("Linked {}", ".vimrc");
+            errln!("Warning: {}", "skipped");
+            42
+        });
+        assert_eq!(result, 42);
+        assert_eq!(
+            held.0,
+            [
+                (Stream::Stdout, "Linked .vimrc\n".to_string()),
+                (Stream::Stderr, "Warning: skipped\n".to_string()),
+            ]
+        );
+    }
+}
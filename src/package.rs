@@ -1,13 +1,123 @@
 use crate::error::{Result, StauError};
+use crate::manifest::MANIFEST_FILE;
 use crate::symlink::SymlinkMapping;
+use crate::verbosity;
+use crate::warning;
+use ignore::gitignore::Gitignore;
+use ignore::{DirEntry, WalkBuilder};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::Path;
+use std::io::BufRead;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+/// File name of a package's own usage notes, shown by `stau info`
+pub const README_FILE: &str = "README.md";
+
+/// Directory in a package root whose files are linked at absolute paths,
+/// e.g. `ROOT/etc/hosts` at `/etc/hosts`, whatever the target
+pub const ROOT_DIR: &str = "ROOT";
+
+/// Files left behind by operating systems and editors, never linked by default
+pub const DEFAULT_JUNK: [&str; 9] = [
+    ".DS_Store",
+    "._*",
+    "Thumbs.db",
+    "desktop.ini",
+    "*.swp",
+    "*.swo",
+    "*~",
+    ".#*",
+    "#*#",
+];
+
+/// Options that decide which files of a package are linked
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    /// Leave out files matched by `.gitignore` files in or above the package
+    pub gitignore: bool,
+    /// Names or glob patterns of files in the package root that are never linked
+    pub skip: Vec<String>,
+    /// Names or glob patterns of files that are never linked, wherever they are
+    pub junk: Vec<String>,
+    /// How many levels deep to look; directories at that level are linked as a whole
+    pub max_depth: Option<NonZeroUsize>,
+    /// Descend into symlinked directories inside the package and link their contents
+    pub follow_symlinks: bool,
+    /// Package subdirectories linked below other directories than the target,
+    /// as relative paths in the package with absolute destinations
+    pub targets: Vec<(PathBuf, PathBuf)>,
+    /// Package subdirectories linked as a whole, as relative paths in the package
+    pub fold: Vec<PathBuf>,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        DiscoveryOptions {
+            gitignore: true,
+            skip: Vec::new(),
+            junk: DEFAULT_JUNK.iter().map(|name| name.to_string()).collect(),
+            max_depth: None,
+            follow_symlinks: false,
+            targets: Vec::new(),
+            fold: Vec::new(),
+        }
+    }
+}
+
+/// What walking a package found
+#[derive(Debug, Default)]
+pub struct Discovery {
+    pub mappings: Vec<SymlinkMapping>,
+    /// Directories that were looked into, whose entries decided the mappings
+    pub dirs: Vec<PathBuf>,
+    /// Whether something was warned about on the way
+    pub warned: bool,
+}
 
 /// Walk a package directory and generate symlink mappings
+///
+/// Collects everything `walk_package_files` finds.
 pub fn discover_package_files(
     package_dir: &Path,
     target_dir: &Path,
-) -> Result<Vec<SymlinkMapping>> {
+    options: &DiscoveryOptions,
+) -> Result<Discovery> {
+    let mut files = walk_package_files(package_dir, target_dir, options)?;
+    let mappings = files.by_ref().collect::<Result<Vec<_>>>()?;
+    Ok(Discovery {
+        mappings,
+        dirs: files.dirs,
+        warned: files.warned,
+    })
+}
+
+/// The symlink mappings of a package, found one at a time as its directory is walked
+///
+/// Iteration ends after the first error.
+pub struct PackageFiles {
+    walker: ignore::Walk,
+    package_dir: PathBuf,
+    target_dir: PathBuf,
+    max_depth: Option<usize>,
+    fold: Vec<PathBuf>,
+    targets: Vec<(PathBuf, PathBuf)>,
+    /// Directories that were looked into so far
+    pub dirs: Vec<PathBuf>,
+    /// Whether something was warned about so far
+    pub warned: bool,
+    failed: bool,
+}
+
+/// Start walking a package directory, yielding its symlink mappings as they are found
+///
+/// Unlike `discover_package_files`, nothing is kept but the directories
+/// walked, so the first files can be dealt with before the walk is done.
+pub fn walk_package_files(
+    package_dir: &Path,
+    target_dir: &Path,
+    options: &DiscoveryOptions,
+) -> Result<PackageFiles> {
     if !package_dir.exists() {
         return Err(StauError::PackageNotFound(
             package_dir.display().to_string(),
@@ -18,103 +128,587 @@ pub fn discover_package_files(
         return Err(StauError::InvalidPath(package_dir.to_path_buf()));
     }
 
-    let mut mappings = Vec::new();
-    walk_directory(package_dir, package_dir, target_dir, &mut mappings)?;
-    Ok(mappings)
+    // The dotfiles directory's own .gitignore applies as well as those in the
+    // package, but nothing further up, where a .gitignore may be a dotfile itself
+    let stau_dir_ignore = match package_dir.parent() {
+        Some(stau_dir) if options.gitignore => {
+            let (gitignore, error) = Gitignore::new(stau_dir.join(".gitignore"));
+            if let Some(e) = error
+                && e.io_error()
+                    .is_none_or(|e| e.kind() != std::io::ErrorKind::NotFound)
+            {
+                return Err(StauError::Other(format!("Cannot read .gitignore: {}", e)));
+            }
+            gitignore
+        }
+        _ => Gitignore::empty(),
+    };
+
+    let skip = compile_patterns(&options.skip, "skip")?;
+    let junk = compile_patterns(&options.junk, "junk")?;
+
+    let max_depth = options.max_depth.map(NonZeroUsize::get);
+    let fold = options.fold.clone();
+    let root = package_dir.to_path_buf();
+    let walker = WalkBuilder::new(package_dir)
+        .max_depth(max_depth)
+        .follow_links(options.follow_symlinks)
+        .standard_filters(false)
+        .git_ignore(options.gitignore)
+        .require_git(false)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            // The contents of folded directories are linked along with them
+            let in_folded = entry.path().strip_prefix(&root).is_ok_and(|rel| {
+                fold.iter()
+                    .any(|dir| rel.starts_with(dir) && rel != dir.as_path())
+            });
+            if let Some(reason) = skip_reason(entry, &skip, &junk) {
+                verbosity::detail(|| format!("Skipping {} ({})", entry.path().display(), reason));
+                return false;
+            }
+            if stau_dir_ignore.matched(entry.path(), is_dir).is_ignore() {
+                verbosity::detail(|| {
+                    format!(
+                        "Skipping {} (ignored by .gitignore)",
+                        entry.path().display()
+                    )
+                });
+                return false;
+            }
+            !in_folded
+        })
+        .build();
+
+    Ok(PackageFiles {
+        walker,
+        package_dir: package_dir.to_path_buf(),
+        target_dir: target_dir.to_path_buf(),
+        max_depth,
+        fold: options.fold.clone(),
+        targets: options.targets.clone(),
+        dirs: Vec::new(),
+        warned: false,
+        failed: false,
+    })
+}
+
+impl PackageFiles {
+    /// The mapping of a walked entry, if it is linked itself
+    fn mapping(
+        &mut self,
+        entry: std::result::Result<DirEntry, ignore::Error>,
+    ) -> Result<Option<SymlinkMapping>> {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => match symlink_loop(&e) {
+                Some((ancestor, child)) => {
+                    self.warned = true;
+                    warning::warn(format!(
+                        "Skipping {} (symlink loop back to {})",
+                        child.display(),
+                        ancestor.display()
+                    ))?;
+                    return Ok(None);
+                }
+                None => return Err(walk_error(e)),
+            },
+        };
+        let path = entry.path();
+        let Some(file_type) = entry.file_type() else {
+            return Ok(None);
+        };
+
+        let rel_path = path
+            .strip_prefix(&self.package_dir)
+            .map_err(|_| StauError::InvalidPath(path.to_path_buf()))?;
+
+        // ROOT itself stands for `/`, which can't be linked as a whole
+        let at_max_depth = self.max_depth == Some(entry.depth())
+            && !(entry.depth() == 1 && entry.file_name() == ROOT_DIR);
+        let folded = self.fold.iter().any(|dir| dir == rel_path);
+        if file_type.is_dir() && !at_max_depth && !folded {
+            self.dirs.push(path.to_path_buf());
+            Ok(None)
+        } else if file_type.is_file() || file_type.is_dir() {
+            if file_type.is_dir() {
+                let why = if folded {
+                    "folded"
+                } else {
+                    "at the maximum depth"
+                };
+                verbosity::detail(|| format!("Linking {} as a whole ({})", path.display(), why));
+            }
+            // A directory at the maximum depth or folded is linked as a whole
+            Ok(Some(SymlinkMapping::new(
+                path.to_path_buf(),
+                target_path(&self.target_dir, rel_path, &self.targets),
+            )))
+        } else {
+            // Skip symlinks and other special files
+            self.warned = true;
+            warning::fail_if_strict(format!(
+                "Skipping {} (not a regular file or directory)",
+                path.display()
+            ))?;
+            Ok(None)
+        }
+    }
+}
+
+impl Iterator for PackageFiles {
+    type Item = Result<SymlinkMapping>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            let entry = self.walker.next()?;
+            match self.mapping(entry) {
+                Ok(Some(mapping)) => return Some(Ok(mapping)),
+                Ok(None) => {}
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
 }
 
-/// Recursively walk a directory and build symlink mappings
-fn walk_directory(
-    base_dir: &Path,
-    current_dir: &Path,
+/// Where a file of a package is linked, given its path relative to the
+/// package: below the destination of the most specific subdirectory with a
+/// target of its own, the absolute path for files under `ROOT/`, or else the
+/// same path below the target directory
+pub fn target_path(target_dir: &Path, rel_path: &Path, targets: &[(PathBuf, PathBuf)]) -> PathBuf {
+    let section = targets
+        .iter()
+        .filter_map(|(dir, dest)| Some((dir, dest, rel_path.strip_prefix(dir).ok()?)))
+        .max_by_key(|(dir, _, _)| dir.components().count());
+    if let Some((_, dest, rest)) = section {
+        // A subdirectory linked as a whole is linked at the destination itself
+        if rest.as_os_str().is_empty() {
+            return dest.clone();
+        }
+        return dest.join(rest);
+    }
+
+    match rel_path.strip_prefix(ROOT_DIR) {
+        Ok(absolute) => Path::new("/").join(absolute),
+        Err(_) => target_dir.join(rel_path),
+    }
+}
+
+/// Discover files across several layers of the same package
+///
+/// Layers are given in priority order: a file in a later layer replaces the
+/// file with the same relative path from an earlier one.
+pub fn discover_overlay_files(
+    package_dirs: &[PathBuf],
     target_dir: &Path,
-    mappings: &mut Vec<SymlinkMapping>,
-) -> Result<()> {
-    let entries = fs::read_dir(current_dir).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::PermissionDenied {
-            StauError::PermissionDenied(format!("Cannot read directory: {}", current_dir.display()))
+    options: &DiscoveryOptions,
+) -> Result<Discovery> {
+    let mut discovery = Discovery::default();
+    let mut by_target: HashMap<PathBuf, usize> = HashMap::new();
+
+    for package_dir in package_dirs {
+        let layer = discover_package_files(package_dir, target_dir, options)?;
+        discovery.dirs.extend(layer.dirs);
+        discovery.warned |= layer.warned;
+        for mapping in layer.mappings {
+            match by_target.get(&mapping.target) {
+                Some(&index) => discovery.mappings[index] = mapping,
+                None => {
+                    by_target.insert(mapping.target.clone(), discovery.mappings.len());
+                    discovery.mappings.push(mapping);
+                }
+            }
+        }
+    }
+
+    Ok(discovery)
+}
+
+/// Compile configured file name patterns, naming the setting they come from in errors
+fn compile_patterns(names: &[String], setting: &str) -> Result<Vec<glob::Pattern>> {
+    names
+        .iter()
+        .map(|name| glob::Pattern::new(name))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| StauError::InvalidConfig(format!("invalid {} pattern: {}", setting, e)))
+}
+
+/// Why an entry of a package is never linked (nor descended into), if it
+/// isn't, given the configured patterns of files to skip in the package root
+/// and of junk files to skip anywhere
+fn skip_reason(
+    entry: &DirEntry,
+    skip: &[glob::Pattern],
+    junk: &[glob::Pattern],
+) -> Option<&'static str> {
+    let file_name = entry.file_name().to_string_lossy();
+
+    // Skip OS and editor cruft anywhere, e.g. .DS_Store or swap files
+    if junk.iter().any(|pattern| pattern.matches(&file_name)) {
+        return Some("junk");
+    }
+
+    // Skip git metadata anywhere, e.g. the .git file of a submodule
+    if file_name == ".git" {
+        return Some("git metadata");
+    }
+
+    // Skip setup.sh and teardown.sh scripts
+    if file_name == "setup.sh" || file_name == "teardown.sh" {
+        return Some("hook script");
+    }
+
+    // Skip OS- and host-specific scripts (setup.<os>.sh, setup.d/, ...), the
+    // scripts/ and .stau/ hook directories, the manifest, the package's
+    // README, version control files and configured files in root of package
+    if entry.depth() != 1 {
+        return None;
+    }
+    if is_variant_script(&file_name) || matches!(file_name.as_ref(), "setup.d" | "teardown.d") {
+        Some("hook script")
+    } else if skip.iter().any(|pattern| pattern.matches(&file_name)) {
+        Some("skip pattern")
+    } else if file_name == MANIFEST_FILE {
+        Some("manifest")
+    } else if file_name == README_FILE {
+        Some("package README")
+    } else if matches!(file_name.as_ref(), "scripts" | ".stau") {
+        Some("hook directory")
+    } else if matches!(
+        file_name.as_ref(),
+        ".gitignore" | ".gitattributes" | ".gitmodules"
+    ) {
+        Some("version control file")
+    } else {
+        None
+    }
+}
+
+/// The paths of a symlink loop found while following symlinks, if that is the error
+fn symlink_loop(e: &ignore::Error) -> Option<(&Path, &Path)> {
+    match e {
+        ignore::Error::Loop { ancestor, child } => Some((ancestor, child)),
+        ignore::Error::WithPath { err, .. }
+        | ignore::Error::WithDepth { err, .. }
+        | ignore::Error::WithLineNumber { err, .. } => symlink_loop(err),
+        _ => None,
+    }
+}
+
+/// Map an error walking a package to a StauError
+fn walk_error(e: ignore::Error) -> StauError {
+    match e.io_error() {
+        Some(io) if io.kind() == std::io::ErrorKind::PermissionDenied => {
+            StauError::PermissionDenied(format!("Cannot read package: {}", e))
+        }
+        _ => StauError::Other(format!("Cannot read package: {}", e)),
+    }
+}
+
+/// List the setup and teardown scripts kept in the root of a package directory
+///
+/// These are `setup.sh` and `teardown.sh`, their OS- and host-specific
+/// variants and the `setup.d/` and `teardown.d/` directories, all of which
+/// work the same from the `scripts/` directory.
+pub fn root_hook_scripts(package_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut scripts = Vec::new();
+    for entry in fs::read_dir(package_dir).map_err(StauError::Io)? {
+        let path = entry.map_err(StauError::Io)?.path();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let is_hook = if path.is_dir() {
+            matches!(file_name.as_str(), "setup.d" | "teardown.d")
         } else {
-            StauError::Io(e)
+            matches!(file_name.as_str(), "setup.sh" | "teardown.sh")
+                || is_variant_script(&file_name)
+        };
+        if is_hook {
+            scripts.push(path);
         }
-    })?;
+    }
+    scripts.sort();
+    Ok(scripts)
+}
 
-    for entry in entries {
-        let entry = entry.map_err(StauError::Io)?;
-        let path = entry.path();
-        let file_name = entry.file_name();
+/// Check if a file name is an OS- or host-specific setup or teardown script
+fn is_variant_script(file_name: &str) -> bool {
+    ["setup.", "teardown."].iter().any(|prefix| {
+        file_name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(".sh"))
+            .is_some_and(|os| !os.is_empty() && !os.contains('.'))
+    })
+}
 
-        // Skip setup.sh and teardown.sh scripts
-        if file_name == "setup.sh" || file_name == "teardown.sh" {
-            continue;
+/// Find target paths claimed by more than one package
+///
+/// Besides two packages linking the same path, this catches one package
+/// linking a file where another needs a directory. Returns each contested
+/// path with the sorted names of the packages claiming it.
+pub fn find_conflicts(
+    packages: &[(String, Vec<SymlinkMapping>)],
+) -> BTreeMap<PathBuf, Vec<String>> {
+    let mut claims: HashMap<&Path, Vec<&str>> = HashMap::new();
+    for (package, mappings) in packages {
+        for mapping in mappings {
+            claims.entry(&mapping.target).or_default().push(package);
         }
+    }
 
-        // Skip version control files/directories in root of package
-        let file_name_str = file_name.to_string_lossy();
-        if current_dir == base_dir
-            && matches!(
-                file_name_str.as_ref(),
-                ".git" | ".gitignore" | ".gitattributes" | ".gitmodules"
-            )
-        {
-            continue;
+    let mut conflicts: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+    for (target, owners) in &claims {
+        if owners.len() > 1 {
+            let owners = owners.iter().map(|owner| owner.to_string()).collect();
+            conflicts.insert(target.to_path_buf(), owners);
+        }
+    }
+
+    for (package, mappings) in packages {
+        for mapping in mappings {
+            for dir in mapping.target.ancestors().skip(1) {
+                if let Some(owners) = claims.get(dir) {
+                    let entry = conflicts
+                        .entry(dir.to_path_buf())
+                        .or_insert_with(|| owners.iter().map(|owner| owner.to_string()).collect());
+                    entry.push(package.clone());
+                }
+            }
         }
+    }
 
-        let metadata = entry.metadata().map_err(StauError::Io)?;
+    for owners in conflicts.values_mut() {
+        owners.sort();
+        owners.dedup();
+    }
+    conflicts
+}
 
-        if metadata.is_dir() {
-            // Recursively walk subdirectories
-            walk_directory(base_dir, &path, target_dir, mappings)?;
-        } else if metadata.is_file() {
-            // Calculate relative path from package base
-            let rel_path = path
-                .strip_prefix(base_dir)
-                .map_err(|_| StauError::InvalidPath(path.clone()))?;
+/// Sort packages so that each comes after the packages it depends on
+///
+/// Dependencies outside the given set only affect ordering if they are part
+/// of it, so they are skipped. Otherwise the input order is kept. A cycle is
+/// reported as an error naming the packages involved.
+pub fn order_by_dependencies(
+    packages: &[String],
+    dependencies: impl Fn(&str) -> Result<Vec<String>>,
+) -> Result<Vec<String>> {
+    let mut ordered = Vec::new();
+    let mut visiting = Vec::new();
+    for package in packages {
+        visit_dependencies(
+            package,
+            packages,
+            &dependencies,
+            &mut visiting,
+            &mut ordered,
+        )?;
+    }
+    Ok(ordered)
+}
 
-            // Target path is target_dir + relative path
-            let target_path = target_dir.join(rel_path);
+/// Depth-first step of order_by_dependencies
+fn visit_dependencies(
+    package: &str,
+    packages: &[String],
+    dependencies: &impl Fn(&str) -> Result<Vec<String>>,
+    visiting: &mut Vec<String>,
+    ordered: &mut Vec<String>,
+) -> Result<()> {
+    if ordered.iter().any(|p| p == package) {
+        return Ok(());
+    }
+    if let Some(start) = visiting.iter().position(|p| p == package) {
+        let mut cycle = visiting[start..].to_vec();
+        cycle.push(package.to_string());
+        return Err(StauError::DependencyCycle(cycle.join(" -> ")));
+    }
 
-            mappings.push(SymlinkMapping::new(path, target_path));
+    visiting.push(package.to_string());
+    for dependency in dependencies(package)? {
+        if packages.contains(&dependency) {
+            visit_dependencies(&dependency, packages, dependencies, visiting, ordered)?;
         }
-        // Skip symlinks and other special files
     }
+    visiting.pop();
 
+    ordered.push(package.to_string());
     Ok(())
 }
 
-/// List all packages in the stau directory
-pub fn list_packages(stau_dir: &Path) -> Result<Vec<String>> {
-    if !stau_dir.exists() {
-        return Err(StauError::StauDirNotFound(stau_dir.to_path_buf()));
-    }
+/// Replace bundles, packages with members, by their member packages
+///
+/// Members may be bundles themselves or glob patterns. Each package is listed
+/// once, at its first appearance, and a bundle that (indirectly) contains
+/// itself is only expanded once.
+pub fn expand_bundles(
+    stau_dirs: &[PathBuf],
+    packages: Vec<String>,
+    members: impl Fn(&str) -> Result<Vec<String>>,
+) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    let mut seen_bundles = Vec::new();
+    let mut pending: Vec<String> = packages.into_iter().rev().collect();
 
-    let entries = fs::read_dir(stau_dir).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::PermissionDenied {
-            StauError::PermissionDenied(format!("Cannot read directory: {}", stau_dir.display()))
-        } else {
-            StauError::Io(e)
+    while let Some(package) = pending.pop() {
+        let bundle_members = members(&package)?;
+        if bundle_members.is_empty() {
+            if !expanded.contains(&package) {
+                expanded.push(package);
+            }
+        } else if !seen_bundles.contains(&package) {
+            pending.extend(
+                resolve_packages(stau_dirs, &bundle_members)?
+                    .into_iter()
+                    .rev(),
+            );
+            seen_bundles.push(package);
         }
-    })?;
+    }
+    Ok(expanded)
+}
 
+/// List all packages across the stau directories
+///
+/// A package that exists in several directories (overlays) is listed once.
+pub fn list_packages(stau_dirs: &[PathBuf]) -> Result<Vec<String>> {
     let mut packages = Vec::new();
-    for entry in entries {
-        let entry = entry.map_err(StauError::Io)?;
-        let path = entry.path();
+    for stau_dir in stau_dirs {
+        if !stau_dir.exists() {
+            return Err(StauError::StauDirNotFound(stau_dir.to_path_buf()));
+        }
+
+        let entries = fs::read_dir(stau_dir).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                StauError::PermissionDenied(format!(
+                    "Cannot read directory: {}",
+                    stau_dir.display()
+                ))
+            } else {
+                StauError::Io(e)
+            }
+        })?;
+
+        for entry in entries {
+            let entry = entry.map_err(StauError::Io)?;
+            let path = entry.path();
 
-        // Only include directories, skip hidden directories
-        if path.is_dir()
-            && let Some(name) = path.file_name()
-        {
-            let name_str = name.to_string_lossy();
-            if !name_str.starts_with('.') {
-                packages.push(name_str.to_string());
+            // Only include directories, skip hidden directories
+            if path.is_dir()
+                && let Some(name) = path.file_name()
+            {
+                let name_str = name.to_string_lossy();
+                if !name_str.starts_with('.') {
+                    packages.push(name_str.to_string());
+                }
             }
         }
     }
 
     packages.sort();
+    packages.dedup();
     Ok(packages)
 }
 
+/// Check that a package name refers to a directory directly inside STAU_DIR
+///
+/// Names with path separators or `.`/`..` would let a package argument
+/// escape the dotfiles directory.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(StauError::InvalidPackageName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Check if a package argument contains glob metacharacters
+fn is_glob_pattern(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Resolve package arguments, expanding glob patterns against the package list
+///
+/// Plain names are passed through unchanged so that commands can report a
+/// missing package themselves. A glob that matches nothing is an error.
+pub fn resolve_packages(stau_dirs: &[PathBuf], patterns: &[String]) -> Result<Vec<String>> {
+    let mut resolved: Vec<String> = Vec::new();
+    let mut available: Option<Vec<String>> = None;
+
+    for pattern in patterns {
+        if !is_glob_pattern(pattern) {
+            validate_name(pattern)?;
+            if !resolved.contains(pattern) {
+                resolved.push(pattern.clone());
+            }
+            continue;
+        }
+
+        let glob = glob::Pattern::new(pattern)
+            .map_err(|e| StauError::Other(format!("Invalid pattern '{}': {}", pattern, e)))?;
+
+        if available.is_none() {
+            available = Some(list_packages(stau_dirs)?);
+        }
+
+        let matches: Vec<&String> = available
+            .as_ref()
+            .map(|pkgs| pkgs.iter().filter(|p| glob.matches(p)).collect())
+            .unwrap_or_default();
+
+        if matches.is_empty() {
+            return Err(StauError::NoMatchingPackages(pattern.clone()));
+        }
+
+        for name in matches {
+            if !resolved.contains(name) {
+                resolved.push(name.clone());
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Read newline-separated package names, ignoring blank lines and # comments
+pub fn read_package_names(reader: impl BufRead) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for line in reader.lines() {
+        let line = line.map_err(StauError::Io)?;
+        let name = line.trim();
+        if !name.is_empty() && !name.starts_with('#') {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Remove packages matching any of the exclude names or glob patterns
+pub fn exclude_packages(packages: Vec<String>, excludes: &[String]) -> Result<Vec<String>> {
+    if excludes.is_empty() {
+        return Ok(packages);
+    }
+
+    let patterns = excludes
+        .iter()
+        .map(|e| {
+            glob::Pattern::new(e)
+                .map_err(|err| StauError::Other(format!("Invalid pattern '{}': {}", e, err)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(packages
+        .into_iter()
+        .filter(|p| !patterns.iter().any(|pattern| pattern.matches(p)))
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -132,7 +726,10 @@ mod tests {
         File::create(package_dir.join(".bashrc")).unwrap();
         File::create(package_dir.join(".vimrc")).unwrap();
 
-        let mappings = discover_package_files(&package_dir, &target_dir).unwrap();
+        let mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
 
         assert_eq!(mappings.len(), 2);
         assert!(
@@ -159,7 +756,10 @@ mod tests {
         File::create(package_dir.join(".config/nvim/init.lua")).unwrap();
         File::create(package_dir.join(".bashrc")).unwrap();
 
-        let mappings = discover_package_files(&package_dir, &target_dir).unwrap();
+        let mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
 
         assert_eq!(mappings.len(), 2);
         assert!(
@@ -170,6 +770,52 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_walk_package_files_as_they_are_asked_for() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("test_package");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir_all(package_dir.join(".config/nvim")).unwrap();
+        File::create(package_dir.join(".config/nvim/init.lua")).unwrap();
+        File::create(package_dir.join(".bashrc")).unwrap();
+
+        let options = DiscoveryOptions::default();
+        let mut files = walk_package_files(&package_dir, &target_dir, &options).unwrap();
+        // Nothing is walked before the first file is asked for
+        assert!(files.dirs.is_empty());
+
+        let mut mappings: Vec<SymlinkMapping> = files.by_ref().map(Result::unwrap).collect();
+        let mut discovered = discover_package_files(&package_dir, &target_dir, &options).unwrap();
+        mappings.sort_by(|a, b| a.target.cmp(&b.target));
+        discovered.mappings.sort_by(|a, b| a.target.cmp(&b.target));
+        assert_eq!(mappings, discovered.mappings);
+        assert_eq!(files.dirs.len(), 3);
+        assert!(!files.warned);
+    }
+
+    #[test]
+    fn test_root_hook_scripts() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("zsh");
+        fs::create_dir_all(package_dir.join("setup.d")).unwrap();
+        fs::create_dir_all(package_dir.join("scripts")).unwrap();
+        File::create(package_dir.join("setup.sh")).unwrap();
+        File::create(package_dir.join("teardown.linux.sh")).unwrap();
+        File::create(package_dir.join("setup.d/workstation.sh")).unwrap();
+        File::create(package_dir.join("scripts/teardown.sh")).unwrap();
+        File::create(package_dir.join("setup.old.sh.bak")).unwrap();
+        File::create(package_dir.join(".zshrc")).unwrap();
+
+        assert_eq!(
+            root_hook_scripts(&package_dir).unwrap(),
+            vec![
+                package_dir.join("setup.d"),
+                package_dir.join("setup.sh"),
+                package_dir.join("teardown.linux.sh"),
+            ]
+        );
+    }
+
     #[test]
     fn test_skip_setup_scripts() {
         let temp_dir = TempDir::new().unwrap();
@@ -181,13 +827,102 @@ mod tests {
         File::create(package_dir.join("teardown.sh")).unwrap();
         File::create(package_dir.join(".bashrc")).unwrap();
 
-        let mappings = discover_package_files(&package_dir, &target_dir).unwrap();
+        let mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
 
         // Should only find .bashrc, not the scripts
         assert_eq!(mappings.len(), 1);
         assert!(mappings[0].source.ends_with(".bashrc"));
     }
 
+    #[test]
+    fn test_skip_os_specific_scripts() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("test_package");
+        let target_dir = temp_dir.path().join("target");
+
+        fs::create_dir(&package_dir).unwrap();
+        File::create(package_dir.join("setup.linux.sh")).unwrap();
+        File::create(package_dir.join("setup.macos.sh")).unwrap();
+        File::create(package_dir.join("teardown.linux.sh")).unwrap();
+        File::create(package_dir.join(".bashrc")).unwrap();
+
+        let mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
+
+        assert_eq!(mappings.len(), 1);
+        assert!(mappings[0].source.ends_with(".bashrc"));
+    }
+
+    #[test]
+    fn test_skip_host_script_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("test_package");
+        let target_dir = temp_dir.path().join("target");
+
+        fs::create_dir_all(package_dir.join("setup.d")).unwrap();
+        fs::create_dir_all(package_dir.join("teardown.d")).unwrap();
+        File::create(package_dir.join("setup.d/workstation.sh")).unwrap();
+        File::create(package_dir.join("teardown.d/workstation.sh")).unwrap();
+        File::create(package_dir.join(".bashrc")).unwrap();
+
+        let mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
+
+        assert_eq!(mappings.len(), 1);
+        assert!(mappings[0].source.ends_with(".bashrc"));
+    }
+
+    #[test]
+    fn test_skip_scripts_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("test_package");
+        let target_dir = temp_dir.path().join("target");
+
+        fs::create_dir_all(package_dir.join("scripts")).unwrap();
+        fs::create_dir_all(package_dir.join(".stau")).unwrap();
+        fs::create_dir_all(package_dir.join(".local/scripts")).unwrap();
+        File::create(package_dir.join("scripts/update.sh")).unwrap();
+        File::create(package_dir.join(".stau/setup.sh")).unwrap();
+        File::create(package_dir.join(".local/scripts/tool")).unwrap();
+
+        let mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
+
+        // Only the root scripts/ and .stau/ directories are reserved
+        assert_eq!(mappings.len(), 1);
+        assert!(mappings[0].source.ends_with(".local/scripts/tool"));
+    }
+
+    #[test]
+    fn test_skip_manifest_and_readme() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("test_package");
+        let target_dir = temp_dir.path().join("target");
+
+        fs::create_dir_all(package_dir.join(".config/tool")).unwrap();
+        File::create(package_dir.join("stau.toml")).unwrap();
+        File::create(package_dir.join("README.md")).unwrap();
+        File::create(package_dir.join(".config/tool/stau.toml")).unwrap();
+
+        let mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
+
+        // Only the manifest and README in the package root are reserved
+        assert_eq!(mappings.len(), 1);
+        assert!(mappings[0].source.ends_with(".config/tool/stau.toml"));
+    }
+
     #[test]
     fn test_skip_hidden_root_files() {
         let temp_dir = TempDir::new().unwrap();
@@ -199,7 +934,10 @@ mod tests {
         File::create(package_dir.join(".gitignore")).unwrap();
         File::create(package_dir.join(".bashrc")).unwrap();
 
-        let mappings = discover_package_files(&package_dir, &target_dir).unwrap();
+        let mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
 
         // Should skip .git and .gitignore at root, but include .bashrc (it's a config file)
         assert_eq!(mappings.len(), 1);
@@ -217,7 +955,7 @@ mod tests {
         fs::create_dir(stau_dir.join("git")).unwrap();
         fs::create_dir(stau_dir.join(".hidden")).unwrap();
 
-        let packages = list_packages(stau_dir).unwrap();
+        let packages = list_packages(&[stau_dir.to_path_buf()]).unwrap();
 
         assert_eq!(packages.len(), 3);
         assert!(packages.contains(&"zsh".to_string()));
@@ -226,13 +964,509 @@ mod tests {
         assert!(!packages.contains(&".hidden".to_string()));
     }
 
+    #[test]
+    fn test_list_packages_across_overlays() {
+        let temp_dir = TempDir::new().unwrap();
+        let personal = temp_dir.path().join("personal");
+        let work = temp_dir.path().join("work");
+
+        fs::create_dir_all(personal.join("vim")).unwrap();
+        fs::create_dir_all(personal.join("zsh")).unwrap();
+        fs::create_dir_all(work.join("zsh")).unwrap();
+        fs::create_dir_all(work.join("aws")).unwrap();
+
+        let packages = list_packages(&[personal, work]).unwrap();
+        assert_eq!(packages, vec!["aws", "vim", "zsh"]);
+    }
+
+    #[test]
+    fn test_discover_respects_gitignore() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("zsh");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir_all(package_dir.join(".config/zsh/__pycache__")).unwrap();
+        // One rule from the repository above the package, one from the package
+        fs::write(temp_dir.path().join(".gitignore"), "zsh/*.log\n").unwrap();
+        fs::write(package_dir.join(".gitignore"), "__pycache__/\n*.zwc\n").unwrap();
+        for file in [
+            ".zshrc",
+            ".zshrc.zwc",
+            "history.log",
+            ".config/zsh/prompt.py",
+            ".config/zsh/__pycache__/prompt.pyc",
+        ] {
+            File::create(package_dir.join(file)).unwrap();
+        }
+
+        let names = |options: &DiscoveryOptions| {
+            let mut names: Vec<String> = discover_package_files(&package_dir, &target_dir, options)
+                .unwrap()
+                .mappings
+                .into_iter()
+                .map(|m| {
+                    m.source
+                        .strip_prefix(&package_dir)
+                        .unwrap()
+                        .display()
+                        .to_string()
+                })
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(
+            names(&DiscoveryOptions::default()),
+            vec![".config/zsh/prompt.py", ".zshrc"]
+        );
+        assert_eq!(
+            names(&DiscoveryOptions {
+                gitignore: false,
+                ..DiscoveryOptions::default()
+            }),
+            vec![
+                ".config/zsh/__pycache__/prompt.pyc",
+                ".config/zsh/prompt.py",
+                ".zshrc",
+                ".zshrc.zwc",
+                "history.log"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_discover_links_directories_at_max_depth() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("vim");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir_all(package_dir.join(".vim/pack/plugins/start/fugitive")).unwrap();
+        File::create(package_dir.join(".vimrc")).unwrap();
+        File::create(package_dir.join(".vim/pack/plugins/start/fugitive/plugin.vim")).unwrap();
+        File::create(package_dir.join(".vim/filetype.vim")).unwrap();
+
+        let options = DiscoveryOptions {
+            max_depth: NonZeroUsize::new(2),
+            ..DiscoveryOptions::default()
+        };
+        let mut mappings = discover_package_files(&package_dir, &target_dir, &options)
+            .unwrap()
+            .mappings;
+        mappings.sort_by(|a, b| a.source.cmp(&b.source));
+
+        assert_eq!(mappings.len(), 3);
+        assert_eq!(mappings[0].source, package_dir.join(".vim/filetype.vim"));
+        assert_eq!(mappings[1].source, package_dir.join(".vim/pack"));
+        assert_eq!(mappings[1].target, target_dir.join(".vim/pack"));
+        assert_eq!(mappings[2].source, package_dir.join(".vimrc"));
+    }
+
+    #[test]
+    fn test_discover_skips_junk_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("vim");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir_all(package_dir.join(".vim/after")).unwrap();
+        for file in [
+            ".vimrc",
+            ".DS_Store",
+            ".vim/after/.DS_Store",
+            ".vim/after/ftplugin.vim",
+            ".vim/after/.ftplugin.vim.swp",
+            ".vim/Thumbs.db",
+            ".vim/notes~",
+        ] {
+            File::create(package_dir.join(file)).unwrap();
+        }
+
+        let mut mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
+        mappings.sort_by(|a, b| a.source.cmp(&b.source));
+        assert_eq!(mappings.len(), 2);
+        assert!(mappings[0].source.ends_with(".vim/after/ftplugin.vim"));
+        assert!(mappings[1].source.ends_with(".vimrc"));
+
+        let options = DiscoveryOptions {
+            junk: Vec::new(),
+            ..DiscoveryOptions::default()
+        };
+        let mappings = discover_package_files(&package_dir, &target_dir, &options)
+            .unwrap()
+            .mappings;
+        assert_eq!(mappings.len(), 7);
+    }
+
+    #[test]
+    fn test_discover_follows_symlinked_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let shared = temp_dir.path().join("shared");
+        let package_dir = temp_dir.path().join("zsh");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir(&shared).unwrap();
+        File::create(shared.join("aliases.zsh")).unwrap();
+        fs::create_dir_all(package_dir.join(".config/zsh")).unwrap();
+        std::os::unix::fs::symlink(&shared, package_dir.join(".config/zsh/shared")).unwrap();
+        // A loop back to the package itself is skipped instead of followed forever
+        std::os::unix::fs::symlink(&package_dir, package_dir.join(".config/zsh/loop")).unwrap();
+
+        let mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
+        assert!(mappings.is_empty());
+
+        let options = DiscoveryOptions {
+            follow_symlinks: true,
+            ..DiscoveryOptions::default()
+        };
+        let mappings = discover_package_files(&package_dir, &target_dir, &options)
+            .unwrap()
+            .mappings;
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(
+            mappings[0].source,
+            package_dir.join(".config/zsh/shared/aliases.zsh")
+        );
+        assert_eq!(
+            mappings[0].target,
+            target_dir.join(".config/zsh/shared/aliases.zsh")
+        );
+    }
+
+    #[test]
+    fn test_discover_maps_root_directory_to_absolute_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("system");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir_all(package_dir.join("ROOT/etc")).unwrap();
+        File::create(package_dir.join("ROOT/etc/hosts")).unwrap();
+        File::create(package_dir.join(".profile")).unwrap();
+
+        let mut mappings =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default())
+                .unwrap()
+                .mappings;
+        mappings.sort_by(|a, b| a.source.cmp(&b.source));
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].target, target_dir.join(".profile"));
+        assert_eq!(mappings[1].target, Path::new("/etc/hosts"));
+
+        // ROOT is descended into even at the maximum depth
+        let options = DiscoveryOptions {
+            max_depth: NonZeroUsize::new(1),
+            ..DiscoveryOptions::default()
+        };
+        let mappings = discover_package_files(&package_dir, &target_dir, &options)
+            .unwrap()
+            .mappings;
+        assert!(mappings.iter().all(|m| m.target != Path::new("/")));
+    }
+
+    #[test]
+    fn test_discover_links_target_sections_elsewhere() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("tools");
+        let target_dir = temp_dir.path().join("home");
+        fs::create_dir_all(package_dir.join("bin")).unwrap();
+        fs::create_dir_all(package_dir.join("config/systemd/units")).unwrap();
+        File::create(package_dir.join("bin/backup")).unwrap();
+        File::create(package_dir.join("config/tool.toml")).unwrap();
+        File::create(package_dir.join("config/systemd/units/backup.service")).unwrap();
+        File::create(package_dir.join(".profile")).unwrap();
+
+        let options = DiscoveryOptions {
+            targets: vec![
+                (PathBuf::from("bin"), target_dir.join(".local/bin")),
+                (PathBuf::from("config"), target_dir.join(".config")),
+                (
+                    PathBuf::from("config/systemd/units"),
+                    target_dir.join(".config/systemd/user"),
+                ),
+            ],
+            ..DiscoveryOptions::default()
+        };
+        let mut targets: Vec<PathBuf> = discover_package_files(&package_dir, &target_dir, &options)
+            .unwrap()
+            .mappings
+            .into_iter()
+            .map(|m| m.target)
+            .collect();
+        targets.sort();
+
+        assert_eq!(
+            targets,
+            vec![
+                target_dir.join(".config/systemd/user/backup.service"),
+                target_dir.join(".config/tool.toml"),
+                target_dir.join(".local/bin/backup"),
+                target_dir.join(".profile"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_discover_skips_configured_root_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("nix");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir_all(package_dir.join(".config/nix")).unwrap();
+        for file in [
+            "LICENSE",
+            "flake.nix",
+            ".config/nix/nix.conf",
+            ".config/nix/LICENSE",
+        ] {
+            File::create(package_dir.join(file)).unwrap();
+        }
+
+        let options = DiscoveryOptions {
+            skip: vec!["LICENSE".to_string(), "*.nix".to_string()],
+            ..DiscoveryOptions::default()
+        };
+        let mut names: Vec<String> = discover_package_files(&package_dir, &target_dir, &options)
+            .unwrap()
+            .mappings
+            .into_iter()
+            .map(|m| {
+                m.source
+                    .strip_prefix(&package_dir)
+                    .unwrap()
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        names.sort();
+
+        // Only the package root is affected
+        assert_eq!(names, vec![".config/nix/LICENSE", ".config/nix/nix.conf"]);
+    }
+
+    #[test]
+    fn test_discover_overlay_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let personal = temp_dir.path().join("personal/git");
+        let work = temp_dir.path().join("work/git");
+        let target_dir = temp_dir.path().join("home");
+
+        fs::create_dir_all(&personal).unwrap();
+        fs::create_dir_all(work.join(".config/git")).unwrap();
+        File::create(personal.join(".gitconfig")).unwrap();
+        File::create(personal.join(".gitignore_global")).unwrap();
+        File::create(work.join(".gitconfig")).unwrap();
+        File::create(work.join(".config/git/work")).unwrap();
+
+        let discovery = discover_overlay_files(
+            &[personal.clone(), work.clone()],
+            &target_dir,
+            &DiscoveryOptions::default(),
+        )
+        .unwrap();
+        let mappings = discovery.mappings;
+        assert_eq!(mappings.len(), 3);
+        assert_eq!(
+            discovery.dirs,
+            [
+                personal.clone(),
+                work.clone(),
+                work.join(".config"),
+                work.join(".config/git")
+            ]
+        );
+
+        let source_for = |name: &str| {
+            mappings
+                .iter()
+                .find(|m| m.target == target_dir.join(name))
+                .map(|m| m.source.clone())
+                .unwrap()
+        };
+        assert_eq!(source_for(".gitconfig"), work.join(".gitconfig"));
+        assert_eq!(
+            source_for(".gitignore_global"),
+            personal.join(".gitignore_global")
+        );
+        assert_eq!(
+            source_for(".config/git/work"),
+            work.join(".config/git/work")
+        );
+    }
+
+    #[test]
+    fn test_resolve_packages_glob() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path();
+
+        fs::create_dir(stau_dir.join("nvim")).unwrap();
+        fs::create_dir(stau_dir.join("nvim-lsp")).unwrap();
+        fs::create_dir(stau_dir.join("zsh")).unwrap();
+
+        let stau_dirs = [stau_dir.to_path_buf()];
+        let packages = resolve_packages(&stau_dirs, &["nvim*".to_string()]).unwrap();
+        assert_eq!(packages, vec!["nvim".to_string(), "nvim-lsp".to_string()]);
+
+        // Plain names pass through and duplicates are dropped
+        let packages = resolve_packages(
+            &stau_dirs,
+            &["zsh".to_string(), "z?h".to_string(), "missing".to_string()],
+        )
+        .unwrap();
+        assert_eq!(packages, vec!["zsh".to_string(), "missing".to_string()]);
+    }
+
+    #[test]
+    fn test_resolve_packages_no_match() {
+        let temp_dir = TempDir::new().unwrap();
+        let stau_dir = temp_dir.path();
+
+        fs::create_dir(stau_dir.join("zsh")).unwrap();
+
+        let result = resolve_packages(&[stau_dir.to_path_buf()], &["work-*".to_string()]);
+        assert!(matches!(
+            result.unwrap_err(),
+            StauError::NoMatchingPackages(_)
+        ));
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("nvim").is_ok());
+        assert!(validate_name(".hidden").is_ok());
+        assert!(validate_name("vim..old").is_ok());
+
+        for name in ["", ".", "..", "../etc", "vim/../../etc", "/etc"] {
+            assert!(
+                matches!(validate_name(name), Err(StauError::InvalidPackageName(_))),
+                "{:?} should be rejected",
+                name
+            );
+        }
+
+        let result = resolve_packages(&[PathBuf::from("/nonexistent")], &["../etc".to_string()]);
+        assert!(matches!(
+            result.unwrap_err(),
+            StauError::InvalidPackageName(_)
+        ));
+    }
+
+    #[test]
+    fn test_find_conflicts() {
+        let mapping = |package: &str, path: &str| {
+            SymlinkMapping::new(
+                PathBuf::from("/dotfiles").join(package).join(path),
+                PathBuf::from("/home").join(path),
+            )
+        };
+        let packages = vec![
+            (
+                "bash".to_string(),
+                vec![mapping("bash", ".bashrc"), mapping("bash", ".profile")],
+            ),
+            ("zsh".to_string(), vec![mapping("zsh", ".profile")]),
+            ("nvim".to_string(), vec![mapping("nvim", ".config/nvim")]),
+            (
+                "nvim-lsp".to_string(),
+                vec![mapping("nvim-lsp", ".config/nvim/lsp.lua")],
+            ),
+        ];
+
+        let conflicts = find_conflicts(&packages);
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(
+            conflicts[Path::new("/home/.profile")],
+            vec!["bash".to_string(), "zsh".to_string()]
+        );
+        assert_eq!(
+            conflicts[Path::new("/home/.config/nvim")],
+            vec!["nvim".to_string(), "nvim-lsp".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_expand_bundles() {
+        let temp_dir = TempDir::new().unwrap();
+        for package in ["nvim", "nvim-lsp", "zsh", "git"] {
+            fs::create_dir(temp_dir.path().join(package)).unwrap();
+        }
+        let members = |package: &str| -> Result<Vec<String>> {
+            Ok(match package {
+                "dev" => vec!["shell".to_string(), "nvim*".to_string(), "git".to_string()],
+                "shell" => vec!["zsh".to_string(), "dev".to_string()],
+                _ => Vec::new(),
+            })
+        };
+        let packages = vec!["git".to_string(), "dev".to_string()];
+
+        let expanded = expand_bundles(&[temp_dir.path().to_path_buf()], packages, members).unwrap();
+        assert_eq!(expanded, vec!["git", "zsh", "nvim", "nvim-lsp"]);
+    }
+
+    #[test]
+    fn test_order_by_dependencies() {
+        let depends = |package: &str| -> Result<Vec<String>> {
+            Ok(match package {
+                "nvim" => vec!["fonts".to_string(), "node".to_string()],
+                "node" => vec!["asdf".to_string()],
+                _ => Vec::new(),
+            })
+        };
+        let packages: Vec<String> = ["zsh", "nvim", "node", "fonts"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+
+        // asdf is not selected, so it is left out
+        let ordered = order_by_dependencies(&packages, depends).unwrap();
+        assert_eq!(ordered, vec!["zsh", "fonts", "node", "nvim"]);
+    }
+
+    #[test]
+    fn test_order_by_dependencies_cycle() {
+        let depends = |package: &str| -> Result<Vec<String>> {
+            Ok(match package {
+                "a" => vec!["b".to_string()],
+                "b" => vec!["c".to_string()],
+                "c" => vec!["a".to_string()],
+                _ => Vec::new(),
+            })
+        };
+        let packages: Vec<String> = ["x", "a", "b", "c"].iter().map(|p| p.to_string()).collect();
+
+        match order_by_dependencies(&packages, depends) {
+            Err(StauError::DependencyCycle(cycle)) => assert_eq!(cycle, "a -> b -> c -> a"),
+            other => panic!("expected a dependency cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_package_names() {
+        let input = "vim\n\n  zsh  \n# work machine only\nwork-*\n";
+        let names = read_package_names(input.as_bytes()).unwrap();
+        assert_eq!(names, vec!["vim", "zsh", "work-*"]);
+    }
+
+    #[test]
+    fn test_exclude_packages() {
+        let packages = vec![
+            "git".to_string(),
+            "work-vpn".to_string(),
+            "work-ssh".to_string(),
+            "zsh".to_string(),
+        ];
+
+        let remaining =
+            exclude_packages(packages, &["zsh".to_string(), "work-*".to_string()]).unwrap();
+        assert_eq!(remaining, vec!["git".to_string()]);
+    }
+
     #[test]
     fn test_nonexistent_package() {
         let temp_dir = TempDir::new().unwrap();
         let package_dir = temp_dir.path().join("nonexistent");
         let target_dir = temp_dir.path().join("target");
 
-        let result = discover_package_files(&package_dir, &target_dir);
+        let result =
+            discover_package_files(&package_dir, &target_dir, &DiscoveryOptions::default());
         assert!(result.is_err());
         assert!(matches!(result.unwrap_err(), StauError::PackageNotFound(_)));
     }
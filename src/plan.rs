@@ -0,0 +1,167 @@
+use crate::conflict::ConflictPolicy;
+use crate::error::{Result, StauError};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::path::PathBuf;
+
+thread_local! {
+    /// Steps noted by the dry run being recorded on this thread, if any
+    static RECORDED: RefCell<Option<Vec<Step>>> = const { RefCell::new(None) };
+}
+
+/// What a step does to its target path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Create a symlink to the source
+    Link,
+    /// Point an existing link of the package at another of its files
+    Relink,
+    /// Remove a link of the package
+    Unlink,
+    /// Replace a link of the package with a copy of the file it points to
+    Copy,
+    /// Leave the target path as it is
+    Skip,
+    /// Stop the command with an error
+    Abort,
+}
+
+/// One change a command would make to a target path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Step {
+    pub package: String,
+    pub action: Action,
+    /// Package file the target links or will link to
+    pub source: Option<PathBuf>,
+    pub target: PathBuf,
+    /// Why the step is needed
+    pub reason: String,
+    /// How the existing file in the way is dealt with, if there is one
+    pub conflict: Option<ConflictPolicy>,
+}
+
+impl Step {
+    pub fn new(
+        package: &str,
+        action: Action,
+        source: Option<PathBuf>,
+        target: PathBuf,
+        reason: &str,
+    ) -> Self {
+        Step {
+            package: package.to_string(),
+            action,
+            source,
+            target,
+            reason: reason.to_string(),
+            conflict: None,
+        }
+    }
+}
+
+/// Everything a dry run of a command would do, printed by `--dry-run --format json`
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub command: &'static str,
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    pub fn new(command: &'static str) -> Self {
+        Plan {
+            command,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| StauError::Other(format!("Cannot serialize plan: {}", e)))
+    }
+}
+
+/// Run a dry run, collecting the steps it notes with `add` instead of printing
+/// its usual output
+///
+/// Warnings and other stderr output are still printed.
+pub fn record<T>(dry_run: impl FnOnce() -> T) -> (T, Vec<Step>) {
+    let outer = RECORDED.replace(Some(Vec::new()));
+    let (result, held) = crate::output::hold(dry_run);
+    let steps = RECORDED.replace(outer).unwrap_or_default();
+    held.print_stderr();
+    (result, steps)
+}
+
+/// Whether a plan is being recorded, so nobody should be asked anything
+pub fn recording() -> bool {
+    RECORDED.with_borrow(Option::is_some)
+}
+
+/// Note a step in the plan being recorded, if there is one
+pub fn add(step: Step) {
+    RECORDED.with_borrow_mut(|steps| {
+        if let Some(steps) = steps {
+            steps.push(step);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let mut plan = Plan::new("install");
+        plan.steps.push(Step::new(
+            "zsh",
+            Action::Link,
+            Some(PathBuf::from("/dotfiles/zsh/.zshrc")),
+            PathBuf::from("/home/user/.zshrc"),
+            "not linked yet",
+        ));
+        let mut conflict = Step::new(
+            "zsh",
+            Action::Skip,
+            Some(PathBuf::from("/dotfiles/zsh/.zshenv")),
+            PathBuf::from("/home/user/.zshenv"),
+            "already exists",
+        );
+        conflict.conflict = Some(ConflictPolicy::Skip);
+        plan.steps.push(conflict);
+
+        let json: serde_json::Value = serde_json::from_str(&plan.to_json().unwrap()).unwrap();
+        assert_eq!(json["command"], "install");
+        assert_eq!(json["steps"][0]["action"], "link");
+        assert_eq!(json["steps"][0]["source"], "/dotfiles/zsh/.zshrc");
+        assert_eq!(json["steps"][0]["conflict"], serde_json::Value::Null);
+        assert_eq!(json["steps"][1]["action"], "skip");
+        assert_eq!(json["steps"][1]["conflict"], "skip");
+        assert_eq!(json["steps"][1]["reason"], "already exists");
+    }
+
+    #[test]
+    fn test_record() {
+        let step = |target: &str| {
+            Step::new(
+                "zsh",
+                Action::Link,
+                None,
+                PathBuf::from(target),
+                "not linked yet",
+            )
+        };
+
+        add(step("/ignored"));
+        assert!(!recording());
+        let (result, steps) = record(|| {
+            assert!(recording());
+            add(step("/home/user/.zshrc"));
+            42
+        });
+        assert_eq!(result, 42);
+        assert_eq!(steps, [step("/home/user/.zshrc")]);
+        assert!(!recording());
+    }
+}
@@ -0,0 +1,41 @@
+use gethostname::gethostname;
+use std::env;
+
+/// Name of the operating system (e.g. "linux", "macos")
+pub fn os() -> &'static str {
+    env::consts::OS
+}
+
+/// CPU architecture of the current machine (e.g. "x86_64", "aarch64")
+pub fn arch() -> &'static str {
+    env::consts::ARCH
+}
+
+/// Short hostname of the current machine, without any domain part
+pub fn hostname() -> Option<String> {
+    let name = gethostname().to_string_lossy().into_owned();
+    let short = name.split('.').next().unwrap_or_default().trim();
+    if short.is_empty() {
+        None
+    } else {
+        Some(short.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_os_and_arch_are_known() {
+        assert!(!os().is_empty());
+        assert!(!arch().is_empty());
+    }
+
+    #[test]
+    fn test_hostname_has_no_domain() {
+        if let Some(name) = hostname() {
+            assert!(!name.contains('.'));
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::ffi::OsStr;
+use std::fmt::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Version of the `--porcelain` output of `list` and `status`
+///
+/// A released version never changes but by new kinds of records, which
+/// readers skip; anything else goes into a new version. The format is
+/// described in the README.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Version {
+    V1,
+}
+
+/// The first line of the output, naming the version
+pub fn header(version: Version) -> String {
+    match version {
+        Version::V1 => "# stau porcelain v1".to_string(),
+    }
+}
+
+/// A name or path as the last field of a record, quoted like git's
+/// `core.quotePath` when it has anything but printable ASCII in it
+///
+/// A quoted field is wrapped in double quotes, with `\"`, `\\`, `\t`, `\n`
+/// and `\r` escaped, and every other control character or byte of 0x80 and
+/// above written as a backslash and three octal digits. Every byte of the
+/// original survives, non-UTF-8 ones included.
+fn quote(field: &OsStr) -> String {
+    let bytes = field.as_bytes();
+    let plain = |b: u8| (0x20..0x7f).contains(&b) && b != b'"' && b != b'\\';
+    if bytes.iter().all(|&b| plain(b)) {
+        return field.to_string_lossy().into_owned();
+    }
+
+    let mut quoted = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b if plain(b) => quoted.push(b as char),
+            b => {
+                let _ = write!(quoted, "\\{:03o}", b);
+            }
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The record starting the packages checked in a target directory
+pub fn target(target_dir: &Path) -> String {
+    format!("target {}", quote(target_dir.as_os_str()))
+}
+
+/// The record of a package with its link counts
+pub fn package(name: &str, state: &str, counts: [usize; 4]) -> String {
+    let [links, installed, broken, conflicts] = counts;
+    format!(
+        "package {} {} {} {} {} {}",
+        state,
+        links,
+        installed,
+        broken,
+        conflicts,
+        quote(name.as_ref())
+    )
+}
+
+/// The record of a file of the package before it
+pub fn file(state: &str, target: &Path) -> String {
+    format!("file {} {}", state, quote(target.as_os_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_v1() {
+        assert_eq!(header(Version::V1), "# stau porcelain v1");
+        assert_eq!(
+            target(Path::new("/home/user/my home")),
+            "target /home/user/my home"
+        );
+        assert_eq!(
+            package("vim", "partial", [3, 2, 0, 1]),
+            "package partial 3 2 0 1 vim"
+        );
+        assert_eq!(
+            file("conflict", Path::new("/home/user/.vimrc")),
+            "file conflict /home/user/.vimrc"
+        );
+    }
+
+    #[test]
+    fn test_quote_paths_like_git() {
+        assert_eq!(
+            file("missing", Path::new("/home/user/a\nb")),
+            "file missing \"/home/user/a\\nb\""
+        );
+        assert_eq!(
+            quote(OsStr::new("say \"hi\"\t\\")),
+            "\"say \\\"hi\\\"\\t\\\\\""
+        );
+        assert_eq!(quote(OsStr::new("caf\u{e9}")), "\"caf\\303\\251\"");
+        assert_eq!(quote(OsStr::from_bytes(b"\xff")), "\"\\377\"");
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Why a directory can't be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Problem {
+    /// The directory is on a file system mounted read-only
+    ReadOnly,
+    /// The current user lacks write permission
+    NotWritable,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::ReadOnly => write!(f, "read-only file system"),
+            Problem::NotWritable => write!(f, "permission denied"),
+        }
+    }
+}
+
+/// The directories that can't be written, each with the paths below it that were to be changed
+#[derive(Debug, Default)]
+pub struct Report {
+    pub problems: BTreeMap<PathBuf, (Problem, Vec<PathBuf>)>,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Whether every problem could be solved with sudo
+    pub fn only_permissions(&self) -> bool {
+        self.problems
+            .values()
+            .all(|(problem, _)| *problem == Problem::NotWritable)
+    }
+
+    /// Number of paths affected by any problem
+    pub fn path_count(&self) -> usize {
+        self.problems.values().map(|(_, paths)| paths.len()).sum()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (dir, (problem, paths)) in &self.problems {
+            let count = match paths.len() {
+                1 => "1 path".to_string(),
+                n => format!("{} paths", n),
+            };
+            write!(f, "\n  {} ({}, {})", dir.display(), problem, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check every path that is about to be created or removed, before anything changes
+pub fn check<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Report {
+    let mut report = Report::default();
+    for path in paths {
+        let Some(dir) = nearest_existing_parent(path) else {
+            continue;
+        };
+        if let Some(problem) = check_dir(&dir) {
+            report
+                .problems
+                .entry(dir)
+                .or_insert_with(|| (problem, Vec::new()))
+                .1
+                .push(path.to_path_buf());
+        }
+    }
+    report
+}
+
+/// A file system without room for the files about to be copied onto it
+#[derive(Debug)]
+pub struct Shortage {
+    /// A directory on that file system
+    pub dir: PathBuf,
+    pub needed: u64,
+    pub available: u64,
+}
+
+/// Check that each file system has room for the files about to be copied
+/// onto it, given as destination paths with their sizes in bytes
+pub fn check_space<'a>(copies: impl IntoIterator<Item = (&'a Path, u64)>) -> Option<Shortage> {
+    // Copies onto the same file system add up
+    let mut needed: BTreeMap<u64, (PathBuf, u64)> = BTreeMap::new();
+    for (path, size) in copies {
+        let Some(dir) = nearest_existing_parent(path) else {
+            continue;
+        };
+        let Ok(metadata) = dir.metadata() else {
+            continue;
+        };
+        needed.entry(metadata.dev()).or_insert((dir, 0)).1 += size;
+    }
+
+    needed.into_values().find_map(|(dir, needed)| {
+        let available = free_space(&dir)?;
+        (needed > available).then_some(Shortage {
+            dir,
+            needed,
+            available,
+        })
+    })
+}
+
+/// Bytes available to the current user on the file system holding a directory
+fn free_space(dir: &Path) -> Option<u64> {
+    let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+
+    // SAFETY: statvfs only reads the NUL-terminated path and writes into the zeroed struct
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Check whether the current user can create or remove entries at a path
+pub fn can_write(path: &Path) -> bool {
+    nearest_existing_parent(path).is_some_and(|dir| check_dir(&dir).is_none())
+}
+
+/// The directory holding a path, or its nearest existing ancestor when that
+/// directory still has to be created
+fn nearest_existing_parent(path: &Path) -> Option<PathBuf> {
+    let mut dir = path.parent()?;
+    while dir.symlink_metadata().is_err() {
+        dir = dir.parent()?;
+    }
+    Some(dir.to_path_buf())
+}
+
+fn check_dir(dir: &Path) -> Option<Problem> {
+    let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+
+    // SAFETY: statvfs only reads the NUL-terminated path and writes into the zeroed struct
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut stat) } == 0 && stat.f_flag & libc::ST_RDONLY != 0
+    {
+        return Some(Problem::ReadOnly);
+    }
+
+    // SAFETY: access only reads the NUL-terminated path
+    if unsafe { libc::access(dir.as_ptr(), libc::W_OK) } != 0 {
+        return Some(Problem::NotWritable);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_can_write_checks_nearest_existing_ancestor() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(can_write(&temp_dir.path().join(".config/nvim/init.lua")));
+        assert!(!can_write(Path::new("/")));
+    }
+
+    #[test]
+    fn test_check_writable_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let vimrc = temp_dir.path().join(".vimrc");
+        let init = temp_dir.path().join(".config/nvim/init.lua");
+        assert!(check([vimrc.as_path(), init.as_path()]).is_empty());
+    }
+
+    #[test]
+    fn test_check_space() {
+        let temp_dir = TempDir::new().unwrap();
+        let vimrc = temp_dir.path().join(".vimrc");
+        let init = temp_dir.path().join(".config/nvim/init.lua");
+        assert!(check_space([(vimrc.as_path(), 1024), (init.as_path(), 1024)]).is_none());
+
+        let shortage = check_space([
+            (vimrc.as_path(), u64::MAX / 2),
+            (init.as_path(), u64::MAX / 2),
+        ])
+        .unwrap();
+        assert_eq!(shortage.dir, temp_dir.path());
+        assert_eq!(shortage.needed, u64::MAX - 1);
+    }
+
+    #[test]
+    fn test_report_lists_directories() {
+        let mut report = Report::default();
+        report.problems.insert(
+            PathBuf::from("/etc"),
+            (
+                Problem::NotWritable,
+                vec![PathBuf::from("/etc/a"), PathBuf::from("/etc/b")],
+            ),
+        );
+        assert!(report.only_permissions());
+        report.problems.insert(
+            PathBuf::from("/mnt/ro"),
+            (Problem::ReadOnly, vec![PathBuf::from("/mnt/ro/c")]),
+        );
+
+        assert!(!report.only_permissions());
+        assert_eq!(report.path_count(), 3);
+        assert_eq!(
+            report.to_string(),
+            "\n  /etc (permission denied, 2 paths)\n  /mnt/ro (read-only file system, 1 path)"
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use crate::error::{Result, StauError};
+use crate::jobs;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by `--yes` to answer every question without asking
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Answer every following question affirmatively instead of reading stdin
+pub fn set_assume_yes(yes: bool) {
+    ASSUME_YES.store(yes, Ordering::SeqCst);
+}
+
+/// Check whether questions are answered without asking
+pub fn assume_yes() -> bool {
+    ASSUME_YES.load(Ordering::SeqCst)
+}
+
+/// Ask a yes/no question on stdin, defaulting to "no"
+///
+/// With `--yes`, the question is printed and answered "yes" without asking.
+pub fn confirm(question: &str) -> Result<bool> {
+    if assume_yes() {
+        outln!("{} [y/N] yes", question);
+        return Ok(true);
+    }
+    jobs::one_at_a_time(|| confirm_with(question, &mut io::stdin().lock(), &mut io::stdout()))
+}
+
+/// Ask a yes/no question using the given reader and writer
+///
+/// Only an explicit "y" or "yes" (case-insensitive) counts as agreement;
+/// an empty answer or end of input means "no".
+pub fn confirm_with(
+    question: &str,
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> Result<bool> {
+    write!(writer, "{} [y/N] ", question).map_err(StauError::Io)?;
+    writer.flush().map_err(StauError::Io)?;
+
+    let mut answer = String::new();
+    if reader.read_line(&mut answer).map_err(StauError::Io)? == 0 {
+        writeln!(writer).map_err(StauError::Io)?;
+        return Ok(false);
+    }
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Ask for a line of free-form input on stdin
+///
+/// With `--yes`, `assumed` is answered without asking.
+pub fn ask(question: &str, assumed: &str) -> Result<String> {
+    if assume_yes() {
+        outln!("{} {}", question, assumed);
+        return Ok(assumed.to_string());
+    }
+    jobs::one_at_a_time(|| ask_with(question, &mut io::stdin().lock(), &mut io::stdout()))
+}
+
+/// Ask for a line of free-form input using the given reader and writer
+///
+/// Returns the trimmed answer, which is empty at end of input.
+pub fn ask_with(
+    question: &str,
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> Result<String> {
+    write!(writer, "{} ", question).map_err(StauError::Io)?;
+    writer.flush().map_err(StauError::Io)?;
+
+    let mut answer = String::new();
+    if reader.read_line(&mut answer).map_err(StauError::Io)? == 0 {
+        writeln!(writer).map_err(StauError::Io)?;
+    }
+    Ok(answer.trim().to_string())
+}
+
+/// Ask the user to pick one of several choices on stdin, defaulting to the first
+///
+/// With `--yes`, the `affirmative` choice is picked without asking.
+pub fn choose(question: &str, choices: &[&str], affirmative: usize) -> Result<usize> {
+    if assume_yes() {
+        outln!("{} {}", question, choices[affirmative]);
+        return Ok(affirmative);
+    }
+    jobs::one_at_a_time(|| {
+        choose_with(
+            question,
+            choices,
+            &mut io::stdin().lock(),
+            &mut io::stdout(),
+        )
+    })
+}
+
+/// Ask the user to pick one of several choices using the given reader and writer
+///
+/// A choice is picked by its name or its first letter. An empty answer or end
+/// of input picks the first choice; anything else asks again.
+pub fn choose_with(
+    question: &str,
+    choices: &[&str],
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> Result<usize> {
+    let options: Vec<String> = choices
+        .iter()
+        .map(|choice| format!("[{}]{}", &choice[..1], &choice[1..]))
+        .collect();
+
+    loop {
+        write!(
+            writer,
+            "{} {}? [{}] ",
+            question,
+            options.join(", "),
+            &choices[0][..1]
+        )
+        .map_err(StauError::Io)?;
+        writer.flush().map_err(StauError::Io)?;
+
+        let mut answer = String::new();
+        if reader.read_line(&mut answer).map_err(StauError::Io)? == 0 {
+            writeln!(writer).map_err(StauError::Io)?;
+            return Ok(0);
+        }
+
+        let answer = answer.trim().to_lowercase();
+        if answer.is_empty() {
+            return Ok(0);
+        }
+        if let Some(index) = choices
+            .iter()
+            .position(|choice| *choice == answer || choice[..1] == answer)
+        {
+            return Ok(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ask(input: &str) -> (bool, String) {
+        let mut output = Vec::new();
+        let answer = confirm_with("Delete it?", &mut input.as_bytes(), &mut output).unwrap();
+        (answer, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_confirm_yes() {
+        assert!(ask("y\n").0);
+        assert!(ask("YES\n").0);
+    }
+
+    #[test]
+    fn test_confirm_defaults_to_no() {
+        assert!(!ask("\n").0);
+        assert!(!ask("nope\n").0);
+        assert!(!ask("").0);
+    }
+
+    #[test]
+    fn test_ask_trims_answer() {
+        let mut output = Vec::new();
+        let answer = ask_with("Which?", &mut " 1 3 \n".as_bytes(), &mut output).unwrap();
+        assert_eq!(answer, "1 3");
+        assert_eq!(String::from_utf8(output).unwrap(), "Which? ");
+        assert_eq!(
+            ask_with("Which?", &mut "".as_bytes(), &mut Vec::new()).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_choose_by_name_or_letter() {
+        let choices = ["fail", "skip", "backup"];
+        let mut output = Vec::new();
+        let pick = |input: &str, output: &mut Vec<u8>| {
+            choose_with("Conflict.", &choices, &mut input.as_bytes(), output).unwrap()
+        };
+
+        assert_eq!(pick("backup\n", &mut output), 2);
+        assert_eq!(pick("S\n", &mut output), 1);
+        assert_eq!(pick("\n", &mut output), 0);
+        assert_eq!(pick("", &mut output), 0);
+        assert_eq!(pick("what\nb\n", &mut output), 2);
+
+        output.clear();
+        pick("f\n", &mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Conflict. [f]ail, [s]kip, [b]ackup? [f] "
+        );
+    }
+
+    #[test]
+    fn test_confirm_prints_question() {
+        let (_, output) = ask("n\n");
+        assert_eq!(output, "Delete it? [y/N] ");
+    }
+}
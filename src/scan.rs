@@ -0,0 +1,348 @@
+use crate::error::{Result, StauError};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Dotfiles in the target that hold caches, history, secrets or tool data
+/// rather than configuration, and are never offered for adoption
+const IGNORED: [&str; 24] = [
+    ".cache",
+    ".local",
+    ".config",
+    ".ssh",
+    ".gnupg",
+    ".pki",
+    ".Trash",
+    ".DS_Store",
+    ".cargo",
+    ".rustup",
+    ".npm",
+    ".node-gyp",
+    ".pyenv",
+    ".rbenv",
+    ".bash_history",
+    ".zsh_history",
+    ".python_history",
+    ".node_repl_history",
+    ".lesshst",
+    ".viminfo",
+    ".wget-hsts",
+    ".sudo_as_admin_successful",
+    ".Xauthority",
+    ".ICEauthority",
+];
+
+/// Package names for well-known dotfiles
+const KNOWN_PACKAGES: [(&str, &str); 14] = [
+    (".bashrc", "bash"),
+    (".bash_profile", "bash"),
+    (".bash_aliases", "bash"),
+    (".bash_logout", "bash"),
+    (".profile", "bash"),
+    (".zshrc", "zsh"),
+    (".zshenv", "zsh"),
+    (".zprofile", "zsh"),
+    (".zlogin", "zsh"),
+    (".vimrc", "vim"),
+    (".vim", "vim"),
+    (".gitconfig", "git"),
+    (".gitignore_global", "git"),
+    (".tmux.conf", "tmux"),
+];
+
+/// A file or directory that could be adopted, with the package suggested for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub package: String,
+}
+
+/// Find files and directories in `dir` that look like configuration and stau doesn't manage
+///
+/// In the target directory itself only dotfiles are considered, and the
+/// entries of `.config` are offered one by one. Any other directory is
+/// scanned as is. Symlinks, directories holding links into `stau_dirs`, and
+/// the `own` directories stau keeps its data in along with anything
+/// containing them, are left out.
+pub fn find_candidates(
+    dir: &Path,
+    target_dir: &Path,
+    stau_dirs: &[PathBuf],
+    own: &[PathBuf],
+) -> Result<Vec<Candidate>> {
+    let mut candidates = Vec::new();
+
+    if dir == target_dir {
+        for path in read_entries(dir)? {
+            let name = file_name(&path);
+            if name.starts_with('.') && !IGNORED.contains(&name.as_str()) {
+                candidates.push(candidate(path));
+            }
+        }
+        let config_dir = dir.join(".config");
+        if config_dir.is_dir() && !config_dir.is_symlink() {
+            candidates.extend(read_entries(&config_dir)?.into_iter().map(candidate));
+        }
+    } else {
+        candidates.extend(read_entries(dir)?.into_iter().map(candidate));
+    }
+
+    let own: Vec<PathBuf> = own.iter().map(|path| canonical(path)).collect();
+    candidates.retain(|candidate| {
+        let path = canonical(&candidate.path);
+        !own.iter().any(|own| own.starts_with(&path))
+            && !holds_managed_links(&candidate.path, stau_dirs)
+    });
+    candidates.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(candidates)
+}
+
+/// List the entries of a directory, leaving out symlinks
+fn read_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(StauError::Io)? {
+        let entry = entry.map_err(StauError::Io)?;
+        if !entry.file_type().map_err(StauError::Io)?.is_symlink() {
+            paths.push(entry.path());
+        }
+    }
+    Ok(paths)
+}
+
+/// A path with symlinks resolved, or as given if it doesn't exist
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Check whether a directory contains a symlink into one of the dotfiles directories
+fn holds_managed_links(path: &Path, stau_dirs: &[PathBuf]) -> bool {
+    let Ok(entries) = fs::read_dir(path) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_symlink() => fs::read_link(&path)
+                .is_ok_and(|dest| stau_dirs.iter().any(|dir| dest.starts_with(dir))),
+            Ok(file_type) if file_type.is_dir() => holds_managed_links(&path, stau_dirs),
+            _ => false,
+        }
+    })
+}
+
+/// Find every symlink below `dir` that points into one of the dotfiles directories
+///
+/// Returns each link with its destination, sorted by path. Symlinked
+/// directories aren't followed, the dotfiles directories themselves are
+/// skipped and directories that can't be read are left out.
+pub fn find_links_into(dir: &Path, stau_dirs: &[PathBuf]) -> Vec<(PathBuf, PathBuf)> {
+    let mut links = Vec::new();
+    collect_links_into(dir, stau_dirs, &mut links);
+    links.sort();
+    links
+}
+
+fn collect_links_into(dir: &Path, stau_dirs: &[PathBuf], links: &mut Vec<(PathBuf, PathBuf)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_symlink() => {
+                if let Ok(dest) = fs::read_link(&path)
+                    && stau_dirs.iter().any(|dir| dest.starts_with(dir))
+                {
+                    links.push((path, dest));
+                }
+            }
+            Ok(file_type) if file_type.is_dir() && !stau_dirs.contains(&path) => {
+                collect_links_into(&path, stau_dirs, links);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn candidate(path: PathBuf) -> Candidate {
+    let package = suggest_package(&file_name(&path));
+    Candidate { path, package }
+}
+
+/// Suggest a package name for a dotfile, e.g. "zsh" for ".zshrc"
+pub fn suggest_package(name: &str) -> String {
+    if let Some((_, package)) = KNOWN_PACKAGES.iter().find(|(file, _)| *file == name) {
+        return package.to_string();
+    }
+
+    let name = name.trim_start_matches('.');
+    let name = name.split('.').next().unwrap_or(name);
+    let name = match name.strip_suffix("rc") {
+        Some(stem) if !stem.is_empty() => stem,
+        _ => name,
+    };
+    name.to_string()
+}
+
+/// Parse the user's choice of candidates, e.g. "1 3-5 7=shell" or "all"
+///
+/// Numbers start at 1. `=name` picks a different package than suggested.
+/// Returns the chosen indices with their package override, if any.
+pub fn parse_selection(input: &str, count: usize) -> Result<Vec<(usize, Option<String>)>> {
+    let invalid = |part: &str| StauError::Other(format!("Invalid selection: {}", part));
+    let mut chosen: Vec<(usize, Option<String>)> = Vec::new();
+
+    for part in input.split([' ', ',']).filter(|part| !part.is_empty()) {
+        let (range, package) = match part.split_once('=') {
+            Some((range, package)) if !package.is_empty() => (range, Some(package.to_string())),
+            Some(_) => return Err(invalid(part)),
+            None => (part, None),
+        };
+
+        let (first, last) = if range == "all" {
+            (1, count)
+        } else if let Some((first, last)) = range.split_once('-') {
+            let first = first.parse().map_err(|_| invalid(part))?;
+            let last = last.parse().map_err(|_| invalid(part))?;
+            (first, last)
+        } else {
+            let number = range.parse().map_err(|_| invalid(part))?;
+            (number, number)
+        };
+        if first == 0 || first > last || last > count {
+            return Err(invalid(part));
+        }
+
+        for index in first - 1..last {
+            chosen.retain(|(i, _)| *i != index);
+            chosen.push((index, package.clone()));
+        }
+    }
+
+    chosen.sort_by_key(|(index, _)| *index);
+    Ok(chosen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs as unix_fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_candidates_in_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let home = temp_dir.path();
+        let stau_dir = temp_dir.path().join("dotfiles");
+        fs::create_dir_all(stau_dir.join("fish")).unwrap();
+        fs::create_dir_all(home.join(".config/nvim")).unwrap();
+        fs::create_dir_all(home.join(".config/fish")).unwrap();
+        unix_fs::symlink(stau_dir.join("fish"), home.join(".config/fish/config.fish")).unwrap();
+        fs::create_dir_all(home.join(".cache")).unwrap();
+        fs::create_dir_all(home.join("Documents")).unwrap();
+        fs::write(home.join(".zshrc"), "").unwrap();
+        fs::write(home.join(".bash_history"), "").unwrap();
+        unix_fs::symlink(home.join(".zshrc"), home.join(".vimrc")).unwrap();
+
+        let candidates = find_candidates(home, home, &[stau_dir], &[]).unwrap();
+        assert_eq!(
+            candidates,
+            vec![
+                Candidate {
+                    path: home.join(".config/nvim"),
+                    package: "nvim".to_string()
+                },
+                Candidate {
+                    path: home.join(".zshrc"),
+                    package: "zsh".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_candidates_leaves_out_own_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let home = temp_dir.path();
+        let stau_dir = home.join(".dotfiles");
+        let state_dir = home.join(".stau-state/stau");
+        fs::create_dir_all(stau_dir.join("zsh")).unwrap();
+        fs::create_dir_all(&state_dir).unwrap();
+        fs::write(home.join(".zshrc"), "").unwrap();
+
+        let own = [stau_dir.clone(), state_dir];
+        let candidates = find_candidates(home, home, &[stau_dir], &own).unwrap();
+        assert_eq!(
+            candidates,
+            vec![Candidate {
+                path: home.join(".zshrc"),
+                package: "zsh".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_find_links_into_skips_dotfiles_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let home = temp_dir.path();
+        let stau_dir = home.join("dotfiles");
+        fs::create_dir_all(stau_dir.join("vim")).unwrap();
+        fs::write(stau_dir.join("vim/.vimrc"), "").unwrap();
+        unix_fs::symlink(stau_dir.join("vim/.vimrc"), stau_dir.join("vim/.exrc")).unwrap();
+        fs::create_dir_all(home.join(".config/nvim")).unwrap();
+        unix_fs::symlink(stau_dir.join("vim/.vimrc"), home.join(".vimrc")).unwrap();
+        unix_fs::symlink(
+            stau_dir.join("nvim/init.lua"),
+            home.join(".config/nvim/init.lua"),
+        )
+        .unwrap();
+        unix_fs::symlink(home.join(".vimrc"), home.join(".exrc")).unwrap();
+        unix_fs::symlink(home.join(".config"), home.join("config")).unwrap();
+
+        assert_eq!(
+            find_links_into(home, std::slice::from_ref(&stau_dir)),
+            vec![
+                (
+                    home.join(".config/nvim/init.lua"),
+                    stau_dir.join("nvim/init.lua")
+                ),
+                (home.join(".vimrc"), stau_dir.join("vim/.vimrc")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_suggest_package() {
+        assert_eq!(suggest_package(".bash_profile"), "bash");
+        assert_eq!(suggest_package(".inputrc"), "input");
+        assert_eq!(suggest_package(".rc"), "rc");
+        assert_eq!(suggest_package(".alacritty.toml"), "alacritty");
+        assert_eq!(suggest_package("starship.toml"), "starship");
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        assert_eq!(
+            parse_selection("3 1-2=shell", 4).unwrap(),
+            vec![
+                (0, Some("shell".to_string())),
+                (1, Some("shell".to_string())),
+                (2, None),
+            ]
+        );
+        assert_eq!(
+            parse_selection("all", 2).unwrap(),
+            vec![(0, None), (1, None)]
+        );
+        assert!(parse_selection("", 2).unwrap().is_empty());
+        assert!(parse_selection("0", 2).is_err());
+        assert!(parse_selection("3", 2).is_err());
+        assert!(parse_selection("1=", 2).is_err());
+        assert!(parse_selection("vim", 2).is_err());
+    }
+}
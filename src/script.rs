@@ -1,74 +1,476 @@
-use crate::error::{Result, StauError};
-use std::path::Path;
-use std::process::Command;
+use crate::error::{Result, StauError, stderr_block};
+use crate::interrupt;
+use crate::jobs;
+use crate::output::{self, Stream};
+use crate::platform;
+use crate::scriptlog;
+use crate::warning;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
-/// Execute a setup or teardown script
-pub fn execute_script(
+/// How long a script may take to exit after being asked to stop on Ctrl-C
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// How many of its last stderr lines a failing script's error shows
+const STDERR_TAIL_LINES: usize = 20;
+
+/// The kind of package script being executed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptKind {
+    /// Runs before any symlinks are created; failure aborts the install
+    PreInstall,
+    /// Runs after symlinks are created
+    Setup,
+    /// Runs before anything else on uninstall; failure aborts the uninstall
+    CheckUninstall,
+    /// Runs before symlinks are removed; failure only warns
+    Teardown,
+    /// Runs after symlinks are removed; failure only warns
+    PostUninstall,
+    /// Runs after files are adopted, with their paths in the package; failure only warns
+    PostAdopt,
+    /// Runs from STAU_DIR's .stau/ before any command that changes files; failure aborts it
+    PreCommand,
+}
+
+impl ScriptKind {
+    /// Base file name of the script, without OS suffix or extension
+    pub fn base_name(&self) -> &'static str {
+        match self {
+            ScriptKind::PreInstall => "pre-install",
+            ScriptKind::Setup => "setup",
+            ScriptKind::CheckUninstall => "check-uninstall",
+            ScriptKind::Teardown => "teardown",
+            ScriptKind::PostUninstall => "post-uninstall",
+            ScriptKind::PostAdopt => "post-adopt",
+            ScriptKind::PreCommand => "pre-command",
+        }
+    }
+
+    /// Human-readable name used in messages (e.g. "Pre-install")
+    pub fn label(&self) -> &'static str {
+        match self {
+            ScriptKind::PreInstall => "Pre-install",
+            ScriptKind::Setup => "Setup",
+            ScriptKind::CheckUninstall => "Check-uninstall",
+            ScriptKind::Teardown => "Teardown",
+            ScriptKind::PostUninstall => "Post-uninstall",
+            ScriptKind::PostAdopt => "Post-adopt",
+            ScriptKind::PreCommand => "Pre-command",
+        }
+    }
+
+    /// Whether the script may live directly in the package root
+    ///
+    /// Only the original setup.sh/teardown.sh hooks are looked up in the root;
+    /// newer hooks live in the scripts/ or .stau/ directory.
+    pub fn allowed_in_root(&self) -> bool {
+        matches!(self, ScriptKind::Setup | ScriptKind::Teardown)
+    }
+
+    /// Whether variants run most-specific first (undo order)
+    pub fn runs_reversed(&self) -> bool {
+        matches!(self, ScriptKind::Teardown | ScriptKind::PostUninstall)
+    }
+
+    /// Whether the script runs before or after the links change (`STAU_PHASE`)
+    pub fn phase(&self) -> &'static str {
+        match self {
+            ScriptKind::PreInstall
+            | ScriptKind::CheckUninstall
+            | ScriptKind::Teardown
+            | ScriptKind::PreCommand => "pre",
+            ScriptKind::Setup | ScriptKind::PostUninstall | ScriptKind::PostAdopt => "post",
+        }
+    }
+}
+
+/// The command a script runs for, passed to it as `STAU_OPERATION`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Install,
+    Uninstall,
+    Restow,
+    Adopt,
+    Upgrade,
+    Run,
+    Rm,
+    Fmt,
+    Clean,
+    Sync,
+    Restore,
+    Gc,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Install => "install",
+            Operation::Uninstall => "uninstall",
+            Operation::Restow => "restow",
+            Operation::Adopt => "adopt",
+            Operation::Upgrade => "upgrade",
+            Operation::Run => "run",
+            Operation::Rm => "rm",
+            Operation::Fmt => "fmt",
+            Operation::Clean => "clean",
+            Operation::Sync => "sync",
+            Operation::Restore => "restore",
+            Operation::Gc => "gc",
+        }
+    }
+}
+
+/// Package and environment information passed to every script
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptContext<'a> {
+    pub package: &'a str,
+    pub stau_dir: &'a Path,
+    pub target_dir: &'a Path,
+    pub dry_run: bool,
+    pub verbose: bool,
+    /// User to run scripts as through sudo (`run_as` in stau.toml)
+    pub run_as: Option<&'a str>,
+    pub operation: Operation,
+    /// State directory to keep a log of each hook script's output in
+    pub state_dir: Option<&'a Path>,
+}
+
+impl ScriptContext<'_> {
+    /// Build a command for a script with the working directory and STAU_* environment set
+    ///
+    /// Hooks also get the phase of their kind. With `run_as`, the script runs
+    /// through `sudo -n -u`, keeping the STAU_* variables.
+    fn command(&self, script_path: &Path, kind: Option<ScriptKind>) -> Command {
+        let hostname = platform::hostname().unwrap_or_default();
+        let mut env = vec![
+            ("STAU_DIR", self.stau_dir.as_os_str()),
+            ("STAU_PACKAGE", OsStr::new(self.package)),
+            ("STAU_TARGET", self.target_dir.as_os_str()),
+            ("STAU_OS", OsStr::new(platform::os())),
+            ("STAU_ARCH", OsStr::new(platform::arch())),
+            ("STAU_HOSTNAME", OsStr::new(&hostname)),
+            ("STAU_OPERATION", OsStr::new(self.operation.as_str())),
+        ];
+        if let Some(kind) = kind {
+            env.push(("STAU_PHASE", OsStr::new(kind.phase())));
+        }
+
+        let mut command = match self.run_as {
+            Some(user) => {
+                let names: Vec<&str> = env.iter().map(|(name, _)| *name).collect();
+                let mut command = Command::new("sudo");
+                command
+                    .args(["-n", "-u", user])
+                    .arg(format!("--preserve-env={}", names.join(",")))
+                    .arg("--")
+                    .arg(script_path);
+                command
+            }
+            None => Command::new(script_path),
+        };
+        command.current_dir(self.target_dir).envs(env);
+        command
+    }
+
+    /// Check that sudo lets scripts run as `run_as` without asking for a password
+    ///
+    /// With sudo run non-interactively, a password it needs would otherwise
+    /// only show up as a failing script.
+    fn check_run_as(&self) -> Result<()> {
+        let Some(user) = self.run_as else {
+            return Ok(());
+        };
+        let output = Command::new("sudo")
+            .args(["-n", "-u", user, "--", "true"])
+            .stdin(Stdio::null())
+            .output();
+        let message = match output {
+            Ok(output) if output.status.success() => return Ok(()),
+            Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            Err(e) => format!("cannot run sudo: {}", e),
+        };
+        Err(StauError::RunAsFailed {
+            package: self.package.to_string(),
+            user: user.to_string(),
+            message,
+        })
+    }
+
+    /// Describe who a script runs as, for verbose output
+    fn as_user(&self) -> String {
+        self.run_as
+            .map(|user| format!(" (as {})", user))
+            .unwrap_or_default()
+    }
+}
+
+/// Map a failure to spawn a script to a StauError
+fn spawn_error(script_path: &Path, e: std::io::Error) -> StauError {
+    if e.kind() == std::io::ErrorKind::PermissionDenied {
+        StauError::PermissionDenied(format!(
+            "Cannot execute script: {}. Make sure it's executable (chmod +x)",
+            script_path.display()
+        ))
+    } else {
+        StauError::Io(e)
+    }
+}
+
+/// Send a signal to a script and everything it started
+fn signal_group(child: &Child, signal: libc::c_int) {
+    // SAFETY: kill has no memory-safety preconditions. The script leads its
+    // own process group (see run_interruptible), so this reaches its children.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), signal);
+    }
+}
+
+/// Wait for a script, stopping it and its children if the user presses Ctrl-C
+fn wait_interruptible(child: &mut Child) -> std::io::Result<ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if interrupt::is_interrupted() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    signal_group(child, libc::SIGTERM);
+    let deadline = Instant::now() + KILL_GRACE;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    signal_group(child, libc::SIGKILL);
+    child.wait()
+}
+
+/// Run a script with captured output, like `Command::output`, but stoppable with Ctrl-C
+///
+/// The script runs in its own process group so that everything it started
+/// can be stopped along with it.
+fn run_interruptible(command: &mut Command) -> std::io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+
+    // Drain both pipes concurrently so a chatty script can't block on a full pipe
+    let readers = [
+        child
+            .stdout
+            .take()
+            .map(|r| Box::new(r) as Box<dyn Read + Send>),
+        child
+            .stderr
+            .take()
+            .map(|r| Box::new(r) as Box<dyn Read + Send>),
+    ]
+    .map(|reader| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut reader) = reader {
+                let _ = reader.read_to_end(&mut buf);
+            }
+            buf
+        })
+    });
+
+    let status = wait_interruptible(&mut child)?;
+    let [stdout, stderr] = readers.map(|reader| reader.join().unwrap_or_default());
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// Split what a failing script wrote to stderr into the earlier lines and the
+/// last ones, which go into its error
+fn split_stderr(stderr: &[u8]) -> (Vec<String>, Vec<String>) {
+    let mut lines: Vec<String> = String::from_utf8_lossy(stderr)
+        .lines()
+        .map(str::to_string)
+        .collect();
+    let tail = lines.split_off(lines.len().saturating_sub(STDERR_TAIL_LINES));
+    (lines, tail)
+}
+
+/// Where to find the stderr lines left out of a script's error
+fn earlier_lines(count: usize, log: Option<&Path>) -> String {
+    let lines = if count == 1 { "line" } else { "lines" };
+    match log {
+        _ if count == 0 => String::new(),
+        Some(log) => format!(
+            " ({} earlier {} of stderr in {})",
+            count,
+            lines,
+            log.display()
+        ),
+        None => format!(" ({} earlier {} of stderr above)", count, lines),
+    }
+}
+
+/// Execute a hook script (setup, teardown, pre-install, ...)
+pub fn execute_script(script_path: &Path, kind: ScriptKind, ctx: &ScriptContext) -> Result<()> {
+    execute_script_with_args(script_path, kind, ctx, &[])
+}
+
+/// Execute a hook script, passing it arguments (e.g. the files a post-adopt hook is about)
+pub fn execute_script_with_args(
     script_path: &Path,
-    package_name: &str,
-    stau_dir: &Path,
-    target_dir: &Path,
-    dry_run: bool,
-    verbose: bool,
+    kind: ScriptKind,
+    ctx: &ScriptContext,
+    args: &[PathBuf],
 ) -> Result<()> {
-    if dry_run {
-        if verbose {
-            println!("Would execute: {}", script_path.display());
+    if ctx.dry_run {
+        if ctx.verbose {
+            outln!("Would execute: {}", script_path.display());
         }
         return Ok(());
     }
 
-    if verbose {
-        println!("Executing: {}", script_path.display());
-    }
-
-    let output = Command::new(script_path)
-        .current_dir(target_dir)
-        .env("STAU_DIR", stau_dir)
-        .env("STAU_PACKAGE", package_name)
-        .env("STAU_TARGET", target_dir)
-        .output()
-        .map_err(|e| {
-            if e.kind() == std::io::ErrorKind::PermissionDenied {
-                StauError::PermissionDenied(format!(
-                    "Cannot execute script: {}. Make sure it's executable (chmod +x)",
-                    script_path.display()
-                ))
-            } else {
-                StauError::Io(e)
-            }
-        })?;
+    if ctx.verbose {
+        outln!("Executing: {}{}", script_path.display(), ctx.as_user());
+    }
+
+    let _span = tracing::info_span!("script", path = %script_path.display()).entered();
+    ctx.check_run_as()?;
+    let output =
+        jobs::one_at_a_time(|| run_interruptible(ctx.command(script_path, Some(kind)).args(args)))
+            .map_err(|e| spawn_error(script_path, e))?;
+    let failed = !output.status.success() && !interrupt::is_interrupted();
 
-    // Print stdout and stderr
     if !output.stdout.is_empty() {
-        print!("{}", String::from_utf8_lossy(&output.stdout));
+        output::write(
+            Stream::Stdout,
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+        );
     }
-    if !output.stderr.is_empty() {
-        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+
+    let mut log = None;
+    if let Some(state_dir) = ctx.state_dir {
+        match scriptlog::save(
+            state_dir,
+            ctx.package,
+            kind.base_name(),
+            script_path,
+            &output,
+        ) {
+            Ok(path) => log = Some(path),
+            Err(e) => warning::warn(format!("Cannot save script log: {}", e))?,
+        }
     }
 
-    // Check exit status
-    if !output.status.success() {
-        let script_type = if script_path.ends_with("setup.sh") {
-            "setup"
-        } else {
-            "teardown"
-        };
+    // A failing script's last stderr lines go into the error, the earlier
+    // ones are printed unless the log has them
+    let (earlier, stderr) = if failed {
+        split_stderr(&output.stderr)
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    if !failed && !output.stderr.is_empty() {
+        output::write(
+            Stream::Stderr,
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        );
+    } else if !earlier.is_empty() && log.is_none() {
+        output::write(Stream::Stderr, format!("{}\n", earlier.join("\n")));
+    }
 
+    interrupt::check()?;
+
+    // Check exit status
+    if failed {
         let exit_code = output.status.code().unwrap_or(-1);
-        let message = format!("{} script failed with exit code {}", script_type, exit_code);
+        let message = format!(
+            "{} script {} failed with exit code {}{}",
+            kind.base_name(),
+            script_path.display(),
+            exit_code,
+            earlier_lines(earlier.len(), log.as_deref())
+        );
 
-        if script_type == "setup" {
-            return Err(StauError::SetupScriptFailed {
-                package: package_name.to_string(),
+        return Err(match kind {
+            ScriptKind::Setup => StauError::SetupScriptFailed {
+                package: ctx.package.to_string(),
                 message,
-            });
-        } else {
-            return Err(StauError::TeardownScriptFailed {
-                package: package_name.to_string(),
+                stderr,
+            },
+            ScriptKind::Teardown => StauError::TeardownScriptFailed {
+                package: ctx.package.to_string(),
                 message,
-            });
-        }
+                stderr,
+            },
+            ScriptKind::CheckUninstall => StauError::UninstallAborted {
+                package: ctx.package.to_string(),
+                message,
+                stderr,
+            },
+            ScriptKind::PreCommand => {
+                StauError::CommandAborted(format!("{}{}", message, stderr_block(&stderr)))
+            }
+            ScriptKind::PreInstall | ScriptKind::PostUninstall | ScriptKind::PostAdopt => {
+                StauError::ScriptFailed {
+                    package: ctx.package.to_string(),
+                    script: kind.base_name().to_string(),
+                    message,
+                    stderr,
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Execute a named package script with inherited stdio and extra arguments
+pub fn run_named_script(
+    script_path: &Path,
+    script_name: &str,
+    args: &[String],
+    ctx: &ScriptContext,
+) -> Result<()> {
+    if ctx.dry_run {
+        outln!(
+            "Would execute: {} {}",
+            script_path.display(),
+            args.join(" ")
+        );
+        return Ok(());
+    }
+
+    if ctx.verbose {
+        outln!("Executing: {}{}", script_path.display(), ctx.as_user());
+    }
+
+    ctx.check_run_as()?;
+    let status = ctx
+        .command(script_path, None)
+        .args(args)
+        .status()
+        .map_err(|e| spawn_error(script_path, e))?;
+
+    if !status.success() {
+        return Err(StauError::ScriptFailed {
+            package: ctx.package.to_string(),
+            script: script_name.to_string(),
+            message: format!("script exited with code {}", status.code().unwrap_or(-1)),
+            stderr: Vec::new(),
+        });
     }
 
     Ok(())
@@ -113,7 +515,20 @@ mod tests {
 
         create_script(&script_path, "#!/bin/bash\necho 'Setup running'\nexit 0\n");
 
-        let result = execute_script(&script_path, "test", &stau_dir, &target_dir, false, false);
+        let result = execute_script(
+            &script_path,
+            ScriptKind::Setup,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
 
         assert!(result.is_ok());
     }
@@ -130,7 +545,20 @@ mod tests {
 
         create_script(&script_path, "#!/bin/bash\nexit 1\n");
 
-        let result = execute_script(&script_path, "test", &stau_dir, &target_dir, false, false);
+        let result = execute_script(
+            &script_path,
+            ScriptKind::Setup,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
 
         assert!(result.is_err());
         assert!(matches!(
@@ -139,6 +567,67 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_failing_script_error_shows_stderr_tail() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("setup.sh");
+        let stau_dir = temp_dir.path().join("stau");
+        let target_dir = temp_dir.path().join("target");
+        let state_dir = temp_dir.path().join("state");
+
+        fs::create_dir(&stau_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        create_script(
+            &script_path,
+            "#!/bin/bash\nfor i in $(seq 1 25); do echo \"error $i\" >&2; done\nexit 3\n",
+        );
+
+        let message = execute_script(
+            &script_path,
+            ScriptKind::Setup,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: Some(&state_dir),
+            },
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(message.contains("failed with exit code 3 (5 earlier lines of stderr in "));
+        assert!(message.contains(&state_dir.join("logs/test/setup-").display().to_string()));
+        assert!(message.contains(":\n  error 6\n"));
+        assert!(message.contains("\n  error 25\n"));
+        assert!(!message.contains("error 5\n"));
+    }
+
+    #[test]
+    fn test_split_stderr() {
+        assert_eq!(split_stderr(b""), (Vec::new(), Vec::new()));
+        assert_eq!(
+            split_stderr(b"first\nsecond\n"),
+            (Vec::new(), vec!["first".to_string(), "second".to_string()])
+        );
+        let long: String = (1..=21).map(|i| format!("{}\n", i)).collect();
+        let (earlier, tail) = split_stderr(long.as_bytes());
+        assert_eq!(earlier, ["1"]);
+        assert_eq!(tail.len(), STDERR_TAIL_LINES);
+        assert_eq!(tail[0], "2");
+
+        assert_eq!(earlier_lines(0, None), "");
+        assert_eq!(earlier_lines(1, None), " (1 earlier line of stderr above)");
+        assert_eq!(
+            earlier_lines(5, Some(Path::new("/state/logs/vim/setup.log"))),
+            " (5 earlier lines of stderr in /state/logs/vim/setup.log)"
+        );
+    }
+
     #[test]
     fn test_execute_failing_teardown_script() {
         let temp_dir = TempDir::new().unwrap();
@@ -151,7 +640,20 @@ mod tests {
 
         create_script(&script_path, "#!/bin/bash\nexit 1\n");
 
-        let result = execute_script(&script_path, "test", &stau_dir, &target_dir, false, false);
+        let result = execute_script(
+            &script_path,
+            ScriptKind::Teardown,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
 
         assert!(result.is_err());
         assert!(matches!(
@@ -160,6 +662,111 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_failing_os_specific_setup_script() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("setup.linux.sh");
+        let stau_dir = temp_dir.path().join("stau");
+        let target_dir = temp_dir.path().join("target");
+
+        fs::create_dir(&stau_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        create_script(&script_path, "#!/bin/bash\nexit 3\n");
+
+        let result = execute_script(
+            &script_path,
+            ScriptKind::Setup,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
+
+        match result.unwrap_err() {
+            StauError::SetupScriptFailed { message, .. } => {
+                assert!(message.contains("setup.linux.sh"));
+                assert!(message.contains("exit code 3"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_failing_pre_install_script() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("pre-install.sh");
+        let stau_dir = temp_dir.path().join("stau");
+        let target_dir = temp_dir.path().join("target");
+
+        fs::create_dir(&stau_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        create_script(&script_path, "#!/bin/bash\nexit 1\n");
+
+        let result = execute_script(
+            &script_path,
+            ScriptKind::PreInstall,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
+
+        match result.unwrap_err() {
+            StauError::ScriptFailed { script, .. } => assert_eq!(script, "pre-install"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_failing_check_uninstall_script() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("check-uninstall.sh");
+        let stau_dir = temp_dir.path().join("stau");
+        let target_dir = temp_dir.path().join("target");
+
+        fs::create_dir(&stau_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        create_script(
+            &script_path,
+            "#!/bin/bash\necho 'gpg-agent running' >&2\nexit 1\n",
+        );
+
+        let result = execute_script(
+            &script_path,
+            ScriptKind::CheckUninstall,
+            &ScriptContext {
+                package: "gpg",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
+
+        assert!(matches!(
+            result.unwrap_err(),
+            StauError::UninstallAborted { .. }
+        ));
+    }
+
     #[test]
     fn test_dry_run_skips_execution() {
         let temp_dir = TempDir::new().unwrap();
@@ -174,7 +781,20 @@ mod tests {
         create_script(&script_path, "#!/bin/bash\nexit 1\n");
 
         // In dry run, it should not execute and should succeed
-        let result = execute_script(&script_path, "test", &stau_dir, &target_dir, true, false);
+        let result = execute_script(
+            &script_path,
+            ScriptKind::Setup,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: true,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
 
         assert!(result.is_ok());
     }
@@ -203,11 +823,17 @@ mod tests {
 
         execute_script(
             &script_path,
-            "test_package",
-            &stau_dir,
-            &target_dir,
-            false,
-            false,
+            ScriptKind::Setup,
+            &ScriptContext {
+                package: "test_package",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
         )
         .unwrap();
 
@@ -219,6 +845,168 @@ mod tests {
         assert_eq!(lines[2], target_dir.to_str().unwrap());
     }
 
+    #[test]
+    fn test_run_named_script_with_args() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("update-plugins.sh");
+        let stau_dir = temp_dir.path().join("stau");
+        let target_dir = temp_dir.path().join("target");
+        let output_file = temp_dir.path().join("args.txt");
+
+        fs::create_dir(&stau_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        create_script(
+            &script_path,
+            &format!(
+                "#!/bin/bash\necho \"$STAU_PACKAGE $*\" > {}\n",
+                output_file.display()
+            ),
+        );
+
+        run_named_script(
+            &script_path,
+            "update-plugins",
+            &["--quiet".to_string(), "lsp".to_string()],
+            &ScriptContext {
+                package: "nvim",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&output_file).unwrap().trim(),
+            "nvim --quiet lsp"
+        );
+    }
+
+    #[test]
+    fn test_run_named_script_failure() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("broken.sh");
+        let stau_dir = temp_dir.path().join("stau");
+        let target_dir = temp_dir.path().join("target");
+
+        fs::create_dir(&stau_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        create_script(&script_path, "#!/bin/bash\nexit 2\n");
+
+        let result = run_named_script(
+            &script_path,
+            "broken",
+            &[],
+            &ScriptContext {
+                package: "nvim",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
+        assert!(matches!(
+            result.unwrap_err(),
+            StauError::ScriptFailed { .. }
+        ));
+    }
+
+    #[test]
+    fn test_script_receives_operation_and_phase() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("hook.sh");
+        let stau_dir = temp_dir.path().join("stau");
+        let target_dir = temp_dir.path().join("target");
+        let output_file = temp_dir.path().join("context.txt");
+
+        fs::create_dir(&stau_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        create_script(
+            &script_path,
+            &format!(
+                "#!/bin/bash\necho \"$STAU_OPERATION ${{STAU_PHASE:-none}}\" >> {}\n",
+                output_file.display()
+            ),
+        );
+
+        let ctx = ScriptContext {
+            package: "test",
+            stau_dir: &stau_dir,
+            target_dir: &target_dir,
+            dry_run: false,
+            verbose: false,
+            run_as: None,
+            operation: Operation::Restow,
+            state_dir: None,
+        };
+        execute_script(&script_path, ScriptKind::PreInstall, &ctx).unwrap();
+        execute_script(&script_path, ScriptKind::Setup, &ctx).unwrap();
+        let ctx = ScriptContext {
+            operation: Operation::Run,
+            state_dir: None,
+            ..ctx
+        };
+        run_named_script(&script_path, "hook", &[], &ctx).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&output_file).unwrap(),
+            "restow pre\nrestow post\nrun none\n"
+        );
+    }
+
+    #[test]
+    fn test_script_receives_platform_variables() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("setup.sh");
+        let stau_dir = temp_dir.path().join("stau");
+        let target_dir = temp_dir.path().join("target");
+        let output_file = temp_dir.path().join("platform.txt");
+
+        fs::create_dir(&stau_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        create_script(
+            &script_path,
+            &format!(
+                "#!/bin/bash\necho \"$STAU_OS\" > {0}\necho \"$STAU_ARCH\" >> {0}\necho \"$STAU_HOSTNAME\" >> {0}\n",
+                output_file.display()
+            ),
+        );
+
+        execute_script(
+            &script_path,
+            ScriptKind::Setup,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        )
+        .unwrap();
+
+        let contents = fs::read_to_string(&output_file).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+
+        assert_eq!(lines[0], std::env::consts::OS);
+        assert_eq!(lines[1], std::env::consts::ARCH);
+        assert_eq!(lines[2], platform::hostname().unwrap_or_default());
+    }
+
     #[test]
     fn test_non_executable_script() {
         let temp_dir = TempDir::new().unwrap();
@@ -235,7 +1023,20 @@ mod tests {
         drop(file);
 
         // Should fail with permission denied
-        let result = execute_script(&script_path, "test", &stau_dir, &target_dir, false, false);
+        let result = execute_script(
+            &script_path,
+            ScriptKind::Setup,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
         assert!(result.is_err());
         assert!(matches!(
             result.unwrap_err(),
@@ -259,7 +1060,89 @@ mod tests {
             "#!/bin/bash\necho 'stdout message'\necho 'stderr message' >&2\nexit 0\n",
         );
 
-        let result = execute_script(&script_path, "test", &stau_dir, &target_dir, false, false);
+        let result = execute_script(
+            &script_path,
+            ScriptKind::Setup,
+            &ScriptContext {
+                package: "test",
+                stau_dir: &stau_dir,
+                target_dir: &target_dir,
+                dry_run: false,
+                verbose: false,
+                run_as: None,
+                operation: Operation::Install,
+                state_dir: None,
+            },
+        );
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_run_as_goes_through_sudo() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("setup.sh");
+        let mut ctx = ScriptContext {
+            package: "postgres",
+            stau_dir: temp_dir.path(),
+            target_dir: temp_dir.path(),
+            dry_run: false,
+            verbose: false,
+            run_as: None,
+            operation: Operation::Install,
+            state_dir: None,
+        };
+        assert_eq!(ctx.command(&script_path, None).get_program(), script_path);
+
+        ctx.run_as = Some("postgres");
+        let command = ctx.command(&script_path, Some(ScriptKind::Setup));
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(command.get_program(), "sudo");
+        assert_eq!(&args[..3], ["-n", "-u", "postgres"]);
+        assert!(
+            args[3]
+                .to_string_lossy()
+                .starts_with("--preserve-env=STAU_DIR,STAU_PACKAGE,STAU_TARGET,")
+        );
+        assert!(
+            args[3]
+                .to_string_lossy()
+                .ends_with(",STAU_OPERATION,STAU_PHASE")
+        );
+        assert_eq!(&args[4..], [OsStr::new("--"), script_path.as_os_str()]);
+        assert!(
+            command.get_envs().any(
+                |(name, value)| name == "STAU_PACKAGE" && value == Some(OsStr::new("postgres"))
+            )
+        );
+    }
+
+    #[test]
+    fn test_run_as_fails_clearly_when_sudo_cannot_run_it() {
+        let temp_dir = TempDir::new().unwrap();
+        let script_path = temp_dir.path().join("setup.sh");
+        let marker = temp_dir.path().join("ran");
+        create_script(
+            &script_path,
+            &format!("#!/bin/sh\ntouch {}\n", marker.display()),
+        );
+        let ctx = ScriptContext {
+            package: "postgres",
+            stau_dir: temp_dir.path(),
+            target_dir: temp_dir.path(),
+            dry_run: false,
+            verbose: false,
+            run_as: Some("stau-no-such-user"),
+            operation: Operation::Install,
+            state_dir: None,
+        };
+
+        let err = execute_script(&script_path, ScriptKind::Setup, &ctx).unwrap_err();
+        assert!(
+            matches!(&err, StauError::RunAsFailed { user, .. } if user == "stau-no-such-user"),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains("sudo -v"));
+        assert!(!marker.exists());
+    }
 }
@@ -0,0 +1,254 @@
+use crate::error::{Result, StauError};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+/// Directory inside the state directory that holds script output logs
+const LOG_DIR: &str = "logs";
+
+/// Format of the timestamp that ends every log file name
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// Number of logs kept for each hook of a package
+const KEEP_PER_HOOK: usize = 10;
+
+/// Output of a package's hook scripts from one run, kept in
+/// `logs/<package>/<hook>-<timestamp>.log`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptLog {
+    pub package: String,
+    /// Base name of the hook (e.g. "setup")
+    pub hook: String,
+    pub timestamp: String,
+    pub path: PathBuf,
+}
+
+impl ScriptLog {
+    /// When the scripts ran, read from the file name
+    pub fn created(&self) -> Option<chrono::NaiveDateTime> {
+        chrono::NaiveDateTime::parse_from_str(&self.timestamp, TIMESTAMP_FORMAT).ok()
+    }
+}
+
+/// Append a script's output to the log of this run of the hook and return its path
+///
+/// The OS- and host-specific variants of a hook run within the same second
+/// end up in the same file, one after the other. Only the latest
+/// `KEEP_PER_HOOK` logs of the hook are kept.
+pub fn save(
+    state_dir: &Path,
+    package: &str,
+    hook: &str,
+    script_path: &Path,
+    output: &Output,
+) -> Result<PathBuf> {
+    let dir = state_dir.join(LOG_DIR).join(package);
+    fs::create_dir_all(&dir).map_err(StauError::Io)?;
+
+    let timestamp = chrono::Local::now().format(TIMESTAMP_FORMAT);
+    let path = dir.join(format!("{}-{}.log", hook, timestamp));
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(StauError::Io)?;
+
+    let exit = match output.status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => "killed by a signal".to_string(),
+    };
+    let mut contents = format!("== {} ({})\n", script_path.display(), exit);
+    for (name, stream) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+        if !stream.is_empty() {
+            contents.push_str(&format!("-- {}\n", name));
+            contents.push_str(&String::from_utf8_lossy(stream));
+            if !contents.ends_with('\n') {
+                contents.push('\n');
+            }
+        }
+    }
+    file.write_all(contents.as_bytes()).map_err(StauError::Io)?;
+
+    prune(state_dir, package, hook)?;
+    Ok(path)
+}
+
+/// Remove all but the latest `KEEP_PER_HOOK` logs of a package's hook
+fn prune(state_dir: &Path, package: &str, hook: &str) -> Result<()> {
+    let old = list(state_dir, Some(package))?
+        .into_iter()
+        .filter(|log| log.hook == hook)
+        .skip(KEEP_PER_HOOK);
+    for log in old {
+        fs::remove_file(&log.path).map_err(StauError::Io)?;
+    }
+    Ok(())
+}
+
+/// All saved logs, or those of one package, newest first
+pub fn list(state_dir: &Path, package: Option<&str>) -> Result<Vec<ScriptLog>> {
+    let root = state_dir.join(LOG_DIR);
+    let packages = match package {
+        Some(package) => vec![package.to_string()],
+        None if root.is_dir() => {
+            let mut packages = Vec::new();
+            for entry in fs::read_dir(&root).map_err(StauError::Io)? {
+                let entry = entry.map_err(StauError::Io)?;
+                if entry.path().is_dir() {
+                    packages.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+            packages
+        }
+        None => Vec::new(),
+    };
+
+    let mut logs = Vec::new();
+    for package in packages {
+        let dir = root.join(&package);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir).map_err(StauError::Io)? {
+            let path = entry.map_err(StauError::Io)?.path();
+            let Some(stem) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".log"))
+            else {
+                continue;
+            };
+            // The timestamp is the last 15 characters, after a dash
+            let Some((hook, timestamp)) = stem
+                .len()
+                .checked_sub(16)
+                .and_then(|split| Some((stem.get(..split)?, stem.get(split + 1..)?)))
+            else {
+                continue;
+            };
+            logs.push(ScriptLog {
+                package: package.clone(),
+                hook: hook.to_string(),
+                timestamp: timestamp.to_string(),
+                path: path.clone(),
+            });
+        }
+    }
+
+    logs.sort_by(|a, b| {
+        (&b.timestamp, &b.package, &b.hook).cmp(&(&a.timestamp, &a.package, &a.hook))
+    });
+    Ok(logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use tempfile::TempDir;
+
+    fn output(code: i32, stdout: &str, stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_save_appends_variants_of_a_hook() {
+        let temp_dir = TempDir::new().unwrap();
+        let setup = Path::new("/dotfiles/vim/setup.sh");
+        let setup_linux = Path::new("/dotfiles/vim/setup.linux.sh");
+
+        let path = save(
+            temp_dir.path(),
+            "vim",
+            "setup",
+            setup,
+            &output(0, "ok\n", ""),
+        )
+        .unwrap();
+        let again = save(
+            temp_dir.path(),
+            "vim",
+            "setup",
+            setup_linux,
+            &output(2, "", "missing plugin"),
+        )
+        .unwrap();
+        assert!(path.starts_with(temp_dir.path().join("logs/vim")));
+
+        // Both ran in the same second unless the clock ticked in between
+        if path == again {
+            assert_eq!(
+                fs::read_to_string(&path).unwrap(),
+                "== /dotfiles/vim/setup.sh (exit code 0)\n-- stdout\nok\n\
+                 == /dotfiles/vim/setup.linux.sh (exit code 2)\n-- stderr\nmissing plugin\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_save_keeps_latest_logs_of_each_hook() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("logs/vim");
+        fs::create_dir_all(&dir).unwrap();
+        for day in 1..=KEEP_PER_HOOK {
+            fs::write(dir.join(format!("setup-202501{:02}-120000.log", day)), "").unwrap();
+        }
+        fs::write(dir.join("teardown-20250101-120000.log"), "").unwrap();
+
+        let path = save(
+            temp_dir.path(),
+            "vim",
+            "setup",
+            Path::new("/dotfiles/vim/setup.sh"),
+            &output(0, "ok\n", ""),
+        )
+        .unwrap();
+
+        let logs = list(temp_dir.path(), Some("vim")).unwrap();
+        let setup: Vec<_> = logs.iter().filter(|log| log.hook == "setup").collect();
+        assert_eq!(setup.len(), KEEP_PER_HOOK);
+        assert_eq!(setup[0].path, path);
+        assert!(!dir.join("setup-20250101-120000.log").exists());
+        assert!(dir.join("setup-20250102-120000.log").exists());
+        // Other hooks keep their own logs
+        assert!(dir.join("teardown-20250101-120000.log").exists());
+    }
+
+    #[test]
+    fn test_list_newest_first() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(list(temp_dir.path(), None).unwrap().is_empty());
+
+        let logs = temp_dir.path().join("logs");
+        fs::create_dir_all(logs.join("vim")).unwrap();
+        fs::create_dir_all(logs.join("git")).unwrap();
+        fs::write(logs.join("vim/setup-20260101-120000.log"), "").unwrap();
+        fs::write(logs.join("vim/pre-install-20260301-080000.log"), "").unwrap();
+        fs::write(logs.join("git/teardown-20260201-090000.log"), "").unwrap();
+        fs::write(logs.join("git/notes.txt"), "").unwrap();
+
+        let all = list(temp_dir.path(), None).unwrap();
+        let names: Vec<_> = all
+            .iter()
+            .map(|log| format!("{} {} {}", log.package, log.hook, log.timestamp))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "vim pre-install 20260301-080000",
+                "git teardown 20260201-090000",
+                "vim setup 20260101-120000",
+            ]
+        );
+        assert_eq!(all[0].created().unwrap().to_string(), "2026-03-01 08:00:00");
+
+        assert_eq!(list(temp_dir.path(), Some("git")).unwrap().len(), 1);
+        assert!(list(temp_dir.path(), Some("zsh")).unwrap().is_empty());
+    }
+}
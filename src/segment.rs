@@ -0,0 +1,146 @@
+use crate::error::{Result, StauError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File name of the cached prompt segment inside the state directory
+const CACHE_FILE: &str = "prompt.json";
+
+/// How long a cached segment is shown without checking again
+pub const FRESH_FOR: Duration = Duration::from_secs(30);
+
+/// What `stau prompt` shows: problems worth noticing in a shell prompt
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    /// Target directory the counts were taken for
+    pub target: PathBuf,
+    /// Dangling symlinks of installed packages
+    pub broken: usize,
+    /// Packages with uncommitted or untracked changes
+    pub dirty: usize,
+    /// When the counts were taken, in seconds since the Unix epoch
+    pub checked: u64,
+}
+
+impl Segment {
+    /// Load the cached segment for a target, if there is a readable one
+    pub fn load(state_dir: &Path, target: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(state_dir.join(CACHE_FILE)).ok()?;
+        serde_json::from_str::<Segment>(&contents)
+            .ok()
+            .filter(|segment| segment.target == target)
+    }
+
+    /// Write the segment to the state directory, replacing the cached one atomically
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        fs::create_dir_all(state_dir).map_err(StauError::Io)?;
+        let contents = serde_json::to_string(self)
+            .map_err(|e| StauError::Other(format!("Cannot serialize prompt cache: {}", e)))?;
+
+        let path = state_dir.join(CACHE_FILE);
+        let tmp_path = state_dir.join(format!("{}.{}.tmp", CACHE_FILE, std::process::id()));
+        fs::write(&tmp_path, contents).map_err(StauError::Io)?;
+        fs::rename(&tmp_path, &path).map_err(StauError::Io)
+    }
+
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.checked) < FRESH_FOR.as_secs()
+    }
+}
+
+/// What a segment counts, per package, so single packages can be checked
+/// again as they change
+#[derive(Debug, Default)]
+pub struct Status {
+    /// Dangling symlinks of each package, none if it isn't installed
+    pub broken: BTreeMap<String, usize>,
+    /// Packages with uncommitted or untracked changes
+    pub dirty: BTreeSet<String>,
+}
+
+impl Status {
+    pub fn segment(&self, target: &Path, checked: u64) -> Segment {
+        Segment {
+            target: target.to_path_buf(),
+            broken: self.broken.values().sum(),
+            dirty: self.dirty.len(),
+            checked,
+        }
+    }
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Empty when there is nothing to report, so prompt modules hide it
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if self.broken > 0 {
+            parts.push(format!("{} broken", self.broken));
+        }
+        if self.dirty > 0 {
+            parts.push(format!("{} dirty", self.dirty));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_display() {
+        let mut segment = Segment::default();
+        assert_eq!(segment.to_string(), "");
+
+        segment.dirty = 1;
+        assert_eq!(segment.to_string(), "1 dirty");
+        segment.broken = 2;
+        assert_eq!(segment.to_string(), "2 broken 1 dirty");
+    }
+
+    #[test]
+    fn test_status_segment() {
+        let mut status = Status::default();
+        status.broken.insert("vim".to_string(), 2);
+        status.broken.insert("zsh".to_string(), 1);
+        status.dirty.insert("zsh".to_string());
+
+        let segment = status.segment(Path::new("/home/user"), 100);
+        assert_eq!(segment.to_string(), "3 broken 1 dirty");
+        assert_eq!(segment.target, Path::new("/home/user"));
+        assert_eq!(segment.checked, 100);
+    }
+
+    #[test]
+    fn test_save_and_load_for_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_dir = temp_dir.path().join("state");
+        let home = temp_dir.path().join("home");
+        assert!(Segment::load(&state_dir, &home).is_none());
+
+        let segment = Segment {
+            target: home.clone(),
+            broken: 1,
+            dirty: 0,
+            checked: 100,
+        };
+        segment.save(&state_dir).unwrap();
+
+        assert_eq!(Segment::load(&state_dir, &home), Some(segment.clone()));
+        assert!(Segment::load(&state_dir, &temp_dir.path().join("other")).is_none());
+        assert!(segment.is_fresh(100 + FRESH_FOR.as_secs() - 1));
+        assert!(!segment.is_fresh(100 + FRESH_FOR.as_secs()));
+    }
+}
@@ -0,0 +1,335 @@
+use crate::conflict::ConflictPolicy;
+use crate::error::{Result, StauError};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Keys accepted by `stau config`, with a short description of each
+pub const KEYS: [(&str, &str); 10] = [
+    (
+        "stau_dir",
+        "Dotfiles directory, or several separated by ':' (STAU_DIR)",
+    ),
+    ("target", "Default target directory (STAU_TARGET)"),
+    (
+        "state_dir",
+        "Directory for per-machine state (STAU_STATE_DIR)",
+    ),
+    (
+        "create_target",
+        "Create a missing target directory on install (true/false)",
+    ),
+    (
+        "adopt_commit",
+        "Commit adopted files to the STAU_DIR git repository (true/false)",
+    ),
+    (
+        "conflicts",
+        "How to handle existing files (fail/skip/backup/overwrite/adopt/prompt)",
+    ),
+    (
+        "gitignore",
+        "Leave out package files matched by .gitignore (true/false)",
+    ),
+    (
+        "skip",
+        "Files never linked from a package root, e.g. LICENSE,Makefile,*.nix",
+    ),
+    (
+        "junk",
+        "Files never linked anywhere in a package (default .DS_Store,Thumbs.db,*.swp,...)",
+    ),
+    (
+        "machines",
+        "Record each host's installed packages in STAU_DIR/.stau/machines.toml (true/false)",
+    ),
+];
+
+/// Settings stored in the global config file
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stau_dir: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub create_target: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adopt_commit: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflicts: Option<ConflictPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gitignore: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub junk: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machines: Option<bool>,
+}
+
+impl Settings {
+    /// Get the path of the global config file
+    ///
+    /// Uses `STAU_CONFIG` if set, otherwise `$XDG_CONFIG_HOME/stau/config.toml`,
+    /// falling back to `~/.config/stau/config.toml`.
+    pub fn path() -> Result<PathBuf> {
+        if let Some(path) = env::var_os("STAU_CONFIG")
+            && !path.is_empty()
+        {
+            return Ok(PathBuf::from(path));
+        }
+
+        if let Ok(dir) = env::var("XDG_CONFIG_HOME")
+            && !dir.is_empty()
+        {
+            return Ok(PathBuf::from(dir).join("stau/config.toml"));
+        }
+
+        env::var("HOME")
+            .map(|home| PathBuf::from(home).join(".config/stau/config.toml"))
+            .map_err(|_| StauError::Other("HOME environment variable not set".to_string()))
+    }
+
+    /// Load settings from a config file, returning empty settings if it doesn't exist
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Settings::default());
+        }
+
+        let contents = fs::read_to_string(path).map_err(StauError::Io)?;
+        toml::from_str(&contents)
+            .map_err(|e| StauError::InvalidConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Write settings to a config file, creating its directory if needed
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(StauError::Io)?;
+        }
+
+        let contents = toml::to_string_pretty(self)
+            .map_err(|e| StauError::Other(format!("Cannot serialize config: {}", e)))?;
+        fs::write(path, contents).map_err(StauError::Io)
+    }
+
+    /// Get the value of a key as a string, or None if it is not set
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let value = match key {
+            "create_target" => return Ok(self.create_target.map(|value| value.to_string())),
+            "adopt_commit" => return Ok(self.adopt_commit.map(|value| value.to_string())),
+            "conflicts" => return Ok(self.conflicts.map(|policy| policy.to_string())),
+            "gitignore" => return Ok(self.gitignore.map(|value| value.to_string())),
+            "machines" => return Ok(self.machines.map(|value| value.to_string())),
+            "skip" => return Ok(self.skip.as_ref().map(|names| names.join(","))),
+            "junk" => return Ok(self.junk.as_ref().map(|names| names.join(","))),
+            "stau_dir" => &self.stau_dir,
+            "target" => &self.target,
+            "state_dir" => &self.state_dir,
+            _ => return Err(unknown_key(key)),
+        };
+        Ok(value.as_ref().map(|path| path.display().to_string()))
+    }
+
+    /// Validate and set the value of a key
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "stau_dir" => {
+                let dirs: Vec<PathBuf> = env::split_paths(value)
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .map(|dir| expand_home(&dir))
+                    .collect::<Result<_>>()?;
+                if dirs.is_empty() {
+                    return Err(invalid_value(key, value, "expected a directory"));
+                }
+                if let Some(dir) = dirs.iter().find(|dir| !dir.is_absolute() || !dir.is_dir()) {
+                    return Err(invalid_value(
+                        key,
+                        value,
+                        &format!("{} is not an existing absolute directory", dir.display()),
+                    ));
+                }
+                let joined = env::join_paths(&dirs)
+                    .map_err(|e| invalid_value(key, value, &e.to_string()))?;
+                self.stau_dir = Some(PathBuf::from(joined));
+            }
+            "target" | "state_dir" => {
+                let path = expand_home(Path::new(value))?;
+                if !path.is_absolute() {
+                    return Err(invalid_value(key, value, "expected an absolute path"));
+                }
+                if key == "target" {
+                    self.target = Some(path);
+                } else {
+                    self.state_dir = Some(path);
+                }
+            }
+            "create_target" | "adopt_commit" | "gitignore" | "machines" => {
+                let flag = value
+                    .parse()
+                    .map_err(|_| invalid_value(key, value, "expected true or false"))?;
+                match key {
+                    "create_target" => self.create_target = Some(flag),
+                    "adopt_commit" => self.adopt_commit = Some(flag),
+                    "gitignore" => self.gitignore = Some(flag),
+                    _ => self.machines = Some(flag),
+                }
+            }
+            "conflicts" => {
+                let policy = value.parse().map_err(|_| {
+                    invalid_value(
+                        key,
+                        value,
+                        "expected fail, skip, backup, overwrite, adopt or prompt",
+                    )
+                })?;
+                self.conflicts = Some(policy);
+            }
+            "skip" => self.skip = Some(parse_patterns(key, value)?),
+            "junk" => self.junk = Some(parse_patterns(key, value)?),
+            _ => return Err(unknown_key(key)),
+        }
+        Ok(())
+    }
+}
+
+/// Expand a leading `~` to the home directory
+pub fn expand_home(path: &Path) -> Result<PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(rest) => env::var("HOME")
+            .map(|home| PathBuf::from(home).join(rest))
+            .map_err(|_| StauError::Other("HOME environment variable not set".to_string())),
+        Err(_) => Ok(path.to_path_buf()),
+    }
+}
+
+/// Parse a comma-separated list of file names or glob patterns
+fn parse_patterns(key: &str, value: &str) -> Result<Vec<String>> {
+    let names: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    if let Some(e) = names.iter().find_map(|name| glob::Pattern::new(name).err()) {
+        return Err(invalid_value(key, value, &e.to_string()));
+    }
+    Ok(names)
+}
+
+fn unknown_key(key: &str) -> StauError {
+    StauError::InvalidConfig(format!("unknown key '{}'", key))
+}
+
+fn invalid_value(key: &str, value: &str, reason: &str) -> StauError {
+    StauError::InvalidConfig(format!("invalid value '{}' for {}: {}", value, key, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_missing_config() {
+        let temp_dir = TempDir::new().unwrap();
+        let settings = Settings::load(&temp_dir.path().join("config.toml")).unwrap();
+        assert!(settings.target.is_none());
+    }
+
+    #[test]
+    fn test_set_save_and_load() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("stau/config.toml");
+
+        let mut settings = Settings::default();
+        settings.set("target", "/srv/home").unwrap();
+        settings.set("create_target", "true").unwrap();
+        settings.set("conflicts", "backup").unwrap();
+        settings.set("gitignore", "false").unwrap();
+        settings.set("machines", "true").unwrap();
+        settings.set("skip", "LICENSE, Makefile,*.nix").unwrap();
+        settings.set("junk", "").unwrap();
+        settings
+            .set("stau_dir", temp_dir.path().to_str().unwrap())
+            .unwrap();
+        settings.save(&path).unwrap();
+
+        let settings = Settings::load(&path).unwrap();
+        assert_eq!(
+            settings.get("target").unwrap().as_deref(),
+            Some("/srv/home")
+        );
+        assert_eq!(
+            settings.get("stau_dir").unwrap().as_deref(),
+            temp_dir.path().to_str()
+        );
+        assert_eq!(settings.get("state_dir").unwrap(), None);
+        assert_eq!(
+            settings.get("create_target").unwrap().as_deref(),
+            Some("true")
+        );
+        assert_eq!(
+            settings.get("conflicts").unwrap().as_deref(),
+            Some("backup")
+        );
+        assert_eq!(settings.get("gitignore").unwrap().as_deref(), Some("false"));
+        assert_eq!(settings.get("machines").unwrap().as_deref(), Some("true"));
+        assert_eq!(
+            settings.get("skip").unwrap().as_deref(),
+            Some("LICENSE,Makefile,*.nix")
+        );
+        assert_eq!(settings.get("junk").unwrap().as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_set_validates_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut settings = Settings::default();
+
+        assert!(matches!(
+            settings.set("target", "relative/path").unwrap_err(),
+            StauError::InvalidConfig(_)
+        ));
+        assert!(matches!(
+            settings
+                .set(
+                    "stau_dir",
+                    temp_dir.path().join("missing").to_str().unwrap()
+                )
+                .unwrap_err(),
+            StauError::InvalidConfig(_)
+        ));
+        assert!(matches!(
+            settings.set("create_target", "yes").unwrap_err(),
+            StauError::InvalidConfig(_)
+        ));
+        assert!(matches!(
+            settings.set("conflicts", "merge").unwrap_err(),
+            StauError::InvalidConfig(_)
+        ));
+        assert!(matches!(
+            settings.set("skip", "LICENSE,[").unwrap_err(),
+            StauError::InvalidConfig(_)
+        ));
+        assert!(matches!(
+            settings.set("color", "always").unwrap_err(),
+            StauError::InvalidConfig(_)
+        ));
+        assert!(settings.target.is_none());
+    }
+
+    #[test]
+    fn test_unknown_key_in_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("config.toml");
+        fs::write(&path, "colour = \"always\"\n").unwrap();
+
+        let result = Settings::load(&path);
+        assert!(matches!(result.unwrap_err(), StauError::InvalidConfig(_)));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::error::{Result, StauError};
+use crate::symlink;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory inside the state directory that holds snapshots
+const SNAPSHOT_DIR: &str = "snapshots";
+
+/// What was found at a target path when the snapshot was taken
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum EntryState {
+    /// A symlink and where it pointed
+    Symlink { destination: PathBuf },
+    /// A regular file, with its contents kept if the snapshot was archived
+    File { archived: bool },
+    /// A directory, which is never touched on restore
+    Directory,
+    /// Nothing
+    Missing,
+}
+
+/// The recorded state of one target path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub state: EntryState,
+}
+
+/// The state of all stau-managed target paths at one point in time
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub created: String,
+    pub entries: Vec<Entry>,
+}
+
+/// What restoring a snapshot changed
+#[derive(Debug, Default)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    pub removed: usize,
+    pub skipped: usize,
+}
+
+impl Snapshot {
+    /// Record the state of the given target paths
+    ///
+    /// With `archive`, the contents of regular files are copied into the
+    /// snapshot so they can be brought back later.
+    pub fn capture(
+        state_dir: &Path,
+        name: &str,
+        created: String,
+        paths: &[PathBuf],
+        archive: bool,
+    ) -> Result<Self> {
+        validate_name(name)?;
+        let mut entries = Vec::new();
+
+        for path in paths {
+            let state = match path.symlink_metadata() {
+                Err(_) => EntryState::Missing,
+                Ok(metadata) if metadata.is_symlink() => EntryState::Symlink {
+                    destination: fs::read_link(path).map_err(StauError::Io)?,
+                },
+                Ok(metadata) if metadata.is_dir() => EntryState::Directory,
+                Ok(_) => {
+                    if archive {
+                        let copy = archive_path(state_dir, name, path);
+                        symlink::copy_file(path, &copy, false)?;
+                    }
+                    EntryState::File { archived: archive }
+                }
+            };
+            entries.push(Entry {
+                path: path.clone(),
+                state,
+            });
+        }
+
+        Ok(Snapshot {
+            name: name.to_string(),
+            created,
+            entries,
+        })
+    }
+
+    /// Write the snapshot to the state directory, refusing to replace an existing one
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        let path = snapshot_file(state_dir, &self.name);
+        if path.exists() {
+            return Err(StauError::Other(format!(
+                "Snapshot '{}' already exists",
+                self.name
+            )));
+        }
+
+        fs::create_dir_all(state_dir.join(SNAPSHOT_DIR)).map_err(StauError::Io)?;
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| StauError::Other(format!("Cannot serialize snapshot: {}", e)))?;
+        fs::write(&path, contents).map_err(StauError::Io)
+    }
+
+    /// Load a snapshot by name
+    pub fn load(state_dir: &Path, name: &str) -> Result<Self> {
+        validate_name(name)?;
+        let path = snapshot_file(state_dir, name);
+        if !path.exists() {
+            return Err(StauError::SnapshotNotFound(name.to_string()));
+        }
+
+        let contents = fs::read_to_string(&path).map_err(StauError::Io)?;
+        serde_json::from_str(&contents).map_err(|e| {
+            StauError::Other(format!("Cannot parse snapshot {}: {}", path.display(), e))
+        })
+    }
+
+    /// List the names of all snapshots, oldest first
+    pub fn list(state_dir: &Path) -> Result<Vec<String>> {
+        let dir = state_dir.join(SNAPSHOT_DIR);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&dir).map_err(StauError::Io)? {
+            let path = entry.map_err(StauError::Io)?.path();
+            if path.extension().is_some_and(|ext| ext == "json")
+                && let Some(stem) = path.file_stem()
+            {
+                names.push(stem.to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Put every recorded path back the way it was
+    ///
+    /// Only symlinks into `stau_dirs` and missing paths are ever replaced, so
+    /// files the user created since the snapshot are left alone and counted
+    /// as skipped. `current` lists today's managed target paths; links among
+    /// them that the snapshot doesn't know about are removed.
+    pub fn restore(
+        &self,
+        state_dir: &Path,
+        stau_dirs: &[PathBuf],
+        current: &[PathBuf],
+        dry_run: bool,
+    ) -> Result<RestoreSummary> {
+        let mut summary = RestoreSummary::default();
+        let replaceable = |path: &Path| match path.symlink_metadata() {
+            Err(_) => true,
+            Ok(metadata) => {
+                metadata.is_symlink()
+                    && fs::read_link(path)
+                        .is_ok_and(|dest| stau_dirs.iter().any(|dir| dest.starts_with(dir)))
+            }
+        };
+
+        for entry in &self.entries {
+            if matches!(entry.state, EntryState::Directory) || current_matches(entry) {
+                continue;
+            }
+            if !replaceable(&entry.path) {
+                summary.skipped += 1;
+                continue;
+            }
+
+            match &entry.state {
+                EntryState::Symlink { destination } => {
+                    symlink::create_symlink_with_force(destination, &entry.path, dry_run, true)?;
+                    summary.restored += 1;
+                }
+                EntryState::File { archived: true } => {
+                    symlink::remove_existing(&entry.path, dry_run)?;
+                    let copy = archive_path(state_dir, &self.name, &entry.path);
+                    symlink::copy_file(&copy, &entry.path, dry_run)?;
+                    summary.restored += 1;
+                }
+                EntryState::File { archived: false } => summary.skipped += 1,
+                EntryState::Missing => {
+                    symlink::remove_existing(&entry.path, dry_run)?;
+                    summary.removed += 1;
+                }
+                EntryState::Directory => {}
+            }
+        }
+
+        for path in current {
+            let known = self.entries.iter().any(|entry| &entry.path == path);
+            if !known && path.symlink_metadata().is_ok() && replaceable(path) {
+                symlink::remove_existing(path, dry_run)?;
+                summary.removed += 1;
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Check whether a path is still in its recorded state
+fn current_matches(entry: &Entry) -> bool {
+    match &entry.state {
+        EntryState::Symlink { destination } => {
+            fs::read_link(&entry.path).is_ok_and(|dest| &dest == destination)
+        }
+        EntryState::Missing => entry.path.symlink_metadata().is_err(),
+        EntryState::File { .. } => entry.path.symlink_metadata().is_ok_and(|m| m.is_file()),
+        EntryState::Directory => true,
+    }
+}
+
+/// Check that a snapshot name can be used as a file name
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\0']) {
+        return Err(StauError::Other(format!("Invalid snapshot name: {}", name)));
+    }
+    Ok(())
+}
+
+fn snapshot_file(state_dir: &Path, name: &str) -> PathBuf {
+    state_dir.join(SNAPSHOT_DIR).join(format!("{}.json", name))
+}
+
+/// Where the archived copy of a file lives, mirroring its absolute path
+fn archive_path(state_dir: &Path, name: &str, path: &Path) -> PathBuf {
+    let relative = path.strip_prefix("/").unwrap_or(path);
+    state_dir
+        .join(SNAPSHOT_DIR)
+        .join(format!("{}.files", name))
+        .join(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs as unix_fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_capture_save_and_restore() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_dir = temp_dir.path().join("state");
+        let stau_dir = temp_dir.path().join("dotfiles");
+        let home = temp_dir.path().join("home");
+        fs::create_dir_all(stau_dir.join("vim")).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        fs::write(stau_dir.join("vim/.vimrc"), "set number").unwrap();
+        fs::write(stau_dir.join("vim/.gvimrc"), "set guifont").unwrap();
+
+        let vimrc = home.join(".vimrc");
+        let gvimrc = home.join(".gvimrc");
+        let exrc = home.join(".exrc");
+        unix_fs::symlink(stau_dir.join("vim/.vimrc"), &vimrc).unwrap();
+        fs::write(&gvimrc, "user file").unwrap();
+
+        let paths = vec![vimrc.clone(), gvimrc.clone(), exrc.clone()];
+        let snapshot = Snapshot::capture(&state_dir, "before", "now".into(), &paths, true).unwrap();
+        snapshot.save(&state_dir).unwrap();
+        assert_eq!(Snapshot::list(&state_dir).unwrap(), vec!["before"]);
+        assert!(snapshot.save(&state_dir).is_err());
+
+        // Restructure: .vimrc unlinked, .gvimrc replaced by a link, .exrc added
+        fs::remove_file(&vimrc).unwrap();
+        fs::remove_file(&gvimrc).unwrap();
+        unix_fs::symlink(stau_dir.join("vim/.gvimrc"), &gvimrc).unwrap();
+        unix_fs::symlink(stau_dir.join("vim/.vimrc"), &exrc).unwrap();
+
+        let snapshot = Snapshot::load(&state_dir, "before").unwrap();
+        let summary = snapshot
+            .restore(&state_dir, std::slice::from_ref(&stau_dir), &paths, false)
+            .unwrap();
+
+        assert_eq!(summary.restored, 2);
+        assert_eq!(summary.removed, 1);
+        assert_eq!(fs::read_link(&vimrc).unwrap(), stau_dir.join("vim/.vimrc"));
+        assert!(!gvimrc.is_symlink());
+        assert_eq!(fs::read_to_string(&gvimrc).unwrap(), "user file");
+        assert!(exrc.symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn test_restore_leaves_user_files_alone() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_dir = temp_dir.path().join("state");
+        let home = temp_dir.path().join("home");
+        fs::create_dir_all(&home).unwrap();
+
+        let zshrc = home.join(".zshrc");
+        let paths = vec![zshrc.clone()];
+        let snapshot = Snapshot::capture(&state_dir, "empty", "now".into(), &paths, false).unwrap();
+
+        fs::write(&zshrc, "mine").unwrap();
+        let summary = snapshot.restore(&state_dir, &[], &paths, false).unwrap();
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(fs::read_to_string(&zshrc).unwrap(), "mine");
+    }
+
+    #[test]
+    fn test_load_missing_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let result = Snapshot::load(temp_dir.path(), "nope");
+        assert!(matches!(
+            result.unwrap_err(),
+            StauError::SnapshotNotFound(_)
+        ));
+        assert!(Snapshot::load(temp_dir.path(), "../etc").is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::error::{Result, StauError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// File name of the state file inside the state directory
+const STATE_FILE: &str = "state.json";
+
+/// Per-machine state persisted between runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Hash of each package's setup scripts at their last successful run
+    #[serde(default)]
+    pub setup_hashes: BTreeMap<String, String>,
+    /// Discovery options each package was linked into each target with
+    #[serde(default)]
+    pub link_options: BTreeMap<String, BTreeMap<PathBuf, LinkOptions>>,
+    /// Directories each package has had links in, per target, where a restow
+    /// looks for links left behind by files removed from the package
+    #[serde(default)]
+    pub link_dirs: BTreeMap<String, BTreeMap<PathBuf, BTreeSet<PathBuf>>>,
+}
+
+/// Discovery options given on the command line when a package was linked,
+/// which later commands use to find the same links
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<NonZeroUsize>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub follow_symlinks: bool,
+}
+
+impl State {
+    /// Load state from the state directory, returning empty state if none exists
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(STATE_FILE);
+        if !path.exists() {
+            return Ok(State::default());
+        }
+
+        let contents = fs::read_to_string(&path).map_err(StauError::Io)?;
+        serde_json::from_str(&contents).map_err(|e| {
+            StauError::Other(format!("Cannot parse state file {}: {}", path.display(), e))
+        })
+    }
+
+    /// Write state to the state directory
+    ///
+    /// The file is written to a temporary path and renamed into place so that
+    /// concurrent readers never see a partially written file.
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        fs::create_dir_all(state_dir).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                StauError::PermissionDenied(format!(
+                    "Cannot create state directory: {}",
+                    state_dir.display()
+                ))
+            } else {
+                StauError::Io(e)
+            }
+        })?;
+
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| StauError::Other(format!("Cannot serialize state: {}", e)))?;
+
+        let path = state_dir.join(STATE_FILE);
+        let tmp_path = state_dir.join(format!("{}.{}.tmp", STATE_FILE, std::process::id()));
+        fs::write(&tmp_path, contents).map_err(StauError::Io)?;
+        fs::rename(&tmp_path, &path).map_err(StauError::Io)?;
+
+        Ok(())
+    }
+
+    /// The options a package was linked into a target with
+    pub fn link_options(&self, package: &str, target_dir: &Path) -> LinkOptions {
+        self.link_options
+            .get(package)
+            .and_then(|targets| targets.get(target_dir))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The directories a package has had links in within a target
+    pub fn link_dirs(&self, package: &str, target_dir: &Path) -> BTreeSet<PathBuf> {
+        self.link_dirs
+            .get(package)
+            .and_then(|targets| targets.get(target_dir))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Load state, apply a change and save it again
+    ///
+    /// Packages worked on concurrently update the state one at a time, so no
+    /// change gets lost.
+    pub fn update(state_dir: &Path, change: impl FnOnce(&mut State)) -> Result<()> {
+        static UPDATING: Mutex<()> = Mutex::new(());
+        let _guard = UPDATING.lock().unwrap_or_else(|e| e.into_inner());
+        let mut state = State::load(state_dir)?;
+        change(&mut state);
+        state.save(state_dir)
+    }
+}
+
+/// Compute a stable hex-encoded SHA-256 hash over the names and contents of files
+pub fn hash_files(paths: &[PathBuf]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for path in paths {
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(fs::read(path).map_err(StauError::Io)?);
+        hasher.update([0]);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_missing_state() {
+        let temp_dir = TempDir::new().unwrap();
+        let state = State::load(&temp_dir.path().join("state")).unwrap();
+        assert!(state.setup_hashes.is_empty());
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_dir = temp_dir.path().join("nested/state");
+
+        State::update(&state_dir, |state| {
+            state
+                .setup_hashes
+                .insert("vim".to_string(), "abc123".to_string());
+        })
+        .unwrap();
+
+        let state = State::load(&state_dir).unwrap();
+        assert_eq!(state.setup_hashes.get("vim").unwrap(), "abc123");
+    }
+
+    #[test]
+    fn test_link_options_per_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = LinkOptions {
+            max_depth: NonZeroUsize::new(2),
+            follow_symlinks: true,
+        };
+
+        State::update(temp_dir.path(), |state| {
+            state
+                .link_options
+                .entry("vim".to_string())
+                .or_default()
+                .insert(PathBuf::from("/home/user"), options.clone());
+        })
+        .unwrap();
+
+        let state = State::load(temp_dir.path()).unwrap();
+        assert_eq!(state.link_options("vim", Path::new("/home/user")), options);
+        assert_eq!(
+            state.link_options("vim", Path::new("/chroot")),
+            LinkOptions::default()
+        );
+        assert_eq!(
+            state.link_options("zsh", Path::new("/home/user")),
+            LinkOptions::default()
+        );
+    }
+
+    #[test]
+    fn test_corrupt_state_file() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join(STATE_FILE), "not json").unwrap();
+
+        let result = State::load(temp_dir.path());
+        assert!(matches!(result.unwrap_err(), StauError::Other(_)));
+    }
+
+    #[test]
+    fn test_hash_files_changes_with_content() {
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("setup.sh");
+
+        fs::write(&script, "echo one").unwrap();
+        let first = hash_files(std::slice::from_ref(&script)).unwrap();
+        let again = hash_files(std::slice::from_ref(&script)).unwrap();
+        assert_eq!(first, again);
+        assert_eq!(first.len(), 64);
+
+        fs::write(&script, "echo two").unwrap();
+        let second = hash_files(std::slice::from_ref(&script)).unwrap();
+        assert_ne!(first, second);
+    }
+}
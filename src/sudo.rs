@@ -0,0 +1,144 @@
+use crate::error::{Result, StauError};
+use crate::verbosity;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by `--sudo` to retry file operations that lack permission through sudo
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Allow the following file operations to fall back to sudo
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Check whether file operations may fall back to sudo
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Run a command through sudo, failing with its error output
+fn run(args: &[&std::ffi::OsStr]) -> Result<()> {
+    verbosity::trace(|| format!("sudo {}", display_args(args)));
+    let output = Command::new("sudo")
+        .arg("--")
+        .args(args)
+        .output()
+        .map_err(|e| StauError::Other(format!("Cannot run sudo: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(StauError::Other(format!(
+            "sudo {} failed: {}",
+            display_args(args),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+fn display_args(args: &[&std::ffi::OsStr]) -> String {
+    args.iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Fail if something is at a path a command run as root would write to
+///
+/// Commands run as root must not follow a symlink or descend into a directory
+/// that is in the way, so nothing may be there at all.
+fn refuse_existing(path: &Path) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(_) => Err(StauError::ConflictingFile(path.to_path_buf())),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Create the parent directories of a path through sudo
+fn create_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) => run(&[
+            "mkdir".as_ref(),
+            "-p".as_ref(),
+            "--".as_ref(),
+            parent.as_os_str(),
+        ]),
+        None => Ok(()),
+    }
+}
+
+/// Create a symlink and its parent directories through sudo
+///
+/// `-T` makes `ln` fail rather than create the link inside a directory that
+/// appeared at the target in the meantime.
+pub fn create_symlink(source: &Path, target: &Path) -> Result<()> {
+    refuse_existing(target)?;
+    create_parent(target)?;
+    run(&[
+        "ln".as_ref(),
+        "-snT".as_ref(),
+        "--".as_ref(),
+        source.as_os_str(),
+        target.as_os_str(),
+    ])
+}
+
+/// Copy a file or directory tree, keeping modes, and create its parent directories through sudo
+///
+/// Whatever appeared at the destination in the meantime is replaced rather
+/// than written through or copied into.
+pub fn copy_file(source: &Path, dest: &Path) -> Result<()> {
+    refuse_existing(dest)?;
+    create_parent(dest)?;
+    run(&[
+        "cp".as_ref(),
+        "-pRT".as_ref(),
+        "--no-dereference".as_ref(),
+        "--remove-destination".as_ref(),
+        "--".as_ref(),
+        source.as_os_str(),
+        dest.as_os_str(),
+    ])
+}
+
+/// Remove a file or symlink through sudo, never what a symlink points to
+///
+/// Directories are refused: removing a tree as root is left to the user.
+pub fn remove(path: &Path) -> Result<()> {
+    if path.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+        return Err(StauError::PermissionDenied(format!(
+            "Cannot remove directory {} through sudo; remove it yourself",
+            path.display()
+        )));
+    }
+    run(&["unlink".as_ref(), "--".as_ref(), path.as_os_str()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_refuses_paths_in_the_way() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        let dir = temp_dir.path().join("dir");
+        let link = temp_dir.path().join("link");
+        fs::write(&source, "").unwrap();
+        fs::create_dir(&dir).unwrap();
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+
+        // None of these get as far as running sudo
+        assert!(matches!(
+            create_symlink(&source, &dir),
+            Err(StauError::ConflictingFile(_))
+        ));
+        assert!(matches!(
+            copy_file(&source, &link),
+            Err(StauError::ConflictingFile(_))
+        ));
+        assert!(matches!(remove(&dir), Err(StauError::PermissionDenied(_))));
+    }
+}
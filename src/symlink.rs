@@ -1,4 +1,9 @@
 use crate::error::{Result, StauError};
+use crate::preflight;
+use crate::sudo;
+use crate::verbosity;
+use crate::xattr;
+use std::collections::{BTreeSet, HashSet};
 use std::fs;
 use std::os::unix::fs as unix_fs;
 use std::path::{Path, PathBuf};
@@ -20,34 +25,46 @@ impl SymlinkMapping {
 
 /// Check if a path is a symlink pointing to the expected target
 pub fn is_stau_symlink(path: &Path, expected_target: &Path) -> Result<bool> {
-    if !path.exists() && path.symlink_metadata().is_err() {
-        return Ok(false);
-    }
-
-    match path.symlink_metadata() {
-        Ok(metadata) => {
-            if metadata.is_symlink() {
-                match fs::read_link(path) {
-                    Ok(link_target) => Ok(link_target == expected_target),
-                    Err(_) => Ok(false),
-                }
-            } else {
-                Ok(false)
-            }
-        }
-        Err(_) => Ok(false),
-    }
+    Ok(TargetState::of(path).links_to(expected_target))
 }
 
 /// Check if a symlink is broken (points to non-existent file)
 pub fn is_broken_symlink(path: &Path) -> bool {
-    if let Ok(metadata) = path.symlink_metadata()
-        && metadata.is_symlink()
-    {
-        // Check if the target exists
-        return !path.exists();
+    TargetState::of(path).is_dangling(path)
+}
+
+/// What is at a target path, found with a single `lstat` and, for a
+/// symlink, a `readlink`, so several questions about it cost no more
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetState {
+    Missing,
+    /// A symlink, with where it points
+    Link(PathBuf),
+    /// A file, directory or anything else that isn't a symlink
+    Other,
+}
+
+impl TargetState {
+    pub fn of(path: &Path) -> Self {
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_symlink() => match fs::read_link(path) {
+                Ok(dest) => TargetState::Link(dest),
+                Err(_) => TargetState::Other,
+            },
+            Ok(_) => TargetState::Other,
+            Err(_) => TargetState::Missing,
+        }
+    }
+
+    /// Whether it is a symlink pointing exactly at `source`
+    pub fn links_to(&self, source: &Path) -> bool {
+        matches!(self, TargetState::Link(dest) if dest == source)
+    }
+
+    /// Whether it is a symlink to nothing, which takes a `stat` of the path
+    pub fn is_dangling(&self, path: &Path) -> bool {
+        matches!(self, TargetState::Link(_)) && !path.exists()
     }
-    false
 }
 
 /// Create a symlink, ensuring parent directories exist
@@ -62,10 +79,11 @@ pub fn create_symlink_with_force(
     dry_run: bool,
     force: bool,
 ) -> Result<()> {
-    // Check if target already exists
-    if target.exists() || target.symlink_metadata().is_ok() {
+    // Check if target already exists, without following a symlink there
+    if target.symlink_metadata().is_ok() {
         // Check if it's already the correct symlink
         if is_stau_symlink(target, source)? {
+            verbosity::detail(|| format!("{} is already linked", target.display()));
             return Ok(()); // Already correct, nothing to do
         }
 
@@ -74,43 +92,7 @@ pub fn create_symlink_with_force(
         }
 
         // Force enabled: remove the existing file/symlink
-        if !dry_run {
-            let metadata = target.symlink_metadata()?;
-            if metadata.is_symlink() {
-                fs::remove_file(target).map_err(|e| {
-                    if e.kind() == std::io::ErrorKind::PermissionDenied {
-                        StauError::PermissionDenied(format!(
-                            "Cannot remove existing symlink: {}",
-                            target.display()
-                        ))
-                    } else {
-                        StauError::Io(e)
-                    }
-                })?;
-            } else if metadata.is_file() {
-                fs::remove_file(target).map_err(|e| {
-                    if e.kind() == std::io::ErrorKind::PermissionDenied {
-                        StauError::PermissionDenied(format!(
-                            "Cannot remove existing file: {}",
-                            target.display()
-                        ))
-                    } else {
-                        StauError::Io(e)
-                    }
-                })?;
-            } else if metadata.is_dir() {
-                fs::remove_dir_all(target).map_err(|e| {
-                    if e.kind() == std::io::ErrorKind::PermissionDenied {
-                        StauError::PermissionDenied(format!(
-                            "Cannot remove existing directory: {}",
-                            target.display()
-                        ))
-                    } else {
-                        StauError::Io(e)
-                    }
-                })?;
-            }
-        }
+        remove_existing(target, dry_run)?;
     }
 
     if dry_run {
@@ -118,27 +100,35 @@ pub fn create_symlink_with_force(
     }
 
     // Create parent directories if they don't exist
-    if let Some(parent) = target.parent() {
-        fs::create_dir_all(parent).map_err(|e| {
-            if e.kind() == std::io::ErrorKind::PermissionDenied {
-                StauError::PermissionDenied(format!(
-                    "Cannot create directory: {}",
-                    parent.display()
-                ))
-            } else {
-                StauError::Io(e)
-            }
-        })?;
+    if let Some(parent) = target.parent()
+        && let Err(e) = fs::create_dir_all(parent)
+    {
+        if e.kind() != std::io::ErrorKind::PermissionDenied {
+            return Err(StauError::Io(e));
+        }
+        if sudo::enabled() {
+            return sudo::create_symlink(source, target);
+        }
+        return Err(StauError::PermissionDenied(format!(
+            "Cannot create directory: {}",
+            parent.display()
+        )));
     }
 
     // Create the symlink
-    unix_fs::symlink(source, target).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::PermissionDenied {
-            StauError::PermissionDenied(format!("Cannot create symlink: {}", target.display()))
-        } else {
-            StauError::Io(e)
+    verbosity::trace(|| format!("symlink {} -> {}", target.display(), source.display()));
+    if let Err(e) = unix_fs::symlink(source, target) {
+        if e.kind() != std::io::ErrorKind::PermissionDenied {
+            return Err(StauError::Io(e));
         }
-    })?;
+        if sudo::enabled() {
+            return sudo::create_symlink(source, target);
+        }
+        return Err(StauError::PermissionDenied(format!(
+            "Cannot create symlink: {}",
+            target.display()
+        )));
+    }
 
     Ok(())
 }
@@ -153,27 +143,74 @@ pub fn remove_symlink(path: &Path, expected_source: &Path, dry_run: bool) -> Res
         return Ok(true);
     }
 
-    fs::remove_file(path).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::PermissionDenied {
-            StauError::PermissionDenied(format!("Cannot remove symlink: {}", path.display()))
-        } else {
-            StauError::Io(e)
+    verbosity::trace(|| format!("unlink {}", path.display()));
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && sudo::enabled() => {
+            sudo::remove(path).map(|()| true)
         }
-    })?;
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(
+            StauError::PermissionDenied(format!("Cannot remove symlink: {}", path.display())),
+        ),
+        Err(e) => Err(StauError::Io(e)),
+    }
+}
+
+/// Remove whatever is at a path without following symlinks
+///
+/// The decision is taken from a single `symlink_metadata` call: a symlink is
+/// unlinked itself, never its destination, and a directory is removed with
+/// `remove_dir_all`, which doesn't follow symlinks inside it either. A path
+/// that has already disappeared is not an error.
+pub fn remove_existing(path: &Path, dry_run: bool) -> Result<()> {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(StauError::Io(e)),
+    };
 
-    Ok(true)
+    if dry_run {
+        return Ok(());
+    }
+
+    let result = if metadata.is_dir() {
+        verbosity::trace(|| format!("rm -r {}", path.display()));
+        fs::remove_dir_all(path)
+    } else {
+        verbosity::trace(|| format!("unlink {}", path.display()));
+        fs::remove_file(path)
+    };
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && sudo::enabled() => {
+            sudo::remove(path)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(
+            StauError::PermissionDenied(format!("Cannot remove existing file: {}", path.display())),
+        ),
+        Err(e) => Err(StauError::Io(e)),
+    }
 }
 
-/// Copy a file from source to destination
+/// Copy a file, or a directory tree linked as a whole, from source to destination
+///
+/// The destination is created exclusively, so a file or symlink appearing
+/// there in the meantime is reported as a conflict instead of written through.
 pub fn copy_file(source: &Path, dest: &Path, dry_run: bool) -> Result<()> {
     if dry_run {
         return Ok(());
     }
 
-    if dest.exists() {
+    if dest.symlink_metadata().is_ok() {
         return Err(StauError::ConflictingFile(dest.to_path_buf()));
     }
 
+    if sudo::enabled() && !preflight::can_write(dest) {
+        return sudo::copy_file(source, dest);
+    }
+
     // Create parent directories if they don't exist
     if let Some(parent) = dest.parent() {
         fs::create_dir_all(parent).map_err(|e| {
@@ -188,17 +225,180 @@ pub fn copy_file(source: &Path, dest: &Path, dry_run: bool) -> Result<()> {
         })?;
     }
 
-    fs::copy(source, dest).map_err(|e| {
+    let permission_error = |e: std::io::Error| {
         if e.kind() == std::io::ErrorKind::PermissionDenied {
             StauError::PermissionDenied(format!("Cannot copy file: {}", dest.display()))
         } else {
             StauError::Io(e)
         }
-    })?;
+    };
+
+    if fs::metadata(source).map_err(permission_error)?.is_dir() {
+        return copy_dir(source, dest);
+    }
+
+    verbosity::trace(|| format!("copy {} -> {}", source.display(), dest.display()));
+    let mut reader = fs::File::open(source).map_err(permission_error)?;
+    let permissions = reader.metadata().map_err(StauError::Io)?.permissions();
+    let mut writer = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)
+        .map_err(|e| {
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                StauError::ConflictingFile(dest.to_path_buf())
+            } else {
+                permission_error(e)
+            }
+        })?;
+    std::io::copy(&mut reader, &mut writer).map_err(StauError::Io)?;
+    writer.set_permissions(permissions).map_err(StauError::Io)?;
+    xattr::copy(&reader, &writer, dest)?;
 
     Ok(())
 }
 
+/// Temporary file next to a package file that a file being adopted is copied to
+fn adopt_tmp_path(source: &Path) -> PathBuf {
+    let name = source.file_name().unwrap_or_default().to_string_lossy();
+    source.with_file_name(format!(".{}.{}.adopt", name, std::process::id()))
+}
+
+/// Replace a package file with the file at its target path, then remove the target
+///
+/// The target is copied next to the package file first and renamed over it,
+/// so the package file is left as it was if copying fails.
+pub fn adopt_file(target: &Path, source: &Path, dry_run: bool) -> Result<()> {
+    if dry_run {
+        return Ok(());
+    }
+
+    let tmp_path = adopt_tmp_path(source);
+    match copy_file(target, &tmp_path, false) {
+        Ok(()) => {}
+        // Something else's file, which must not be cleaned up
+        Err(StauError::ConflictingFile(path)) => return Err(StauError::ConflictingFile(path)),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    }
+    verbosity::trace(|| format!("rename {} -> {}", tmp_path.display(), source.display()));
+    if let Err(e) = fs::rename(&tmp_path, source) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(StauError::Io(e));
+    }
+
+    verbosity::trace(|| format!("unlink {}", target.display()));
+    fs::remove_file(target).map_err(StauError::Io)
+}
+
+/// Copy a directory tree, recreating the symlinks in it rather than following them
+fn copy_dir(source: &Path, dest: &Path) -> Result<()> {
+    fs::create_dir(dest).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            StauError::ConflictingFile(dest.to_path_buf())
+        } else {
+            StauError::Io(e)
+        }
+    })?;
+
+    for entry in fs::read_dir(source).map_err(StauError::Io)? {
+        let entry = entry.map_err(StauError::Io)?;
+        let dest_path = dest.join(entry.file_name());
+        if entry.file_type().map_err(StauError::Io)?.is_symlink() {
+            let link = fs::read_link(entry.path()).map_err(StauError::Io)?;
+            std::os::unix::fs::symlink(link, &dest_path).map_err(StauError::Io)?;
+        } else {
+            copy_file(&entry.path(), &dest_path, false)?;
+        }
+    }
+
+    let permissions = fs::metadata(source).map_err(StauError::Io)?.permissions();
+    fs::set_permissions(dest, permissions).map_err(StauError::Io)
+}
+
+/// Changes needed to bring a package's links in line with its files
+#[derive(Debug, Default)]
+pub struct LinkDiff {
+    /// Mappings whose target doesn't exist yet
+    pub add: Vec<SymlinkMapping>,
+    /// Mappings whose target links to a different file of the same package
+    pub repoint: Vec<SymlinkMapping>,
+    /// Links into the package that no longer correspond to a package file
+    pub remove: Vec<PathBuf>,
+    /// Mappings whose target is taken by something that isn't a link into the package
+    pub conflict: Vec<SymlinkMapping>,
+    /// Number of links that are already correct
+    pub unchanged: usize,
+}
+
+impl LinkDiff {
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty()
+            && self.repoint.is_empty()
+            && self.remove.is_empty()
+            && self.conflict.is_empty()
+    }
+}
+
+/// Check whether a path is a symlink pointing into one of the package directories
+pub fn links_into(path: &Path, package_dirs: &[PathBuf]) -> bool {
+    match fs::read_link(path) {
+        Ok(dest) => package_dirs.iter().any(|dir| dest.starts_with(dir)),
+        Err(_) => false,
+    }
+}
+
+/// Compare the desired mappings of a package with the links found in the target
+///
+/// Stale links are looked for in the directories that hold the package's
+/// targets and in `linked_dirs`, where the package had links before. Anything
+/// at a target that is not a link into the package is a conflict, left for
+/// the caller to resolve.
+pub fn diff_links(
+    mappings: &[SymlinkMapping],
+    package_dirs: &[PathBuf],
+    linked_dirs: &BTreeSet<PathBuf>,
+) -> Result<LinkDiff> {
+    let _span = tracing::info_span!("plan").entered();
+    let mut diff = LinkDiff::default();
+
+    for mapping in mappings {
+        if mapping.target.symlink_metadata().is_err() {
+            diff.add.push(mapping.clone());
+        } else if is_stau_symlink(&mapping.target, &mapping.source)? {
+            diff.unchanged += 1;
+        } else if links_into(&mapping.target, package_dirs) {
+            diff.repoint.push(mapping.clone());
+        } else {
+            diff.conflict.push(mapping.clone());
+        }
+    }
+
+    let targets: HashSet<&Path> = mappings.iter().map(|m| m.target.as_path()).collect();
+    let parents: BTreeSet<&Path> = mappings
+        .iter()
+        .filter_map(|m| m.target.parent())
+        .chain(linked_dirs.iter().map(PathBuf::as_path))
+        .collect();
+
+    for parent in parents {
+        let Ok(entries) = fs::read_dir(parent) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry.map_err(StauError::Io)?.path();
+            if !targets.contains(path.as_path()) && links_into(&path, package_dirs) {
+                diff.remove.push(path);
+            }
+        }
+    }
+    diff.remove.sort();
+
+    Ok(diff)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -213,6 +413,28 @@ mod tests {
         assert_eq!(mapping.target, PathBuf::from("/target/file"));
     }
 
+    #[test]
+    fn test_adopt_file_keeps_source_when_copy_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("package/.vimrc");
+        let target = temp_dir.path().join(".vimrc");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "package").unwrap();
+        fs::write(&target, "local").unwrap();
+
+        // A directory in the way of the temporary copy makes copying fail
+        fs::create_dir(adopt_tmp_path(&source)).unwrap();
+        assert!(adopt_file(&target, &source, false).is_err());
+        assert_eq!(fs::read_to_string(&source).unwrap(), "package");
+        assert_eq!(fs::read_to_string(&target).unwrap(), "local");
+
+        fs::remove_dir(adopt_tmp_path(&source)).unwrap();
+        adopt_file(&target, &source, false).unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "local");
+        assert!(target.symlink_metadata().is_err());
+        assert!(adopt_tmp_path(&source).symlink_metadata().is_err());
+    }
+
     #[test]
     fn test_create_and_check_symlink() {
         let temp_dir = TempDir::new().unwrap();
@@ -449,6 +671,33 @@ mod tests {
         assert!(target.exists()); // Should still exist
     }
 
+    #[test]
+    fn test_target_state() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let link = temp_dir.path().join("link");
+        let dangling = temp_dir.path().join("dangling");
+        File::create(&source).unwrap();
+        unix_fs::symlink(&source, &link).unwrap();
+        unix_fs::symlink(temp_dir.path().join("gone"), &dangling).unwrap();
+
+        let state = TargetState::of(&link);
+        assert_eq!(state, TargetState::Link(source.clone()));
+        assert!(state.links_to(&source));
+        assert!(!state.is_dangling(&link));
+
+        let state = TargetState::of(&dangling);
+        assert!(!state.links_to(&source));
+        assert!(state.is_dangling(&dangling));
+
+        assert_eq!(TargetState::of(&source), TargetState::Other);
+        assert!(!TargetState::of(&source).is_dangling(&source));
+        assert_eq!(
+            TargetState::of(&temp_dir.path().join("none")),
+            TargetState::Missing
+        );
+    }
+
     #[test]
     fn test_is_broken_symlink_non_symlink() {
         let temp_dir = TempDir::new().unwrap();
@@ -497,4 +746,129 @@ mod tests {
         assert_eq!(mapping1, mapping2);
         assert_ne!(mapping1, mapping3);
     }
+
+    #[test]
+    fn test_diff_links() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("vim");
+        let target_dir = temp_dir.path().join("home");
+        fs::create_dir_all(&package_dir).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+
+        for name in [".vimrc", ".gvimrc", ".exrc", "vimrc.old"] {
+            File::create(package_dir.join(name)).unwrap();
+        }
+        let mapping =
+            |name: &str| SymlinkMapping::new(package_dir.join(name), target_dir.join(name));
+
+        // .vimrc is correct, .gvimrc points at the wrong package file and
+        // .old-vimrc is a leftover of a file that was removed from the package
+        unix_fs::symlink(package_dir.join(".vimrc"), target_dir.join(".vimrc")).unwrap();
+        unix_fs::symlink(package_dir.join("vimrc.old"), target_dir.join(".gvimrc")).unwrap();
+        unix_fs::symlink(
+            package_dir.join(".old-vimrc"),
+            target_dir.join(".old-vimrc"),
+        )
+        .unwrap();
+        // Links elsewhere are not ours to remove
+        unix_fs::symlink("/etc/hosts", target_dir.join(".hosts")).unwrap();
+
+        let mappings = vec![mapping(".vimrc"), mapping(".gvimrc"), mapping(".exrc")];
+        let diff = diff_links(
+            &mappings,
+            std::slice::from_ref(&package_dir),
+            &BTreeSet::new(),
+        )
+        .unwrap();
+
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.add, vec![mapping(".exrc")]);
+        assert_eq!(diff.repoint, vec![mapping(".gvimrc")]);
+        assert_eq!(diff.remove, vec![target_dir.join(".old-vimrc")]);
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn test_diff_links_conflict() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path().join("vim");
+        let target_dir = temp_dir.path().join("home");
+        fs::create_dir_all(&package_dir).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+        File::create(package_dir.join(".vimrc")).unwrap();
+        File::create(target_dir.join(".vimrc")).unwrap();
+
+        let mappings = vec![SymlinkMapping::new(
+            package_dir.join(".vimrc"),
+            target_dir.join(".vimrc"),
+        )];
+        let diff = diff_links(&mappings, &[package_dir], &BTreeSet::new()).unwrap();
+        assert_eq!(diff.conflict, mappings);
+        assert!(diff.add.is_empty());
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn test_force_does_not_follow_symlinked_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let outside = temp_dir.path().join("outside");
+        let target = temp_dir.path().join("home/.config");
+
+        File::create(&source).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        File::create(outside.join("precious")).unwrap();
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        unix_fs::symlink(&outside, &target).unwrap();
+
+        create_symlink_with_force(&source, &target, false, true).unwrap();
+
+        // Only the symlink was replaced, not the directory it pointed to
+        assert!(is_stau_symlink(&target, &source).unwrap());
+        assert!(outside.join("precious").exists());
+    }
+
+    #[test]
+    fn test_remove_existing_missing_path() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(remove_existing(&temp_dir.path().join("missing"), false).is_ok());
+    }
+
+    #[test]
+    fn test_copy_file_does_not_write_through_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let outside = temp_dir.path().join("outside.txt");
+        let dest = temp_dir.path().join("dest.txt");
+
+        fs::write(&source, "new").unwrap();
+        fs::write(&outside, "original").unwrap();
+        unix_fs::symlink(&outside, &dest).unwrap();
+
+        let result = copy_file(&source, &dest, false);
+        assert!(matches!(result.unwrap_err(), StauError::ConflictingFile(_)));
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "original");
+    }
+
+    #[test]
+    fn test_copy_directory_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("plugins");
+        let dest = temp_dir.path().join("home/.vim/plugins");
+        fs::create_dir_all(source.join("fugitive")).unwrap();
+        fs::write(source.join("fugitive/plugin.vim"), "\" fugitive").unwrap();
+        unix_fs::symlink("fugitive", source.join("current")).unwrap();
+
+        copy_file(&source, &dest, false).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest.join("fugitive/plugin.vim")).unwrap(),
+            "\" fugitive"
+        );
+        assert!(!dest.is_symlink());
+        assert_eq!(
+            fs::read_link(dest.join("current")).unwrap(),
+            Path::new("fugitive")
+        );
+    }
 }
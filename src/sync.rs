@@ -0,0 +1,184 @@
+use crate::error::{Result, StauError};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parse an interval like "90s", "15m", "1h" or "1d"; a bare number means seconds
+pub fn parse_interval(value: &str) -> std::result::Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid interval '{}'", value))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid interval unit '{}' (use s, m, h or d)",
+                unit
+            ));
+        }
+    };
+    if number == 0 {
+        return Err("interval must be greater than zero".to_string());
+    }
+    Ok(Duration::from_secs(number * seconds))
+}
+
+/// A random delay of up to a tenth of the interval, so machines started
+/// together don't all pull at the same moment
+pub fn jitter(interval: Duration) -> Duration {
+    let max = interval.as_millis() as u64 / 10;
+    if max == 0 {
+        return Duration::ZERO;
+    }
+    // Good enough randomness without pulling in a crate for it
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or_default();
+    let seed = nanos ^ (u64::from(std::process::id()) << 16);
+    Duration::from_millis(seed % max)
+}
+
+/// An exclusive lock on a file, held until dropped
+pub struct Lock {
+    file: File,
+}
+
+impl Lock {
+    /// Take the lock without waiting, failing if another process holds it
+    ///
+    /// `command` names what holds the lock in the error, e.g. "stau sync".
+    pub fn acquire(path: &Path, command: &str) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(StauError::Io)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(StauError::Io)?;
+
+        // SAFETY: flock only operates on the open file descriptor
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Err(StauError::Other(format!(
+                    "Another {} is already running (lock file {})",
+                    command,
+                    path.display()
+                )));
+            }
+            return Err(StauError::Io(err));
+        }
+
+        Ok(Lock { file })
+    }
+
+    /// Record the current process id in the lock file
+    pub fn write_pid(&mut self) -> Result<()> {
+        self.file.set_len(0).map_err(StauError::Io)?;
+        writeln!(self.file, "{}", std::process::id()).map_err(StauError::Io)
+    }
+}
+
+/// Run the current command again as a background process in a session of its
+/// own, with all output going to a log file, and exit
+///
+/// Rather than forking a process that already runs threads (the Ctrl-C
+/// handler, the trace writer), stau is started anew with `--foreground`
+/// added to its arguments, so it runs the command itself instead of starting
+/// yet another process. It takes over the lock, which is released for it,
+/// and writes the trace of `--trace-file` on its own. Only returns on error.
+pub fn daemonize(command: &str, log: &Path, lock: Lock) -> Result<()> {
+    if let Some(parent) = log.parent() {
+        std::fs::create_dir_all(parent).map_err(StauError::Io)?;
+    }
+    let log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .map_err(StauError::Io)?;
+    let null = File::open("/dev/null").map_err(StauError::Io)?;
+    let exe = std::env::current_exe().map_err(StauError::Io)?;
+
+    let mut background = Command::new(exe);
+    background
+        .args(std::env::args_os().skip(1))
+        .arg("--foreground")
+        .stdin(null)
+        .stdout(log_file.try_clone().map_err(StauError::Io)?)
+        .stderr(log_file);
+    // SAFETY: setsid is async-signal-safe and touches no memory of this process
+    unsafe {
+        background.pre_exec(|| match libc::setsid() {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+
+    drop(lock);
+    let child = background.spawn().map_err(StauError::Io)?;
+    println!(
+        "Started {} in the background (pid {}), logging to {}",
+        command,
+        child.id(),
+        log.display()
+    );
+    // Leave the trace file to the background process
+    io::stdout().flush().map_err(StauError::Io)?;
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_interval("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_interval("1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_interval("2d").unwrap(), Duration::from_secs(172800));
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("1w").is_err());
+        assert!(parse_interval("h").is_err());
+    }
+
+    #[test]
+    fn test_jitter_stays_below_a_tenth() {
+        let interval = Duration::from_secs(3600);
+        assert!(jitter(interval) < Duration::from_secs(360));
+        assert_eq!(jitter(Duration::from_millis(5)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("state/sync.lock");
+
+        let mut lock = Lock::acquire(&path, "stau sync").unwrap();
+        lock.write_pid().unwrap();
+        assert!(matches!(
+            Lock::acquire(&path, "stau sync").err().unwrap(),
+            StauError::Other(_)
+        ));
+
+        drop(lock);
+        assert!(Lock::acquire(&path, "stau sync").is_ok());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::Subscriber;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Spans shown as columns of the breakdown, with their headers
+const PHASES: [(&str, &str); 5] = [
+    ("discover", "DISCOVER"),
+    ("plan", "PLAN"),
+    ("link", "LINK"),
+    ("script", "SCRIPTS"),
+    ("git", "GIT"),
+];
+
+/// Key under which the time of a package's outermost spans adds up
+const TOTAL: &str = "total";
+
+/// Adds up the time spent in each kind of span, per package, for `--timings`
+///
+/// A span belongs to the package named by its `package` field, or else to the
+/// package of the span it was entered in.
+#[derive(Clone, Default)]
+pub struct Timings {
+    totals: Arc<Mutex<BTreeMap<(String, &'static str), Duration>>>,
+}
+
+/// What is kept about a span while it is open
+struct Timing {
+    package: Option<String>,
+    /// Whether the span is the outermost of its package
+    outermost: bool,
+    start: Instant,
+}
+
+#[derive(Default)]
+struct PackageField(Option<String>);
+
+impl Visit for PackageField {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "package" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+impl<S> Layer<S> for Timings
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<Timing>()
+                .map(|timing| timing.package.clone())
+        });
+        let mut field = PackageField::default();
+        attrs.record(&mut field);
+
+        let package = field.0.or_else(|| parent.clone().flatten());
+        let timing = Timing {
+            outermost: parent.is_none_or(|parent| parent != package),
+            package,
+            start: Instant::now(),
+        };
+        span.extensions_mut().insert(timing);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(timing) = extensions.get::<Timing>() else {
+            return;
+        };
+        let elapsed = timing.start.elapsed();
+        let package = timing.package.clone().unwrap_or_default();
+
+        let mut totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        *totals.entry((package.clone(), span.name())).or_default() += elapsed;
+        if timing.outermost {
+            *totals.entry((package, TOTAL)).or_default() += elapsed;
+        }
+    }
+}
+
+impl Timings {
+    /// A table of the time spent per package, slowest first, ending with the
+    /// wall-clock time of the whole command
+    ///
+    /// Time outside of any package, such as the pre-command hook, is listed
+    /// last under `-`.
+    pub fn report(&self, elapsed: Duration) -> String {
+        let totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        let mut packages: Vec<(&str, Duration)> = totals
+            .iter()
+            .filter(|((_, name), _)| *name == TOTAL)
+            .map(|((package, _), total)| (package.as_str(), *total))
+            .collect();
+        packages.sort_by(|a, b| (a.0.is_empty(), b.1, a.0).cmp(&(b.0.is_empty(), a.1, b.0)));
+
+        let mut rows = vec![
+            std::iter::once("PACKAGE")
+                .chain(PHASES.iter().map(|(_, header)| *header))
+                .chain(["TOTAL"])
+                .map(String::from)
+                .collect::<Vec<_>>(),
+        ];
+        for (package, total) in packages {
+            let mut row = vec![if package.is_empty() { "-" } else { package }.to_string()];
+            for (phase, _) in PHASES {
+                let time = totals.get(&(package.to_string(), phase));
+                row.push(time.map_or("-".to_string(), |time| format_duration(*time)));
+            }
+            row.push(format_duration(total));
+            rows.push(row);
+        }
+
+        let widths: Vec<usize> = (0..rows[0].len())
+            .map(|column| {
+                rows.iter()
+                    .map(|row| row[column].chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let mut report = String::new();
+        for row in &rows {
+            let mut line = String::new();
+            for (cell, width) in row.iter().zip(&widths) {
+                let _ = write!(line, "{:<width$}  ", cell, width = width);
+            }
+            report.push_str(line.trim_end());
+            report.push('\n');
+        }
+        let _ = write!(report, "Total: {}", format_duration(elapsed));
+        report
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.1?}", duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_report_per_package() {
+        let timings = Timings::default();
+        let subscriber = tracing_subscriber::registry().with(timings.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            drop(tracing::info_span!("script").entered());
+            for package in ["vim", "zsh"] {
+                let _install = tracing::info_span!("install", package).entered();
+                drop(tracing::info_span!("discover", package).entered());
+                drop(tracing::info_span!("link").entered());
+                if package == "vim" {
+                    std::thread::sleep(Duration::from_millis(20));
+                    drop(tracing::info_span!("script").entered());
+                }
+            }
+        });
+
+        let report = timings.report(Duration::from_millis(1500));
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[0].split_whitespace().collect::<Vec<_>>(),
+            [
+                "PACKAGE", "DISCOVER", "PLAN", "LINK", "SCRIPTS", "GIT", "TOTAL"
+            ]
+        );
+        // Slowest first, with the time outside packages last
+        assert!(lines[1].starts_with("vim "));
+        assert!(lines[2].starts_with("zsh "));
+        assert!(lines[3].starts_with("- "));
+        assert_eq!(lines[4], "Total: 1.5s");
+
+        let zsh: Vec<&str> = lines[2].split_whitespace().collect();
+        assert_eq!(zsh[2], "-");
+        assert_eq!(zsh[4], "-");
+        assert_ne!(zsh[1], "-");
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How often `-v` was given
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+
+/// `-vv`: also show what was decided about each file and why
+const DETAIL: u8 = 2;
+
+/// `-vvv`: also show every file system change as it is made
+const TRACE: u8 = 3;
+
+/// Set the verbosity level for the rest of the run
+pub fn set_level(level: u8) {
+    LEVEL.store(level, Ordering::SeqCst);
+}
+
+fn level() -> u8 {
+    LEVEL.load(Ordering::SeqCst)
+}
+
+/// Whether decisions about single files are shown
+pub fn shows_detail() -> bool {
+    level() >= DETAIL
+}
+
+/// Print a decision about a single file, such as why it is skipped, with `-vv`
+pub fn detail(message: impl FnOnce() -> String) {
+    if level() >= DETAIL {
+        outln!("  {}", message());
+    }
+}
+
+/// Print a file system change just before it is made, with `-vvv`
+pub fn trace(message: impl FnOnce() -> String) {
+    if level() >= TRACE {
+        outln!("  [trace] {}", message());
+    }
+}
@@ -0,0 +1,27 @@
+use crate::error::{Result, StauError};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by `--strict` to turn warnings into errors
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Make every following warning a hard error
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::SeqCst);
+}
+
+/// Print a warning, or fail with it in strict mode
+pub fn warn(message: String) -> Result<()> {
+    if STRICT.load(Ordering::SeqCst) {
+        return Err(StauError::Strict(message));
+    }
+    errln!("Warning: {}", message);
+    Ok(())
+}
+
+/// Fail in strict mode for something that is otherwise skipped silently
+pub fn fail_if_strict(message: String) -> Result<()> {
+    if STRICT.load(Ordering::SeqCst) {
+        return Err(StauError::Strict(message));
+    }
+    Ok(())
+}
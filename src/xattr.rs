@@ -0,0 +1,185 @@
+use crate::error::{Result, StauError};
+use crate::warning;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Copy all extended attributes from one open file to another
+///
+/// On Linux this includes POSIX ACLs and file capabilities, which are stored
+/// as extended attributes. Attributes the destination refuses, such as
+/// capabilities without the privilege to set them, are reported as warnings.
+pub fn copy(source: &File, dest: &File, dest_path: &Path) -> Result<()> {
+    let names = match list(source) {
+        Ok(names) => names,
+        Err(e) if unsupported(&e) => return Ok(()),
+        Err(e) => return Err(StauError::Io(e)),
+    };
+
+    for name in names {
+        let value = match get(source, &name) {
+            Ok(value) => value,
+            // Removed since it was listed
+            Err(e) if e.raw_os_error() == Some(sys::ENOATTR) => continue,
+            Err(e) => return Err(StauError::Io(e)),
+        };
+        if let Err(e) = set(dest, &name, &value) {
+            warning::warn(format!(
+                "Cannot copy extended attribute {} to {}: {}",
+                name.to_string_lossy(),
+                dest_path.display(),
+                e
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+fn unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// Names of the extended attributes of a file
+fn list(file: &File) -> io::Result<Vec<CString>> {
+    let fd = file.as_raw_fd();
+    // SAFETY: the buffer passed is valid for `size` bytes (or null with size 0)
+    let buf = read_sized(|buf, size| unsafe { sys::flistxattr(fd, buf.cast(), size) })?;
+    Ok(buf
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| CString::new(name).ok())
+        .collect())
+}
+
+fn get(file: &File, name: &CStr) -> io::Result<Vec<u8>> {
+    let fd = file.as_raw_fd();
+    // SAFETY: the buffer passed is valid for `size` bytes (or null with size 0)
+    read_sized(|buf, size| unsafe { sys::fgetxattr(fd, name.as_ptr(), buf.cast(), size) })
+}
+
+fn set(file: &File, name: &CStr, value: &[u8]) -> io::Result<()> {
+    // SAFETY: name is NUL-terminated and value is valid for its length
+    let result = unsafe {
+        sys::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Call a function that fills a buffer of unknown size: first to learn the
+/// size, then to fill it, again if it grew in between
+fn read_sized(mut call: impl FnMut(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let read = call(buf.as_mut_ptr(), buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc::{c_char, c_int, c_void, size_t, ssize_t};
+
+    pub const ENOATTR: c_int = libc::ENODATA;
+
+    pub unsafe fn flistxattr(fd: c_int, list: *mut c_char, size: size_t) -> ssize_t {
+        unsafe { libc::flistxattr(fd, list, size) }
+    }
+
+    pub unsafe fn fgetxattr(
+        fd: c_int,
+        name: *const c_char,
+        value: *mut c_void,
+        size: size_t,
+    ) -> ssize_t {
+        unsafe { libc::fgetxattr(fd, name, value, size) }
+    }
+
+    pub unsafe fn fsetxattr(
+        fd: c_int,
+        name: *const c_char,
+        value: *const c_void,
+        size: size_t,
+    ) -> c_int {
+        unsafe { libc::fsetxattr(fd, name, value, size, 0) }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use libc::{c_char, c_int, c_void, size_t, ssize_t};
+
+    pub const ENOATTR: c_int = libc::ENOATTR;
+
+    pub unsafe fn flistxattr(fd: c_int, list: *mut c_char, size: size_t) -> ssize_t {
+        unsafe { libc::flistxattr(fd, list, size, 0) }
+    }
+
+    pub unsafe fn fgetxattr(
+        fd: c_int,
+        name: *const c_char,
+        value: *mut c_void,
+        size: size_t,
+    ) -> ssize_t {
+        unsafe { libc::fgetxattr(fd, name, value, size, 0, 0) }
+    }
+
+    pub unsafe fn fsetxattr(
+        fd: c_int,
+        name: *const c_char,
+        value: *const c_void,
+        size: size_t,
+    ) -> c_int {
+        unsafe { libc::fsetxattr(fd, name, value, size, 0, 0) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_copy_extended_attributes() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_path = temp_dir.path().join("source");
+        let dest_path = temp_dir.path().join("dest");
+        fs::write(&source_path, "data").unwrap();
+        fs::write(&dest_path, "data").unwrap();
+
+        let source = File::open(&source_path).unwrap();
+        let name = CString::new("user.stau").unwrap();
+        match set(&source, &name, b"kept") {
+            Ok(()) => {}
+            // The file system holding the temporary directory can't store them
+            Err(e) if unsupported(&e) => return,
+            Err(e) => panic!("cannot set attribute: {}", e),
+        }
+
+        let dest = fs::OpenOptions::new().write(true).open(&dest_path).unwrap();
+        copy(&source, &dest, &dest_path).unwrap();
+        assert!(list(&dest).unwrap().contains(&name));
+        assert_eq!(get(&dest, &name).unwrap(), b"kept");
+    }
+}
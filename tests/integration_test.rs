@@ -1,7 +1,7 @@
 use std::fs::{self, File};
 use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use tempfile::TempDir;
 
@@ -14,6 +14,18 @@ fn stau_binary() -> PathBuf {
     path
 }
 
+/// A stau command that keeps to the test's temporary directory
+///
+/// The state directory and config file are set to paths in `temp_dir`, so
+/// tests never read the user's config or write to their state.
+fn stau_command(temp_dir: &Path) -> Command {
+    let mut command = Command::new(stau_binary());
+    command
+        .env("STAU_STATE_DIR", temp_dir.join("state"))
+        .env("STAU_CONFIG", temp_dir.join("config.toml"));
+    command
+}
+
 /// Helper to create a test package with files
 fn create_test_package(stau_dir: &std::path::Path, package_name: &str, files: &[&str]) {
     let package_dir = stau_dir.join(package_name);
@@ -61,7 +73,7 @@ fn test_install_and_uninstall_workflow() {
     create_test_package(&stau_dir, "vim", &[".vimrc", ".vim/colors/theme.vim"]);
 
     // Install the package
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -74,7 +86,7 @@ fn test_install_and_uninstall_workflow() {
     assert!(target_dir.join(".vimrc").is_symlink());
 
     // Uninstall the package
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["uninstall", "vim"])
@@ -109,7 +121,7 @@ fn test_install_with_setup_script() {
     );
 
     // Install with setup script
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "zsh"])
@@ -147,7 +159,7 @@ fn test_install_no_setup_flag() {
     );
 
     // Install with --no-setup
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "zsh", "--no-setup"])
@@ -172,7 +184,7 @@ fn test_list_command() {
     create_test_package(&stau_dir, "git", &[".gitconfig"]);
 
     // Install only vim
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -180,7 +192,7 @@ fn test_list_command() {
         .unwrap();
 
     // List packages
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["list"])
@@ -209,7 +221,7 @@ fn test_adopt_command() {
     fs::write(&config_file, "echo 'hello'").unwrap();
 
     // Adopt the file
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["adopt", "bash", config_file.to_str().unwrap()])
@@ -236,7 +248,7 @@ fn test_status_command() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Status before install
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["status", "vim"])
@@ -246,6 +258,7 @@ fn test_status_command() {
     assert!(output.status.success());
     let stdout = String::from_utf8_lossy(&output.stdout);
     assert!(stdout.contains("not installed") || stdout.contains("Status for package"));
+    assert!(stdout.contains("Files (1 total):"));
 }
 
 #[test]
@@ -260,7 +273,7 @@ fn test_dry_run_mode() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install with --dry-run
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim", "--dry-run"])
@@ -289,7 +302,7 @@ fn test_conflict_detection() {
     fs::write(target_dir.join(".vimrc"), "existing content").unwrap();
 
     // Try to install - should fail
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -312,7 +325,7 @@ fn test_restow_command() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -320,7 +333,7 @@ fn test_restow_command() {
         .unwrap();
 
     // Restow
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["restow", "vim"])
@@ -346,7 +359,7 @@ fn test_package_not_found_error() {
     fs::create_dir(&target_dir).unwrap();
 
     // Try to install non-existent package
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "nonexistent"])
@@ -372,7 +385,7 @@ fn test_force_flag_overwrites_file() {
     fs::write(target_dir.join(".vimrc"), "existing content").unwrap();
 
     // Install without force - should fail
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -382,7 +395,7 @@ fn test_force_flag_overwrites_file() {
     assert!(!output.status.success(), "Should fail without --force");
 
     // Install with force - should succeed
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim", "--force"])
@@ -417,7 +430,7 @@ fn test_force_flag_overwrites_directory() {
     fs::write(conflict_dir.join("old_file.txt"), "old content").unwrap();
 
     // Install without force - should fail
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "config"])
@@ -427,7 +440,7 @@ fn test_force_flag_overwrites_directory() {
     assert!(!output.status.success(), "Should fail without --force");
 
     // Install with force - should succeed and remove directory
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "config", "--force"])
@@ -455,7 +468,7 @@ fn test_uninstall_force_flag() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -466,7 +479,7 @@ fn test_uninstall_force_flag() {
     assert!(target_dir.join(".vimrc").is_symlink());
 
     // Test that uninstall with --force flag is accepted and works
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["uninstall", "vim", "--force"])
@@ -498,7 +511,7 @@ fn test_clean_command() {
     create_test_package(&stau_dir, "vim", &[".vimrc", ".vim/plugin.vim"]);
 
     // Install
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -518,7 +531,7 @@ fn test_clean_command() {
     assert!(!target_vimrc.exists()); // Broken symlink
 
     // Clean the broken symlinks
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["clean", "vim"])
@@ -553,7 +566,7 @@ fn test_clean_no_broken_symlinks() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -561,7 +574,7 @@ fn test_clean_no_broken_symlinks() {
         .unwrap();
 
     // Clean when there are no broken symlinks
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["clean", "vim"])
@@ -596,7 +609,7 @@ fn test_uninstall_with_teardown_script() {
     );
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "zsh", "--no-setup"])
@@ -604,7 +617,7 @@ fn test_uninstall_with_teardown_script() {
         .unwrap();
 
     // Uninstall with teardown script
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["uninstall", "zsh"])
@@ -642,7 +655,7 @@ fn test_uninstall_no_teardown_flag() {
     );
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "zsh", "--no-setup"])
@@ -650,7 +663,7 @@ fn test_uninstall_no_teardown_flag() {
         .unwrap();
 
     // Uninstall with --no-teardown
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["uninstall", "zsh", "--no-teardown"])
@@ -683,7 +696,7 @@ fn test_teardown_script_failure_continues() {
     create_script(&teardown_script, "#!/bin/bash\nexit 1\n");
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim", "--no-setup"])
@@ -691,7 +704,7 @@ fn test_teardown_script_failure_continues() {
         .unwrap();
 
     // Uninstall - should succeed despite teardown failure
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["uninstall", "vim"])
@@ -719,7 +732,7 @@ fn test_verbose_flag() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install with --verbose
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim", "--verbose"])
@@ -733,6 +746,251 @@ fn test_verbose_flag() {
     assert!(stdout.contains("STAU_DIR:"));
 }
 
+#[test]
+fn test_verbosity_levels() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc", "README.md", ".DS_Store"]);
+
+    let stau = |args: &[&str]| {
+        let output = stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "stau failed: {:?}", output);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = stau(&["-v", "install", "vim"]);
+    assert!(stdout.contains("Package directory:"));
+    assert!(!stdout.contains("Skipping"));
+
+    let stdout = stau(&["-vv", "restow", "vim"]);
+    assert!(stdout.contains(&format!(
+        "Skipping {} (package README)",
+        stau_dir.join("vim/README.md").display()
+    )));
+    assert!(stdout.contains("(junk)"));
+    assert!(!stdout.contains("[trace]"));
+
+    let stdout = stau(&["-vvv", "uninstall", "--no-copy", "vim"]);
+    assert!(stdout.contains(&format!(
+        "[trace] unlink {}",
+        target_dir.join(".vimrc").display()
+    )));
+}
+
+#[test]
+fn test_trace_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let trace = temp_dir.path().join("trace.json");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim", "--trace-file", trace.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    let events: Vec<serde_json::Value> =
+        serde_json::from_str(&fs::read_to_string(&trace).unwrap()).unwrap();
+    let names: Vec<&str> = events.iter().filter_map(|e| e["name"].as_str()).collect();
+    for span in ["install", "discover", "link"] {
+        assert!(names.contains(&span), "no {} span in {:?}", span, names);
+    }
+}
+
+#[test]
+fn test_timings() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_script(&stau_dir.join("vim/setup.sh"), "#!/bin/sh\nsleep 0.1\n");
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim", "--timings"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("TOTAL"));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    let header = lines.iter().position(|l| l.starts_with("PACKAGE")).unwrap();
+    assert_eq!(
+        lines[header].split_whitespace().collect::<Vec<_>>(),
+        [
+            "PACKAGE", "DISCOVER", "PLAN", "LINK", "SCRIPTS", "GIT", "TOTAL"
+        ]
+    );
+    let vim: Vec<&str> = lines[header + 1].split_whitespace().collect();
+    assert_eq!(vim[0], "vim");
+    assert!(vim[4].ends_with("ms"), "no script time in {:?}", vim);
+    assert!(lines.last().unwrap().starts_with("Total: "));
+}
+
+#[test]
+fn test_jobs_keep_output_grouped_per_package() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    let packages = ["bash", "git", "vim", "zsh"];
+    for package in packages {
+        create_test_package(&stau_dir, package, &[&format!(".{}rc", package)]);
+        create_script(
+            &stau_dir.join(package).join("setup.sh"),
+            &format!(
+                "#!/bin/sh\necho start {0}\nsleep 0.1\necho end {0}\n",
+                package
+            ),
+        );
+    }
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "--all", "--jobs", "3", "-v"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    let position = |line: &str| lines.iter().position(|l| *l == line).unwrap();
+    let mut last = 0;
+    for package in packages {
+        assert!(target_dir.join(format!(".{}rc", package)).is_symlink());
+        let directory = format!("Package directory: {}", stau_dir.join(package).display());
+        let (first, start, end) = (
+            position(&directory),
+            position(&format!("start {}", package)),
+            position(&format!("end {}", package)),
+        );
+        assert!(
+            last <= first && first < start && start + 1 == end,
+            "{}",
+            stdout
+        );
+        last = end;
+    }
+}
+
+#[test]
+fn test_dry_run_json_plan() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc", ".zshenv"]);
+    fs::write(target_dir.join(".zshenv"), "existing").unwrap();
+    create_script(
+        &stau_dir.join("zsh/setup.sh"),
+        "#!/bin/sh\necho setup ran\n",
+    );
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&[
+        "install",
+        "zsh",
+        "--dry-run",
+        "--format",
+        "json",
+        "--conflicts",
+        "backup",
+    ]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(plan["command"], "install");
+    let steps = plan["steps"].as_array().unwrap();
+    assert_eq!(steps.len(), 2);
+    for step in steps {
+        assert_eq!(step["package"], "zsh");
+        assert_eq!(step["action"], "link");
+        let target = step["target"].as_str().unwrap();
+        if target.ends_with(".zshenv") {
+            assert_eq!(step["conflict"], "backup");
+            assert_eq!(step["reason"], "already exists");
+        } else {
+            assert_eq!(step["conflict"], serde_json::Value::Null);
+            assert!(step["source"].as_str().unwrap().ends_with("zsh/.zshrc"));
+        }
+    }
+    // Nothing was linked and no script ran
+    assert!(!target_dir.join(".zshrc").exists());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("setup ran"));
+
+    let output = stau(&["install", "zsh", "--format", "json"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--dry-run"));
+
+    // The plan comes from the dry run itself, so it stops where the install would
+    let output = stau(&["install", "zsh", "--dry-run", "--format", "json"]);
+    assert!(!output.status.success());
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let last = plan["steps"].as_array().unwrap().last().unwrap();
+    assert_eq!(last["action"], "abort");
+    assert!(last["target"].as_str().unwrap().ends_with(".zshenv"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains(".zshenv"));
+
+    fs::remove_file(target_dir.join(".zshenv")).unwrap();
+    assert!(stau(&["install", "zsh", "--no-setup"]).status.success());
+    let output = stau(&["uninstall", "zsh", "-n", "--format", "json", "--no-copy"]);
+    assert!(output.status.success(), "uninstall failed: {:?}", output);
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let actions: Vec<&str> = plan["steps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|step| step["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(actions, ["unlink", "unlink"]);
+
+    // A symlink left behind by a removed file is unlinked by a restow
+    fs::remove_file(stau_dir.join("zsh/.zshenv")).unwrap();
+    let output = stau(&["restow", "zsh", "-n", "--format", "json"]);
+    assert!(output.status.success(), "restow failed: {:?}", output);
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let steps = plan["steps"].as_array().unwrap();
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[0]["action"], "unlink");
+    assert_eq!(steps[0]["reason"], "no longer in the package");
+    assert_eq!(steps[1]["action"], "skip");
+    assert!(target_dir.join(".zshenv").is_symlink());
+}
+
 #[test]
 fn test_adopt_multiple_files() {
     let temp_dir = TempDir::new().unwrap();
@@ -749,7 +1007,7 @@ fn test_adopt_multiple_files() {
     fs::write(&zshrc, "echo 'zsh'").unwrap();
 
     // Adopt multiple files
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args([
@@ -780,7 +1038,7 @@ fn test_partial_install_status() {
     create_test_package(&stau_dir, "vim", &[".vimrc", ".vim/plugin.vim"]);
 
     // Install the package
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -791,7 +1049,7 @@ fn test_partial_install_status() {
     fs::remove_file(target_dir.join(".vimrc")).unwrap();
 
     // List should show partial status
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["list"])
@@ -818,7 +1076,7 @@ fn test_install_empty_package() {
     fs::create_dir(&empty_pkg).unwrap();
 
     // Install empty package
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "empty"])
@@ -840,7 +1098,7 @@ fn test_list_with_empty_stau_dir() {
     fs::create_dir(&target_dir).unwrap();
 
     // List with no packages
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["list"])
@@ -866,7 +1124,7 @@ fn test_list_with_broken_symlinks() {
     create_test_package(&stau_dir, "vim", &[".vimrc", ".vim/plugin.vim"]);
 
     // Install
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -879,7 +1137,7 @@ fn test_list_with_broken_symlinks() {
     unix_fs::symlink(stau_dir.join("vim/.nonexistent"), &target_vimrc).unwrap();
 
     // List should show broken status
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["list"])
@@ -903,7 +1161,7 @@ fn test_adopt_nonexistent_file() {
     let nonexistent = target_dir.join(".nonexistent");
 
     // Try to adopt nonexistent file
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["adopt", "test", nonexistent.to_str().unwrap()])
@@ -926,7 +1184,7 @@ fn test_adopt_file_outside_target() {
     fs::write(&outside_file, "content").unwrap();
 
     // Try to adopt file outside target directory
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["adopt", "test", outside_file.to_str().unwrap()])
@@ -956,7 +1214,7 @@ fn test_adopt_with_existing_file_in_package() {
     fs::write(&vimrc, "new").unwrap();
 
     // Try to adopt - should fail due to conflict
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["adopt", "vim", vimrc.to_str().unwrap()])
@@ -967,6 +1225,214 @@ fn test_adopt_with_existing_file_in_package() {
     assert_eq!(output.status.code().unwrap(), 2); // ConflictingFile error
 }
 
+#[test]
+fn test_adopt_identical_file_already_in_package() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    let package_dir = stau_dir.join("vim");
+    fs::create_dir(&package_dir).unwrap();
+    fs::write(package_dir.join(".vimrc"), "set number").unwrap();
+
+    let vimrc = target_dir.join(".vimrc");
+    fs::write(&vimrc, "set number").unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["adopt", "vim", vimrc.to_str().unwrap()],
+        "",
+    );
+    assert!(output.status.success(), "adopt failed: {:?}", output);
+    assert!(vimrc.is_symlink());
+    assert_eq!(fs::read_link(&vimrc).unwrap(), package_dir.join(".vimrc"));
+    assert_eq!(
+        fs::read_to_string(package_dir.join(".vimrc")).unwrap(),
+        "set number"
+    );
+}
+
+#[test]
+fn test_adopt_fold_links_directory_as_whole() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    let alacritty = target_dir.join(".config/alacritty");
+    fs::create_dir_all(alacritty.join("themes")).unwrap();
+    fs::write(alacritty.join("alacritty.toml"), "[font]").unwrap();
+    fs::write(alacritty.join("themes/dark.toml"), "[colors]").unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["adopt", "--fold", "term", alacritty.to_str().unwrap()],
+        "",
+    );
+    assert!(output.status.success(), "adopt failed: {:?}", output);
+    let package_dir = stau_dir.join("term/.config/alacritty");
+    assert!(alacritty.is_symlink());
+    assert_eq!(fs::read_link(&alacritty).unwrap(), package_dir);
+    assert_eq!(
+        fs::read_to_string(stau_dir.join("term/stau.toml")).unwrap(),
+        "fold = [\".config/alacritty\"]\n"
+    );
+
+    // The folded directory counts as one healthy link
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "-s", "term"],
+        "",
+    );
+    assert!(output.status.success(), "status failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "1 ok, 0 missing, 0 broken, 0 conflicts\n"
+    );
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "term"],
+        "",
+    );
+    assert!(output.status.success(), "uninstall failed: {:?}", output);
+    assert!(!alacritty.is_symlink());
+    assert_eq!(
+        fs::read_to_string(alacritty.join("themes/dark.toml")).unwrap(),
+        "[colors]"
+    );
+
+    fs::remove_dir_all(&alacritty).unwrap();
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "term"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert_eq!(fs::read_link(&alacritty).unwrap(), package_dir);
+}
+
+#[test]
+fn test_adopt_into_target_section() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir_all(stau_dir.join("tools")).unwrap();
+    fs::write(
+        stau_dir.join("tools/stau.toml"),
+        "[targets]\nbin = \".local/bin\"\n",
+    )
+    .unwrap();
+    let script = target_dir.join(".local/bin/backup");
+    fs::create_dir_all(script.parent().unwrap()).unwrap();
+    fs::write(&script, "#!/bin/sh").unwrap();
+    let npmrc = target_dir.join(".npmrc");
+    fs::write(&npmrc, "registry").unwrap();
+
+    // .npmrc would be linked at ~/npm/.npmrc from there
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["adopt", "tools", npmrc.to_str().unwrap(), "--into", "npm/"],
+        "",
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("[targets]"));
+    assert!(!npmrc.is_symlink());
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["adopt", "tools", script.to_str().unwrap(), "--into", "bin/"],
+        "",
+    );
+    assert!(output.status.success(), "adopt failed: {:?}", output);
+    assert_eq!(
+        fs::read_link(&script).unwrap(),
+        stau_dir.join("tools/bin/backup")
+    );
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "-s", "tools"],
+        "",
+    );
+    assert!(output.status.success(), "status failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "1 ok, 0 missing, 0 broken, 0 conflicts\n"
+    );
+}
+
+#[test]
+fn test_adopt_runs_post_adopt_hooks() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let log = temp_dir.path().join("hooks.log");
+
+    fs::create_dir_all(stau_dir.join("git/scripts")).unwrap();
+    fs::create_dir_all(stau_dir.join(".stau")).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_script(
+        &stau_dir.join("git/scripts/post-adopt.sh"),
+        &format!("#!/bin/bash\necho \"package $*\" >> {}\n", log.display()),
+    );
+    create_script(
+        &stau_dir.join(".stau/post-adopt.sh"),
+        &format!(
+            "#!/bin/bash\necho \"repo $STAU_PACKAGE $#\" >> {}\nexit 1\n",
+            log.display()
+        ),
+    );
+    let gitconfig = target_dir.join(".gitconfig");
+    let gitignore = target_dir.join(".gitignore_global");
+    fs::write(&gitconfig, "[user]").unwrap();
+    fs::write(&gitignore, "*.swp").unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &[
+            "adopt",
+            "git",
+            gitconfig.to_str().unwrap(),
+            gitignore.to_str().unwrap(),
+        ],
+        "",
+    );
+    // A failing post-adopt hook only warns
+    assert!(output.status.success(), "adopt failed: {:?}", output);
+    assert!(gitconfig.is_symlink());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Post-adopt script failed"));
+    assert_eq!(
+        fs::read_to_string(&log).unwrap(),
+        format!(
+            "package {} {}\nrepo git 2\n",
+            stau_dir.join("git/.gitconfig").display(),
+            stau_dir.join("git/.gitignore_global").display()
+        )
+    );
+}
+
 #[test]
 fn test_clean_with_dry_run() {
     use std::os::unix::fs as unix_fs;
@@ -981,7 +1447,7 @@ fn test_clean_with_dry_run() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -994,7 +1460,7 @@ fn test_clean_with_dry_run() {
     unix_fs::symlink(stau_dir.join("vim/.nonexistent"), &target_vimrc).unwrap();
 
     // Clean with dry-run
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["clean", "vim", "--dry-run"])
@@ -1006,6 +1472,95 @@ fn test_clean_with_dry_run() {
     assert!(target_vimrc.symlink_metadata().is_ok());
 }
 
+#[test]
+fn test_restow_only_changes_what_differs() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc", ".gvimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let inode_before = fs::symlink_metadata(target_dir.join(".vimrc"))
+        .unwrap()
+        .ino();
+
+    // Drop one file from the package and add another
+    fs::remove_file(stau_dir.join("vim/.gvimrc")).unwrap();
+    fs::write(stau_dir.join("vim/.exrc"), "set number\n").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["restow", "vim"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Restow failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 added, 0 re-pointed, 1 removed, 1 unchanged"));
+
+    // The unchanged link is the very same link
+    let inode_after = fs::symlink_metadata(target_dir.join(".vimrc"))
+        .unwrap()
+        .ino();
+    assert_eq!(inode_before, inode_after);
+
+    assert!(target_dir.join(".exrc").is_symlink());
+    assert!(target_dir.join(".gvimrc").symlink_metadata().is_err());
+}
+
+#[test]
+fn test_restow_removes_links_under_removed_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(
+        &stau_dir,
+        "nvim",
+        &[".config/nvim/init.lua", ".config/nvim/lua/plugins.lua"],
+    );
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    assert!(stau(&["install", "nvim"]).status.success());
+    let plugins = target_dir.join(".config/nvim/lua/plugins.lua");
+    assert!(plugins.is_symlink());
+
+    // No file of the package is left in lua/, so only the state knows to look there
+    fs::remove_dir_all(stau_dir.join("nvim/.config/nvim/lua")).unwrap();
+
+    let output = stau(&["restow", "nvim"]);
+    assert!(output.status.success(), "Restow failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("0 added, 0 re-pointed, 1 removed, 1 unchanged"));
+    assert!(plugins.symlink_metadata().is_err());
+    assert!(target_dir.join(".config/nvim/init.lua").is_symlink());
+}
+
 #[test]
 fn test_restow_with_run_setup() {
     let temp_dir = TempDir::new().unwrap();
@@ -1028,7 +1583,7 @@ fn test_restow_with_run_setup() {
     );
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim", "--no-setup"])
@@ -1036,7 +1591,7 @@ fn test_restow_with_run_setup() {
         .unwrap();
 
     // Restow with run-setup
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["restow", "vim", "--run-setup"])
@@ -1061,7 +1616,7 @@ fn test_uninstall_empty_package() {
     fs::create_dir(&empty_pkg).unwrap();
 
     // Uninstall empty package
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["uninstall", "empty"])
@@ -1088,14 +1643,15 @@ fn test_status_with_conflict() {
     fs::write(target_dir.join(".vimrc"), "conflict").unwrap();
 
     // Status should show conflict
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["status", "vim"])
         .output()
         .unwrap();
 
-    assert!(output.status.success());
+    // A conflicting mapping makes the status check fail
+    assert_eq!(output.status.code(), Some(5));
     let stdout = String::from_utf8_lossy(&output.stdout);
     assert!(stdout.contains("[conflict]") || stdout.contains("not installed"));
 }
@@ -1118,7 +1674,7 @@ fn test_install_with_setup_script_failure() {
     create_script(&setup_script, "#!/bin/bash\nexit 1\n");
 
     // Install should fail
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1142,7 +1698,7 @@ fn test_install_with_target_flag() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install using --target flag instead of env var
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .args(["install", "vim", "--target", target_dir.to_str().unwrap()])
         .output()
@@ -1152,6 +1708,56 @@ fn test_install_with_target_flag() {
     assert!(target_dir.join(".vimrc").is_symlink());
 }
 
+#[test]
+fn test_install_into_multiple_targets() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let home = temp_dir.path().join("home");
+    let chroot = temp_dir.path().join("chroot");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&home).unwrap();
+    fs::create_dir(&chroot).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .args(args)
+            .args(["-t", home.to_str().unwrap(), "-t", chroot.to_str().unwrap()])
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["install", "vim"]);
+    assert!(output.status.success(), "Install into two targets failed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("Target {}:", home.display())));
+    assert!(stdout.contains(&format!("Target {}:", chroot.display())));
+    assert!(home.join(".vimrc").is_symlink());
+    assert!(chroot.join(".vimrc").is_symlink());
+
+    // Each target is checked on its own
+    let output = stau(&["status", "--short", "vim"]);
+    assert!(output.status.success());
+    fs::remove_file(chroot.join(".vimrc")).unwrap();
+    fs::write(chroot.join(".vimrc"), "local").unwrap();
+    let output = stau(&["status", "--short", "vim"]);
+    assert_eq!(output.status.code(), Some(5));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 ok, 0 missing, 0 broken, 0 conflicts"));
+    assert!(stdout.contains("0 ok, 0 missing, 0 broken, 1 conflicts"));
+
+    let output = stau(&["uninstall", "vim"]);
+    assert!(output.status.success(), "Uninstall from two targets failed");
+    assert!(!home.join(".vimrc").is_symlink());
+    assert_eq!(fs::read_to_string(chroot.join(".vimrc")).unwrap(), "local");
+
+    let output = stau(&["uninstall", "--purge", "vim"]);
+    assert!(!output.status.success());
+}
+
 #[test]
 fn test_uninstall_with_target_flag() {
     let temp_dir = TempDir::new().unwrap();
@@ -1164,7 +1770,7 @@ fn test_uninstall_with_target_flag() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1172,7 +1778,7 @@ fn test_uninstall_with_target_flag() {
         .unwrap();
 
     // Uninstall using --target flag
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .args(["uninstall", "vim", "--target", target_dir.to_str().unwrap()])
         .output()
@@ -1194,7 +1800,7 @@ fn test_restow_with_target_flag() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1202,7 +1808,7 @@ fn test_restow_with_target_flag() {
         .unwrap();
 
     // Restow using --target flag
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .args(["restow", "vim", "--target", target_dir.to_str().unwrap()])
         .output()
@@ -1225,7 +1831,7 @@ fn test_adopt_with_target_flag() {
     fs::write(&config_file, "echo 'hello'").unwrap();
 
     // Adopt using --target flag
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .args([
             "adopt",
@@ -1253,7 +1859,7 @@ fn test_list_with_target_flag() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1261,7 +1867,7 @@ fn test_list_with_target_flag() {
         .unwrap();
 
     // List using --target flag
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .args(["list", "--target", target_dir.to_str().unwrap()])
         .output()
@@ -1285,7 +1891,7 @@ fn test_status_with_target_flag() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Status using --target flag
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .args(["status", "vim", "--target", target_dir.to_str().unwrap()])
         .output()
@@ -1308,7 +1914,7 @@ fn test_clean_with_target_flag() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1316,7 +1922,7 @@ fn test_clean_with_target_flag() {
         .unwrap();
 
     // Clean using --target flag
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .args(["clean", "vim", "--target", target_dir.to_str().unwrap()])
         .output()
@@ -1338,7 +1944,7 @@ fn test_uninstall_verbose() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1346,7 +1952,7 @@ fn test_uninstall_verbose() {
         .unwrap();
 
     // Uninstall with --verbose
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["uninstall", "vim", "--verbose"])
@@ -1370,7 +1976,7 @@ fn test_restow_verbose() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1378,7 +1984,7 @@ fn test_restow_verbose() {
         .unwrap();
 
     // Restow with --verbose
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["restow", "vim", "--verbose"])
@@ -1403,7 +2009,7 @@ fn test_adopt_verbose() {
     fs::write(&config_file, "echo 'hello'").unwrap();
 
     // Adopt with --verbose
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["adopt", "bash", config_file.to_str().unwrap(), "--verbose"])
@@ -1428,7 +2034,7 @@ fn test_clean_verbose() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1436,7 +2042,7 @@ fn test_clean_verbose() {
         .unwrap();
 
     // Clean with --verbose
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["clean", "vim", "--verbose"])
@@ -1461,7 +2067,7 @@ fn test_uninstall_dry_run() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1469,7 +2075,7 @@ fn test_uninstall_dry_run() {
         .unwrap();
 
     // Uninstall with --dry-run
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["uninstall", "vim", "--dry-run"])
@@ -1497,7 +2103,7 @@ fn test_restow_dry_run() {
     create_test_package(&stau_dir, "vim", &[".vimrc"]);
 
     // Install first
-    let _ = Command::new(stau_binary())
+    let _ = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["install", "vim"])
@@ -1505,7 +2111,7 @@ fn test_restow_dry_run() {
         .unwrap();
 
     // Restow with --dry-run
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["restow", "vim", "--dry-run"])
@@ -1530,7 +2136,7 @@ fn test_adopt_dry_run() {
     fs::write(&config_file, "echo 'hello'").unwrap();
 
     // Adopt with --dry-run
-    let output = Command::new(stau_binary())
+    let output = stau_command(temp_dir.path())
         .env("STAU_DIR", &stau_dir)
         .env("STAU_TARGET", &target_dir)
         .args(["adopt", "bash", config_file.to_str().unwrap(), "--dry-run"])
@@ -1543,3 +2149,3739 @@ fn test_adopt_dry_run() {
     // Package directory should not be created
     assert!(!stau_dir.join("bash").exists());
 }
+
+#[test]
+fn test_install_glob_pattern() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "nvim", &[".config/nvim/init.lua"]);
+    create_test_package(&stau_dir, "nvim-lsp", &[".config/nvim/lsp.lua"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "nvim*"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert!(target_dir.join(".config/nvim/init.lua").is_symlink());
+    assert!(target_dir.join(".config/nvim/lsp.lua").is_symlink());
+    assert!(!target_dir.join(".zshrc").exists());
+}
+
+#[test]
+fn test_glob_pattern_no_match() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["status", "work-*"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert_eq!(output.status.code().unwrap(), 1);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("No packages match pattern: work-*"));
+}
+
+#[test]
+fn test_install_all_with_exclude() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig"]);
+    create_test_package(&stau_dir, "work-vpn", &[".vpnrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "--all", "--exclude", "work-*"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert!(target_dir.join(".vimrc").is_symlink());
+    assert!(target_dir.join(".gitconfig").is_symlink());
+    assert!(!target_dir.join(".vpnrc").exists());
+
+    // Bulk restow only touches installed packages and honours --exclude
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["restow", "--all", "--exclude", "git"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Restow failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("vim"));
+    assert!(!stdout.contains("git"));
+    assert!(!target_dir.join(".vpnrc").exists());
+}
+
+#[test]
+fn test_all_conflicts_with_package_names() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim", "--all"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(!target_dir.join(".vimrc").exists());
+}
+
+#[test]
+fn test_install_packages_from_stdin() {
+    use std::process::Stdio;
+
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+
+    let mut child = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"vim\n\n# comment\ngit\n")
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert!(target_dir.join(".vimrc").is_symlink());
+    assert!(target_dir.join(".gitconfig").is_symlink());
+    assert!(!target_dir.join(".zshrc").exists());
+}
+
+#[test]
+fn test_install_runs_os_specific_setup_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    let package_dir = stau_dir.join("zsh");
+
+    let log_file = temp_dir.path().join("setup.log");
+    create_script(
+        &package_dir.join("setup.sh"),
+        &format!("#!/bin/bash\necho generic >> {}\n", log_file.display()),
+    );
+    create_script(
+        &package_dir.join(format!("setup.{}.sh", std::env::consts::OS)),
+        &format!("#!/bin/bash\necho os >> {}\n", log_file.display()),
+    );
+    create_script(
+        &package_dir.join("setup.plan9.sh"),
+        &format!("#!/bin/bash\necho plan9 >> {}\n", log_file.display()),
+    );
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "zsh"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), "generic\nos\n");
+    assert!(!target_dir.join("setup.plan9.sh").exists());
+    assert!(
+        !target_dir
+            .join(format!("setup.{}.sh", std::env::consts::OS))
+            .exists()
+    );
+}
+
+#[test]
+fn test_install_runs_host_specific_setup_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    let package_dir = stau_dir.join("zsh");
+    fs::create_dir(package_dir.join("setup.d")).unwrap();
+
+    let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+    let hostname = hostname.split('.').next().unwrap().to_string();
+
+    let log_file = temp_dir.path().join("setup.log");
+    create_script(
+        &package_dir.join(format!("setup.d/{}.sh", hostname)),
+        &format!("#!/bin/bash\necho this-host >> {}\n", log_file.display()),
+    );
+    create_script(
+        &package_dir.join("setup.d/some-other-machine.sh"),
+        &format!("#!/bin/bash\necho other-host >> {}\n", log_file.display()),
+    );
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "zsh"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), "this-host\n");
+    assert!(!target_dir.join("setup.d").exists());
+}
+
+#[test]
+fn test_run_named_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "nvim", &[".config/nvim/init.lua"]);
+    let scripts_dir = stau_dir.join("nvim/scripts");
+    fs::create_dir(&scripts_dir).unwrap();
+
+    let marker = temp_dir.path().join("updated");
+    create_script(
+        &scripts_dir.join("update-plugins.sh"),
+        &format!(
+            "#!/bin/bash\necho \"$STAU_PACKAGE $STAU_TARGET $1\" > {}\n",
+            marker.display()
+        ),
+    );
+
+    // Scripts directory is not linked into the target
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "nvim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!target_dir.join("scripts").exists());
+
+    // Listing available scripts
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["run", "nvim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("update-plugins"));
+
+    // Running a script with arguments
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["run", "nvim", "update-plugins", "--sync"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "Run failed: {:?}", output);
+    assert_eq!(
+        fs::read_to_string(&marker).unwrap().trim(),
+        format!("nvim {} --sync", target_dir.display())
+    );
+
+    // Unknown script
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["run", "nvim", "missing"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code().unwrap(), 1);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Script not found"));
+}
+
+#[test]
+fn test_hook_scripts_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    let hooks_dir = stau_dir.join("zsh/.stau");
+    fs::create_dir(&hooks_dir).unwrap();
+
+    let log_file = temp_dir.path().join("hooks.log");
+    for hook in ["pre-install", "setup", "teardown", "post-uninstall"] {
+        create_script(
+            &hooks_dir.join(format!("{}.sh", hook)),
+            &format!("#!/bin/bash\necho {} >> {}\n", hook, log_file.display()),
+        );
+    }
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "zsh"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert!(!target_dir.join(".stau").exists());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["uninstall", "zsh"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "Uninstall failed: {:?}", output);
+
+    assert_eq!(
+        fs::read_to_string(&log_file).unwrap(),
+        "pre-install\nsetup\nteardown\npost-uninstall\n"
+    );
+}
+
+#[test]
+fn test_failing_pre_install_hook_aborts_install() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    let scripts_dir = stau_dir.join("zsh/scripts");
+    fs::create_dir(&scripts_dir).unwrap();
+    create_script(&scripts_dir.join("pre-install.sh"), "#!/bin/bash\nexit 1\n");
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "zsh"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code().unwrap(), 4);
+    assert!(!target_dir.join(".zshrc").exists());
+}
+
+#[test]
+fn test_check_uninstall_hook_aborts_uninstall() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "gpg", &[".gnupg/gpg.conf"]);
+    let hooks_dir = stau_dir.join("gpg/.stau");
+    fs::create_dir(&hooks_dir).unwrap();
+
+    let teardown_marker = temp_dir.path().join("teardown-ran");
+    create_script(
+        &hooks_dir.join("teardown.sh"),
+        &format!("#!/bin/bash\ntouch {}\n", teardown_marker.display()),
+    );
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "gpg"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Veto the uninstall
+    create_script(
+        &hooks_dir.join("check-uninstall.sh"),
+        "#!/bin/bash\necho 'gpg-agent is running' >&2\nexit 1\n",
+    );
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["uninstall", "gpg"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code().unwrap(), 4);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("gpg-agent is running"));
+    assert!(stderr.contains("Uninstall aborted"));
+    assert!(target_dir.join(".gnupg/gpg.conf").is_symlink());
+    assert!(!teardown_marker.exists());
+}
+
+#[test]
+fn test_upgrade_reruns_setup_scripts() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let state_dir = temp_dir.path().join("state");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let log_file = temp_dir.path().join("setup.log");
+    let setup_script = stau_dir.join("zsh/setup.sh");
+    create_script(
+        &setup_script,
+        &format!("#!/bin/bash\necho v1 >> {}\n", log_file.display()),
+    );
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .env("STAU_STATE_DIR", &state_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    assert!(stau(&["install", "zsh"]).status.success());
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), "v1\n");
+
+    // Upgrade always re-runs setup, only for installed packages
+    let output = stau(&["upgrade", "--all"]);
+    assert!(output.status.success(), "Upgrade failed: {:?}", output);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("vim"));
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), "v1\nv1\n");
+
+    // --changed skips unchanged scripts
+    assert!(stau(&["upgrade", "zsh", "--changed"]).status.success());
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), "v1\nv1\n");
+
+    // ...but re-runs them once they are edited
+    create_script(
+        &setup_script,
+        &format!("#!/bin/bash\necho v2 >> {}\n", log_file.display()),
+    );
+    assert!(stau(&["upgrade", "zsh", "--changed"]).status.success());
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), "v1\nv1\nv2\n");
+
+    assert!(target_dir.join(".zshrc").is_symlink());
+}
+
+#[test]
+fn test_uninstall_no_copy() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc", ".vim/colors/theme.vim"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["uninstall", "vim", "--no-copy"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Uninstall failed: {:?}", output);
+    assert!(target_dir.join(".vimrc").symlink_metadata().is_err());
+    assert!(
+        target_dir
+            .join(".vim/colors/theme.vim")
+            .symlink_metadata()
+            .is_err()
+    );
+    // Package sources are untouched
+    assert!(stau_dir.join("vim/.vimrc").exists());
+}
+
+/// Helper to run stau with the given input piped to stdin
+fn run_stau_with_input(
+    temp_dir: &Path,
+    stau_dir: &std::path::Path,
+    target_dir: &std::path::Path,
+    args: &[&str],
+    input: &str,
+) -> std::process::Output {
+    use std::process::Stdio;
+
+    let mut child = stau_command(temp_dir)
+        .env("STAU_DIR", stau_dir)
+        .env("STAU_TARGET", target_dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+
+    child.wait_with_output().unwrap()
+}
+
+/// Helper to run git in a directory, panicking on failure
+fn run_git(dir: &std::path::Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git failed: {:?}", output);
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn test_uninstall_purge_deletes_package() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "vim"],
+        "",
+    );
+    assert!(output.status.success());
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "vim", "--no-copy", "--purge"],
+        "y\n",
+    );
+
+    assert!(output.status.success(), "Uninstall failed: {:?}", output);
+    assert!(target_dir.join(".vimrc").symlink_metadata().is_err());
+    assert!(!stau_dir.join("vim").exists());
+    assert!(stau_dir.join("zsh").exists());
+}
+
+#[test]
+fn test_yes_answers_prompts() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    fs::write(target_dir.join(".vimrc"), "my vimrc").unwrap();
+
+    // A conflict prompt is answered by backing the file up
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim", "--conflicts", "prompt", "--yes"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("already exists. backup"));
+    assert!(target_dir.join(".vimrc").is_symlink());
+
+    // Nothing on stdin, but the purge is still confirmed
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["-y", "uninstall", "vim", "--no-copy", "--purge"],
+        "",
+    );
+    assert!(output.status.success(), "Uninstall failed: {:?}", output);
+    assert!(!stau_dir.join("vim").exists());
+}
+
+#[test]
+fn test_uninstall_purge_declined_keeps_package() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "vim"],
+        "",
+    );
+    assert!(output.status.success());
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "vim", "--purge"],
+        "n\n",
+    );
+
+    assert!(output.status.success(), "Uninstall failed: {:?}", output);
+    // The package is still unlinked, just not deleted
+    assert!(!target_dir.join(".vimrc").is_symlink());
+    assert!(stau_dir.join("vim/.vimrc").exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Keeping package directory"));
+}
+
+#[test]
+fn test_uninstall_purge_stages_git_deletion() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    run_git(&stau_dir, &["init", "-q"]);
+    run_git(&stau_dir, &["add", "."]);
+    run_git(&stau_dir, &["commit", "-q", "-m", "add vim"]);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "vim", "--purge"],
+        "yes\n",
+    );
+
+    assert!(output.status.success(), "Uninstall failed: {:?}", output);
+    assert!(!stau_dir.join("vim").exists());
+    let status = run_git(&stau_dir, &["status", "--porcelain"]);
+    assert!(status.contains("D  vim/.vimrc"), "status: {}", status);
+}
+
+#[test]
+fn test_uninstall_purge_refuses_uncommitted_changes() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    run_git(&stau_dir, &["init", "-q"]);
+    run_git(&stau_dir, &["add", "."]);
+    run_git(&stau_dir, &["commit", "-q", "-m", "add vim"]);
+    fs::write(stau_dir.join("vim/.vimrc"), "set number").unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "vim", "--purge"],
+        "y\n",
+    );
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("uncommitted changes"));
+    assert!(stau_dir.join("vim/.vimrc").exists());
+
+    // Moving files out of the way doesn't mean throwing away work
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "vim", "--purge", "--force"],
+        "y\n",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stau_dir.join("vim/.vimrc").exists());
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "vim", "--purge", "--discard-changes"],
+        "y\n",
+    );
+    assert!(output.status.success(), "Uninstall failed: {:?}", output);
+    assert!(!stau_dir.join("vim").exists());
+}
+
+#[test]
+fn test_uninstall_purge_deletes_every_layer() {
+    let temp_dir = TempDir::new().unwrap();
+    let personal_dir = temp_dir.path().join("dotfiles");
+    let work_dir = temp_dir.path().join("work-dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&personal_dir).unwrap();
+    fs::create_dir(&work_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&personal_dir, "git", &[".gitconfig", ".gitignore_global"]);
+    create_test_package(&work_dir, "git", &[".gitconfig"]);
+    create_test_package(&personal_dir, "vim", &[".vimrc"]);
+
+    let stau_dirs = std::env::join_paths([&personal_dir, &work_dir]).unwrap();
+    let stau_dirs = Path::new(&stau_dirs);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        stau_dirs,
+        &target_dir,
+        &["install", "git", "vim"],
+        "",
+    );
+    assert!(output.status.success(), "Install failed: {:?}", output);
+
+    // Both answers are read before any package is unlinked
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        stau_dirs,
+        &target_dir,
+        &["-j", "2", "uninstall", "git", "vim", "--no-copy", "--purge"],
+        "y\nn\n",
+    );
+    assert!(output.status.success(), "Uninstall failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!(
+        "Delete package directories {}, {}?",
+        personal_dir.join("git").display(),
+        work_dir.join("git").display()
+    )));
+    assert!(!personal_dir.join("git").exists());
+    assert!(!work_dir.join("git").exists());
+    assert!(personal_dir.join("vim/.vimrc").exists());
+    assert!(target_dir.join(".vimrc").symlink_metadata().is_err());
+}
+
+#[test]
+fn test_rm_removes_file_and_symlink() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(
+        &stau_dir,
+        "vim",
+        &[".vimrc", ".vim/old-plugin.vim", ".vim/new-plugin.vim"],
+    );
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["rm", "vim", ".vim/old-plugin.vim"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "rm failed: {:?}", output);
+    assert!(!stau_dir.join("vim/.vim/old-plugin.vim").exists());
+    assert!(
+        target_dir
+            .join(".vim/old-plugin.vim")
+            .symlink_metadata()
+            .is_err()
+    );
+    assert!(target_dir.join(".vim/new-plugin.vim").is_symlink());
+    assert!(target_dir.join(".vimrc").is_symlink());
+}
+
+#[test]
+fn test_rm_keeps_foreign_target_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    fs::write(target_dir.join(".vimrc"), "my own vimrc").unwrap();
+
+    let target_file = target_dir.join(".vimrc");
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["rm", "vim", target_file.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "rm failed: {:?}", output);
+    assert!(!stau_dir.join("vim/.vimrc").exists());
+    assert_eq!(fs::read_to_string(&target_file).unwrap(), "my own vimrc");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("not a stau-managed symlink"));
+}
+
+#[test]
+fn test_rm_removes_file_from_every_layer() {
+    let temp_dir = TempDir::new().unwrap();
+    let personal_dir = temp_dir.path().join("dotfiles");
+    let work_dir = temp_dir.path().join("work-dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&personal_dir).unwrap();
+    fs::create_dir(&work_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&personal_dir, "git", &[".gitconfig", ".gitignore_global"]);
+    create_test_package(&work_dir, "git", &[".gitconfig"]);
+
+    let stau_dirs = std::env::join_paths([&personal_dir, &work_dir]).unwrap();
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dirs)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    assert!(stau(&["install", "git"]).status.success());
+
+    let output = stau(&["rm", "git", ".gitconfig"]);
+    assert!(output.status.success(), "rm failed: {:?}", output);
+    assert!(!work_dir.join("git/.gitconfig").exists());
+    assert!(!personal_dir.join("git/.gitconfig").exists());
+    assert!(target_dir.join(".gitconfig").symlink_metadata().is_err());
+    assert!(target_dir.join(".gitignore_global").is_symlink());
+
+    // Nothing comes back from a lower layer
+    let output = stau(&["restow", "git"]);
+    assert!(output.status.success(), "Restow failed: {:?}", output);
+    assert!(target_dir.join(".gitconfig").symlink_metadata().is_err());
+}
+
+#[test]
+fn test_rm_missing_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["rm", "vim", ".gvimrc"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("File not found in package 'vim'"));
+}
+
+#[test]
+fn test_install_from_package_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let checkout_dir = temp_dir.path().join("checkout");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&checkout_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&checkout_dir, "tmux", &[".tmux.conf"]);
+
+    // STAU_DIR does not exist and is not needed
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", temp_dir.path().join("missing"))
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "--package-dir"])
+        .arg(checkout_dir.join("tmux"))
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    let link = target_dir.join(".tmux.conf");
+    assert!(link.is_symlink());
+    assert_eq!(
+        fs::read_link(&link).unwrap(),
+        checkout_dir.join("tmux/.tmux.conf").canonicalize().unwrap()
+    );
+}
+
+#[test]
+fn test_install_package_dir_conflicts_with_packages() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim", "--package-dir"])
+        .arg(stau_dir.join("vim"))
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(!target_dir.join(".vimrc").exists());
+}
+
+#[test]
+fn test_install_with_overlay_dirs() {
+    let temp_dir = TempDir::new().unwrap();
+    let personal_dir = temp_dir.path().join("dotfiles");
+    let work_dir = temp_dir.path().join("work-dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&personal_dir).unwrap();
+    fs::create_dir(&work_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&personal_dir, "git", &[".gitconfig", ".gitignore_global"]);
+    create_test_package(&work_dir, "git", &[".gitconfig"]);
+    create_test_package(&work_dir, "aws", &[".aws/config"]);
+
+    let stau_dirs = std::env::join_paths([&personal_dir, &work_dir]).unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dirs)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "git", "aws"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    // The work overlay overrides .gitconfig, the rest comes from the base
+    assert_eq!(
+        fs::read_link(target_dir.join(".gitconfig")).unwrap(),
+        work_dir.join("git/.gitconfig")
+    );
+    assert_eq!(
+        fs::read_link(target_dir.join(".gitignore_global")).unwrap(),
+        personal_dir.join("git/.gitignore_global")
+    );
+    assert!(target_dir.join(".aws/config").is_symlink());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dirs)
+        .env("STAU_TARGET", &target_dir)
+        .args(["uninstall", "git", "--no-copy"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Uninstall failed: {:?}", output);
+    assert!(target_dir.join(".gitconfig").symlink_metadata().is_err());
+    assert!(
+        target_dir
+            .join(".gitignore_global")
+            .symlink_metadata()
+            .is_err()
+    );
+}
+
+#[test]
+fn test_porcelain_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc", ".zshenv"]);
+    fs::write(target_dir.join(".zshenv"), "existing").unwrap();
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    assert!(stau(&["install", "vim"]).status.success());
+
+    let output = stau(&["list", "--porcelain"]);
+    assert!(output.status.success(), "list failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "# stau porcelain v1\ntarget {}\npackage installed 1 1 0 0 vim\npackage not-installed 2 0 0 1 zsh\n",
+            target_dir.display()
+        )
+    );
+
+    let output = stau(&["status", "vim", "zsh", "--porcelain=v1"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "# stau porcelain v1\ntarget {0}\npackage installed 1 1 0 0 vim\nfile installed {0}/.vimrc\n\
+             package not-installed 2 0 0 1 zsh\nfile conflict {0}/.zshenv\nfile missing {0}/.zshrc\n",
+            target_dir.display()
+        )
+    );
+
+    // Versions that don't exist are refused rather than guessed
+    assert!(!stau(&["list", "--porcelain=v2"]).status.success());
+
+    // A package that can't be read doesn't keep the others from being checked
+    fs::write(stau_dir.join("zsh/stau.toml"), "fold = [\"../outside\"]\n").unwrap();
+    let output = stau(&["status", "zsh", "vim", "--porcelain"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "# stau porcelain v1\ntarget {0}\npackage error 0 0 0 0 zsh\n\
+             package installed 1 1 0 0 vim\nfile installed {0}/.vimrc\n",
+            target_dir.display()
+        )
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("folded directory"));
+    fs::remove_file(stau_dir.join("zsh/stau.toml")).unwrap();
+
+    // A newline in a file name doesn't break the record in two
+    fs::write(stau_dir.join("vim/two\nlines"), "").unwrap();
+    let output = stau(&["status", "vim", "--porcelain"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!(
+            "\nfile missing \"{}/two\\nlines\"\n",
+            target_dir.display()
+        )),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_list_paths() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["list", "--paths"]);
+    assert!(output.status.success(), "list failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "{}\n{}\n",
+            stau_dir.join("vim").display(),
+            stau_dir.join("zsh").display()
+        )
+    );
+
+    let output = stau(&["install", "zsh"]);
+    assert!(output.status.success());
+    let output = stau(&[
+        "list",
+        "--paths",
+        "--with-target",
+        "-0",
+        "--filter",
+        "installed",
+    ]);
+    assert!(output.status.success(), "list failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "{}\0{}\0",
+            stau_dir.join("zsh").display(),
+            target_dir.display()
+        )
+    );
+
+    let output = stau(&["list", "--paths", "--format", "table"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_list_formats_keep_going_past_invalid_manifest() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "broken", &[".brokenrc"]);
+    fs::write(stau_dir.join("broken/stau.toml"), "description = \n").unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+
+    for format in ["table", "tree"] {
+        let output = stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(["list", "--format", format])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "list failed: {:?}", output);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let broken = stdout.lines().find(|l| l.contains("broken")).unwrap();
+        assert!(broken.contains("error"), "{}", stdout);
+        let zsh = stdout.lines().find(|l| l.contains("zsh")).unwrap();
+        assert!(zsh.contains("not installed"), "{}", stdout);
+    }
+}
+
+#[test]
+fn test_list_format_table() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "a-package-with-a-very-long-name", &[".longrc"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc", ".gvimrc"]);
+    fs::write(
+        stau_dir.join("vim/stau.toml"),
+        "description = \"Vim editor config\"\n",
+    )
+    .unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // The manifest is metadata, not a dotfile
+    assert!(!target_dir.join("stau.toml").exists());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["list", "--format", "table"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("NAME"));
+    assert!(lines[1].starts_with("a-package-with-a-very-long-name  not installed  0/1"));
+    assert!(lines[2].contains("installed"));
+    assert!(lines[2].contains("2/2"));
+    assert!(lines[2].ends_with("Vim editor config"));
+    // Columns line up regardless of name length
+    assert_eq!(lines[0].find("STATE"), lines[2].find("installed"));
+}
+
+#[test]
+fn test_list_format_tree() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "nvim", &[".config/nvim/init.lua"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig"]);
+    fs::write(stau_dir.join("nvim/stau.toml"), "tags = [\"editor\"]\n").unwrap();
+    fs::write(stau_dir.join("vim/stau.toml"), "tags = [\"editor\"]\n").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["list", "--format", "tree"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let tree: Vec<&str> = stdout.lines().skip(2).collect();
+    assert_eq!(
+        tree,
+        vec![
+            "editor",
+            "├── nvim  [not installed]",
+            "└── vim   [not installed]",
+            "(untagged)",
+            "└── git   [not installed]",
+        ]
+    );
+}
+
+#[test]
+fn test_status_exit_code_reflects_health() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["status", "vim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Replace the link with a dangling one
+    fs::remove_file(target_dir.join(".vimrc")).unwrap();
+    std::os::unix::fs::symlink(stau_dir.join("vim/.missing"), target_dir.join(".vimrc")).unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["status", "vim"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unhealthy packages: vim"));
+}
+
+#[test]
+fn test_list_check() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc", ".gvimrc"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+
+    // A conflict in a package that isn't installed is not a failure
+    fs::write(target_dir.join(".zshrc"), "local zshrc").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["list", "--check"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "list --check failed: {:?}", output);
+
+    // Occupy one of vim's targets with a regular file
+    fs::remove_file(target_dir.join(".gvimrc")).unwrap();
+    fs::write(target_dir.join(".gvimrc"), "local gvimrc").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["list", "--check"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unhealthy packages: vim"));
+}
+
+#[test]
+fn test_list_filter_by_state() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig", ".gitignore_global"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim", "git"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    fs::remove_file(target_dir.join(".gitignore_global")).unwrap();
+
+    let list = |filter: &str| {
+        let output = stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(["list", "--filter", filter])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        ["vim", "git", "zsh"]
+            .into_iter()
+            .filter(|pkg| stdout.contains(&format!("  {} ", pkg)))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(list("installed"), vec!["vim"]);
+    assert_eq!(list("partial"), vec!["git"]);
+    assert_eq!(list("not-installed"), vec!["zsh"]);
+    assert_eq!(list("partial,not-installed"), vec!["git", "zsh"]);
+}
+
+#[test]
+fn test_status_long_shows_details() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc", ".gvimrc"]);
+    fs::write(target_dir.join(".vimrc"), "local").unwrap();
+    std::os::unix::fs::symlink("/elsewhere/gvimrc", target_dir.join(".gvimrc")).unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["status", "vim", "--long"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("target: 5 bytes, modified "), "{}", stdout);
+    assert!(stdout.contains("link:   -> /elsewhere/gvimrc (missing)"));
+    assert!(stdout.contains("source: 24 bytes, modified "));
+}
+
+#[test]
+fn test_info_shows_package_readme() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "nvim", &[".config/nvim/init.lua"]);
+    fs::write(
+        stau_dir.join("nvim/README.md"),
+        "# nvim\n\nRun :Lazy sync after installing.\n",
+    )
+    .unwrap();
+    fs::write(
+        stau_dir.join("nvim/stau.toml"),
+        "description = \"Neovim config\"\ntags = [\"editor\"]\n",
+    )
+    .unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "nvim"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // The package README is documentation, not a dotfile
+    assert!(!target_dir.join("README.md").exists());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["info", "nvim"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Description: Neovim config"));
+    assert!(stdout.contains("Tags: editor"));
+    assert!(stdout.contains("Run :Lazy sync after installing."));
+}
+
+#[test]
+fn test_config_set_get_list() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_home = temp_dir.path().join("config");
+    let target_dir = temp_dir.path().join("home");
+
+    let stau_config = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("XDG_CONFIG_HOME", &config_home)
+            .env_remove("STAU_CONFIG")
+            .env("STAU_DIR", temp_dir.path().join("missing"))
+            .args(["config"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau_config(&["set", "target", target_dir.to_str().unwrap()]);
+    assert!(output.status.success(), "config set failed: {:?}", output);
+    assert!(config_home.join("stau/config.toml").exists());
+
+    let output = stau_config(&["get", "target"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        target_dir.to_str().unwrap()
+    );
+
+    let output = stau_config(&["list"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("state_dir  = (not set)"));
+
+    // Invalid keys and values are rejected
+    let output = stau_config(&["set", "colour", "always"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown key 'colour'"));
+
+    let output = stau_config(&["set", "target", "relative"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_config_file_provides_defaults() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_home = temp_dir.path().join("config");
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    fs::create_dir_all(config_home.join("stau")).unwrap();
+    fs::write(
+        config_home.join("stau/config.toml"),
+        format!(
+            "stau_dir = {:?}\ntarget = {:?}\n",
+            stau_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("XDG_CONFIG_HOME", &config_home)
+        .env_remove("STAU_CONFIG")
+        .env_remove("STAU_DIR")
+        .env_remove("STAU_TARGET")
+        .args(["install", "vim"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert!(target_dir.join(".vimrc").is_symlink());
+}
+
+#[test]
+fn test_env_shows_provenance() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let config_file = temp_dir.path().join("stau.toml");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::write(&config_file, "target = \"/from/file\"\n").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_CONFIG", &config_file)
+        .env("STAU_DIR", &stau_dir)
+        .env_remove("STAU_TARGET")
+        .args(["env"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "env failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!(
+        "STAU_CONFIG     {}  [env STAU_CONFIG]",
+        config_file.display()
+    )));
+    assert!(stdout.contains(&format!(
+        "STAU_DIR        {}  [env STAU_DIR]",
+        stau_dir.display()
+    )));
+    assert!(stdout.contains("STAU_TARGET     /from/file  [config file]"));
+
+    // A flag beats both the environment and the config file
+    let output = stau_command(temp_dir.path())
+        .env("STAU_CONFIG", &config_file)
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", "/from/env")
+        .args(["env", "--target", "/from/flag"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("STAU_TARGET     /from/flag  [flag]"));
+}
+
+#[test]
+fn test_install_missing_target() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home/user");
+    let config_file = temp_dir.path().join("stau.toml");
+
+    fs::create_dir(&stau_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_CONFIG", &config_file)
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Target directory not found"));
+    assert!(stderr.contains("--create-target"));
+    assert!(!target_dir.exists());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_CONFIG", &config_file)
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim", "--create-target"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert!(target_dir.join(".vimrc").is_symlink());
+}
+
+#[test]
+fn test_install_missing_target_from_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let config_file = temp_dir.path().join("stau.toml");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::write(&config_file, "create_target = true\n").unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_CONFIG", &config_file)
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert!(target_dir.join(".vimrc").is_symlink());
+}
+
+#[test]
+fn test_package_name_cannot_escape_stau_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    // A directory next to STAU_DIR that a traversal would reach
+    create_test_package(temp_dir.path(), "outside", &[".profile"]);
+
+    for args in [
+        vec!["install", "../outside"],
+        vec!["status", "../outside"],
+        vec!["info", "../outside"],
+        vec!["adopt", "..", ".profile"],
+    ] {
+        let output = stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(&args)
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(1), "{:?} was accepted", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("Invalid package name"), "{:?}", args);
+    }
+
+    assert!(target_dir.join(".profile").symlink_metadata().is_err());
+}
+
+#[test]
+fn test_interrupt_stops_pre_install_script() {
+    use std::time::{Duration, Instant};
+
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    let marker = temp_dir.path().join("started");
+    fs::create_dir(stau_dir.join("vim/scripts")).unwrap();
+    create_script(
+        &stau_dir.join("vim/scripts/pre-install.sh"),
+        &format!("#!/bin/bash\ntouch {}\nsleep 30\n", marker.display()),
+    );
+
+    let child = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "vim"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let start = Instant::now();
+    while !marker.exists() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "script never ran"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        start.elapsed() < Duration::from_secs(10),
+        "script was not stopped"
+    );
+    assert_eq!(output.status.code(), Some(130));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Interrupted"));
+    assert!(target_dir.join(".vimrc").symlink_metadata().is_err());
+}
+
+#[test]
+fn test_gc_removes_empty_directories() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(
+        &stau_dir,
+        "nvim",
+        &[".config/nvim/lua/plugins.lua", ".config/nvim/init.lua"],
+    );
+
+    // Left behind after uninstalling, plus a file of the user's next to it
+    fs::create_dir_all(target_dir.join(".config/nvim/lua")).unwrap();
+    fs::write(target_dir.join(".config/other.conf"), "keep").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["gc", "nvim", "--dry-run"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "gc failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(".config/nvim/lua"));
+    assert!(stdout.contains(&format!("{}\n", target_dir.join(".config/nvim").display())));
+    assert!(target_dir.join(".config/nvim/lua").exists());
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["gc", "nvim"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "gc failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Removed 2 empty directories"));
+    assert!(!target_dir.join(".config/nvim").exists());
+    assert!(target_dir.join(".config/other.conf").exists());
+}
+
+#[test]
+fn test_check_reports_cross_package_conflicts() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    create_test_package(&stau_dir, "bash", &[".bashrc", ".profile"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc", ".profile"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["check", "--all"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!(
+        "{}: bash, zsh",
+        target_dir.join(".profile").display()
+    )));
+    assert!(!stdout.contains(".bashrc"));
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["check", "bash", "vim"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "check failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("No conflicts between 2 packages"));
+}
+
+#[test]
+fn test_install_orders_by_dependencies() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let log = temp_dir.path().join("setup.log");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    for (name, depends) in [("nvim", "[\"fonts\"]"), ("fonts", "[]")] {
+        create_test_package(&stau_dir, name, &[&format!(".{}rc", name)]);
+        fs::write(
+            stau_dir.join(name).join("stau.toml"),
+            format!("depends = {}\n", depends),
+        )
+        .unwrap();
+        create_script(
+            &stau_dir.join(name).join("setup.sh"),
+            &format!("#!/bin/bash\necho {} >> {}\n", name, log.display()),
+        );
+    }
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "nvim", "fonts"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "Install failed: {:?}", output);
+    assert_eq!(fs::read_to_string(&log).unwrap(), "fonts\nnvim\n");
+}
+
+#[test]
+fn test_install_dependency_cycle() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    for (name, dependency) in [("a", "b"), ("b", "a")] {
+        create_test_package(&stau_dir, name, &[&format!(".{}rc", name)]);
+        fs::write(
+            stau_dir.join(name).join("stau.toml"),
+            format!("depends = [\"{}\"]\n", dependency),
+        )
+        .unwrap();
+    }
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "--all"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Dependency cycle: a -> b -> a"));
+    assert!(target_dir.join(".arc").symlink_metadata().is_err());
+}
+
+#[test]
+fn test_clone_and_install() {
+    let temp_dir = TempDir::new().unwrap();
+    let origin = temp_dir.path().join("origin");
+    let target_dir = temp_dir.path().join("home");
+    let clone_dir = temp_dir.path().join("machine/dotfiles");
+
+    fs::create_dir(&origin).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&origin, "vim", &[".vimrc"]);
+    create_test_package(&origin, "zsh", &[".zshrc"]);
+    run_git(&origin, &["init", "-q"]);
+    run_git(&origin, &["add", "."]);
+    run_git(&origin, &["commit", "-q", "-m", "dotfiles"]);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", temp_dir.path().join("unused"))
+        .env("STAU_TARGET", &target_dir)
+        .args([
+            "clone",
+            origin.to_str().unwrap(),
+            "--path",
+            clone_dir.to_str().unwrap(),
+            "--install",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "clone failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("is not your STAU_DIR"));
+    assert!(clone_dir.join(".git").exists());
+    assert!(target_dir.join(".vimrc").is_symlink());
+    assert!(target_dir.join(".zshrc").is_symlink());
+}
+
+#[test]
+fn test_clone_into_stau_dir_without_installing() {
+    let temp_dir = TempDir::new().unwrap();
+    let origin = temp_dir.path().join("origin");
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&origin).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&origin, "vim", &[".vimrc"]);
+    run_git(&origin, &["init", "-q"]);
+    run_git(&origin, &["add", "."]);
+    run_git(&origin, &["commit", "-q", "-m", "dotfiles"]);
+
+    // STAU_DIR doesn't exist yet; clone creates it
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["clone", origin.to_str().unwrap()],
+        "n\n",
+    );
+
+    assert!(output.status.success(), "clone failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Install all 1 packages (vim)? [y/N]"));
+    assert!(!stdout.contains("is not your STAU_DIR"));
+    assert!(stau_dir.join("vim/.vimrc").exists());
+    assert!(target_dir.join(".vimrc").symlink_metadata().is_err());
+}
+
+#[test]
+fn test_adopt_commit() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    run_git(&stau_dir, &["init", "-q"]);
+    fs::write(target_dir.join(".bashrc"), "alias ll='ls -l'\n").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .env("GIT_AUTHOR_NAME", "Test")
+        .env("GIT_AUTHOR_EMAIL", "test@example.com")
+        .env("GIT_COMMITTER_NAME", "Test")
+        .env("GIT_COMMITTER_EMAIL", "test@example.com")
+        .args([
+            "adopt",
+            "shell",
+            target_dir.join(".bashrc").to_str().unwrap(),
+            "--commit",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "adopt failed: {:?}", output);
+    assert!(target_dir.join(".bashrc").is_symlink());
+    assert_eq!(
+        run_git(&stau_dir, &["log", "--format=%s"]),
+        "adopt .bashrc into shell\n"
+    );
+    assert_eq!(run_git(&stau_dir, &["status", "--porcelain"]), "");
+}
+
+#[test]
+fn test_list_long_shows_git_status() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    run_git(&stau_dir, &["init", "-q"]);
+    run_git(&stau_dir, &["add", "."]);
+    run_git(&stau_dir, &["commit", "-q", "-m", "dotfiles"]);
+
+    fs::write(stau_dir.join("vim/.vimrc"), "set number\n").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["list", "--long"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "list failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let vim = stdout.lines().find(|l| l.contains("vim")).unwrap();
+    let zsh = stdout.lines().find(|l| l.contains("zsh")).unwrap();
+    assert!(vim.ends_with("<uncommitted>"), "{}", vim);
+    assert!(!zsh.contains('<'), "{}", zsh);
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["list", "--long", "--format", "table"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().next().unwrap().contains("GIT"));
+    assert!(
+        stdout
+            .lines()
+            .find(|l| l.starts_with("vim"))
+            .unwrap()
+            .contains("uncommitted")
+    );
+}
+
+#[test]
+fn test_install_initializes_submodules() {
+    let temp_dir = TempDir::new().unwrap();
+    let plugin = temp_dir.path().join("plugin");
+    let origin = temp_dir.path().join("origin");
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&plugin).unwrap();
+    fs::create_dir(&origin).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+
+    fs::write(plugin.join("plugin.zsh"), "echo loaded\n").unwrap();
+    run_git(&plugin, &["init", "-q"]);
+    run_git(&plugin, &["add", "."]);
+    run_git(&plugin, &["commit", "-q", "-m", "plugin"]);
+
+    create_test_package(&origin, "zsh", &[".zshrc"]);
+    run_git(&origin, &["init", "-q"]);
+    run_git(
+        &origin,
+        &[
+            "-c",
+            "protocol.file.allow=always",
+            "submodule",
+            "add",
+            "-q",
+            plugin.to_str().unwrap(),
+            "zsh/.zsh/plugin",
+        ],
+    );
+    run_git(&origin, &["commit", "-q", "-m", "dotfiles"]);
+
+    // A plain clone leaves the submodule empty
+    run_git(
+        temp_dir.path(),
+        &["clone", "-q", origin.to_str().unwrap(), "dotfiles"],
+    );
+    assert!(!stau_dir.join("zsh/.zsh/plugin/plugin.zsh").exists());
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .env("GIT_CONFIG_COUNT", "1")
+            .env("GIT_CONFIG_KEY_0", "protocol.file.allow")
+            .env("GIT_CONFIG_VALUE_0", "always")
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    // A dry run reports what would be initialized
+    let output = stau(&["install", "zsh", "--dry-run"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(
+        String::from_utf8_lossy(&output.stdout)
+            .contains("Would initialize submodules in 'zsh': .zsh/plugin")
+    );
+
+    let output = stau(&["install", "zsh", "--init-submodules"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".zsh/plugin/plugin.zsh").is_symlink());
+    assert!(
+        target_dir
+            .join(".zsh/plugin/.git")
+            .symlink_metadata()
+            .is_err()
+    );
+}
+
+#[test]
+fn test_force_install_backs_up_replaced_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let state_dir = temp_dir.path().join("state");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    fs::write(target_dir.join(".vimrc"), "my old vimrc").unwrap();
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .env("STAU_STATE_DIR", &state_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["backups", "list"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("No backups found"));
+
+    assert!(stau(&["install", "vim", "--force"]).status.success());
+    assert!(target_dir.join(".vimrc").is_symlink());
+
+    let output = stau(&["backups", "list"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 files"), "unexpected list: {}", stdout);
+    let id = stdout.split_whitespace().next().unwrap().to_string();
+
+    let output = stau(&["backups", "show", &id]);
+    let vimrc = target_dir.join(".vimrc");
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&*vimrc.to_string_lossy()));
+
+    // The stau link in its place is replaced without --force
+    let output = stau(&["backups", "restore", &id, vimrc.to_str().unwrap()]);
+    assert!(output.status.success(), "restore failed: {:?}", output);
+    assert!(!vimrc.is_symlink());
+    assert_eq!(fs::read_to_string(&vimrc).unwrap(), "my old vimrc");
+
+    // Restoring again would overwrite the user's file
+    let output = stau(&["backups", "restore", &id]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = stau(&["backups", "prune", "--max-size", "0"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 of 1 backups pruned"));
+    let output = stau(&["backups", "show", &id]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Backup not found"));
+
+    assert!(!stau(&["backups", "prune"]).status.success());
+}
+
+#[test]
+fn test_adopt_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir_all(target_dir.join(".cache")).unwrap();
+    fs::write(target_dir.join(".inputrc"), "set editing-mode vi").unwrap();
+    fs::write(target_dir.join(".zshrc"), "bindkey -v").unwrap();
+    fs::write(target_dir.join(".zsh_history"), "ls").unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["adopt", "--scan"],
+        "1=shell 2\n",
+    );
+    assert!(output.status.success(), "adopt failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(".inputrc -> input"));
+    assert!(stdout.contains(".zshrc -> zsh"));
+    assert!(!stdout.contains(".zsh_history"));
+    assert!(!stdout.contains(".cache"));
+
+    assert!(target_dir.join(".inputrc").is_symlink());
+    assert!(stau_dir.join("shell/.inputrc").is_file());
+    assert!(stau_dir.join("zsh/.zshrc").is_file());
+
+    // Everything is managed now
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["adopt", "--scan"],
+        "",
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("No unmanaged dotfiles"));
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["adopt", "zsh", "--scan"],
+        "",
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_adopt_scan_leaves_out_dotfiles_inside_target() {
+    let temp_dir = TempDir::new().unwrap();
+    let target_dir = temp_dir.path().join("home");
+    let stau_dir = target_dir.join(".dotfiles");
+    let state_dir = target_dir.join(".stau-state");
+
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    fs::write(target_dir.join(".zshrc"), "bindkey -v").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .env("STAU_STATE_DIR", &state_dir)
+        .args(["--yes", "adopt", "--scan"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "adopt failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains(".dotfiles ->"), "stdout: {}", stdout);
+    assert!(!stdout.contains(".stau-state ->"), "stdout: {}", stdout);
+    assert!(stdout.contains(".zshrc -> zsh"));
+
+    assert!(target_dir.join(".zshrc").is_symlink());
+    assert!(stau_dir.join("zsh/.zshrc").is_file());
+    assert!(!stau_dir.join("dotfiles").exists());
+}
+
+#[test]
+fn test_grep_searches_package_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "bash", &[".bashrc"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    fs::write(
+        stau_dir.join("bash/.bashrc"),
+        "alias ls='ls -G'\nset -o vi\n",
+    )
+    .unwrap();
+    fs::write(stau_dir.join("zsh/.zshrc"), "bindkey -v\n").unwrap();
+    // Scripts are never linked, so they aren't searched either
+    create_script(&stau_dir.join("zsh/setup.sh"), "#!/bin/sh\nset -o vi\n");
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["grep", "set -o vi"],
+        "",
+    );
+    assert!(output.status.success(), "grep failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "bash/.bashrc:2:set -o vi\n"
+    );
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["grep", "-i", "-l", "BINDKEY|ALIAS"],
+        "",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "bash/.bashrc\nzsh/.zshrc\n"
+    );
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["grep", "alias", "zsh"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_summary_after_several_packages() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "git", &[".gitconfig"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc", ".gvimrc"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    create_script(&stau_dir.join("vim/setup.sh"), "#!/bin/sh\nexit 0\n");
+    fs::write(target_dir.join(".zshrc"), "mine").unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "--all"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(2));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout
+        .lines()
+        .skip_while(|l| !l.starts_with("PACKAGE"))
+        .collect();
+    assert_eq!(
+        lines[0].split_whitespace().collect::<Vec<_>>(),
+        vec![
+            "PACKAGE",
+            "CREATED",
+            "REMOVED",
+            "CONFLICTS",
+            "SCRIPTS",
+            "RESULT"
+        ]
+    );
+    assert_eq!(
+        lines[1].split_whitespace().collect::<Vec<_>>(),
+        vec!["git", "1", "0", "0", "0", "ok"]
+    );
+    assert_eq!(
+        lines[2].split_whitespace().collect::<Vec<_>>(),
+        vec!["vim", "2", "0", "0", "1", "ok"]
+    );
+    assert!(lines[3].starts_with("zsh") && lines[3].ends_with("failed"));
+
+    // A single package gets no summary
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "git"],
+        "",
+    );
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("PACKAGE"));
+}
+
+#[test]
+fn test_strict_turns_warnings_into_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_script(&stau_dir.join("vim/teardown.sh"), "#!/bin/sh\nexit 1\n");
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    // Adopting a file that doesn't exist
+    let missing = target_dir.join(".gvimrc");
+    let missing = missing.to_str().unwrap();
+    assert!(stau(&["adopt", "vim", missing]).status.success());
+    let output = stau(&["--strict", "adopt", "vim", missing]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("File does not exist"));
+
+    // A failing teardown script
+    assert!(stau(&["install", "vim"]).status.success());
+    let output = stau(&["uninstall", "vim", "--strict"]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(target_dir.join(".vimrc").is_symlink());
+
+    // A symlink inside the package, which is never linked
+    std::os::unix::fs::symlink("/etc/hostname", stau_dir.join("vim/.exrc")).unwrap();
+    assert!(stau(&["restow", "vim"]).status.success());
+    let output = stau(&["restow", "vim", "--strict"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not a regular file"));
+}
+
+#[test]
+fn test_conflict_policies() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "shell", &[".bashrc", ".profile"]);
+    fs::write(target_dir.join(".bashrc"), "my bashrc").unwrap();
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    // skip: the existing file stays, everything else is linked
+    let output = stau(&["install", "shell", "--conflicts", "skip"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 symlinks created, 1 skipped"));
+    assert_eq!(
+        fs::read_to_string(target_dir.join(".bashrc")).unwrap(),
+        "my bashrc"
+    );
+    assert!(target_dir.join(".profile").is_symlink());
+
+    // restow still sees the conflict and fails by default
+    assert_eq!(stau(&["restow", "shell"]).status.code(), Some(2));
+
+    // adopt from the package manifest: the user's file replaces the package's
+    fs::write(stau_dir.join("shell/stau.toml"), "conflicts = \"adopt\"\n").unwrap();
+    let output = stau(&["restow", "shell"]);
+    assert!(output.status.success(), "restow failed: {:?}", output);
+    assert!(target_dir.join(".bashrc").is_symlink());
+    assert_eq!(
+        fs::read_to_string(stau_dir.join("shell/.bashrc")).unwrap(),
+        "my bashrc"
+    );
+
+    // The command line wins over the manifest
+    assert!(stau(&["uninstall", "shell", "--no-copy"]).status.success());
+    fs::write(target_dir.join(".profile"), "my profile").unwrap();
+    let output = stau(&["install", "shell", "--conflicts", "overwrite"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".profile").is_symlink());
+    assert_eq!(
+        fs::read_to_string(stau_dir.join("shell/.profile")).unwrap(),
+        "test content for .profile\n"
+    );
+
+    // The global setting applies when neither says otherwise
+    fs::remove_file(stau_dir.join("shell/stau.toml")).unwrap();
+    assert!(stau(&["uninstall", "shell", "--no-copy"]).status.success());
+    fs::write(target_dir.join(".profile"), "my profile").unwrap();
+    assert!(
+        stau(&["config", "set", "conflicts", "skip"])
+            .status
+            .success()
+    );
+    let output = stau(&["install", "shell"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(!target_dir.join(".profile").is_symlink());
+
+    assert!(
+        !stau(&["install", "shell", "--force", "--conflicts", "skip"])
+            .status
+            .success()
+    );
+}
+
+#[test]
+fn test_snapshot_and_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let state_dir = temp_dir.path().join("state");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .env("STAU_STATE_DIR", &state_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    assert!(stau(&["install", "vim"]).status.success());
+
+    let output = stau(&["snapshot", "before"]);
+    assert!(output.status.success(), "snapshot failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Recorded 2 paths"));
+
+    let output = stau(&["snapshot", "--list"]);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("before"));
+
+    // Restructure: swap which package is installed
+    assert!(stau(&["uninstall", "vim", "--no-copy"]).status.success());
+    assert!(stau(&["install", "zsh"]).status.success());
+
+    let output = stau(&["restore", "before"]);
+    assert!(output.status.success(), "restore failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 restored, 1 removed, 0 skipped"));
+    assert!(target_dir.join(".vimrc").is_symlink());
+    assert!(target_dir.join(".zshrc").symlink_metadata().is_err());
+
+    let output = stau(&["restore", "missing"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Snapshot not found"));
+}
+
+#[test]
+fn test_sync_pulls_and_restows_changed_packages() {
+    let temp_dir = TempDir::new().unwrap();
+    let origin = temp_dir.path().join("origin");
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&origin).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&origin, "vim", &[".vimrc"]);
+    create_test_package(&origin, "zsh", &[".zshrc"]);
+    run_git(&origin, &["init", "-q"]);
+    run_git(&origin, &["add", "."]);
+    run_git(&origin, &["commit", "-q", "-m", "initial"]);
+    run_git(
+        temp_dir.path(),
+        &["clone", "-q", origin.to_str().unwrap(), "dotfiles"],
+    );
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    assert!(stau(&["install", "vim"]).status.success());
+
+    fs::write(origin.join("vim/.gvimrc"), "set guifont=Mono").unwrap();
+    fs::write(origin.join("zsh/.zlogin"), "echo hi").unwrap();
+    run_git(&origin, &["add", "."]);
+    run_git(&origin, &["commit", "-q", "-m", "more files"]);
+
+    let output = stau(&["sync"]);
+    assert!(output.status.success(), "sync failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Restowed vim"));
+    assert!(target_dir.join(".gvimrc").is_symlink());
+    // zsh changed too, but it isn't installed
+    assert!(!target_dir.join(".zlogin").exists());
+
+    let output = stau(&["sync"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("is up to date"));
+
+    let output = stau(&["sync", "--daemon"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_install_leaves_out_gitignored_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc", ".zshrc.zwc"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc", ".vim/.netrwhist"]);
+    fs::write(stau_dir.join(".gitignore"), "*.zwc\n").unwrap();
+    fs::write(stau_dir.join("vim/.gitignore"), ".netrwhist\n").unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "zsh", "vim"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".zshrc").is_symlink());
+    assert!(target_dir.join(".vimrc").is_symlink());
+    assert!(!target_dir.join(".zshrc.zwc").exists());
+    assert!(!target_dir.join(".vim/.netrwhist").exists());
+    assert!(!target_dir.join(".gitignore").exists());
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["restow", "--no-gitignore", "zsh", "vim"],
+        "",
+    );
+    assert!(output.status.success(), "restow failed: {:?}", output);
+    assert!(target_dir.join(".zshrc.zwc").is_symlink());
+    assert!(target_dir.join(".vim/.netrwhist").is_symlink());
+}
+
+#[test]
+fn test_install_leaves_out_skipped_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let config_file = temp_dir.path().join("stau.toml");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc", "LICENSE", "Makefile"]);
+    fs::write(stau_dir.join("zsh/stau.toml"), "skip = [\"Makefile\"]\n").unwrap();
+    fs::write(&config_file, "skip = [\"LICENSE\"]\n").unwrap();
+
+    let output = stau_command(temp_dir.path())
+        .env("STAU_CONFIG", &config_file)
+        .env("STAU_DIR", &stau_dir)
+        .env("STAU_TARGET", &target_dir)
+        .args(["install", "zsh"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".zshrc").is_symlink());
+    assert!(!target_dir.join("LICENSE").exists());
+    assert!(!target_dir.join("Makefile").exists());
+}
+
+#[test]
+fn test_install_leaves_out_junk_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let config_file = temp_dir.path().join("stau.toml");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(
+        &stau_dir,
+        "zsh",
+        &[".zshrc", ".DS_Store", ".config/zsh/.aliases.swp"],
+    );
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_CONFIG", &config_file)
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["install", "zsh"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".zshrc").is_symlink());
+    assert!(!target_dir.join(".DS_Store").exists());
+    assert!(!target_dir.join(".config/zsh/.aliases.swp").exists());
+
+    // An empty list links everything
+    let output = stau(&["config", "set", "junk", ""]);
+    assert!(output.status.success(), "config set failed: {:?}", output);
+    let output = stau(&["restow", "zsh"]);
+    assert!(output.status.success(), "restow failed: {:?}", output);
+    assert!(target_dir.join(".DS_Store").is_symlink());
+}
+
+#[test]
+fn test_install_follows_package_symlinks() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "common", &["snippets/git.zsh"]);
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    std::os::unix::fs::symlink("../common/snippets", stau_dir.join("zsh/.zsh")).unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "zsh"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(!target_dir.join(".zsh").exists());
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["restow", "--follow-package-symlinks", "zsh"],
+        "",
+    );
+    assert!(output.status.success(), "restow failed: {:?}", output);
+    assert!(target_dir.join(".zsh/git.zsh").is_symlink());
+    assert!(target_dir.join(".zsh/git.zsh").is_file());
+
+    // Later commands follow the symlink like the restow did
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "zsh"],
+        "",
+    );
+    assert!(output.status.success(), "status failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains(".zsh/git.zsh"));
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "zsh"],
+        "",
+    );
+    assert!(output.status.success(), "uninstall failed: {:?}", output);
+    assert!(!target_dir.join(".zsh/git.zsh").is_symlink());
+    assert!(target_dir.join(".zsh/git.zsh").is_file());
+}
+
+#[test]
+fn test_defer_and_override_links_of_other_packages() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "base", &[".config/git/config", ".bashrc"]);
+    create_test_package(&stau_dir, "work", &[".config/git/config", ".bashrc"]);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "base"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &[
+            "install",
+            "--defer",
+            r"\.bashrc",
+            "--override",
+            r"\.config/git/",
+            "work",
+        ],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert_eq!(
+        fs::read_link(target_dir.join(".bashrc")).unwrap(),
+        stau_dir.join("base/.bashrc")
+    );
+    assert_eq!(
+        fs::read_link(target_dir.join(".config/git/config")).unwrap(),
+        stau_dir.join("work/.config/git/config")
+    );
+
+    // Without a matching pattern the other package's link is still a conflict
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["restow", "base"],
+        "",
+    );
+    assert!(!output.status.success());
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["restow", "--override", ".config", "base"],
+        "",
+    );
+    assert!(output.status.success(), "restow failed: {:?}", output);
+    assert_eq!(
+        fs::read_link(target_dir.join(".config/git/config")).unwrap(),
+        stau_dir.join("base/.config/git/config")
+    );
+}
+
+#[test]
+fn test_install_links_root_directory_at_absolute_paths() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    // Stands in for a system directory such as /etc
+    let etc = temp_dir.path().join("etc");
+    let relative_etc = etc.strip_prefix("/").unwrap();
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    let hosts = Path::new("ROOT").join(relative_etc).join("hosts");
+    create_test_package(&stau_dir, "system", &[".profile", hosts.to_str().unwrap()]);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "system"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".profile").is_symlink());
+    assert!(!target_dir.join("ROOT").exists());
+    assert_eq!(
+        fs::read_link(etc.join("hosts")).unwrap(),
+        stau_dir.join("system").join(&hosts)
+    );
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "system"],
+        "",
+    );
+    assert!(output.status.success(), "uninstall failed: {:?}", output);
+    assert!(!etc.join("hosts").is_symlink());
+}
+
+#[test]
+fn test_install_links_target_sections() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "tools", &["bin/backup", "config/tool.toml"]);
+    fs::write(
+        stau_dir.join("tools/stau.toml"),
+        "[targets]\nbin = \".local/bin\"\nconfig = \".config\"\n",
+    )
+    .unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "tools"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".local/bin/backup").is_symlink());
+    assert!(target_dir.join(".config/tool.toml").is_symlink());
+    assert!(!target_dir.join("bin").exists());
+
+    // A section has to stay inside the package
+    fs::write(
+        stau_dir.join("tools/stau.toml"),
+        "[targets]\n\"../other\" = \".config\"\n",
+    )
+    .unwrap();
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["restow", "tools"],
+        "",
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("must be a subdirectory"));
+}
+
+#[test]
+fn test_bundle_installs_and_uninstalls_members() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    fs::create_dir(stau_dir.join("dev")).unwrap();
+    fs::write(
+        stau_dir.join("dev/stau.toml"),
+        "members = [\"zsh\", \"git\"]\n",
+    )
+    .unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "dev"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".zshrc").is_symlink());
+    assert!(target_dir.join(".gitconfig").is_symlink());
+    assert!(!target_dir.join(".vimrc").exists());
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "--no-copy", "dev"],
+        "",
+    );
+    assert!(output.status.success(), "uninstall failed: {:?}", output);
+    assert!(!target_dir.join(".zshrc").exists());
+    assert!(!target_dir.join(".gitconfig").exists());
+}
+
+#[test]
+fn test_machines_registry_records_installs() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let config_file = temp_dir.path().join("stau.toml");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig"]);
+    fs::write(&config_file, "machines = true\n").unwrap();
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_CONFIG", &config_file)
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["install", "zsh", "git"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    let registry = fs::read_to_string(stau_dir.join(".stau/machines.toml")).unwrap();
+    assert!(registry.contains("packages = [\n    \"git\",\n    \"zsh\",\n]"));
+
+    let output = stau(&["uninstall", "--no-copy", "git"]);
+    assert!(output.status.success(), "uninstall failed: {:?}", output);
+    let output = stau(&["machines"]);
+    assert!(output.status.success(), "machines failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("HOST"));
+    assert!(stdout.lines().nth(1).unwrap().ends_with("  zsh"));
+}
+
+#[test]
+fn test_machines_registry_records_concurrent_installs() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let config_file = temp_dir.path().join("stau.toml");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    let packages: Vec<String> = (0..24).map(|i| format!("p{}", i)).collect();
+    for package in &packages {
+        create_test_package(&stau_dir, package, &[&format!(".{}rc", package)]);
+    }
+    fs::write(&config_file, "machines = true\n").unwrap();
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_CONFIG", &config_file)
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["install", "--all", "--jobs", "8"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    let registry = fs::read_to_string(stau_dir.join(".stau/machines.toml")).unwrap();
+    for package in &packages {
+        assert!(
+            registry.contains(&format!("\"{}\"", package)),
+            "{} missing from {}",
+            package,
+            registry
+        );
+    }
+
+    let output = stau(&["drift"]);
+    assert!(output.status.success(), "drift failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("No drift"));
+}
+
+#[test]
+fn test_drift_lists_packages_to_converge() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let config_file = temp_dir.path().join("stau.toml");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    fs::write(&config_file, "machines = true\n").unwrap();
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_CONFIG", &config_file)
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["install", "zsh", "git"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+    let output = stau(&["drift"]);
+    assert!(output.status.success(), "drift failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("No drift"));
+
+    // Changes made behind the registry's back
+    fs::remove_file(target_dir.join(".gitconfig")).unwrap();
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "vim"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    let output = stau(&["drift"]);
+    assert_eq!(output.status.code(), Some(5));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("  install: git\n"));
+    assert!(stdout.contains("  uninstall: vim\n"));
+    assert!(!stdout.contains("zsh"));
+}
+
+#[test]
+fn test_status_short_prints_one_line() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc", ".zshenv"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "zsh", "git"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    // vim isn't installed, so --all leaves it out
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "--short", "--all"],
+        "",
+    );
+    assert!(output.status.success(), "status failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "3 ok, 0 missing, 0 broken, 0 conflicts\n"
+    );
+
+    fs::remove_file(target_dir.join(".gitconfig")).unwrap();
+    fs::remove_file(target_dir.join(".zshenv")).unwrap();
+    fs::write(target_dir.join(".zshenv"), "local").unwrap();
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "-s", "zsh", "git"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(5));
+    assert!(output.stderr.is_empty());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "1 ok, 1 missing, 0 broken, 1 conflicts\n"
+    );
+
+    // Failing quietly still reports the timings
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "-s", "--timings", "zsh", "git"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("TOTAL"), "stderr: {}", stderr);
+    assert!(!stderr.contains("Error"), "stderr: {}", stderr);
+}
+
+#[test]
+fn test_prompt_counts_broken_links_and_dirty_packages() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let state_dir = temp_dir.path().join("state");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig", ".gitmessage"]);
+    for args in [
+        &["init", "-q"][..],
+        &["add", "."],
+        &[
+            "-c",
+            "user.name=Test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "-q",
+            "-m",
+            "initial",
+        ],
+    ] {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&stau_dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git failed: {:?}", output);
+    }
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .env("STAU_STATE_DIR", &state_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["install", "zsh", "git"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    // Nothing to report prints nothing, so prompt modules stay hidden
+    let output = stau(&["prompt", "--budget", "10000"]);
+    assert!(output.status.success(), "prompt failed: {:?}", output);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "\n");
+    assert!(state_dir.join("prompt.json").is_file());
+
+    fs::remove_file(target_dir.join(".gitconfig")).unwrap();
+    std::os::unix::fs::symlink(temp_dir.path().join("gone"), target_dir.join(".gitconfig"))
+        .unwrap();
+    fs::write(stau_dir.join("zsh/.zshrc"), "changed").unwrap();
+
+    // The cached result is still fresh
+    let output = stau(&["prompt"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "\n");
+
+    fs::remove_file(state_dir.join("prompt.json")).unwrap();
+    let output = stau(&["prompt", "--budget", "10000"]);
+    assert!(output.status.success(), "prompt failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "1 broken 1 dirty\n"
+    );
+}
+
+#[test]
+fn test_prompt_watch_keeps_cache_up_to_date() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let state_dir = temp_dir.path().join("state");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "git", &[".gitconfig", ".config/git/ignore"]);
+
+    let stau = |args: &[&str]| {
+        let mut command = stau_command(temp_dir.path());
+        command
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .env("STAU_STATE_DIR", &state_dir)
+            .args(args);
+        command
+    };
+    let output = stau(&["install", "git"]).output().unwrap();
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    // Stops the watch also when an assertion fails
+    struct Watch(std::process::Child);
+    impl Drop for Watch {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+    let _watch = Watch(
+        stau(&["prompt", "--watch"])
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    let broken_after_change = |expected: u64| {
+        for _ in 0..100 {
+            if let Ok(contents) = fs::read_to_string(state_dir.join("prompt.json")) {
+                let segment: serde_json::Value = serde_json::from_str(&contents).unwrap();
+                if segment["broken"] == expected {
+                    return true;
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        false
+    };
+    assert!(broken_after_change(0));
+
+    // A link replaced in the target
+    fs::remove_file(target_dir.join(".gitconfig")).unwrap();
+    std::os::unix::fs::symlink(temp_dir.path().join("gone"), target_dir.join(".gitconfig"))
+        .unwrap();
+    assert!(broken_after_change(1));
+
+    // A file added to the package whose link is broken
+    fs::write(stau_dir.join("git/.gitmessage"), "").unwrap();
+    std::os::unix::fs::symlink(temp_dir.path().join("gone"), target_dir.join(".gitmessage"))
+        .unwrap();
+    assert!(broken_after_change(2));
+
+    // The segment doesn't need checking again
+    let output = stau(&["prompt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "2 broken\n");
+
+    let output = stau(&["prompt", "--watch"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already running"));
+}
+
+#[test]
+fn test_status_global_classifies_links_in_target() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "zsh", &[".config/zsh/.zshrc"]);
+    fs::write(stau_dir.join("README.md"), "dotfiles").unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "vim", "zsh"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "--global"],
+        "",
+    );
+    assert!(output.status.success(), "status failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[zsh]"));
+    assert!(stdout.contains("2 valid, 0 broken, 0 orphaned, 0 foreign"));
+
+    std::os::unix::fs::symlink(stau_dir.join("vim/.gvimrc"), target_dir.join(".gvimrc")).unwrap();
+    std::os::unix::fs::symlink(stau_dir.join("vim/.vimrc"), target_dir.join(".exrc")).unwrap();
+    std::os::unix::fs::symlink(stau_dir.join("README.md"), target_dir.join("README.md")).unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "--global"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(5));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!(
+        "[orphaned]           {}",
+        target_dir.join(".exrc").display()
+    )));
+    assert!(stdout.contains(&format!(
+        "[BROKEN]             {}",
+        target_dir.join(".gvimrc").display()
+    )));
+    assert!(stdout.contains(&format!(
+        "[foreign]            {}",
+        target_dir.join("README.md").display()
+    )));
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "--global", "-s"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "2 valid, 1 broken, 1 orphaned, 1 foreign\n"
+    );
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "--global", "vim"],
+        "",
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_fmt_moves_hook_scripts_into_scripts_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let state_dir = temp_dir.path().join("state");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    let log_file = temp_dir.path().join("setup.log");
+    create_script(
+        &stau_dir.join("zsh/setup.sh"),
+        &format!("#!/bin/bash\necho setup >> {}\n", log_file.display()),
+    );
+    create_script(&stau_dir.join("zsh/teardown.linux.sh"), "#!/bin/bash\n");
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .env("STAU_STATE_DIR", &state_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    assert!(stau(&["install", "zsh"]).status.success());
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), "setup\n");
+
+    let output = stau(&["fmt", "zsh", "--dry-run"]);
+    assert!(output.status.success(), "fmt failed: {:?}", output);
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("Would move setup.sh to scripts/setup.sh")
+    );
+    assert!(stau_dir.join("zsh/setup.sh").exists());
+
+    let output = stau(&["fmt", "zsh"]);
+    assert!(output.status.success(), "fmt failed: {:?}", output);
+    assert!(!stau_dir.join("zsh/setup.sh").exists());
+    assert!(stau_dir.join("zsh/scripts/setup.sh").is_file());
+    assert!(stau_dir.join("zsh/scripts/teardown.linux.sh").is_file());
+    assert!(target_dir.join(".zshrc").is_symlink());
+
+    // The moved setup script still counts as unchanged
+    assert!(stau(&["upgrade", "zsh", "--changed"]).status.success());
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), "setup\n");
+
+    let output = stau(&["fmt", "zsh"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("zsh is already formatted"));
+
+    create_script(&stau_dir.join("zsh/setup.sh"), "#!/bin/bash\n");
+    let output = stau(&["fmt", "zsh"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+    assert!(stau_dir.join("zsh/setup.sh").exists());
+}
+
+#[test]
+fn test_fmt_orders_manifest_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    let manifest = stau_dir.join("zsh/stau.toml");
+    fs::write(
+        &manifest,
+        "tags = [\"shell\"]\n# Shown by stau list\ndescription = \"Z shell\"\n",
+    )
+    .unwrap();
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["fmt", "zsh", "--dry-run"]);
+    assert!(output.status.success(), "fmt failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Would reorder the keys of"));
+    assert!(fs::read_to_string(&manifest).unwrap().starts_with("tags"));
+
+    let output = stau(&["fmt", "zsh"]);
+    assert!(output.status.success(), "fmt failed: {:?}", output);
+    assert_eq!(
+        fs::read_to_string(&manifest).unwrap(),
+        "# Shown by stau list\ndescription = \"Z shell\"\ntags = [\"shell\"]\n"
+    );
+
+    let output = stau(&["fmt", "zsh"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("zsh is already formatted"));
+}
+
+#[test]
+fn test_install_only_given_paths() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(
+        &stau_dir,
+        "nvim",
+        &[
+            ".config/nvim/init.lua",
+            ".config/nvim/lua/plugins.lua",
+            ".config/nvim/lua/keys.lua",
+            ".local/share/nvim/site/x.vim",
+        ],
+    );
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &[
+            "install",
+            "nvim",
+            "--path",
+            ".config/nvim/init.lua",
+            "--path",
+            ".config/nvim/lua",
+        ],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".config/nvim/init.lua").is_symlink());
+    assert!(target_dir.join(".config/nvim/lua/plugins.lua").is_symlink());
+    assert!(target_dir.join(".config/nvim/lua/keys.lua").is_symlink());
+    assert!(!target_dir.join(".local").exists());
+
+    for path in [".config/nvim/missing.lua", "../nvim", ".config/nvim/ini"] {
+        let output = run_stau_with_input(
+            temp_dir.path(),
+            &stau_dir,
+            &target_dir,
+            &["install", "nvim", "--path", path],
+            "",
+        );
+        assert!(!output.status.success(), "--path {} should fail", path);
+    }
+    assert!(!target_dir.join(".local").exists());
+}
+
+#[test]
+fn test_restow_since_revision() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "zsh", &[".zshrc"]);
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_test_package(&stau_dir, "git", &[".gitconfig"]);
+
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&stau_dir)
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git failed: {:?}", output);
+    };
+    git(&["init", "-q"]);
+    git(&["add", "."]);
+    git(&["commit", "-q", "-m", "initial"]);
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "zsh", "vim"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    fs::write(stau_dir.join("zsh/.zshenv"), "").unwrap();
+    fs::write(stau_dir.join("vim/.gvimrc"), "").unwrap();
+    fs::write(stau_dir.join("git/.gitignore_global"), "").unwrap();
+    git(&["add", "zsh", "git"]);
+    git(&["commit", "-q", "-m", "add zshenv"]);
+
+    // vim's new file isn't committed and git isn't installed
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["restow", "--since", "HEAD~1"],
+        "",
+    );
+    assert!(output.status.success(), "restow failed: {:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("zsh"));
+    assert!(!stdout.contains("vim"));
+    assert!(!stdout.contains("git"));
+    assert!(target_dir.join(".zshenv").is_symlink());
+    assert!(!target_dir.join(".gvimrc").exists());
+    assert!(!target_dir.join(".gitignore_global").exists());
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["restow", "--since", "HEAD"],
+        "",
+    );
+    assert!(output.status.success(), "restow failed: {:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("No installed packages changed"));
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["restow", "--since", "HEAD", "zsh"],
+        "",
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_pre_command_hook_can_abort() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let lock = temp_dir.path().join("locked");
+    let log = temp_dir.path().join("hooks.log");
+
+    fs::create_dir_all(stau_dir.join(".stau")).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_script(
+        &stau_dir.join(".stau/pre-command.sh"),
+        &format!(
+            "#!/bin/bash\necho \"$STAU_OPERATION $STAU_PHASE\" >> {}\n[ ! -e {} ]\n",
+            log.display(),
+            lock.display()
+        ),
+    );
+
+    fs::write(&lock, "").unwrap();
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "vim"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Command aborted"));
+    assert!(!target_dir.join(".vimrc").exists());
+
+    // Read-only commands don't run it
+    let output = run_stau_with_input(temp_dir.path(), &stau_dir, &target_dir, &["list"], "");
+    assert!(output.status.success());
+
+    fs::remove_file(&lock).unwrap();
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "vim"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".vimrc").is_symlink());
+    assert_eq!(
+        fs::read_to_string(&log).unwrap(),
+        "install pre\ninstall pre\n"
+    );
+}
+
+#[test]
+fn test_script_output_is_logged() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+    let state_dir = temp_dir.path().join("state");
+
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(&stau_dir, "vim", &[".vimrc"]);
+    create_script(
+        &stau_dir.join("vim/setup.sh"),
+        "#!/bin/bash\necho 'plugins installed'\necho 'one warning' >&2\n",
+    );
+
+    let stau = |args: &[&str]| {
+        stau_command(temp_dir.path())
+            .env("STAU_DIR", &stau_dir)
+            .env("STAU_TARGET", &target_dir)
+            .env("STAU_STATE_DIR", &state_dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = stau(&["log", "vim"]);
+    assert!(!output.status.success());
+
+    let output = stau(&["install", "vim"]);
+    assert!(output.status.success(), "install failed: {:?}", output);
+
+    let output = stau(&["log", "--scripts"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 1);
+    assert!(stdout.contains("vim"));
+    assert!(stdout.contains(&state_dir.join("logs/vim/setup-").display().to_string()));
+
+    let output = stau(&["log", "vim"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "== {} (exit code 0)\n-- stdout\nplugins installed\n-- stderr\none warning\n",
+            stau_dir.join("vim/setup.sh").display()
+        )
+    );
+}
+
+#[test]
+fn test_install_links_directories_at_max_depth() {
+    let temp_dir = TempDir::new().unwrap();
+    let stau_dir = temp_dir.path().join("dotfiles");
+    let target_dir = temp_dir.path().join("home");
+
+    fs::create_dir(&stau_dir).unwrap();
+    fs::create_dir(&target_dir).unwrap();
+    create_test_package(
+        &stau_dir,
+        "vim",
+        &[".vimrc", ".vim/plugged/fugitive/plugin.vim"],
+    );
+    create_test_package(&stau_dir, "node", &[".npm/lib/node_modules/x/index.js"]);
+    fs::write(stau_dir.join("node/stau.toml"), "max_depth = 1\n").unwrap();
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "--max-depth", "2", "vim"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".vimrc").is_symlink());
+    assert!(!target_dir.join(".vim").is_symlink());
+    assert!(target_dir.join(".vim/plugged").is_symlink());
+
+    // Later commands find the links at the depth the package was installed with
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["status", "vim"],
+        "",
+    );
+    assert!(output.status.success(), "status failed: {:?}", output);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("[conflict]"));
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "vim"],
+        "",
+    );
+    assert!(output.status.success(), "uninstall failed: {:?}", output);
+    assert!(!target_dir.join(".vim/plugged").is_symlink());
+    assert!(
+        target_dir
+            .join(".vim/plugged/fugitive/plugin.vim")
+            .is_file()
+    );
+
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["install", "node"],
+        "",
+    );
+    assert!(output.status.success(), "install failed: {:?}", output);
+    assert!(target_dir.join(".npm").is_symlink());
+
+    // Uninstalling copies the whole directory back
+    let output = run_stau_with_input(
+        temp_dir.path(),
+        &stau_dir,
+        &target_dir,
+        &["uninstall", "node"],
+        "",
+    );
+    assert!(output.status.success(), "uninstall failed: {:?}", output);
+    assert!(!target_dir.join(".npm").is_symlink());
+    assert!(
+        target_dir
+            .join(".npm/lib/node_modules/x/index.js")
+            .is_file()
+    );
+}